@@ -3,7 +3,7 @@ extern crate criterion;
 extern crate rand;
 
 use criterion::{BatchSize, Criterion, ParameterizedBenchmark};
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use kvs::{CompactionPolicy, Compression, Config, KvStore, KvsEngine, SledKvsEngine};
 use rand::prelude::*;
 use rand::rngs::SmallRng;
 use std::iter;
@@ -18,7 +18,7 @@ fn set_bench(c: &mut Criterion) {
                     let temp_dir = TempDir::new().unwrap();
                     (KvStore::open(temp_dir.path()).unwrap(), temp_dir)
                 },
-                |(mut store, _temp_dir)| {
+                |(store, _temp_dir)| {
                     for i in 1..(1 << 12) {
                         store.set(format!("key{}", i), "value".to_string()).unwrap();
                     }
@@ -34,7 +34,7 @@ fn set_bench(c: &mut Criterion) {
                 let temp_dir = TempDir::new().unwrap();
                 (SledKvsEngine::open(temp_dir.path()).unwrap(), temp_dir)
             },
-            |(mut db, _temp_dir)| {
+            |(db, _temp_dir)| {
                 for i in 1..(1 << 12) {
                     db.set(format!("key{}", i), "value".to_string()).unwrap();
                 }
@@ -50,7 +50,7 @@ fn get_bench(c: &mut Criterion) {
         "kvs",
         |b, i| {
             let temp_dir = TempDir::new().unwrap();
-            let mut store = KvStore::open(temp_dir.path()).unwrap();
+            let store = KvStore::open(temp_dir.path()).unwrap();
             for key_i in 1..(1 << i) {
                 store
                     .set(format!("key{}", key_i), "value".to_string())
@@ -67,7 +67,7 @@ fn get_bench(c: &mut Criterion) {
     )
     .with_function("sled", |b, i| {
         let temp_dir = TempDir::new().unwrap();
-        let mut db = SledKvsEngine::open(temp_dir.path()).unwrap();
+        let db = SledKvsEngine::open(temp_dir.path()).unwrap();
         for key_i in 1..(1 << i) {
             db.set(format!("key{}", key_i), "value".to_string())
                 .unwrap();
@@ -80,5 +80,149 @@ fn get_bench(c: &mut Criterion) {
     c.bench("get_bench", bench);
 }
 
-criterion_group!(benches, set_bench, get_bench);
+// Reads a small set of hot keys out of many, with and without the value cache
+fn cached_get_bench(c: &mut Criterion) {
+    let bench = ParameterizedBenchmark::new(
+        "kvs",
+        |b, capacity| {
+            let temp_dir = TempDir::new().unwrap();
+            let mut config = Config::builder();
+            if let Some(capacity) = capacity {
+                config = config.cache_capacity(*capacity);
+            }
+            let store =
+                KvStore::open_with_config(temp_dir.path(), config.build().unwrap()).unwrap();
+            for key_i in 1..(1 << 12) {
+                store
+                    .set(format!("key{}", key_i), "value".to_string())
+                    .unwrap();
+            }
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter(|| {
+                store
+                    .get(format!("key{}", rng.gen_range(1, 1 << 4)))
+                    .unwrap();
+            })
+        },
+        vec![None, Some(1 << 4)],
+    );
+    c.bench("cached_get_bench", bench);
+}
+
+// Looks up keys that were never set, with and without the Bloom filter
+fn missing_get_bench(c: &mut Criterion) {
+    let bench = ParameterizedBenchmark::new(
+        "kvs",
+        |b, rate| {
+            let temp_dir = TempDir::new().unwrap();
+            let mut config = Config::builder();
+            if let Some(rate) = rate {
+                config = config.bloom_false_positive_rate(*rate);
+            }
+            let store =
+                KvStore::open_with_config(temp_dir.path(), config.build().unwrap()).unwrap();
+            for key_i in 1..(1 << 12) {
+                store
+                    .set(format!("key{}", key_i), "value".to_string())
+                    .unwrap();
+            }
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter(|| {
+                store
+                    .get(format!("missing{}", rng.gen_range(1, 1 << 12)))
+                    .unwrap();
+            })
+        },
+        vec![None, Some(0.01)],
+    );
+    c.bench("missing_get_bench", bench);
+}
+
+// Sets highly compressible text values with and without compression, after printing how much
+// disk the same values take up with each
+fn compression_bench(c: &mut Criterion) {
+    let value = "the quick brown fox jumps over the lazy dog ".repeat(24);
+    let open = |compression| {
+        let temp_dir = TempDir::new().unwrap();
+        let config = Config::builder()
+            .compression(compression)
+            .filesize_limit(1 << 20)
+            .compaction_policy(CompactionPolicy::Manual)
+            .build()
+            .unwrap();
+        (
+            KvStore::open_with_config(temp_dir.path(), config).unwrap(),
+            temp_dir,
+        )
+    };
+    let compressions = vec![Compression::None, Compression::Deflate(6)];
+    for compression in &compressions {
+        let (store, _temp_dir) = open(*compression);
+        for key_i in 0..(1 << 10) {
+            store.set(format!("key{}", key_i), value.clone()).unwrap();
+        }
+        println!(
+            "{:?}: {} bytes on disk for {} bytes of values",
+            compression,
+            store.size_on_disk().unwrap().unwrap(),
+            value.len() << 10
+        );
+    }
+    let bench = ParameterizedBenchmark::new(
+        "kvs",
+        move |b, compression| {
+            b.iter_batched(
+                || open(*compression),
+                |(store, _temp_dir)| {
+                    for key_i in 0..(1 << 6) {
+                        store.set(format!("key{}", key_i), value.clone()).unwrap();
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        },
+        compressions,
+    );
+    c.bench("compression_bench", bench);
+}
+
+// Opens a store of 64k keys in 1 MiB log files with read buffers of a few sizes
+fn load_bench(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+    let config = |read_buffer_size| {
+        Config::builder()
+            .filesize_limit(1 << 20)
+            .compaction_policy(CompactionPolicy::Manual)
+            .read_buffer_size(read_buffer_size)
+            .build()
+            .unwrap()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config(8 << 10)).unwrap();
+    for key_i in 0..(1 << 16) {
+        store
+            .set(format!("key{}", key_i), "value".repeat(4))
+            .unwrap();
+    }
+    drop(store);
+    let bench = ParameterizedBenchmark::new(
+        "kvs",
+        move |b, read_buffer_size| {
+            b.iter(|| {
+                KvStore::open_with_config(temp_dir.path(), config(*read_buffer_size)).unwrap()
+            })
+        },
+        vec![4 << 10, 8 << 10, 64 << 10, 1 << 20],
+    );
+    c.bench("load_bench", bench);
+}
+
+criterion_group!(
+    benches,
+    set_bench,
+    get_bench,
+    cached_get_bench,
+    missing_get_bench,
+    compression_bench,
+    load_bench
+);
 criterion_main!(benches);
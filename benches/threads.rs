@@ -1,18 +1,24 @@
 #[macro_use]
 extern crate criterion;
 
-use criterion::{BenchmarkId, Criterion};
+use criterion::{Bencher, BenchmarkId, Criterion};
 
-use kvs::thread_pool::*;
-use kvs::{KvStore, KvsClient, KvsEngine, KvsServer, SledKvsEngine};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool, WorkStealingThreadPool};
+use kvs::{CompactionPolicy, Config, KvStore, KvsClient, KvsEngine, MemoryKvsEngine, ServerConfig};
 
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::{process, sync, thread, time};
+use std::{process, sync, thread};
 
 use assert_cmd::prelude::*;
-use num_cpus;
+use crossbeam_utils::sync::WaitGroup;
+use slog::FilterLevel;
 use tempfile::TempDir;
 
+#[path = "../tests/common/mod.rs"]
+mod common;
+use common::{spawn_test_server_with_config, wait_for_server};
+
+#[allow(dead_code)]
 fn write_queued_kvstore(c: &mut Criterion) {
     let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000);
     let mut num_threads: Vec<u32> = vec![1];
@@ -26,15 +32,16 @@ fn write_queued_kvstore(c: &mut Criterion) {
         let temp_dir = TempDir::new().unwrap();
         let mut child = process::Command::cargo_bin("kvs-server")
             .unwrap()
-            .args(&["--threads", &threads.to_string()])
+            .args(["--threads", &threads.to_string()])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         let handle = thread::spawn(move || {
             let _ = receiver.recv(); // wait for main thread to finish
             child.kill().expect("server exited before killed");
+            child.wait().expect("server was not running");
         });
-        thread::sleep(time::Duration::from_secs(1));
+        wait_for_server(socket);
         group.bench_function(BenchmarkId::from_parameter(threads), |b| {
             b.iter(|| {
                 let barrier = sync::Arc::new(sync::Barrier::new(11));
@@ -57,6 +64,7 @@ fn write_queued_kvstore(c: &mut Criterion) {
     group.finish();
 }
 
+#[allow(dead_code)]
 fn read_queued_kvstore(c: &mut Criterion) {
     let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000);
     let mut num_threads: Vec<u32> = vec![1];
@@ -69,15 +77,16 @@ fn read_queued_kvstore(c: &mut Criterion) {
         let temp_dir = TempDir::new().unwrap();
         let mut child = process::Command::cargo_bin("kvs-server")
             .unwrap()
-            .args(&["--threads", &threads.to_string()])
+            .args(["--threads", &threads.to_string()])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         let handle = thread::spawn(move || {
             let _ = receiver.recv(); // wait for main thread to finish
             child.kill().expect("server exited before killed");
+            child.wait().expect("server was not running");
         });
-        thread::sleep(time::Duration::from_secs(1));
+        wait_for_server(socket);
         for i in 0..10 {
             let mut client = KvsClient::new(socket).expect("Could not create client");
             client
@@ -120,15 +129,16 @@ fn write_rayon_kvstore(c: &mut Criterion) {
         let temp_dir = TempDir::new().unwrap();
         let mut child = process::Command::cargo_bin("kvs-server")
             .unwrap()
-            .args(&["--threads", &threads.to_string(), "--pool", "rayon"])
+            .args(["--threads", &threads.to_string(), "--pool", "rayon"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         let handle = thread::spawn(move || {
             let _ = receiver.recv(); // wait for main thread to finish
             child.kill().expect("server exited before killed");
+            child.wait().expect("server was not running");
         });
-        thread::sleep(time::Duration::from_secs(1));
+        wait_for_server(socket);
         group.bench_function(BenchmarkId::from_parameter(threads), |b| {
             b.iter(|| {
                 let barrier = sync::Arc::new(sync::Barrier::new(11));
@@ -151,5 +161,102 @@ fn write_rayon_kvstore(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, write_rayon_kvstore);
+// Runs a mix of fast gets and slow sets that trigger compaction, so jobs of very different
+// durations share the pool
+fn mixed_workload<P: ThreadPool>(b: &mut Bencher, pool: P) {
+    let temp_dir = TempDir::new().unwrap();
+    let config = Config::builder()
+        .filesize_limit(256)
+        .compaction_policy(CompactionPolicy::ByFileCount(2))
+        .build()
+        .unwrap();
+    let store = KvStore::open_with_config(temp_dir.path(), config).unwrap();
+    for i in 0..100 {
+        store.set(format!("key{}", i), "value".to_owned()).unwrap();
+    }
+    b.iter(|| {
+        let wg = WaitGroup::new();
+        for i in 0..100 {
+            let store = store.clone();
+            let wg = wg.clone();
+            pool.spawn(move || {
+                if i % 10 == 0 {
+                    for j in 0..20 {
+                        store
+                            .set(format!("key{}", j), format!("value{}", i))
+                            .unwrap();
+                    }
+                } else {
+                    store.get(format!("key{}", i)).unwrap();
+                }
+                drop(wg);
+            });
+        }
+        wg.wait();
+    });
+}
+
+fn mixed_workload_pools(c: &mut Criterion) {
+    let threads = num_cpus::get() as u32 * 2;
+    let mut group = c.benchmark_group("mixed_workload_pools");
+    group.bench_function("crossbeam", |b| {
+        mixed_workload(b, SharedQueueThreadPool::new(threads).unwrap())
+    });
+    group.bench_function("worksteal", |b| {
+        mixed_workload(b, WorkStealingThreadPool::new(threads).unwrap())
+    });
+    group.finish();
+}
+
+// Gets values of a few sizes from a server answering in JSON and in the binary format. Clients
+// on several threads keep the server busy, as they open a connection per get.
+fn get_response_formats(c: &mut Criterion) {
+    let engine = MemoryKvsEngine::new();
+    let config = ServerConfig {
+        log_level: FilterLevel::Warning,
+        ..ServerConfig::default()
+    };
+    let (_server, socket) = spawn_test_server_with_config(
+        engine.clone(),
+        SharedQueueThreadPool::new(4).unwrap(),
+        config,
+    );
+    let mut group = c.benchmark_group("get_response_formats");
+    for len in [16, 1 << 16] {
+        let key = format!("key{}", len);
+        engine.set(key.clone(), "v".repeat(len)).unwrap();
+        for binary in [false, true] {
+            let name = if binary { "binary" } else { "json" };
+            group.bench_function(BenchmarkId::new(name, len), |b| {
+                b.iter(|| {
+                    let clients: Vec<_> = (0..4)
+                        .map(|_| {
+                            let key = key.clone();
+                            thread::spawn(move || {
+                                for _ in 0..25 {
+                                    let mut client = KvsClient::new(socket).unwrap();
+                                    if binary {
+                                        client.enable_binary_gets().unwrap();
+                                    }
+                                    assert!(client.get(key.clone()).unwrap().is_some());
+                                }
+                            })
+                        })
+                        .collect();
+                    for client in clients {
+                        client.join().unwrap();
+                    }
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    write_rayon_kvstore,
+    mixed_workload_pools,
+    get_response_formats
+);
 criterion_main!(benches);
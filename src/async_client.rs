@@ -0,0 +1,186 @@
+use crate::async_server::MessageBuf;
+use crate::error::KvStoreError;
+use crate::kv::Result;
+use crate::network::{ClientRequest, ClientRequestType, Response};
+
+use std::fmt;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpStream, ToSocketAddrs};
+
+// How many bytes the client reads at a time while a response is incomplete
+const READ_CHUNK_SIZE: usize = 8 * 1024;
+
+/// KvsAsyncClient sends requests to KvsServer or AsyncKvsServer from async code. Requests are
+/// pipelined over one connection, which is reused until it fails or the client is dropped. A
+/// request that fails drops the connection, and the next one opens a new connection. KvsServer
+/// serves the connection from one of its pool threads for as long as it is open.
+pub struct KvsAsyncClient {
+    addrs: Vec<SocketAddr>,
+    conn: Option<Connection>,
+    seq: u64,
+    timeout: Option<Duration>,
+    trace_id: Option<String>,
+}
+
+// Connection is an open connection along with the bytes read past the last response
+struct Connection {
+    stream: TcpStream,
+    buf: MessageBuf,
+}
+
+impl KvsAsyncClient {
+    /// connect resolves addr, which may be a hostname, and connects to the first of its
+    /// addresses that accepts. Returns AddrResolveError if addr does not resolve to any address.
+    pub async fn connect(addr: impl ToSocketAddrs + fmt::Debug) -> Result<Self> {
+        let addrs: Vec<SocketAddr> = match lookup_host(&addr).await {
+            Ok(addrs) => addrs.collect(),
+            Err(_) => Vec::new(),
+        };
+        if addrs.is_empty() {
+            return Err(KvStoreError::AddrResolveError {
+                addr: format!("{:?}", addr),
+            });
+        }
+        let mut client = KvsAsyncClient {
+            addrs,
+            conn: None,
+            seq: 0,
+            timeout: None,
+            trace_id: None,
+        };
+        client.conn = Some(client.open().await?);
+        Ok(client)
+    }
+
+    /// set_timeout sets how long a request may take, from connecting if needed to reading the
+    /// response, before it fails with a TimedOut IoError. None waits as long as it takes.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// set_trace_id sets the id of the client trace sent with every request, so the server spans
+    /// of the requests link to it. None stops sending one.
+    pub fn set_trace_id(&mut self, trace_id: Option<String>) {
+        self.trace_id = trace_id;
+    }
+
+    /// set sends a set request to the server
+    pub async fn set(&mut self, key: String, value: String) -> Result<String> {
+        let resp = self.send(ClientRequestType::Set, key, value).await?;
+        Ok(resp.value)
+    }
+
+    /// get sends a get request to the server
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        let resp = self
+            .send(ClientRequestType::Get, key, String::new())
+            .await?;
+        // Servers that don't send exists with gets can't tell an empty value from a missing key
+        if !resp.exists && resp.value.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(resp.value))
+    }
+
+    /// get_or sends a get request to the server and returns default if the key does not exist. A
+    /// key set to the empty string gets the empty string.
+    pub async fn get_or(&mut self, key: String, default: String) -> Result<String> {
+        Ok(self.get(key).await?.unwrap_or(default))
+    }
+
+    /// remove sends a remove request to the server
+    pub async fn remove(&mut self, key: String) -> Result<String> {
+        let resp = self.send(ClientRequestType::Rm, key, String::new()).await?;
+        Ok(resp.value)
+    }
+
+    // Sends a request within the timeout and returns its response, or ServerError if the server
+    // answered with an error
+    async fn send(
+        &mut self,
+        command_type: ClientRequestType,
+        key: String,
+        value: String,
+    ) -> Result<Response> {
+        let req = ClientRequest {
+            command_type,
+            key,
+            value,
+            batch: Vec::new(),
+            keys: Vec::new(),
+            trace_id: self.trace_id.clone(),
+            seq: Some(self.seq),
+            request_id: None,
+        };
+        self.seq += 1;
+        let resp = match self.timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, self.round_trip(&req)).await {
+                Ok(resp) => resp,
+                Err(_) => Err(std::io::Error::from(ErrorKind::TimedOut).into()),
+            },
+            None => self.round_trip(&req).await,
+        };
+        // The connection may be in the middle of a request or response, so it is not reused
+        let resp = resp.inspect_err(|_| self.conn = None)?;
+        if !resp.error.is_empty() {
+            return Err(resp.into_error());
+        }
+        Ok(resp)
+    }
+
+    // Writes req to the connection, opening one if there is none, and reads its response. A
+    // reused connection the server closed while it was idle is replaced once, since the server
+    // did not see the request.
+    async fn round_trip(&mut self, req: &ClientRequest) -> Result<Response> {
+        if let Some(conn) = self.conn.take() {
+            match self.exchange(conn, req).await {
+                Err(KvStoreError::IoError { error })
+                    if matches!(
+                        error.kind(),
+                        ErrorKind::UnexpectedEof
+                            | ErrorKind::BrokenPipe
+                            | ErrorKind::ConnectionReset
+                    ) => {}
+                resp => return resp,
+            }
+        }
+        let conn = self.open().await?;
+        self.exchange(conn, req).await
+    }
+
+    // Writes req to conn and reads its response, keeping conn for the next request if it worked
+    async fn exchange(&mut self, mut conn: Connection, req: &ClientRequest) -> Result<Response> {
+        conn.stream.write_all(&serde_json::to_vec(req)?).await?;
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        let resp: Response = loop {
+            if let Some(resp) = conn.buf.take().await? {
+                break resp;
+            }
+            let read = conn.stream.read(&mut chunk).await?;
+            if read == 0 {
+                return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+            }
+            conn.buf.extend(&chunk[..read]);
+        };
+        if resp.seq != req.seq {
+            return Err(KvStoreError::ServerError {
+                error: format!("Got response {:?} to request {:?}", resp.seq, req.seq),
+            });
+        }
+        self.conn = Some(conn);
+        Ok(resp)
+    }
+
+    // Connects to the first address of the server that accepts
+    async fn open(&self) -> Result<Connection> {
+        let stream = TcpStream::connect(&self.addrs[..]).await?;
+        stream.set_nodelay(true)?;
+        Ok(Connection {
+            stream,
+            buf: MessageBuf::default(),
+        })
+    }
+}
@@ -0,0 +1,478 @@
+use crate::cache::Reserved;
+use crate::config::ServerConfig;
+use crate::engine::KvsEngine;
+use crate::error::KvStoreError;
+use crate::kv::Result;
+use crate::network::{encode_binary, ClientRequest, ClientRequestType, Response};
+#[cfg(feature = "metrics")]
+use crate::server::serve_engine_metrics;
+use crate::server::{
+    auth_response, bad_request, handle_request, new_logger, request_digest, reused,
+    select_protocol, subscribe, throttled, too_large, unauthenticated, Context,
+    ServerMetricsSnapshot, TokenBucket,
+};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+
+// How many bytes a connection reads at a time while a request is incomplete
+const READ_CHUNK_SIZE: usize = 8 * 1024;
+// Messages this large are parsed on a blocking thread so they don't hold up the other tasks
+const BLOCKING_PARSE_SIZE: usize = 64 * 1024;
+
+/// AsyncKvsServer serves the same protocol as KvsServer on a tokio runtime. Connections are
+/// handled by tasks instead of pool threads, and the engine calls run on the blocking threads of
+/// the runtime since KvsEngine is sync.
+pub struct AsyncKvsServer<E: KvsEngine + Clone> {
+    listener: std::net::TcpListener,
+    ctx: Context,
+    db: E,
+    #[cfg(feature = "metrics")]
+    metrics_addr: Option<SocketAddr>,
+    max_connections: Option<usize>,
+}
+
+impl<E: KvsEngine + Clone> AsyncKvsServer<E> {
+    /// Instantiates new AsyncKvsServer with log and db engine. Like KvsServer, the listener is
+    /// bound to socket right away.
+    pub fn new(socket: SocketAddr, engine_name: &str, engine: E) -> Result<Self> {
+        AsyncKvsServer::with_config(socket, engine_name, engine, ServerConfig::default())
+    }
+
+    /// Instantiates new AsyncKvsServer with the given config instead of the default one
+    pub fn with_config(
+        socket: SocketAddr,
+        engine_name: &str,
+        engine: E,
+        config: ServerConfig,
+    ) -> Result<Self> {
+        let log = new_logger(&config)?;
+        let ctx = Context::new(&config, log)?;
+        let listener = std::net::TcpListener::bind(socket)?;
+        // tokio needs the listener to be non-blocking to take it over
+        listener.set_nonblocking(true)?;
+
+        info!(ctx.log, "{}", env!("CARGO_PKG_VERSION"));
+        info!(ctx.log, "{}", listener.local_addr()?);
+        info!(ctx.log, "{}", engine_name);
+
+        Ok(AsyncKvsServer {
+            listener,
+            ctx,
+            db: engine,
+            #[cfg(feature = "metrics")]
+            metrics_addr: config.metrics_addr,
+            max_connections: config.max_connections,
+        })
+    }
+
+    /// metrics returns the request counters of the server so far
+    pub fn metrics(&self) -> ServerMetricsSnapshot {
+        self.ctx.server_metrics.snapshot()
+    }
+
+    /// local_addr returns the address the server listens on, which has the port the OS picked if
+    /// the server was created with port 0
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Starts AsyncKvsServer on a new multi-threaded runtime and accepts connections until
+    /// accepting fails
+    pub fn start(self) -> Result<()> {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?
+            .block_on(self.serve())
+    }
+
+    /// serve accepts connections on the current runtime until accepting fails. Dropping the
+    /// future stops accepting, but connections already accepted are served to the end.
+    pub async fn serve(self) -> Result<()> {
+        #[cfg(feature = "metrics")]
+        if let Some(addr) = self.metrics_addr {
+            serve_engine_metrics(addr, &self.db, &self.ctx)?;
+        }
+
+        let listener = TcpListener::from_std(self.listener)?;
+        let slots = self
+            .max_connections
+            .map(|max| Arc::new(Semaphore::new(max)));
+        loop {
+            // New connections wait in the listen backlog until a slot frees up
+            let permit = match &slots {
+                Some(slots) => Some(slots.clone().acquire_owned().await.unwrap()),
+                None => None,
+            };
+            let (stream, _) = listener.accept().await?;
+            let db = self.db.clone();
+            let ctx = self.ctx.clone();
+            tokio::spawn(async move {
+                #[cfg(feature = "metrics")]
+                ctx.metrics.connections.inc();
+                if let Err(e) = process_cmd(db, stream, &ctx).await {
+                    error!(ctx.log, "{}", e.to_string());
+                }
+                #[cfg(feature = "metrics")]
+                ctx.metrics.connections.dec();
+                drop(permit);
+            });
+        }
+    }
+}
+
+async fn process_cmd<E: KvsEngine + Clone>(
+    db: E,
+    mut stream: TcpStream,
+    ctx: &Context,
+) -> Result<()> {
+    let peer = stream.peer_addr().ok();
+    let mut buf = MessageBuf::default();
+    let mut cmd = match read_request(&mut stream, &mut buf, peer, ctx).await? {
+        Some(cmd) => cmd,
+        None => return Ok(()),
+    };
+    if cmd.command_type == ClientRequestType::Auth {
+        let resp = auth_response(&cmd.value, peer, ctx);
+        respond(&mut stream, &resp, ctx).await?;
+        if !resp.error.is_empty() {
+            return Ok(());
+        }
+        cmd = match read_request(&mut stream, &mut buf, peer, ctx).await? {
+            Some(cmd) => cmd,
+            None => return Ok(()),
+        };
+    } else if ctx.auth_token.is_some() {
+        respond(&mut stream, &unauthenticated(peer, ctx), ctx).await?;
+        return Ok(());
+    }
+    let mut binary = false;
+    if cmd.command_type == ClientRequestType::Protocol {
+        let resp = select_protocol(&cmd.value, &mut binary);
+        respond(&mut stream, &resp, ctx).await?;
+        if !resp.error.is_empty() {
+            return Ok(());
+        }
+        cmd = match read_request(&mut stream, &mut buf, peer, ctx).await? {
+            Some(cmd) => cmd,
+            None => return Ok(()),
+        };
+    }
+    let mut limiter = ctx.rate_limit.map(TokenBucket::new);
+    if cmd.seq.is_some() {
+        return serve_pipelined(db, stream, buf, cmd, limiter, peer, ctx).await;
+    }
+    match cmd.command_type {
+        // A batch with a request id is answered from the response cache like a single request
+        ClientRequestType::Batch => {
+            let mut reservation = None;
+            if let Some(id) = cmd.request_id.take() {
+                let (responses, digest, reserved_id) =
+                    (ctx.responses.clone(), request_digest(&cmd), id.clone());
+                // A retry waits for the batch it retries to be answered, which blocks
+                let reserve = move || responses.reserve(reserved_id, digest);
+                match tokio::task::spawn_blocking(reserve).await.unwrap() {
+                    Reserved::New(new) => reservation = Some(new),
+                    Reserved::Done(resps) => return respond(&mut stream, &resps, ctx).await,
+                    Reserved::Reused => return respond(&mut stream, &[reused(id)], ctx).await,
+                }
+            }
+            let mut resps = Vec::with_capacity(cmd.batch.len());
+            for cmd in cmd.batch {
+                resps.push(join(spawn_request(&db, cmd, &mut limiter, peer, ctx)).await);
+            }
+            if let Some(reservation) = reservation {
+                reservation.answer(resps.clone());
+            }
+            respond(&mut stream, &resps, ctx).await?;
+        }
+        // A subscription writes events as the engine sends them, so it keeps a blocking thread
+        // for as long as it lasts
+        ClientRequestType::Subscribe => {
+            let stream = stream.into_std()?;
+            stream.set_nonblocking(false)?;
+            let ctx = ctx.clone();
+            tokio::task::spawn_blocking(move || subscribe(&db, stream, cmd.key, &ctx))
+                .await
+                .unwrap()?;
+        }
+        ClientRequestType::Get if binary => {
+            let resp = join(spawn_request(&db, cmd, &mut limiter, peer, ctx)).await;
+            write_counted(&mut stream, &encode_binary(&resp), ctx).await?;
+        }
+        _ => {
+            let resp = join(spawn_request(&db, cmd, &mut limiter, peer, ctx)).await;
+            respond(&mut stream, &resp, ctx).await?;
+        }
+    }
+    Ok(())
+}
+
+// Serves the requests of a pipelined connection, starting with first, until the client closes its
+// side of the connection. Every request runs as its own blocking task, and the responses are
+// written in the order the requests came in while later requests are still being read.
+async fn serve_pipelined<E: KvsEngine + Clone>(
+    db: E,
+    mut stream: TcpStream,
+    mut buf: MessageBuf,
+    first: ClientRequest,
+    mut limiter: Option<TokenBucket>,
+    peer: Option<SocketAddr>,
+    ctx: &Context,
+) -> Result<()> {
+    let mut pending = VecDeque::new();
+    let mut next = Some(first);
+    let mut reading = true;
+    while reading || !pending.is_empty() {
+        if let Some(cmd) = next.take() {
+            let seq = cmd.seq;
+            let command_type = cmd.command_type;
+            let task = match command_type {
+                // These take over the connection or answer with more than one response
+                ClientRequestType::Batch
+                | ClientRequestType::Subscribe
+                | ClientRequestType::Auth => tokio::spawn(std::future::ready(Response {
+                    error: format!("{:?} requests can't be pipelined", command_type),
+                    ..Response::default()
+                })),
+                _ => spawn_request(&db, cmd, &mut limiter, peer, ctx),
+            };
+            pending.push_back((seq, task));
+        }
+        tokio::select! {
+            // Reading is cancelled whenever the oldest response is ready first, which loses
+            // nothing since every byte read is kept in buf
+            cmd = read_request(&mut stream, &mut buf, peer, ctx), if reading => match cmd? {
+                Some(cmd) => next = Some(cmd),
+                None => reading = false,
+            },
+            resp = next_response(&mut pending), if !pending.is_empty() => {
+                respond(&mut stream, &resp, ctx).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Waits for the oldest request of a pipelined connection to finish and returns its response
+async fn next_response(pending: &mut VecDeque<(Option<u64>, JoinHandle<Response>)>) -> Response {
+    let (seq, task) = pending.front_mut().unwrap();
+    let resp = join(task).await;
+    let seq = *seq;
+    pending.pop_front();
+    Response { seq, ..resp }
+}
+
+// Runs cmd on a blocking thread unless it is over the rate limit of the connection, and counts it
+// once it is answered
+fn spawn_request<E: KvsEngine + Clone>(
+    db: &E,
+    cmd: ClientRequest,
+    limiter: &mut Option<TokenBucket>,
+    peer: Option<SocketAddr>,
+    ctx: &Context,
+) -> JoinHandle<Response> {
+    let command_type = cmd.command_type;
+    if !limiter.as_mut().is_none_or(TokenBucket::take) {
+        let resp = throttled(peer, ctx);
+        ctx.server_metrics.record(command_type, &resp);
+        return tokio::spawn(std::future::ready(resp));
+    }
+    let db = db.clone();
+    let ctx = ctx.clone();
+    tokio::task::spawn_blocking(move || {
+        let resp = handle_request(&db, cmd, &ctx);
+        ctx.server_metrics.record(command_type, &resp);
+        resp
+    })
+}
+
+// Waits for the response of a task, answering with an error if the task panicked
+async fn join<F>(task: F) -> Response
+where
+    F: std::future::Future<Output = std::result::Result<Response, tokio::task::JoinError>>,
+{
+    task.await.unwrap_or_else(|_| Response {
+        error: "Request failed with a panic".to_owned(),
+        ..Response::default()
+    })
+}
+
+// Reads the next request from stream, keeping any bytes read past its end in buf for the next
+// one. Returns None if the client closed the connection or left it idle for longer than the idle
+// timeout, or if the request was too large or malformed, in which case the client is sent an error.
+async fn read_request<S>(
+    stream: &mut S,
+    buf: &mut MessageBuf,
+    peer: Option<SocketAddr>,
+    ctx: &Context,
+) -> Result<Option<ClientRequest>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let max = ctx.max_request_size.unwrap_or(u64::MAX);
+    let mut chunk = [0; READ_CHUNK_SIZE];
+    loop {
+        match buf.take().await {
+            Ok(Some(cmd)) => return Ok(Some(cmd)),
+            Ok(None) => {}
+            Err(KvStoreError::SerdeError { error }) => {
+                respond(stream, &bad_request(peer, &error, ctx), ctx).await?;
+                // The rest of the stream can't be read as requests once one of them is malformed
+                stream.shutdown().await?;
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        }
+        let started = buf.started();
+        if started && buf.len() as u64 >= max {
+            respond(stream, &too_large(peer, ctx), ctx).await?;
+            // Closing the write side first gets the response to the client even though the rest
+            // of the request is left unread
+            stream.shutdown().await?;
+            return Ok(None);
+        }
+        let read = match ctx.idle_timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, stream.read(&mut chunk)).await {
+                Ok(read) => read?,
+                Err(_) => {
+                    warn!(ctx.log, "dropped idle connection"; "peer" => ?peer);
+                    return Ok(None);
+                }
+            },
+            None => stream.read(&mut chunk).await?,
+        };
+        if read == 0 {
+            if started {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+            return Ok(None);
+        }
+        buf.extend(&chunk[..read]);
+    }
+}
+
+// MessageBuf holds the bytes read from a connection until they make up a whole JSON message,
+// along with any bytes of the messages after it. The first message is only parsed once all of it
+// has been read, and the scan for its end picks up where the last one stopped, so each byte is
+// looked at once however many reads the message takes.
+#[derive(Default)]
+pub(crate) struct MessageBuf {
+    bytes: Vec<u8>,
+    // How far bytes has been scanned for the end of the first message
+    scanned: usize,
+    // Nesting depth of the first message at scanned, 0 if it has not started
+    depth: usize,
+    // Whether scanned is inside a string, and right after a backslash in it
+    in_string: bool,
+    escaped: bool,
+    // End of the first message, once it has been found
+    end: Option<usize>,
+}
+
+impl MessageBuf {
+    pub(crate) fn extend(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    // Returns whether the first message has started, as opposed to the buffer holding nothing
+    // but the whitespace between messages
+    pub(crate) fn started(&self) -> bool {
+        self.depth > 0 || self.end.is_some()
+    }
+
+    // Parses the first message and takes it off the buffer if all of it has been read. Returns
+    // None if the message is still incomplete. Cancelling leaves the buffer as it was.
+    pub(crate) async fn take<T: DeserializeOwned + Send + 'static>(&mut self) -> Result<Option<T>> {
+        let end = match self.message_end()? {
+            Some(end) => end,
+            None => return Ok(None),
+        };
+        let message = if end < BLOCKING_PARSE_SIZE {
+            serde_json::from_slice(&self.bytes[..end])?
+        } else {
+            let bytes = self.bytes[..end].to_vec();
+            tokio::task::spawn_blocking(move || serde_json::from_slice(&bytes))
+                .await
+                .unwrap()?
+        };
+        // A whole message leaves the scan outside of any string, at depth 0
+        self.bytes.drain(..end);
+        self.scanned = 0;
+        self.end = None;
+        Ok(Some(message))
+    }
+
+    // Scans the bytes not scanned yet for the end of the first message, which must be a JSON
+    // object or array. Strings are skipped so brackets inside them don't count.
+    fn message_end(&mut self) -> Result<Option<usize>> {
+        if self.end.is_some() {
+            return Ok(self.end);
+        }
+        for (pos, &b) in self.bytes.iter().enumerate().skip(self.scanned) {
+            if self.in_string {
+                match b {
+                    _ if self.escaped => self.escaped = false,
+                    b'\\' => self.escaped = true,
+                    b'"' => self.in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            match b {
+                b'"' if self.depth > 0 => self.in_string = true,
+                b'{' | b'[' => self.depth += 1,
+                b'}' | b']' if self.depth > 0 => {
+                    self.depth -= 1;
+                    if self.depth == 0 {
+                        self.end = Some(pos + 1);
+                        return Ok(self.end);
+                    }
+                }
+                _ if self.depth > 0 || b.is_ascii_whitespace() => {}
+                _ => {
+                    let error: serde_json::Error = serde::de::Error::custom(format!(
+                        "expected a JSON object or array at byte {}",
+                        pos
+                    ));
+                    return Err(error.into());
+                }
+            }
+        }
+        self.scanned = self.bytes.len();
+        Ok(None)
+    }
+}
+
+// Writes value to stream and counts it towards the bytes served
+async fn respond<S, T>(stream: &mut S, value: &T, ctx: &Context) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    write_counted(stream, &serde_json::to_vec(value)?, ctx).await
+}
+
+// Writes buf to stream and counts it towards the bytes served
+async fn write_counted<S>(stream: &mut S, buf: &[u8], ctx: &Context) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    stream.write_all(buf).await?;
+    ctx.server_metrics
+        .bytes_served
+        .fetch_add(buf.len() as u64, Ordering::Relaxed);
+    Ok(())
+}
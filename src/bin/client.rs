@@ -1,24 +1,49 @@
 #[macro_use]
 extern crate clap;
 
-use clap::App;
-use kvs::{KvsClient, Result};
-use std::env;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use clap::{App, ArgMatches};
+use kvs::{ClientRequest, ClientRequestType, KvsClient, Result, StatsReport};
+use serde_json::json;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::{env, fmt, process};
 
-fn main() -> Result<()> {
+// The address requests are sent to when none is given
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+
+fn main() {
     let yaml = load_yaml!("client.yml");
     let app = App::from_yaml(yaml);
     app.name(env!("CARGO_PKG_NAME"));
 
     let matches = App::from_yaml(yaml).get_matches();
+    let json_output = matches.value_of("format") == Some("json");
 
-    let socket = match matches.value_of("addr") {
-        Some(v) => v.parse()?,
-        None => SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000),
+    if let Err(e) = run(&matches, json_output) {
+        print_error(e, json_output);
+        process::exit(1);
+    }
+}
+
+fn run(matches: &ArgMatches, json_output: bool) -> Result<()> {
+    let server = Server {
+        addr: matches.value_of("addr").unwrap_or(DEFAULT_ADDR).to_owned(),
+        auth_token: matches.value_of("auth-token").map(str::to_owned),
     };
 
-    let mut client = KvsClient::new(socket)?;
+    match matches.subcommand() {
+        ("repl", Some(_)) => return repl(&server, json_output),
+        ("load", Some(matches)) => {
+            let input: Box<dyn BufRead> = match matches.value_of("FILE").unwrap_or("-") {
+                "-" => Box::new(BufReader::new(io::stdin())),
+                path => Box::new(BufReader::new(File::open(path)?)),
+            };
+            return load(&server, input, json_output);
+        }
+        _ => {}
+    }
+
+    let mut client = server.connect()?;
 
     match matches.subcommand() {
         ("set", Some(matches)) => {
@@ -30,11 +55,20 @@ fn main() -> Result<()> {
         ("get", Some(matches)) => {
             let key = matches.value_of("KEY").unwrap();
             let result = client.get(key.to_owned())?;
-            if let Some(v) = result {
-                println!("{}", v);
-            } else {
-                println!("Key not found");
-            }
+            print_get(key, result, json_output);
+            Ok(())
+        }
+        ("exists", Some(matches)) => {
+            let key = matches.value_of("KEY").unwrap();
+            let exists = client.exists(key.to_owned())?;
+            print_exists(key, exists, json_output);
+            Ok(())
+        }
+        ("scan", Some(matches)) => {
+            let start = matches.value_of("START").unwrap();
+            let end = matches.value_of("END").unwrap_or("");
+            let pairs = client.scan(start.to_owned(), end.to_owned())?;
+            print_scan(pairs, json_output);
             Ok(())
         }
         ("rm", Some(matches)) => {
@@ -45,3 +79,210 @@ fn main() -> Result<()> {
         _ => unreachable!(),
     }
 }
+
+// Server is where requests are sent and how connections authenticate. The address is resolved on
+// every connect, and each address it resolves to is tried until one connects.
+struct Server {
+    addr: String,
+    auth_token: Option<String>,
+}
+
+impl Server {
+    fn connect(&self) -> Result<KvsClient> {
+        match &self.auth_token {
+            Some(token) => KvsClient::with_auth_token(self.addr.as_str(), token.clone()),
+            None => KvsClient::connect(self.addr.as_str()),
+        }
+    }
+}
+
+// repl runs one command per line of stdin until EOF. Blank lines and lines starting with # are
+// skipped. Errors are printed and do not end the repl.
+fn repl(server: &Server, json_output: bool) -> Result<()> {
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Err(e) = run_line(server, line, json_output) {
+            print_error(e, json_output);
+        }
+    }
+    Ok(())
+}
+
+// The server handles a single request per connection, so every line connects again
+fn run_line(server: &Server, line: &str, json_output: bool) -> Result<()> {
+    let (cmd, rest) = split_word(line);
+    // The value of a set is the rest of the line, so it may contain spaces
+    let (key, value) = split_word(rest);
+    match (cmd, key, value) {
+        ("get", key, "") if !key.is_empty() => {
+            let result = server.connect()?.get(key.to_owned())?;
+            print_get(key, result, json_output);
+        }
+        ("exists", key, "") if !key.is_empty() => {
+            let exists = server.connect()?.exists(key.to_owned())?;
+            print_exists(key, exists, json_output);
+        }
+        ("set", key, value) if !key.is_empty() && !value.is_empty() => {
+            server.connect()?.set(key.to_owned(), value.to_owned())?;
+        }
+        ("rm", key, "") if !key.is_empty() => {
+            server.connect()?.remove(key.to_owned())?;
+        }
+        ("scan", start, end) if !end.contains(char::is_whitespace) => {
+            let pairs = server.connect()?.scan(start.to_owned(), end.to_owned())?;
+            print_scan(pairs, json_output);
+        }
+        ("stats", "", "") => {
+            let report = server.connect()?.stats()?;
+            print_stats(&report, json_output)?;
+        }
+        _ => print_error(format!("Unknown command: {}", line), json_output),
+    }
+    Ok(())
+}
+
+// split_word splits s into its first word and the rest, without surrounding whitespace
+fn split_word(s: &str) -> (&str, &str) {
+    let s = s.trim();
+    match s.find(char::is_whitespace) {
+        Some(i) => (&s[..i], s[i..].trim()),
+        None => (s, ""),
+    }
+}
+
+// Operations are sent to the server in batches of this size
+const LOAD_BATCH_SIZE: usize = 1000;
+
+// load sends the set and rm lines of input to the server in batches and reports how many were
+// applied. Blank lines and lines starting with # are skipped.
+fn load(server: &Server, input: Box<dyn BufRead>, json_output: bool) -> Result<()> {
+    let (mut applied, mut failed) = (0, 0);
+    let mut batch = Vec::with_capacity(LOAD_BATCH_SIZE);
+    for line in input.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (cmd, rest) = split_word(line);
+        let (key, value) = split_word(rest);
+        let command_type = match (cmd, value.is_empty()) {
+            ("set", false) => ClientRequestType::Set,
+            ("rm", true) if !key.is_empty() => ClientRequestType::Rm,
+            _ => {
+                print_error(format!("Unknown command: {}", line), json_output);
+                failed += 1;
+                continue;
+            }
+        };
+        batch.push(ClientRequest {
+            command_type,
+            key: key.to_owned(),
+            value: value.to_owned(),
+            batch: Vec::new(),
+            keys: Vec::new(),
+            trace_id: None,
+            seq: None,
+            request_id: None,
+        });
+        if batch.len() == LOAD_BATCH_SIZE {
+            send_batch(server, &mut batch, &mut applied, &mut failed, json_output)?;
+        }
+    }
+    if !batch.is_empty() {
+        send_batch(server, &mut batch, &mut applied, &mut failed, json_output)?;
+    }
+    if json_output {
+        println!("{}", json!({ "applied": applied, "failed": failed }));
+    } else {
+        println!("Applied {} operations, {} failed", applied, failed);
+    }
+    Ok(())
+}
+
+fn send_batch(
+    server: &Server,
+    batch: &mut Vec<ClientRequest>,
+    applied: &mut u64,
+    failed: &mut u64,
+    json_output: bool,
+) -> Result<()> {
+    for resp in server.connect()?.batch(std::mem::take(batch))? {
+        if resp.error.is_empty() {
+            *applied += 1;
+        } else {
+            print_error(resp.error, json_output);
+            *failed += 1;
+        }
+    }
+    Ok(())
+}
+
+fn print_get(key: &str, result: Option<String>, json_output: bool) {
+    if json_output {
+        let output = match result {
+            Some(v) => json!({ "key": key, "value": v, "found": true }),
+            None => json!({ "key": key, "value": null, "found": false }),
+        };
+        println!("{}", output);
+    } else if let Some(v) = result {
+        println!("{}", v);
+    } else {
+        println!("Key not found");
+    }
+}
+
+fn print_exists(key: &str, exists: bool, json_output: bool) {
+    if json_output {
+        println!("{}", json!({ "key": key, "exists": exists }));
+    } else {
+        println!("{}", exists);
+    }
+}
+
+fn print_scan(pairs: Vec<(String, String)>, json_output: bool) {
+    if json_output {
+        let output: Vec<_> = pairs
+            .iter()
+            .map(|(key, value)| json!({ "key": key, "value": value }))
+            .collect();
+        println!("{}", json!(output));
+    } else {
+        for (key, value) in pairs {
+            println!("{} {}", key, value);
+        }
+    }
+}
+
+fn print_stats(report: &StatsReport, json_output: bool) -> Result<()> {
+    if json_output {
+        println!("{}", serde_json::to_string(report)?);
+        return Ok(());
+    }
+    println!("keys {}", report.keys);
+    println!("compactions {}", report.compactions);
+    if let Some(disk_bytes) = report.disk_bytes {
+        println!("disk_bytes {}", disk_bytes);
+    }
+    let server = &report.server;
+    println!("requests {}", server.requests);
+    println!("gets {}", server.gets);
+    println!("sets {}", server.sets);
+    println!("removes {}", server.removes);
+    println!("errors {}", server.errors);
+    println!("bytes_served {}", server.bytes_served);
+    Ok(())
+}
+
+fn print_error(error: impl fmt::Display, json_output: bool) {
+    if json_output {
+        eprintln!("{}", json!({ "error": error.to_string() }));
+    } else {
+        eprintln!("{}", error);
+    }
+}
@@ -2,116 +2,102 @@
 extern crate clap;
 
 use clap::App;
-use kvs::thread_pool::*;
-use kvs::{KvStore, KvsEngine, KvsServer, Result, SledKvsEngine};
-use num_cpus;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::{env, fs, process};
+use kvs::thread_pool::PoolKind;
+use kvs::{resolve_addr, resolve_engine, run_server, KvStoreError, Result, ServerConfig};
+use std::path::PathBuf;
+use std::time::Duration;
+use std::{env, process};
+
+// The address served when none is given
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+
+// Every error ends the server with exit code 1 and its message on stderr, rather than its Debug
+// form, so typed errors such as EngineMismatch read the same as they do from the library
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<()> {
+    // Request spans are written to stderr as they close, with their timings
+    #[cfg(feature = "tracing")]
+    tracing_subscriber::fmt()
+        .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::IsTerminal::is_terminal(&std::io::stderr()))
+        .init();
 
-fn main() -> Result<()> {
     let yaml = load_yaml!("server.yml");
     let matches = App::from_yaml(yaml)
         .name(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
         .get_matches();
 
-    let socket = match matches.value_of("addr") {
-        Some(v) => v.parse()?,
-        None => SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000),
-    };
+    let socket = resolve_addr(matches.value_of("addr").unwrap_or(DEFAULT_ADDR))?;
 
     let curr_dir = env::current_dir()?;
-    let path = curr_dir.join("engine");
-    let mut engine = "";
-    fs::create_dir_all(&path)?;
-    if path.join("kvs").exists() {
-        engine = "kvs";
-    } else if path.join("sled").exists() {
-        engine = "sled";
-    }
-
-    match matches.value_of("engine") {
-        Some(v) => {
-            if engine == "" {
-                engine = v;
-            } else if engine != v {
-                eprintln!("Selected engine does not match previous data");
-                process::exit(1);
-            }
-        }
-        None => {
-            if engine == "" {
-                engine = "kvs";
-            }
-        }
-    }
-
-    fs::OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .create(true)
-        .open(path.join(engine))?;
+    let requested = match matches.value_of("engine") {
+        Some(v) => Some(v.parse()?),
+        None => None,
+    };
+    let engine = resolve_engine(&curr_dir, requested)?;
 
     let num_threads = match matches.value_of("threads") {
         Some(v) => v.parse::<u32>()?,
         None => num_cpus::get() as u32,
     };
-    println!("num_threads: {}", num_threads);
+    eprintln!("num_threads: {}", num_threads);
 
-    let pool = match matches.value_of("pool") {
-        Some(v) => v,
-        None => "crossbeam",
+    let pool: PoolKind = matches.value_of("pool").unwrap_or("crossbeam").parse()?;
+    let log_level = matches.value_of("log-level").unwrap_or("info");
+    let config = ServerConfig {
+        log_format: matches.value_of("log-format").unwrap_or("term").parse()?,
+        log_level: log_level
+            .parse()
+            .map_err(|_| KvStoreError::InvalidConfigError {
+                reason: format!("unknown log level: {}", log_level),
+            })?,
+        metrics_addr: match matches.value_of("metrics-addr") {
+            Some(v) => Some(resolve_addr(v)?),
+            None => None,
+        },
+        max_connections: match matches.value_of("max-connections") {
+            Some(v) => Some(v.parse()?),
+            None => None,
+        },
+        max_request_size: match matches.value_of("max-request-size") {
+            Some(v) => Some(v.parse()?),
+            None => ServerConfig::default().max_request_size,
+        },
+        idle_timeout: match matches.value_of("idle-timeout") {
+            Some(v) => Some(Duration::from_secs(v.parse()?)),
+            None => None,
+        },
+        auth_token: matches.value_of("auth-token").map(str::to_owned),
+        rate_limit: match matches.value_of("rate-limit") {
+            Some(v) => Some(v.parse()?),
+            None => None,
+        },
+        log_file: matches.value_of("log-file").map(PathBuf::from),
+        log_max_size: match matches.value_of("log-max-size") {
+            Some(v) => Some(v.parse()?),
+            None => None,
+        },
+        log_keep: match matches.value_of("log-keep") {
+            Some(v) => v.parse()?,
+            None => ServerConfig::default().log_keep,
+        },
+        audit_log: matches.value_of("audit-log").map(PathBuf::from),
+        dedup_capacity: match matches.value_of("dedup-capacity") {
+            Some(v) => v.parse()?,
+            None => ServerConfig::default().dedup_capacity,
+        },
+        dedup_ttl: match matches.value_of("dedup-ttl") {
+            Some(v) => Duration::from_secs(v.parse()?),
+            None => ServerConfig::default().dedup_ttl,
+        },
     };
-
-    if pool == "crossbeam" {
-        if engine == "kvs" {
-            run(
-                socket,
-                &engine,
-                KvStore::open(&curr_dir)?,
-                SharedQueueThreadPool::new(num_threads)?,
-            )?;
-        } else if engine == "sled" {
-            run(
-                socket,
-                &engine,
-                SledKvsEngine::open(&curr_dir)?,
-                SharedQueueThreadPool::new(num_threads)?,
-            )?;
-        } else {
-            unreachable!()
-        }
-    } else if pool == "rayon" {
-        if engine == "kvs" {
-            run(
-                socket,
-                &engine,
-                KvStore::open(&curr_dir)?,
-                RayonThreadPool::new(num_threads)?,
-            )?;
-        } else if engine == "sled" {
-            run(
-                socket,
-                &engine,
-                SledKvsEngine::open(&curr_dir)?,
-                RayonThreadPool::new(num_threads)?,
-            )?;
-        } else {
-            unreachable!()
-        }
-    } else {
-        unreachable!()
-    }
-
-    Ok(())
-}
-
-fn run<E: KvsEngine, P: ThreadPool>(
-    socket: SocketAddr,
-    engine_name: &str,
-    engine: E,
-    pool: P,
-) -> Result<()> {
-    let server = KvsServer::new(socket, engine_name, engine, pool)?;
-    server.start()
+    run_server(socket, engine, pool, num_threads, &curr_dir, config)
 }
@@ -0,0 +1,58 @@
+use std::collections::hash_map::DefaultHasher;
+use std::f64::consts::LN_2;
+use std::hash::Hasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Filters are sized for at least this many keys, so a small store can grow before a rebuild
+const MIN_CAPACITY: usize = 1024;
+
+// BloomFilter answers whether a key may be present. A false answer is certain, a true one is
+// wrong with about the rate the filter was built with, as long as it holds no more keys than it
+// was sized for. Keys can't be taken out, so filters are rebuilt from the index instead.
+pub(crate) struct BloomFilter {
+    bits: Vec<AtomicU64>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    // Returns a filter holding keys, sized for twice as many keys at false_positive_rate
+    pub(crate) fn build<'a>(
+        keys: impl ExactSizeIterator<Item = &'a Vec<u8>>,
+        false_positive_rate: f64,
+    ) -> Self {
+        let capacity = (keys.len() * 2).max(MIN_CAPACITY) as f64;
+        let len = (-capacity * false_positive_rate.ln() / (LN_2 * LN_2)).ceil() as usize;
+        let words = len.div_ceil(64);
+        let hashes = ((words * 64) as f64 / capacity * LN_2).round().max(1.0) as u32;
+        let filter = BloomFilter {
+            bits: (0..words).map(|_| AtomicU64::new(0)).collect(),
+            hashes,
+        };
+        for key in keys {
+            filter.insert(key);
+        }
+        filter
+    }
+
+    pub(crate) fn insert(&self, key: &[u8]) {
+        for bit in self.bit_positions(key) {
+            self.bits[bit / 64].fetch_or(1 << (bit % 64), Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn may_contain(&self, key: &[u8]) -> bool {
+        self.bit_positions(key)
+            .all(|bit| self.bits[bit / 64].load(Ordering::Relaxed) & (1 << (bit % 64)) != 0)
+    }
+
+    // Derives the positions of key from two halves of one hash
+    fn bit_positions(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(key);
+        let hash = hasher.finish();
+        let (h1, h2) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+        let len = (self.bits.len() * 64) as u64;
+        (0..u64::from(self.hashes))
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+}
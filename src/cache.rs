@@ -0,0 +1,162 @@
+use crate::network::Response;
+
+use linked_hash_map::LinkedHashMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+// ValueCache holds the most recently read values of a store, up to capacity of them. Reading or
+// inserting a value makes it the most recent one, and inserting into a full cache evicts the
+// least recent one.
+pub(crate) struct ValueCache {
+    entries: Mutex<LinkedHashMap<Vec<u8>, Vec<u8>>>,
+    capacity: usize,
+}
+
+impl ValueCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        ValueCache {
+            entries: Mutex::new(LinkedHashMap::new()),
+            capacity,
+        }
+    }
+
+    pub(crate) fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.entries.lock().unwrap().get_refresh(key).cloned()
+    }
+
+    pub(crate) fn insert(&self, key: Vec<u8>, value: Vec<u8>) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(key, value);
+        while entries.len() > self.capacity {
+            entries.pop_front();
+        }
+    }
+
+    pub(crate) fn remove(&self, key: &[u8]) {
+        self.entries.lock().unwrap().remove(key);
+    }
+
+    pub(crate) fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+// ResponseCache holds the responses to requests sent with a request id, so a retried request can
+// be answered again without being run twice. A batch is held as the responses to its requests,
+// and any other request as its one response. It holds up to capacity entries, each for up to ttl
+// after it was answered, and evicts the oldest ones first. The id of a request that is still
+// running is held too, so a retry that comes in meanwhile waits for its response.
+pub(crate) struct ResponseCache {
+    entries: Mutex<LinkedHashMap<String, Entry>>,
+    // Notified whenever a running request is answered or gives up its id
+    answered: Condvar,
+    capacity: usize,
+    ttl: Duration,
+}
+
+struct Entry {
+    // When the id was reserved, or when its request was answered
+    at: Instant,
+    // Digest of the request the id belongs to
+    digest: u64,
+    // None while the request is running
+    resps: Option<Vec<Response>>,
+}
+
+// Reserved is what ResponseCache::reserve found for a request id
+pub(crate) enum Reserved {
+    // The id is new, so the request should run and hand its responses to the reservation
+    New(Reservation),
+    // The request was answered before with these responses
+    Done(Vec<Response>),
+    // The id belongs to a different request
+    Reused,
+}
+
+// Reservation holds a request id while its request runs. Dropping it without answering gives the
+// id up, so a waiting retry runs the request instead.
+pub(crate) struct Reservation {
+    cache: Arc<ResponseCache>,
+    // None once answered, or if the cache keeps no responses
+    id: Option<String>,
+}
+
+impl Reservation {
+    pub(crate) fn answer(mut self, resps: Vec<Response>) {
+        let id = match self.id.take() {
+            Some(id) => id,
+            None => return,
+        };
+        let mut entries = self.cache.entries.lock().unwrap();
+        // Taken out and put back so it moves behind the entries answered before it
+        if let Some(mut entry) = entries.remove(&id) {
+            entry.at = Instant::now();
+            entry.resps = Some(resps);
+            entries.insert(id, entry);
+        }
+        while entries.len() > self.cache.capacity {
+            entries.pop_front();
+        }
+        self.cache.answered.notify_all();
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if let Some(id) = self.id.take() {
+            self.cache.entries.lock().unwrap().remove(&id);
+            self.cache.answered.notify_all();
+        }
+    }
+}
+
+impl ResponseCache {
+    pub(crate) fn new(capacity: usize, ttl: Duration) -> Self {
+        ResponseCache {
+            entries: Mutex::new(LinkedHashMap::new()),
+            answered: Condvar::new(),
+            capacity,
+            ttl,
+        }
+    }
+
+    // Looks up id for a request with the given digest, reserving it if it is new. If the request
+    // with the id is still running, waits for it to be answered.
+    pub(crate) fn reserve(self: &Arc<Self>, id: String, digest: u64) -> Reserved {
+        if self.capacity == 0 {
+            return Reserved::New(Reservation {
+                cache: self.clone(),
+                id: None,
+            });
+        }
+        let mut entries = self.entries.lock().unwrap();
+        loop {
+            // Entries are roughly in the order they were answered, so the expired ones are at
+            // the front. A running request stops the expiry until it is answered.
+            while entries
+                .front()
+                .is_some_and(|(_, entry)| entry.resps.is_some() && entry.at.elapsed() >= self.ttl)
+            {
+                entries.pop_front();
+            }
+            match entries.get(&id) {
+                None => break,
+                Some(entry) if entry.digest != digest => return Reserved::Reused,
+                Some(Entry {
+                    resps: Some(resps), ..
+                }) => return Reserved::Done(resps.clone()),
+                Some(_) => entries = self.answered.wait(entries).unwrap(),
+            }
+        }
+        let entry = Entry {
+            at: Instant::now(),
+            digest,
+            resps: None,
+        };
+        entries.insert(id.clone(), entry);
+        Reserved::New(Reservation {
+            cache: self.clone(),
+            id: Some(id),
+        })
+    }
+}
@@ -1,64 +1,337 @@
+use crate::engine::page_start;
 use crate::error::KvStoreError;
 use crate::kv::Result;
-use crate::network::{ClientRequest, ClientRequestType, Response};
+use crate::network::{read_binary, ClientRequest, ClientRequestType, Response, StatsReport};
+use crate::pubsub::KeyEvent;
 
-use std::net::{SocketAddr, TcpStream};
+use serde::Deserialize;
+use std::fmt;
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
 
 /// KvsClient sends requests to KvsServer
 pub struct KvsClient {
     stream: TcpStream,
+    trace_id: Option<String>,
+    // Whether the server answers gets in the binary format
+    binary: bool,
 }
 
 impl KvsClient {
-    /// new establishes a TcpStream and instantiates client
-    pub fn new(socket: SocketAddr) -> Result<Self> {
-        let stream = TcpStream::connect(socket)?;
-        Ok(KvsClient { stream })
+    /// new establishes a TcpStream to addr and instantiates client. It is the same as connect.
+    pub fn new(addr: impl ToSocketAddrs + fmt::Debug) -> Result<Self> {
+        KvsClient::connect(addr)
     }
 
-    /// set sends a set request to the server
-    pub fn set(&mut self, key: String, value: String) -> Result<String> {
+    /// connect establishes a TcpStream to addr, which may be a hostname, and instantiates client.
+    /// Each address addr resolves to is tried in turn until one connects. Returns
+    /// AddrResolveError if addr does not resolve to any address.
+    pub fn connect(addr: impl ToSocketAddrs + fmt::Debug) -> Result<Self> {
+        let addrs: Vec<SocketAddr> = match addr.to_socket_addrs() {
+            Ok(addrs) => addrs.collect(),
+            Err(_) => Vec::new(),
+        };
+        if addrs.is_empty() {
+            return Err(KvStoreError::AddrResolveError {
+                addr: format!("{:?}", addr),
+            });
+        }
+        let stream = TcpStream::connect(&addrs[..])?;
+        // Requests are written in pieces, which must not wait for the server to acknowledge
+        // the ones before them
+        stream.set_nodelay(true)?;
+        Ok(KvsClient {
+            stream,
+            trace_id: None,
+            binary: false,
+        })
+    }
+
+    /// with_auth_token establishes a TcpStream and authenticates it with token, for servers that
+    /// require an auth token. Returns AuthError if the server rejects the token.
+    pub fn with_auth_token(addr: impl ToSocketAddrs + fmt::Debug, token: String) -> Result<Self> {
+        let mut client = KvsClient::connect(addr)?;
+        let req = client.request(ClientRequestType::Auth, String::new(), token);
+        serde_json::to_writer(&mut client.stream, &req)?;
+        // The connection stays open for the next request, so only the response is read
+        let mut de = serde_json::Deserializer::from_reader(&mut client.stream);
+        let resp = Response::deserialize(&mut de)?;
+        if !resp.error.is_empty() {
+            return Err(KvStoreError::AuthError {});
+        }
+        Ok(client)
+    }
+
+    /// enable_binary_gets asks the server to answer the get request on this connection in the
+    /// binary format instead of JSON, which saves decoding the JSON envelope. It is sent before
+    /// the request to run, and after with_auth_token if the server requires a token.
+    pub fn enable_binary_gets(&mut self) -> Result<()> {
         let req = ClientRequest {
-            command_type: ClientRequestType::Set,
-            key: key.to_owned(),
-            value: value.to_owned(),
+            trace_id: None,
+            ..self.request(
+                ClientRequestType::Protocol,
+                String::new(),
+                "binary".to_owned(),
+            )
         };
         serde_json::to_writer(&mut self.stream, &req)?;
+        // The connection stays open for the next request, so only the response is read
+        let mut de = serde_json::Deserializer::from_reader(&mut self.stream);
+        let resp = Response::deserialize(&mut de)?;
+        if !resp.error.is_empty() {
+            return Err(resp.into_error());
+        }
+        self.binary = true;
+        Ok(())
+    }
+
+    /// set_trace_id sets the id of the client trace sent with every request, so the server spans
+    /// of the requests link to it. None stops sending one.
+    pub fn set_trace_id(&mut self, trace_id: Option<String>) {
+        self.trace_id = trace_id;
+    }
+
+    // Builds a request of command_type with the trace id of the client
+    fn request(
+        &self,
+        command_type: ClientRequestType,
+        key: String,
+        value: String,
+    ) -> ClientRequest {
+        ClientRequest {
+            command_type,
+            key,
+            value,
+            batch: Vec::new(),
+            keys: Vec::new(),
+            trace_id: self.trace_id.clone(),
+            seq: None,
+            request_id: None,
+        }
+    }
+
+    // Sends req and returns its response, or the error the server answered with
+    fn send(&mut self, req: &ClientRequest) -> Result<Response> {
+        serde_json::to_writer(&mut self.stream, req)?;
         let resp: Response = serde_json::from_reader(&mut self.stream)?;
-        if resp.error != "" {
-            return Err(KvStoreError::ServerError { error: resp.error });
+        if !resp.error.is_empty() {
+            return Err(resp.into_error());
         }
-        Ok(resp.value)
+        Ok(resp)
     }
-    /// get sends a get request to the server
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+
+    /// set sends a set request to the server
+    pub fn set(&mut self, key: String, value: String) -> Result<String> {
+        let req = self.request(ClientRequestType::Set, key, value);
+        Ok(self.send(&req)?.value)
+    }
+    /// set_with_request_id sends a set request with request_id, so a set retried with the same
+    /// id after its response was lost is not run again. The server remembers ids for a limited
+    /// time.
+    pub fn set_with_request_id(
+        &mut self,
+        key: String,
+        value: String,
+        request_id: String,
+    ) -> Result<String> {
         let req = ClientRequest {
-            command_type: ClientRequestType::Get,
-            key: key.to_owned(),
-            value: "".to_owned(),
+            request_id: Some(request_id),
+            ..self.request(ClientRequestType::Set, key, value)
         };
+        Ok(self.send(&req)?.value)
+    }
+    /// get sends a get request to the server
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let req = self.request(ClientRequestType::Get, key, String::new());
         serde_json::to_writer(&mut self.stream, &req)?;
+        if self.binary {
+            return read_binary(BufReader::new(&mut self.stream));
+        }
         let resp: Response = serde_json::from_reader(&mut self.stream)?;
-        if resp.error != "" {
-            return Err(KvStoreError::ServerError { error: resp.error });
+        if !resp.error.is_empty() {
+            return Err(resp.into_error());
+        }
+        // Servers that don't send exists with gets can't tell an empty value from a missing key
+        if !resp.exists && resp.value.is_empty() {
+            return Ok(None);
         }
-        if resp.value == "".to_owned() {
+        Ok(Some(resp.value))
+    }
+    /// get_or sends a get request to the server and returns default if the key does not exist. A
+    /// key set to the empty string gets the empty string.
+    pub fn get_or(&mut self, key: String, default: String) -> Result<String> {
+        Ok(self.get(key)?.unwrap_or(default))
+    }
+    /// exists sends an exists request to the server and returns whether key exists. Unlike get,
+    /// the value is not sent back.
+    pub fn exists(&mut self, key: String) -> Result<bool> {
+        let req = self.request(ClientRequestType::Exists, key, String::new());
+        Ok(self.send(&req)?.exists)
+    }
+    /// set_nx sends a set_nx request to the server and returns whether key was set, false if it
+    /// already existed
+    pub fn set_nx(&mut self, key: String, value: String) -> Result<bool> {
+        let req = self.request(ClientRequestType::SetNx, key, value);
+        Ok(self.send(&req)?.value == "true")
+    }
+    /// get_set sends a get_set request to the server and returns the value that key had before,
+    /// None if it did not exist
+    pub fn get_set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        let req = self.request(ClientRequestType::GetSet, key, value);
+        let resp = self.send(&req)?;
+        if !resp.exists {
             return Ok(None);
         }
         Ok(Some(resp.value))
     }
+    /// merge sends a merge request to the server, which combines operand into the value of key
+    /// with the merge operator of its engine
+    pub fn merge(&mut self, key: String, operand: String) -> Result<String> {
+        let req = self.request(ClientRequestType::Merge, key, operand);
+        Ok(self.send(&req)?.value)
+    }
+    /// merge_with_request_id sends a merge request with request_id, so a merge retried with the
+    /// same id after its response was lost is not applied twice. The server remembers ids for a
+    /// limited time.
+    pub fn merge_with_request_id(
+        &mut self,
+        key: String,
+        operand: String,
+        request_id: String,
+    ) -> Result<String> {
+        let req = ClientRequest {
+            request_id: Some(request_id),
+            ..self.request(ClientRequestType::Merge, key, operand)
+        };
+        Ok(self.send(&req)?.value)
+    }
     /// remove sends a remove request to the server
     pub fn remove(&mut self, key: String) -> Result<String> {
+        let req = self.request(ClientRequestType::Rm, key, String::new());
+        Ok(self.send(&req)?.value)
+    }
+    /// stats sends a stats request to the server and returns its report of the server and engine
+    pub fn stats(&mut self) -> Result<StatsReport> {
+        let req = self.request(ClientRequestType::Stats, String::new(), String::new());
+        Ok(serde_json::from_str(&self.send(&req)?.value)?)
+    }
+    /// count sends a count request to the server and returns the number of keys in its engine
+    pub fn count(&mut self) -> Result<usize> {
+        let req = self.request(ClientRequestType::Count, String::new(), String::new());
+        Ok(self.send(&req)?.value.parse()?)
+    }
+    /// echo sends an echo request to the server and returns the payload it sent back
+    pub fn echo(&mut self, payload: String) -> Result<String> {
+        let req = self.request(ClientRequestType::Echo, String::new(), payload);
+        Ok(self.send(&req)?.value)
+    }
+    /// clear sends a clear request to the server, which removes every key from its engine
+    pub fn clear(&mut self) -> Result<String> {
+        let req = self.request(ClientRequestType::Clear, String::new(), String::new());
+        Ok(self.send(&req)?.value)
+    }
+    /// scan sends a scan request to the server and returns the pairs with keys from start up
+    /// to, but not including, end in key order. An empty end means the range has no upper bound.
+    /// The server returns at most 1000 pairs, scan again after the last key to get the rest.
+    pub fn scan(&mut self, start: String, end: String) -> Result<Vec<(String, String)>> {
+        let req = self.request(ClientRequestType::Scan, start, end);
+        Ok(self.send(&req)?.pairs)
+    }
+    /// scan_page sends a scan_page request to the server and returns up to limit pairs with keys
+    /// strictly after the key after, starting from the first key if after is None. The server
+    /// caps the page at 1000 pairs.
+    pub fn scan_page(
+        &mut self,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let req = self.request(
+            ClientRequestType::ScanPage,
+            page_start(after),
+            limit.to_string(),
+        );
+        Ok(self.send(&req)?.pairs)
+    }
+    /// multi_get sends the keys to the server in a single request and returns their values in
+    /// the same order, None for keys that don't exist
+    pub fn multi_get(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
         let req = ClientRequest {
-            command_type: ClientRequestType::Rm,
-            key: key.to_owned(),
-            value: "".to_owned(),
+            keys,
+            ..self.request(ClientRequestType::MultiGet, String::new(), String::new())
         };
+        Ok(self.send(&req)?.values)
+    }
+    /// subscribe asks the server for the changes of the keys starting with prefix and returns
+    /// them as they arrive. The connection is used for the subscription only, so the client is
+    /// consumed. Iteration blocks until the next event and ends when the server closes the
+    /// connection. Events are dropped by the server if the client falls too far behind.
+    pub fn subscribe(mut self, prefix: String) -> Result<impl Iterator<Item = Result<KeyEvent>>> {
+        let req = self.request(ClientRequestType::Subscribe, prefix, String::new());
         serde_json::to_writer(&mut self.stream, &req)?;
-        let resp: Response = serde_json::from_reader(&mut self.stream)?;
-        if resp.error != "" {
-            return Err(KvStoreError::ServerError { error: resp.error });
+        let mut de = serde_json::Deserializer::from_reader(self.stream);
+        let resp = Response::deserialize(&mut de)?;
+        if !resp.error.is_empty() {
+            return Err(resp.into_error());
+        }
+        Ok(de
+            .into_iter::<KeyEvent>()
+            .map(|event| event.map_err(KvStoreError::from)))
+    }
+    /// batch sends ops to the server in a single request. The server runs them in order and
+    /// returns one response per op, in the same order. Ops are not applied atomically.
+    pub fn batch(&mut self, ops: Vec<ClientRequest>) -> Result<Vec<Response>> {
+        let req = ClientRequest {
+            batch: ops,
+            ..self.request(ClientRequestType::Batch, String::new(), String::new())
+        };
+        serde_json::to_writer(&mut self.stream, &req)?;
+        let resps: Vec<Response> = serde_json::from_reader(&mut self.stream)?;
+        Ok(resps)
+    }
+    /// batch_with_request_id sends a batch request with request_id, so a batch retried with the
+    /// same id after its responses were lost is not run again
+    pub fn batch_with_request_id(
+        &mut self,
+        ops: Vec<ClientRequest>,
+        request_id: String,
+    ) -> Result<Vec<Response>> {
+        let req = ClientRequest {
+            batch: ops,
+            request_id: Some(request_id),
+            ..self.request(ClientRequestType::Batch, String::new(), String::new())
+        };
+        serde_json::to_writer(&mut self.stream, &req)?;
+        let resps: Vec<Response> = serde_json::from_reader(&mut self.stream)?;
+        Ok(resps)
+    }
+    /// pipeline sends reqs to the server as separate requests without waiting for responses in
+    /// between, and returns one response per request, in the same order. Unlike a batch, the
+    /// server may run the requests concurrently, so a request may not see the writes of the ones
+    /// before it. The connection is closed for writing afterwards.
+    pub fn pipeline(&mut self, reqs: Vec<ClientRequest>) -> Result<Vec<Response>> {
+        let n = reqs.len();
+        let mut writer = BufWriter::new(&self.stream);
+        for (seq, req) in reqs.into_iter().enumerate() {
+            let req = ClientRequest {
+                trace_id: req.trace_id.or_else(|| self.trace_id.clone()),
+                seq: Some(seq as u64),
+                ..req
+            };
+            serde_json::to_writer(&mut writer, &req)?;
+        }
+        writer.flush()?;
+        drop(writer);
+        // The server keeps reading requests until it sees the end of the stream
+        self.stream.shutdown(Shutdown::Write)?;
+        let resps = serde_json::Deserializer::from_reader(&self.stream)
+            .into_iter::<Response>()
+            .take(n)
+            .collect::<serde_json::Result<Vec<Response>>>()?;
+        if resps.len() < n {
+            return Err(KvStoreError::ServerError {
+                error: format!("Got {} responses to {} requests", resps.len(), n),
+            });
         }
-        Ok(resp.value)
+        Ok(resps)
     }
 }
@@ -1,17 +1,388 @@
+use crate::{KvStoreError, Result};
+
+use slog::{Discard, FilterLevel, Logger};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// MergeOperator combines the existing value of a key (if any) with a merge operand.
+/// It must be associative since operands may be folded lazily on reads or eagerly on compaction.
+pub type MergeOperator = Arc<dyn Fn(&str, Option<&str>, &str) -> String + Send + Sync>;
+
+/// CompactionPolicy decides when KvStore compacts its logs on its own. `KvStore::compact_now`
+/// compacts them whatever the policy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompactionPolicy {
+    /// BySize compacts the logs whenever the fraction of bytes on disk no longer referenced by
+    /// the index exceeds the given ratio. It is checked by a background thread every
+    /// compaction_interval.
+    BySize(f64),
+    /// ByFileCount compacts the logs every time the given number of log files has been filled
+    ByFileCount(u16),
+    /// Manual never compacts the logs on its own
+    Manual,
+}
+
+/// Compression decides how KvStore compresses the values it writes to its logs. Logs are read the
+/// same whatever the setting, so it can be changed between opens of a store.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    /// None writes values as they are
+    None,
+    /// Deflate compresses each value on its own with DEFLATE at the given level, from 0 to 10.
+    /// Values that don't get smaller, and values written with `KvStore::set_stream`, are written
+    /// as they are.
+    Deflate(u8),
+}
+
 /// Config has options for the KvStore
 #[derive(Clone)]
 pub struct Config {
     /// filesize_limit denotes the size at which a file will be set to immutable
     pub filesize_limit: u64,
-    /// compaction_thresh is the threshold that triggers compaction
-    pub compaction_thresh: u16,
+    /// compaction_policy decides when the logs are compacted
+    pub compaction_policy: CompactionPolicy,
+    /// merge_operator resolves merge operands recorded by `KvsEngine::merge`
+    pub merge_operator: Option<MergeOperator>,
+    /// compaction_interval is how often the BySize compaction policy checks the dead space ratio
+    pub compaction_interval: Duration,
+    /// logger receives background events such as skipped and failed compactions
+    pub logger: Logger,
+    /// max_key_size is the size in bytes of the largest key that can be written, if limited
+    pub max_key_size: Option<u64>,
+    /// max_value_size is the size in bytes of the largest value that can be written, if limited
+    pub max_value_size: Option<u64>,
+    /// recover makes opening a store skip the rest of a log file that can't be read, keeping the
+    /// records read before the failure, instead of failing to open
+    pub recover: bool,
+    /// cache_capacity is the number of recently read values KvStore keeps in memory, if any
+    pub cache_capacity: Option<usize>,
+    /// bloom_false_positive_rate enables a Bloom filter of the keys of KvStore, built for the
+    /// given rate of lookups of missing keys that still have to check the index
+    pub bloom_false_positive_rate: Option<f64>,
+    /// compression decides how values are compressed in the logs
+    pub compression: Compression,
+    /// parallel_compaction makes compaction look for the live records of the log files on the
+    /// global rayon pool, as many files at a time as it has threads. The live values of those
+    /// files are held in memory until they are written.
+    pub parallel_compaction: bool,
+    /// history_depth is how many earlier values of each key KvStore keeps for get_version. Only
+    /// sets make a new value, merges change the latest one. 0 keeps none.
+    pub history_depth: usize,
+    /// write_buffer_size is the size in bytes of the buffers that log files and compaction output
+    /// are written through
+    pub write_buffer_size: usize,
+    /// read_buffer_size is the size in bytes of the buffers that log files are read through when
+    /// the store is opened, compacted or its stats computed, and that streamed values are read
+    /// through
+    pub read_buffer_size: usize,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             filesize_limit: 1024,
-            compaction_thresh: 4,
+            compaction_policy: CompactionPolicy::ByFileCount(4),
+            merge_operator: None,
+            compaction_interval: Duration::from_secs(10),
+            logger: Logger::root(Discard, o!()),
+            max_key_size: None,
+            max_value_size: None,
+            recover: false,
+            cache_capacity: None,
+            bloom_false_positive_rate: None,
+            compression: Compression::None,
+            parallel_compaction: false,
+            history_depth: 0,
+            write_buffer_size: 8 * 1024,
+            read_buffer_size: 8 * 1024,
+        }
+    }
+}
+
+impl Config {
+    /// builder returns a ConfigBuilder starting from the default config
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder {
+            config: Config::default(),
+        }
+    }
+
+    pub(crate) fn limits(&self) -> Limits {
+        Limits {
+            max_key_size: self.max_key_size,
+            max_value_size: self.max_value_size,
+        }
+    }
+}
+
+// Limits are the size limits of Config that every engine enforces on writes
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Limits {
+    max_key_size: Option<u64>,
+    max_value_size: Option<u64>,
+}
+
+impl Limits {
+    // Returns KeyTooLarge or ValueTooLarge if the key or value is above its limit
+    pub(crate) fn check(&self, key_size: usize, value_size: u64) -> Result<()> {
+        let key_size = key_size as u64;
+        if let Some(max) = self.max_key_size.filter(|max| key_size > *max) {
+            return Err(KvStoreError::KeyTooLarge {
+                size: key_size,
+                max,
+            });
+        }
+        if let Some(max) = self.max_value_size.filter(|max| value_size > *max) {
+            return Err(KvStoreError::ValueTooLarge {
+                size: value_size,
+                max,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// ConfigBuilder builds a validated Config
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    /// filesize_limit sets the size at which a file will be set to immutable
+    pub fn filesize_limit(mut self, filesize_limit: u64) -> Self {
+        self.config.filesize_limit = filesize_limit;
+        self
+    }
+
+    /// compaction_policy sets when the logs are compacted
+    pub fn compaction_policy(mut self, compaction_policy: CompactionPolicy) -> Self {
+        self.config.compaction_policy = compaction_policy;
+        self
+    }
+
+    /// merge_operator sets the operator used to resolve merge operands
+    pub fn merge_operator(mut self, merge_operator: MergeOperator) -> Self {
+        self.config.merge_operator = Some(merge_operator);
+        self
+    }
+
+    /// compaction_interval sets how often the dead space ratio is checked
+    pub fn compaction_interval(mut self, compaction_interval: Duration) -> Self {
+        self.config.compaction_interval = compaction_interval;
+        self
+    }
+
+    /// logger sets the logger that background events are written to
+    pub fn logger(mut self, logger: Logger) -> Self {
+        self.config.logger = logger;
+        self
+    }
+
+    /// max_key_size sets the size in bytes of the largest key that can be written
+    pub fn max_key_size(mut self, max_key_size: u64) -> Self {
+        self.config.max_key_size = Some(max_key_size);
+        self
+    }
+
+    /// max_value_size sets the size in bytes of the largest value that can be written
+    pub fn max_value_size(mut self, max_value_size: u64) -> Self {
+        self.config.max_value_size = Some(max_value_size);
+        self
+    }
+
+    /// recover makes opening a store skip unreadable log files instead of failing
+    pub fn recover(mut self, recover: bool) -> Self {
+        self.config.recover = recover;
+        self
+    }
+
+    /// cache_capacity enables a cache of the given number of recently read values
+    pub fn cache_capacity(mut self, cache_capacity: usize) -> Self {
+        self.config.cache_capacity = Some(cache_capacity);
+        self
+    }
+
+    /// bloom_false_positive_rate enables a Bloom filter of the keys with the given false
+    /// positive rate, which lets lookups of most missing keys skip the index
+    pub fn bloom_false_positive_rate(mut self, bloom_false_positive_rate: f64) -> Self {
+        self.config.bloom_false_positive_rate = Some(bloom_false_positive_rate);
+        self
+    }
+
+    /// compression sets how values are compressed in the logs
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.config.compression = compression;
+        self
+    }
+
+    /// parallel_compaction sets whether compaction reads several log files at a time
+    pub fn parallel_compaction(mut self, parallel_compaction: bool) -> Self {
+        self.config.parallel_compaction = parallel_compaction;
+        self
+    }
+
+    /// history_depth sets how many earlier values of each key are kept
+    pub fn history_depth(mut self, history_depth: usize) -> Self {
+        self.config.history_depth = history_depth;
+        self
+    }
+
+    /// write_buffer_size sets the size of the buffers log files are written through
+    pub fn write_buffer_size(mut self, write_buffer_size: usize) -> Self {
+        self.config.write_buffer_size = write_buffer_size;
+        self
+    }
+
+    /// read_buffer_size sets the size of the buffers log files are read through
+    pub fn read_buffer_size(mut self, read_buffer_size: usize) -> Self {
+        self.config.read_buffer_size = read_buffer_size;
+        self
+    }
+
+    /// build validates the options and returns the Config
+    pub fn build(self) -> Result<Config> {
+        if self.config.filesize_limit == 0 {
+            return Err(KvStoreError::InvalidConfigError {
+                reason: "filesize_limit must be greater than 0".to_owned(),
+            });
+        }
+        if self.config.write_buffer_size == 0 || self.config.read_buffer_size == 0 {
+            return Err(KvStoreError::InvalidConfigError {
+                reason: "buffer sizes must be greater than 0".to_owned(),
+            });
+        }
+        if self.config.cache_capacity == Some(0) {
+            return Err(KvStoreError::InvalidConfigError {
+                reason: "cache_capacity must be greater than 0".to_owned(),
+            });
+        }
+        if let Some(rate) = self.config.bloom_false_positive_rate {
+            if !(rate > 0.0 && rate < 1.0) {
+                return Err(KvStoreError::InvalidConfigError {
+                    reason: "bloom_false_positive_rate must be between 0 and 1".to_owned(),
+                });
+            }
+        }
+        if let Compression::Deflate(level) = self.config.compression {
+            if level > 10 {
+                return Err(KvStoreError::InvalidConfigError {
+                    reason: "the deflate level must be at most 10".to_owned(),
+                });
+            }
+        }
+        match self.config.compaction_policy {
+            CompactionPolicy::BySize(ratio) => {
+                if !(ratio > 0.0 && ratio < 1.0) {
+                    return Err(KvStoreError::InvalidConfigError {
+                        reason: "the dead byte ratio must be between 0 and 1".to_owned(),
+                    });
+                }
+                if self.config.compaction_interval.is_zero() {
+                    return Err(KvStoreError::InvalidConfigError {
+                        reason: "compaction_interval must be greater than 0".to_owned(),
+                    });
+                }
+            }
+            CompactionPolicy::ByFileCount(0) => {
+                return Err(KvStoreError::InvalidConfigError {
+                    reason: "the file count must be greater than 0, use Manual to disable \
+                             compaction"
+                        .to_owned(),
+                });
+            }
+            CompactionPolicy::ByFileCount(_) | CompactionPolicy::Manual => {}
+        }
+        Ok(self.config)
+    }
+}
+
+/// LogFormat selects how KvsServer writes its log
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// Term writes human readable lines to stderr
+    Term,
+    /// Json writes one JSON object per line to stdout
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = KvStoreError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "term" => Ok(LogFormat::Term),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(KvStoreError::InvalidConfigError {
+                reason: format!("unknown log format: {}", s),
+            }),
+        }
+    }
+}
+
+/// ServerConfig has options for the KvsServer
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// log_format is the format of the server log
+    pub log_format: LogFormat,
+    /// log_level is the most verbose level that is logged. Every handled request is logged at
+    /// Info, so a level of Warning or below silences them.
+    pub log_level: FilterLevel,
+    /// metrics_addr is where the Prometheus metrics endpoint listens, if anywhere. Serving metrics
+    /// requires the metrics feature.
+    pub metrics_addr: Option<SocketAddr>,
+    /// max_connections is the number of connections served at once, if limited. Once it is
+    /// reached, new connections are not accepted until a connection being served is closed.
+    pub max_connections: Option<usize>,
+    /// max_request_size is the size in bytes of the largest request read, if limited. A larger
+    /// request gets an error response and its connection is closed before the rest of it is read.
+    pub max_request_size: Option<u64>,
+    /// idle_timeout is how long a connection may go without sending its request before it is
+    /// dropped, if limited
+    pub idle_timeout: Option<Duration>,
+    /// auth_token is the shared secret clients must authenticate with before their requests are
+    /// served, if any
+    pub auth_token: Option<String>,
+    /// rate_limit is the number of requests per second served on a connection, if limited. A
+    /// connection may burst up to a second's worth of requests, the ops of a batch included.
+    pub rate_limit: Option<u32>,
+    /// log_file is the file the server log is written to instead of stderr or stdout, if any
+    pub log_file: Option<PathBuf>,
+    /// log_max_size is the size in bytes at which log_file is rotated, if it is rotated at all.
+    /// A rotated file is renamed with a suffix of .1, pushing older ones to .2 and so on.
+    pub log_max_size: Option<u64>,
+    /// log_keep is the number of rotated log files kept, the oldest being deleted
+    pub log_keep: usize,
+    /// audit_log is the file every request that writes is appended to, as a JSON line with its
+    /// time, command type and key, if any. It is rotated like log_file.
+    pub audit_log: Option<PathBuf>,
+    /// dedup_capacity is the number of responses to requests with a request id that are kept, so
+    /// retries of those requests get the same response instead of being run again. 0 turns
+    /// deduplication off.
+    pub dedup_capacity: usize,
+    /// dedup_ttl is how long the response to a request with a request id is kept
+    pub dedup_ttl: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            log_format: LogFormat::Term,
+            log_level: FilterLevel::Info,
+            metrics_addr: None,
+            max_connections: None,
+            max_request_size: Some(64 << 20),
+            idle_timeout: None,
+            auth_token: None,
+            rate_limit: None,
+            log_file: None,
+            log_max_size: None,
+            log_keep: 5,
+            audit_log: None,
+            dedup_capacity: 10_000,
+            dedup_ttl: Duration::from_secs(300),
         }
     }
 }
@@ -1,55 +1,614 @@
-use crate::{KvStoreError, Result};
+use crate::config::Limits;
+use crate::pubsub::KeyEvent;
+#[cfg(feature = "rocksdb")]
+use crate::RocksKvsEngine;
+use crate::{Config, KvStore, KvStoreError, MergeOperator, Result};
 
-use sled::Db;
+use crossbeam_channel::Receiver;
+use sled::{self, Db};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::ops::Bound;
 use std::path::Path;
-use std::str::from_utf8;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
 
-/// KvsEngine is a trait for plug-in database engines to implement
-pub trait KvsEngine: Clone + Send + 'static {
+/// KvsEngine is a trait for plug-in database engines to implement.
+/// It is object safe, so an engine picked at runtime can be used as a `Box<dyn KvsEngine>`, or as
+/// an `Arc<dyn KvsEngine>` where the engine has to be cloned, such as in KvsServer.
+pub trait KvsEngine: Send + Sync + 'static {
+    /// Set the value of a byte key to a byte value.
+    /// Return an error if the value is not written successfully.
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+    /// Get the byte value of a byte key. If the key does not exist, return None.
+    /// Return an error if the value is not read successfully.
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+    /// Remove a given byte key.
+    /// Return an error if the key does not exit or value is not read successfully.
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+    /// Get up to limit byte key, value pairs with keys in start..end, in key order.
+    /// An empty end means the range has no upper bound.
+    fn scan_bytes(
+        &self,
+        start: Vec<u8>,
+        end: Vec<u8>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
     /// Set the value of a string key to a string.
     /// Return an error if the value is not written successfully.
-    fn set(&self, key: String, value: String) -> Result<()>;
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
     /// Get the string value of a string key. If the key does not exist, return None.
-    /// Return an error if the value is not read successfully.
-    fn get(&self, key: String) -> Result<Option<String>>;
+    /// Return an error if the value is not read successfully or is not valid UTF-8.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(v) => {
+                let s = String::from_utf8(v)?;
+                Ok(Some(s))
+            }
+            None => Ok(None),
+        }
+    }
+    /// Get the string value of a string key, or default if the key does not exist. A key set to
+    /// the empty string gets the empty string.
+    /// Return an error if the value is not read successfully or is not valid UTF-8.
+    fn get_or(&self, key: String, default: String) -> Result<String> {
+        Ok(self.get(key)?.unwrap_or(default))
+    }
     /// Remove a given string key.
     /// Return an error if the key does not exit or value is not read successfully.
-    fn remove(&self, key: String) -> Result<()>;
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+    /// Get up to limit string key, value pairs with keys in start..end, in key order.
+    /// An empty end means the range has no upper bound.
+    /// Return an error if a pair is not read successfully or is not valid UTF-8.
+    fn scan(&self, start: String, end: String, limit: usize) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for (key, value) in self.scan_bytes(start.into_bytes(), end.into_bytes(), limit)? {
+            let key = String::from_utf8(key)?;
+            let value = String::from_utf8(value)?;
+            pairs.push((key, value));
+        }
+        Ok(pairs)
+    }
+    /// Get up to limit string key, value pairs with keys strictly after the key after, in key
+    /// order, starting from the first key if after is None. Passing the last key of a page as
+    /// after gets the next page, so paging needs no iterator or lock held between pages.
+    /// Return an error if a pair is not read successfully or is not valid UTF-8.
+    fn scan_page(&self, after: Option<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        self.scan(page_start(after), String::new(), limit)
+    }
+    /// Set the value of a byte key to a byte value and return the value it replaced, as one
+    /// atomic step. If the key did not exist, return None.
+    /// Return UnsupportedError if the engine can't swap values atomically.
+    fn get_set_bytes(&self, _key: Vec<u8>, _value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Err(KvStoreError::UnsupportedError {
+            operation: "get_set".to_owned(),
+        })
+    }
+    /// Set the value of a string key to a string and return the value it replaced, as one
+    /// atomic step. If the key did not exist, return None.
+    /// Return an error if the value is not written successfully or the replaced value is not
+    /// valid UTF-8.
+    fn get_set(&self, key: String, value: String) -> Result<Option<String>> {
+        match self.get_set_bytes(key.into_bytes(), value.into_bytes())? {
+            Some(v) => {
+                let s = String::from_utf8(v)?;
+                Ok(Some(s))
+            }
+            None => Ok(None),
+        }
+    }
+    /// Set the value of a byte key to a byte value only if the key does not exist, as one atomic
+    /// step. Return whether the value was written.
+    /// Return UnsupportedError if the engine can't check and set atomically.
+    fn set_nx_bytes(&self, _key: Vec<u8>, _value: Vec<u8>) -> Result<bool> {
+        Err(KvStoreError::UnsupportedError {
+            operation: "set_nx".to_owned(),
+        })
+    }
+    /// Set the value of a string key to a string only if the key does not exist, as one atomic
+    /// step. Return false if the key already existed, in which case its value is left as it was.
+    /// Return an error if the value is not written successfully.
+    fn set_nx(&self, key: String, value: String) -> Result<bool> {
+        self.set_nx_bytes(key.into_bytes(), value.into_bytes())
+    }
+    /// Append suffix to the value of a byte key as one atomic step, setting the key to suffix if
+    /// it does not exist. Return the length of the new value.
+    /// Return UnsupportedError if the engine can't append atomically.
+    fn append_bytes(&self, _key: Vec<u8>, _suffix: Vec<u8>) -> Result<usize> {
+        Err(KvStoreError::UnsupportedError {
+            operation: "append".to_owned(),
+        })
+    }
+    /// Append suffix to the value of a string key as one atomic step, setting the key to suffix
+    /// if it does not exist. Return the length of the new value in bytes.
+    /// Return an error if the value is not written successfully.
+    fn append(&self, key: String, suffix: String) -> Result<usize> {
+        self.append_bytes(key.into_bytes(), suffix.into_bytes())
+    }
+    /// Return true if a byte key exists, without reading its value where the engine can.
+    /// Return an error if the key is not looked up successfully.
+    fn contains_key_bytes(&self, key: Vec<u8>) -> Result<bool> {
+        Ok(self.get_bytes(key)?.is_some())
+    }
+    /// Return true if a string key exists.
+    /// Return an error if the key is not looked up successfully.
+    fn contains_key(&self, key: String) -> Result<bool> {
+        self.contains_key_bytes(key.into_bytes())
+    }
+    /// Merge an operand into the value of a string key using the configured merge operator.
+    /// Return an error if no merge operator is configured or the operand is not written successfully.
+    fn merge(&self, key: String, operand: String) -> Result<()>;
+    /// Get the number of keys in the engine.
+    fn len(&self) -> Result<usize>;
+    /// Remove every key from the engine.
+    /// Return UnsupportedError if the engine can't be cleared.
+    fn clear(&self) -> Result<()> {
+        Err(KvStoreError::UnsupportedError {
+            operation: "clear".to_owned(),
+        })
+    }
+    /// Return true if the engine has no keys.
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+    /// Subscribe to the changes of the keys starting with prefix.
+    /// Return UnsupportedError if the engine does not publish changes.
+    fn subscribe(&self, _prefix: String) -> Result<Receiver<KeyEvent>> {
+        Err(KvStoreError::UnsupportedError {
+            operation: "subscribe".to_owned(),
+        })
+    }
+    /// Get the number of times the engine has compacted its data.
+    /// Engines that don't track compactions return 0.
+    fn compactions(&self) -> u64 {
+        0
+    }
+    /// Return the number of bytes the engine takes up on disk.
+    /// Return None if the engine does not know.
+    fn size_on_disk(&self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+    /// Write every buffered write to disk, making a durability point without closing the engine.
+    /// Engines that don't buffer writes do nothing.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Returns the first key of the page after the key after. Keys are ordered bytewise, so the
+// smallest key above after is after with a NUL byte appended.
+pub(crate) fn page_start(after: Option<String>) -> String {
+    after.map_or_else(String::new, |mut key| {
+        key.push('\0');
+        key
+    })
+}
+
+impl<E: KvsEngine + ?Sized> KvsEngine for Arc<E> {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        (**self).set_bytes(key, value)
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        (**self).get_bytes(key)
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        (**self).remove_bytes(key)
+    }
+
+    fn scan_bytes(
+        &self,
+        start: Vec<u8>,
+        end: Vec<u8>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        (**self).scan_bytes(start, end, limit)
+    }
+
+    fn get_set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        (**self).get_set_bytes(key, value)
+    }
+
+    fn set_nx_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        (**self).set_nx_bytes(key, value)
+    }
+
+    fn append_bytes(&self, key: Vec<u8>, suffix: Vec<u8>) -> Result<usize> {
+        (**self).append_bytes(key, suffix)
+    }
+
+    fn contains_key_bytes(&self, key: Vec<u8>) -> Result<bool> {
+        (**self).contains_key_bytes(key)
+    }
+
+    fn merge(&self, key: String, operand: String) -> Result<()> {
+        (**self).merge(key, operand)
+    }
+
+    fn len(&self) -> Result<usize> {
+        (**self).len()
+    }
+
+    fn clear(&self) -> Result<()> {
+        (**self).clear()
+    }
+
+    fn subscribe(&self, prefix: String) -> Result<Receiver<KeyEvent>> {
+        (**self).subscribe(prefix)
+    }
+
+    fn compactions(&self) -> u64 {
+        (**self).compactions()
+    }
+
+    fn size_on_disk(&self) -> Result<Option<u64>> {
+        (**self).size_on_disk()
+    }
+
+    fn flush(&self) -> Result<()> {
+        (**self).flush()
+    }
+}
+
+/// EngineKind names the engines the server can run with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EngineKind {
+    /// Kvs is KvStore
+    Kvs,
+    /// Sled is SledKvsEngine
+    Sled,
+    /// Rocksdb is RocksKvsEngine
+    #[cfg(feature = "rocksdb")]
+    Rocksdb,
+}
+
+impl EngineKind {
+    /// ALL lists every engine this build supports
+    pub const ALL: &'static [EngineKind] = &[
+        EngineKind::Kvs,
+        EngineKind::Sled,
+        #[cfg(feature = "rocksdb")]
+        EngineKind::Rocksdb,
+    ];
+
+    /// as_str returns the name the engine is parsed from
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EngineKind::Kvs => "kvs",
+            EngineKind::Sled => "sled",
+            #[cfg(feature = "rocksdb")]
+            EngineKind::Rocksdb => "rocksdb",
+        }
+    }
+}
+
+impl FromStr for EngineKind {
+    type Err = KvStoreError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "kvs" => Ok(EngineKind::Kvs),
+            "sled" => Ok(EngineKind::Sled),
+            #[cfg(feature = "rocksdb")]
+            "rocksdb" => Ok(EngineKind::Rocksdb),
+            _ => Err(KvStoreError::UnknownEngineError { name: s.to_owned() }),
+        }
+    }
+}
+
+impl fmt::Display for EngineKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// resolve_engine picks the engine for the data in path. The engine of existing data is recorded
+/// by a marker file in path/engine. Returns EngineMismatch if requested differs from the engine
+/// of existing data. Without existing data the requested engine is used, or Kvs if none was
+/// requested, and its marker is written.
+pub fn resolve_engine(path: &Path, requested: Option<EngineKind>) -> Result<EngineKind> {
+    let marker_dir = path.join("engine");
+    fs::create_dir_all(&marker_dir)?;
+    let existing = EngineKind::ALL
+        .iter()
+        .copied()
+        .find(|kind| marker_dir.join(kind.as_str()).exists());
+    let engine = match (existing, requested) {
+        (Some(existing), Some(requested)) if existing != requested => {
+            return Err(KvStoreError::EngineMismatch {
+                existing,
+                requested,
+            })
+        }
+        (Some(engine), _) | (None, Some(engine)) => engine,
+        (None, None) => EngineKind::Kvs,
+    };
+    OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .open(marker_dir.join(engine.as_str()))?;
+    Ok(engine)
+}
+
+/// Engine is one of the engines the server can run with, picked at runtime by EngineKind.
+/// It implements KvsEngine by dispatching to the engine it holds.
+// An Engine is opened once per server, so the size of KvStore's config doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone)]
+pub enum Engine {
+    /// Kvs is a KvStore
+    Kvs(KvStore),
+    /// Sled is a SledKvsEngine
+    Sled(SledKvsEngine),
+    /// Rocks is a RocksKvsEngine
+    #[cfg(feature = "rocksdb")]
+    Rocks(RocksKvsEngine),
+}
+
+impl Engine {
+    /// open opens the engine of the given kind at path
+    pub fn open(kind: EngineKind, path: &Path) -> Result<Engine> {
+        Engine::open_with_config(kind, path, Config::default())
+    }
+
+    /// open_with_config opens the engine of the given kind at path with config
+    pub fn open_with_config(kind: EngineKind, path: &Path, config: Config) -> Result<Engine> {
+        match kind {
+            EngineKind::Kvs => Ok(Engine::Kvs(KvStore::open_with_config(path, config)?)),
+            EngineKind::Sled => Ok(Engine::Sled(SledKvsEngine::open_with_config(path, config)?)),
+            #[cfg(feature = "rocksdb")]
+            EngineKind::Rocksdb => Ok(Engine::Rocks(RocksKvsEngine::open_with_config(
+                path, config,
+            )?)),
+        }
+    }
+
+    /// kind returns the kind of the engine
+    pub fn kind(&self) -> EngineKind {
+        match self {
+            Engine::Kvs(_) => EngineKind::Kvs,
+            Engine::Sled(_) => EngineKind::Sled,
+            #[cfg(feature = "rocksdb")]
+            Engine::Rocks(_) => EngineKind::Rocksdb,
+        }
+    }
+}
+
+impl KvsEngine for Engine {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        match self {
+            Engine::Kvs(db) => db.set_bytes(key, value),
+            Engine::Sled(db) => db.set_bytes(key, value),
+            #[cfg(feature = "rocksdb")]
+            Engine::Rocks(db) => db.set_bytes(key, value),
+        }
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self {
+            Engine::Kvs(db) => db.get_bytes(key),
+            Engine::Sled(db) => db.get_bytes(key),
+            #[cfg(feature = "rocksdb")]
+            Engine::Rocks(db) => db.get_bytes(key),
+        }
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        match self {
+            Engine::Kvs(db) => db.remove_bytes(key),
+            Engine::Sled(db) => db.remove_bytes(key),
+            #[cfg(feature = "rocksdb")]
+            Engine::Rocks(db) => db.remove_bytes(key),
+        }
+    }
+
+    fn scan_bytes(
+        &self,
+        start: Vec<u8>,
+        end: Vec<u8>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        match self {
+            Engine::Kvs(db) => db.scan_bytes(start, end, limit),
+            Engine::Sled(db) => db.scan_bytes(start, end, limit),
+            #[cfg(feature = "rocksdb")]
+            Engine::Rocks(db) => db.scan_bytes(start, end, limit),
+        }
+    }
+
+    fn get_set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self {
+            Engine::Kvs(db) => db.get_set_bytes(key, value),
+            Engine::Sled(db) => db.get_set_bytes(key, value),
+            #[cfg(feature = "rocksdb")]
+            Engine::Rocks(db) => db.get_set_bytes(key, value),
+        }
+    }
+
+    fn set_nx_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        match self {
+            Engine::Kvs(db) => db.set_nx_bytes(key, value),
+            Engine::Sled(db) => db.set_nx_bytes(key, value),
+            #[cfg(feature = "rocksdb")]
+            Engine::Rocks(db) => db.set_nx_bytes(key, value),
+        }
+    }
+
+    fn append_bytes(&self, key: Vec<u8>, suffix: Vec<u8>) -> Result<usize> {
+        match self {
+            Engine::Kvs(db) => db.append_bytes(key, suffix),
+            Engine::Sled(db) => db.append_bytes(key, suffix),
+            #[cfg(feature = "rocksdb")]
+            Engine::Rocks(db) => db.append_bytes(key, suffix),
+        }
+    }
+
+    fn contains_key_bytes(&self, key: Vec<u8>) -> Result<bool> {
+        match self {
+            Engine::Kvs(db) => db.contains_key_bytes(key),
+            Engine::Sled(db) => db.contains_key_bytes(key),
+            #[cfg(feature = "rocksdb")]
+            Engine::Rocks(db) => db.contains_key_bytes(key),
+        }
+    }
+
+    fn merge(&self, key: String, operand: String) -> Result<()> {
+        match self {
+            Engine::Kvs(db) => db.merge(key, operand),
+            Engine::Sled(db) => db.merge(key, operand),
+            #[cfg(feature = "rocksdb")]
+            Engine::Rocks(db) => db.merge(key, operand),
+        }
+    }
+
+    fn len(&self) -> Result<usize> {
+        match self {
+            Engine::Kvs(db) => db.len(),
+            Engine::Sled(db) => db.len(),
+            #[cfg(feature = "rocksdb")]
+            Engine::Rocks(db) => db.len(),
+        }
+    }
+
+    fn clear(&self) -> Result<()> {
+        match self {
+            Engine::Kvs(db) => db.clear(),
+            Engine::Sled(db) => db.clear(),
+            #[cfg(feature = "rocksdb")]
+            Engine::Rocks(db) => db.clear(),
+        }
+    }
+
+    fn subscribe(&self, prefix: String) -> Result<Receiver<KeyEvent>> {
+        match self {
+            Engine::Kvs(db) => KvsEngine::subscribe(db, prefix),
+            Engine::Sled(db) => db.subscribe(prefix),
+            #[cfg(feature = "rocksdb")]
+            Engine::Rocks(db) => db.subscribe(prefix),
+        }
+    }
+
+    fn compactions(&self) -> u64 {
+        match self {
+            Engine::Kvs(db) => db.compactions(),
+            Engine::Sled(db) => db.compactions(),
+            #[cfg(feature = "rocksdb")]
+            Engine::Rocks(db) => db.compactions(),
+        }
+    }
+
+    fn size_on_disk(&self) -> Result<Option<u64>> {
+        match self {
+            Engine::Kvs(db) => db.size_on_disk(),
+            Engine::Sled(db) => db.size_on_disk(),
+            #[cfg(feature = "rocksdb")]
+            Engine::Rocks(db) => db.size_on_disk(),
+        }
+    }
+
+    fn flush(&self) -> Result<()> {
+        match self {
+            Engine::Kvs(db) => db.flush(),
+            Engine::Sled(db) => db.flush(),
+            #[cfg(feature = "rocksdb")]
+            Engine::Rocks(db) => db.flush(),
+        }
+    }
+}
+
+/// WriteOp is one write of a batch applied by `SledKvsEngine::apply_batch`
+#[derive(Debug, Clone, PartialEq)]
+pub enum WriteOp {
+    /// Set sets key to value
+    Set {
+        /// key to set
+        key: Vec<u8>,
+        /// value to set key to
+        value: Vec<u8>,
+    },
+    /// Remove removes key. Removing a key that does not exist does nothing.
+    Remove {
+        /// key to remove
+        key: Vec<u8>,
+    },
 }
 
 /// SledKvsEngine implements the KvsEngine
 #[derive(Clone)]
 pub struct SledKvsEngine {
     db: Db,
+    merge_operator: Option<MergeOperator>,
+    limits: Limits,
 }
 
 impl SledKvsEngine {
     /// open calls sled's open and returns the db
     pub fn open(path: &Path) -> Result<Self> {
-        let db = Db::open(path)?;
-        Ok(SledKvsEngine { db })
+        SledKvsEngine::open_with_config(path, Config::default())
+    }
+
+    /// open_with_config calls sled's open and keeps the options of config that apply to sled
+    pub fn open_with_config(path: &Path, config: Config) -> Result<Self> {
+        let db = sled::open(path)?;
+        Ok(SledKvsEngine {
+            db,
+            limits: config.limits(),
+            merge_operator: config.merge_operator,
+        })
+    }
+
+    /// db returns the sled database under the engine, as an escape hatch for sled features that
+    /// KvsEngine doesn't have, such as transactions or compare_and_swap. Writes made through it
+    /// skip the size limits and merge operator of the engine, and sled may change it between
+    /// versions.
+    pub fn db(&self) -> &Db {
+        &self.db
+    }
+
+    /// apply_batch applies ops in order as one atomic write and flushes once. Either every op is
+    /// applied or, if the batch fails, none of them. Returns KeyTooLarge or ValueTooLarge without
+    /// applying any op if a set is above the size limits.
+    pub fn apply_batch(&self, ops: Vec<WriteOp>) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        for op in ops {
+            match op {
+                WriteOp::Set { key, value } => {
+                    self.limits.check(key.len(), value.len() as u64)?;
+                    batch.insert(key, value);
+                }
+                WriteOp::Remove { key } => batch.remove(key),
+            }
+        }
+        self.db.apply_batch(batch)?;
+        self.db.flush()?;
+        Ok(())
     }
 }
 
 impl KvsEngine for SledKvsEngine {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.db.insert(key.as_bytes(), value.as_bytes())?;
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.limits.check(key.len(), value.len() as u64)?;
+        self.db.insert(key, value)?;
         self.db.flush()?;
         Ok(())
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        match self.db.get(key.as_bytes())? {
-            Some(v) => {
-                let vec = v.as_ref();
-                let s = from_utf8(vec)?;
-                Ok(Some(s.to_owned()))
-            }
-            None => Ok(None),
-        }
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.db.get(key)?.map(|v| v.to_vec()))
     }
 
-    fn remove(&self, key: String) -> Result<()> {
+    fn contains_key_bytes(&self, key: Vec<u8>) -> Result<bool> {
+        Ok(self.db.contains_key(key)?)
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
         let res = self.db.remove(key)?;
         match res {
             Some(_) => {
@@ -59,4 +618,215 @@ impl KvsEngine for SledKvsEngine {
             None => Err(KvStoreError::KeyNotFoundError {}),
         }
     }
+
+    fn scan_bytes(
+        &self,
+        start: Vec<u8>,
+        end: Vec<u8>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut pairs = Vec::new();
+        if !end.is_empty() && start >= end {
+            return Ok(pairs);
+        }
+        for res in self
+            .db
+            .range((Bound::Included(start), upper_bound(end)))
+            .take(limit)
+        {
+            let (key, value) = res?;
+            pairs.push((key.to_vec(), value.to_vec()));
+        }
+        Ok(pairs)
+    }
+
+    fn get_set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.limits.check(key.len(), value.len() as u64)?;
+        let old = self.db.insert(key, value)?;
+        self.db.flush()?;
+        Ok(old.map(|v| v.to_vec()))
+    }
+
+    fn set_nx_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        self.limits.check(key.len(), value.len() as u64)?;
+        let swapped = self
+            .db
+            .compare_and_swap(key, None as Option<&[u8]>, Some(value))?;
+        self.db.flush()?;
+        Ok(swapped.is_ok())
+    }
+
+    fn append_bytes(&self, key: Vec<u8>, suffix: Vec<u8>) -> Result<usize> {
+        // update_and_fetch retries the closure until no other write got in between
+        let mut too_large = None;
+        let value = self.db.update_and_fetch(&key, |existing| {
+            too_large = None;
+            let mut value = existing.map(<[u8]>::to_vec).unwrap_or_default();
+            value.extend_from_slice(&suffix);
+            match self.limits.check(key.len(), value.len() as u64) {
+                Ok(()) => Some(value),
+                Err(e) => {
+                    too_large = Some(e);
+                    existing.map(<[u8]>::to_vec)
+                }
+            }
+        })?;
+        if let Some(e) = too_large {
+            return Err(e);
+        }
+        self.db.flush()?;
+        Ok(value.map_or(0, |v| v.len()))
+    }
+
+    fn merge(&self, key: String, operand: String) -> Result<()> {
+        let merge_operator = match &self.merge_operator {
+            Some(merge_operator) => merge_operator,
+            None => return Err(KvStoreError::NoMergeOperatorError {}),
+        };
+        self.limits.check(key.len(), operand.len() as u64)?;
+        self.db.update_and_fetch(key.as_bytes(), |existing| {
+            let existing = existing.map(String::from_utf8_lossy);
+            Some(merge_operator(&key, existing.as_deref(), &operand).into_bytes())
+        })?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn len(&self) -> Result<usize> {
+        Ok(self.db.len())
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.db.clear()?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn size_on_disk(&self) -> Result<Option<u64>> {
+        Ok(Some(self.db.size_on_disk()?))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+/// MemoryKvsEngine implements the KvsEngine entirely in memory. Nothing is written to disk, so
+/// all data is lost once the last clone of the engine is dropped.
+#[derive(Clone, Default)]
+pub struct MemoryKvsEngine {
+    map: Arc<RwLock<HashMap<Vec<u8>, Vec<u8>>>>,
+    merge_operator: Option<MergeOperator>,
+    limits: Limits,
+}
+
+impl MemoryKvsEngine {
+    /// new returns an empty engine
+    pub fn new() -> Self {
+        MemoryKvsEngine::default()
+    }
+
+    /// with_config returns an empty engine that keeps the options of config that apply to memory
+    pub fn with_config(config: Config) -> Self {
+        MemoryKvsEngine {
+            map: Arc::default(),
+            limits: config.limits(),
+            merge_operator: config.merge_operator,
+        }
+    }
+}
+
+impl KvsEngine for MemoryKvsEngine {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.limits.check(key.len(), value.len() as u64)?;
+        self.map.write().unwrap().insert(key, value);
+        Ok(())
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.map.read().unwrap().get(&key).cloned())
+    }
+
+    fn contains_key_bytes(&self, key: Vec<u8>) -> Result<bool> {
+        Ok(self.map.read().unwrap().contains_key(&key))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        match self.map.write().unwrap().remove(&key) {
+            Some(_) => Ok(()),
+            None => Err(KvStoreError::KeyNotFoundError {}),
+        }
+    }
+
+    fn scan_bytes(
+        &self,
+        start: Vec<u8>,
+        end: Vec<u8>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let map = self.map.read().unwrap();
+        let mut pairs: Vec<(Vec<u8>, Vec<u8>)> = map
+            .iter()
+            .filter(|(key, _)| **key >= start && (end.is_empty() || **key < end))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        pairs.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        pairs.truncate(limit);
+        Ok(pairs)
+    }
+
+    fn get_set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.limits.check(key.len(), value.len() as u64)?;
+        Ok(self.map.write().unwrap().insert(key, value))
+    }
+
+    fn set_nx_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        self.limits.check(key.len(), value.len() as u64)?;
+        let mut map = self.map.write().unwrap();
+        if map.contains_key(&key) {
+            return Ok(false);
+        }
+        map.insert(key, value);
+        Ok(true)
+    }
+
+    fn append_bytes(&self, key: Vec<u8>, suffix: Vec<u8>) -> Result<usize> {
+        let mut map = self.map.write().unwrap();
+        let len = map.get(&key).map_or(0, Vec::len) + suffix.len();
+        self.limits.check(key.len(), len as u64)?;
+        map.entry(key).or_default().extend_from_slice(&suffix);
+        Ok(len)
+    }
+
+    fn merge(&self, key: String, operand: String) -> Result<()> {
+        let merge_operator = match &self.merge_operator {
+            Some(merge_operator) => merge_operator,
+            None => return Err(KvStoreError::NoMergeOperatorError {}),
+        };
+        self.limits.check(key.len(), operand.len() as u64)?;
+        let mut map = self.map.write().unwrap();
+        let existing = map.get(key.as_bytes()).map(|v| String::from_utf8_lossy(v));
+        let merged = merge_operator(&key, existing.as_deref(), &operand);
+        map.insert(key.into_bytes(), merged.into_bytes());
+        Ok(())
+    }
+
+    fn len(&self) -> Result<usize> {
+        Ok(self.map.read().unwrap().len())
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.map.write().unwrap().clear();
+        Ok(())
+    }
+}
+
+/// upper_bound turns the end of a scan into a range bound, where an empty end means unbounded
+pub(crate) fn upper_bound(end: Vec<u8>) -> Bound<Vec<u8>> {
+    if end.is_empty() {
+        Bound::Unbounded
+    } else {
+        Bound::Excluded(end)
+    }
 }
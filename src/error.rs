@@ -1,5 +1,9 @@
+#![allow(non_local_definitions)]
+
 extern crate failure;
 
+use crate::engine::EngineKind;
+
 /// Custom errors for KvStore
 #[derive(Debug, Fail)]
 pub enum KvStoreError {
@@ -27,6 +31,12 @@ pub enum KvStoreError {
         /// utf8 error
         error: std::str::Utf8Error,
     },
+    /// DecompressError occurs when a compressed value in a log can't be decompressed
+    #[fail(display = "DecompressError: {}", error)]
+    DecompressError {
+        /// decompress error
+        error: miniz_oxide::inflate::DecompressError,
+    },
     /// SledError occurs when interacting with Sled embedded db
     #[fail(display = "SledError: {}", error)]
     SledError {
@@ -39,9 +49,132 @@ pub enum KvStoreError {
         /// addr parse error
         error: std::net::AddrParseError,
     },
+    /// AddrResolveError occurs when an address resolves to no socket addresses
+    #[fail(display = "{} did not resolve to any address", addr)]
+    AddrResolveError {
+        /// address that was resolved
+        addr: String,
+    },
     /// KeyNotFoundError occurs when a key is not found in KvStore index
     #[fail(display = "Key not found")]
     KeyNotFoundError {},
+    /// NoMergeOperatorError occurs when merging without a configured merge operator
+    #[fail(display = "No merge operator configured")]
+    NoMergeOperatorError {},
+    /// TransactionConflict occurs when a key read by a transaction changed before it committed.
+    /// None of the writes of the transaction were applied, so it can be retried.
+    #[fail(display = "Transaction conflict: a key read by the transaction was changed")]
+    TransactionConflict {},
+    /// RequestIdReused occurs when a request is sent with the request id of a different request
+    /// the server answered recently
+    #[fail(display = "Request id {} was used by a different request", id)]
+    RequestIdReused {
+        /// the reused request id
+        id: String,
+    },
+    /// AuthError occurs when the server rejects the auth token of a connection, or a request is
+    /// sent without authenticating to a server that requires it
+    #[fail(display = "Authentication failed")]
+    AuthError {},
+    /// KeyTooLarge occurs when writing a key larger than the configured max_key_size
+    #[fail(
+        display = "Key of {} bytes is larger than the limit of {} bytes",
+        size, max
+    )]
+    KeyTooLarge {
+        /// size of the key
+        size: u64,
+        /// max_key_size
+        max: u64,
+    },
+    /// ValueTooLarge occurs when writing a value larger than the configured max_value_size
+    #[fail(
+        display = "Value of {} bytes is larger than the limit of {} bytes",
+        size, max
+    )]
+    ValueTooLarge {
+        /// size of the value
+        size: u64,
+        /// max_value_size
+        max: u64,
+    },
+    /// CompactionMismatch occurs when a record of a compacted log file does not read back as it
+    /// was written. The compaction is abandoned and the old log files are kept.
+    #[fail(
+        display = "Compacted log record at offset {} does not match what was written",
+        offset
+    )]
+    CompactionMismatch {
+        /// offset of the record in the compacted log file
+        offset: u64,
+    },
+    /// KeyMismatch occurs when the index points a key at a log record of another key, which means
+    /// the log or the index is corrupt
+    #[fail(
+        display = "Corrupt log: found a record of key {} where key {} was expected",
+        found, expected
+    )]
+    KeyMismatch {
+        /// key that was looked up
+        expected: String,
+        /// key of the record that was found
+        found: String,
+    },
+    /// IncompleteRecord occurs when a log file ends partway through a record, as it does after a
+    /// crash in the middle of a write
+    #[fail(display = "Log file ends in an incomplete record at offset {}", offset)]
+    IncompleteRecord {
+        /// offset of the incomplete record
+        offset: u64,
+    },
+    /// CorruptLog occurs when the log directory holds log files that can't be told apart, such as
+    /// two files with the same id
+    #[fail(display = "Corrupt log: {}", reason)]
+    CorruptLog {
+        /// what is wrong with the log files
+        reason: String,
+    },
+    /// LogIdsExhausted occurs when KvStore needs a new log file but its log ids have run out
+    #[fail(display = "No log ids left after {}", id)]
+    LogIdsExhausted {
+        /// id of the newest log file
+        id: u64,
+    },
+    /// InvalidConfigError occurs when building a Config with invalid options
+    #[fail(display = "InvalidConfigError: {}", reason)]
+    InvalidConfigError {
+        /// reason the config is invalid
+        reason: String,
+    },
+    /// UnknownEngineError occurs when parsing a name that is not a supported engine
+    #[fail(display = "Unknown engine: {}", name)]
+    UnknownEngineError {
+        /// name that failed to parse
+        name: String,
+    },
+    /// UnknownPoolError occurs when parsing a name that is not a supported thread pool
+    #[fail(display = "Unknown thread pool: {}", name)]
+    UnknownPoolError {
+        /// name that failed to parse
+        name: String,
+    },
+    /// EngineMismatch occurs when the requested engine differs from the engine of existing data
+    #[fail(
+        display = "Selected engine {} does not match previous data engine {}",
+        requested, existing
+    )]
+    EngineMismatch {
+        /// engine the existing data was written with
+        existing: EngineKind,
+        /// engine that was requested
+        requested: EngineKind,
+    },
+    /// UnsupportedError occurs when an engine does not support an operation
+    #[fail(display = "{} is not supported by this engine", operation)]
+    UnsupportedError {
+        /// operation that is not supported
+        operation: String,
+    },
     /// ServerError is error from server in response to client request
     #[fail(display = "ServerError: {}", error)]
     ServerError {
@@ -54,6 +187,20 @@ pub enum KvStoreError {
         /// rayon error
         error: rayon_core::ThreadPoolBuildError,
     },
+    /// RocksDbError is error from rocksdb lib
+    #[cfg(feature = "rocksdb")]
+    #[fail(display = "RocksDbError: {}", error)]
+    RocksDbError {
+        /// rocksdb error
+        error: rocksdb::Error,
+    },
+    /// MetricsError is error from prometheus lib
+    #[cfg(feature = "metrics")]
+    #[fail(display = "MetricsError: {}", error)]
+    MetricsError {
+        /// prometheus error
+        error: prometheus::Error,
+    },
 }
 
 impl From<serde_json::Error> for KvStoreError {
@@ -80,6 +227,20 @@ impl From<std::str::Utf8Error> for KvStoreError {
     }
 }
 
+impl From<miniz_oxide::inflate::DecompressError> for KvStoreError {
+    fn from(error: miniz_oxide::inflate::DecompressError) -> Self {
+        KvStoreError::DecompressError { error }
+    }
+}
+
+impl From<std::string::FromUtf8Error> for KvStoreError {
+    fn from(error: std::string::FromUtf8Error) -> Self {
+        KvStoreError::Utf8Error {
+            error: error.utf8_error(),
+        }
+    }
+}
+
 impl From<sled::Error> for KvStoreError {
     fn from(error: sled::Error) -> Self {
         KvStoreError::SledError { error }
@@ -92,6 +253,20 @@ impl From<std::net::AddrParseError> for KvStoreError {
     }
 }
 
+#[cfg(feature = "rocksdb")]
+impl From<rocksdb::Error> for KvStoreError {
+    fn from(error: rocksdb::Error) -> Self {
+        KvStoreError::RocksDbError { error }
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl From<prometheus::Error> for KvStoreError {
+    fn from(error: prometheus::Error) -> Self {
+        KvStoreError::MetricsError { error }
+    }
+}
+
 impl From<rayon_core::ThreadPoolBuildError> for KvStoreError {
     fn from(error: rayon_core::ThreadPoolBuildError) -> Self {
         KvStoreError::RayonError { error }
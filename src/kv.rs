@@ -1,51 +1,532 @@
 //! In-memory kv store
 
-use crate::config::Config;
+use crate::bloom::BloomFilter;
+use crate::cache::ValueCache;
+use crate::config::{CompactionPolicy, Compression, Config};
 use crate::engine::KvsEngine;
 use crate::error::KvStoreError;
+use crate::pubsub::{KeyEvent, Subscribers};
 
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::{self, create_dir_all, remove_file, rename, File, OpenOptions};
-use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::hash::Hasher;
+use std::io::{self, BufReader, BufWriter, Cursor, ErrorKind, Read, Seek, SeekFrom, Take, Write};
+use std::mem;
+use std::ops::{Bound, Deref};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
 use std::sync::{Arc, Mutex, RwLock};
-use std::thread;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use crossbeam_channel::Receiver;
+use miniz_oxide::deflate::compress_to_vec;
+use miniz_oxide::inflate::decompress_to_vec_with_limit;
+use rayon::prelude::*;
 use tempfile::{Builder, NamedTempFile};
 
 /// Result is alias for std::result::Result that defaults KvStoreError
 pub type Result<T> = std::result::Result<T, KvStoreError>;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 enum CommandType {
     Set,
     Rm,
+    Merge,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Command {
     cmd: CommandType,
-    key: String,
-    value: String,
+    #[serde(with = "bytes_format")]
+    key: Vec<u8>,
+    #[serde(with = "bytes_format")]
+    value: Vec<u8>,
+    // Length of the raw value bytes written directly after a streamed record. It is 0, and left
+    // out of the log, when the value is stored inline in the record.
+    #[serde(default, skip_serializing_if = "is_inline")]
+    len: u64,
+    // Time the record was written, in nanoseconds since the Unix epoch. Records written before
+    // the time was recorded, and Rm records, have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    time: Option<u64>,
+    // Namespace of the key, left out of the log for the default namespace
+    #[serde(default, skip_serializing_if = "is_default_namespace")]
+    ns: u32,
+    // Length of the value before it was compressed, for records whose raw value bytes are
+    // compressed. It is left out of the log for uncompressed records.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    compressed: Option<u64>,
+}
+
+fn is_inline(len: &u64) -> bool {
+    *len == 0
+}
+
+fn is_default_namespace(ns: &u32) -> bool {
+    *ns == DEFAULT_NAMESPACE
+}
+
+// The namespace used by the KvsEngine methods of KvStore
+const DEFAULT_NAMESPACE: u32 = 0;
+
+// Name prefix of compaction tempfiles, which tells them apart from other files in the log directory
+const COMPACTION_TEMP_PREFIX: &str = ".kvs-compact";
+
+// Index keys are keys prefixed by their namespace, so each namespace is a contiguous range of the
+// index
+fn index_key(ns: u32, key: &[u8]) -> Vec<u8> {
+    let mut index_key = Vec::with_capacity(4 + key.len());
+    index_key.extend_from_slice(&ns.to_be_bytes());
+    index_key.extend_from_slice(key);
+    index_key
+}
+
+// Returns the key of an index key without its namespace
+fn strip_namespace(index_key: &[u8]) -> &[u8] {
+    &index_key[4..]
+}
+
+// Returns the namespace of an index key
+fn namespace_of(index_key: &[u8]) -> u32 {
+    let mut ns = [0; 4];
+    ns.copy_from_slice(&index_key[..4]);
+    u32::from_be_bytes(ns)
+}
+
+// Returns the range of the index keys of namespace ns
+fn namespace_range(ns: u32) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let end = match ns.checked_add(1) {
+        Some(next) => Bound::Excluded(next.to_be_bytes().to_vec()),
+        None => Bound::Unbounded,
+    };
+    (Bound::Included(ns.to_be_bytes().to_vec()), end)
+}
+
+impl Command {
+    // Creates a command written now
+    fn new(cmd: CommandType, key: Vec<u8>, value: Vec<u8>, len: u64) -> Command {
+        let time = match cmd {
+            CommandType::Rm => None,
+            _ => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|d| d.as_nanos() as u64),
+        };
+        Command {
+            cmd,
+            key,
+            value,
+            len,
+            time,
+            ns: DEFAULT_NAMESPACE,
+            compressed: None,
+        }
+    }
+
+    // Returns the key of the record in the index
+    fn index_key(&self) -> Vec<u8> {
+        index_key(self.ns, &self.key)
+    }
+
+    // Returns the time the record was written, or fallback if it was not recorded
+    fn modified(&self, fallback: SystemTime) -> SystemTime {
+        match self.time {
+            Some(nanos) => UNIX_EPOCH + Duration::from_nanos(nanos),
+            None => fallback,
+        }
+    }
+
+    // Returns the length of the value of a Set record, whether inline or streamed
+    fn value_len(&self) -> Option<u64> {
+        match (&self.cmd, self.len, self.compressed) {
+            (CommandType::Set, _, Some(raw_len)) => Some(raw_len),
+            (CommandType::Set, 0, None) => Some(self.value.len() as u64),
+            (CommandType::Set, len, None) => Some(len),
+            _ => None,
+        }
+    }
+}
+
+// Keys and values are written as JSON strings when they are valid UTF-8, which keeps logs readable
+// and compatible with logs written before they were bytes, and as arrays of bytes otherwise.
+mod bytes_format {
+    use serde::de::{self, Deserializer, SeqAccess, Visitor};
+    use serde::Serializer;
+    use std::fmt;
+
+    pub fn serialize<S>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match std::str::from_utf8(bytes) {
+            Ok(s) => serializer.serialize_str(s),
+            Err(_) => serializer.collect_seq(bytes),
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(BytesVisitor)
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a string or an array of bytes")
+        }
+
+        fn visit_str<E>(self, value: &str) -> Result<Vec<u8>, E>
+        where
+            E: de::Error,
+        {
+            Ok(value.as_bytes().to_vec())
+        }
+
+        fn visit_string<E>(self, value: String) -> Result<Vec<u8>, E>
+        where
+            E: de::Error,
+        {
+            Ok(value.into_bytes())
+        }
+
+        fn visit_seq<V>(self, mut seq: V) -> Result<Vec<u8>, V::Error>
+        where
+            V: SeqAccess<'de>,
+        {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(b) = seq.next_element()? {
+                bytes.push(b);
+            }
+            Ok(bytes)
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 struct FilePointer {
     path: PathBuf,
     offset: u64,
+    // Length of the record, not counting the raw value bytes of a streamed record
+    len: u64,
+    // Path, offset and length of the merge records written after the record at path and offset,
+    // oldest first
+    operands: Vec<(PathBuf, u64, u64)>,
+    // Time the latest record of the key was written
+    modified: SystemTime,
+    // Write that last set or merged the key, from KvStore::versions. Keys loaded from the logs
+    // have version 0.
+    version: u64,
+    // Length of the value, if it is known without applying merge operands
+    value_len: Option<u64>,
+    // Earlier values of the key, latest first, up to config.history_depth of them. Their own
+    // history is empty.
+    history: Vec<FilePointer>,
+}
+
+// Inserts fp into map as the latest value of index_key. The value it replaces becomes the first
+// one of its history, which keeps up to depth values.
+fn insert_version(map: &mut Index, index_key: Vec<u8>, mut fp: FilePointer, depth: usize) {
+    if depth > 0 {
+        if let Some(mut old) = map.remove(&index_key) {
+            fp.history = std::mem::take(&mut old.history);
+            fp.history.insert(0, old);
+            fp.history.truncate(depth);
+        }
+    }
+    map.insert(index_key, fp);
+}
+
+/// KeyMetadata describes the latest value of a key without its contents
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyMetadata {
+    /// last_modified is the time the key was last set or merged. For keys last written before
+    /// times were recorded, it is the time their log file was last modified.
+    pub last_modified: SystemTime,
+    /// value_len is the length of the value in bytes
+    pub value_len: usize,
+}
+
+// Index maps each key to the location of its latest record in the logs. Keys are kept in order so
+// ranges of keys can be scanned. The number of keys of each namespace is kept alongside, so
+// counting them doesn't walk the index. Reads go through the map itself, while every change of
+// its keys goes through Index so the counts follow.
+#[derive(Clone, Default)]
+struct Index {
+    entries: BTreeMap<Vec<u8>, FilePointer>,
+    counts: HashMap<u32, usize>,
+}
+
+impl Deref for Index {
+    type Target = BTreeMap<Vec<u8>, FilePointer>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.entries
+    }
+}
+
+impl Index {
+    fn new() -> Index {
+        Index::default()
+    }
+
+    fn insert(&mut self, index_key: Vec<u8>, fp: FilePointer) -> Option<FilePointer> {
+        let ns = namespace_of(&index_key);
+        let old = self.entries.insert(index_key, fp);
+        if old.is_none() {
+            *self.counts.entry(ns).or_insert(0) += 1;
+        }
+        old
+    }
+
+    fn remove(&mut self, index_key: &[u8]) -> Option<FilePointer> {
+        let old = self.entries.remove(index_key);
+        if old.is_some() {
+            let ns = namespace_of(index_key);
+            if let Some(count) = self.counts.get_mut(&ns) {
+                *count -= 1;
+                if *count == 0 {
+                    self.counts.remove(&ns);
+                }
+            }
+        }
+        old
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.counts.clear();
+    }
+
+    // Changes the pointers in place, which leaves the keys as they are
+    fn get_mut(&mut self, index_key: &[u8]) -> Option<&mut FilePointer> {
+        self.entries.get_mut(index_key)
+    }
+
+    fn values_mut(&mut self) -> impl Iterator<Item = &mut FilePointer> {
+        self.entries.values_mut()
+    }
+
+    // Returns the number of keys of namespace ns
+    fn count(&self, ns: u32) -> usize {
+        self.counts.get(&ns).copied().unwrap_or(0)
+    }
+}
+
+/// CompactionProgress reports how far a compaction started by KvStore::compact_now has come
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompactionProgress {
+    /// files_done is the number of log files processed so far
+    pub files_done: usize,
+    /// files_total is the number of log files being compacted
+    pub files_total: usize,
+    /// bytes_written is the size of the compacted output so far
+    pub bytes_written: u64,
+}
+
+/// Stats describes how much of the logs on disk is still referenced by the index
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Stats {
+    /// live_bytes is the size of the records the index points to
+    pub live_bytes: u64,
+    /// total_bytes is the size of all log files
+    pub total_bytes: u64,
+    /// compactions is the number of compactions merged since the store was opened
+    pub compactions: u64,
+    /// last_reclaimed_bytes is the disk space freed by the last compaction
+    pub last_reclaimed_bytes: u64,
+    /// last_compaction is when the last compaction finished, if one has
+    pub last_compaction: Option<SystemTime>,
+}
+
+impl Stats {
+    /// dead_bytes is the space compaction could reclaim
+    pub fn dead_bytes(&self) -> u64 {
+        self.total_bytes.saturating_sub(self.live_bytes)
+    }
+
+    /// dead_ratio is the fraction of total_bytes that is dead space
+    pub fn dead_ratio(&self) -> f64 {
+        match self.total_bytes {
+            0 => 0.0,
+            total => self.dead_bytes() as f64 / total as f64,
+        }
+    }
 }
 
 /// KvStore is an in-memory database that maps strings to string
 #[derive(Clone)]
 pub struct KvStore {
-    map: Arc<RwLock<HashMap<String, FilePointer>>>,
-    writer: Arc<Mutex<BufWriter<File>>>,
-    id: Arc<Mutex<u16>>,
+    map: Arc<RwLock<Index>>,
+    writer: Arc<Mutex<LogWriter>>,
+    id: Arc<Mutex<u64>>,
+    // Held while log files are being compacted so only one compaction runs at a time
+    compaction: Arc<Mutex<()>>,
+    // Compactions triggered by roll overs, which share a single background thread
+    triggered: Arc<Mutex<Triggered>>,
+    // Number of compactions that have been merged into the index
+    compactions: Arc<AtomicU64>,
+    // Log files filled since the ByFileCount policy last triggered a compaction
+    filled: Arc<AtomicU64>,
+    // Bytes freed by the last compaction
+    last_reclaimed: Arc<AtomicU64>,
+    // Nanoseconds since the epoch when the last compaction finished, or 0 if none has
+    last_compaction: Arc<AtomicU64>,
+    // Number of writes since the store was opened, bumped under the writer lock to version the
+    // keys written
+    versions: Arc<AtomicU64>,
+    // Set once the store is closed, after which no compaction may start
+    closed: Arc<AtomicBool>,
+    // Shared by the user-facing clones only. Clones owned by background threads have none, so
+    // dropping the last user-facing clone closes the store.
+    guard: Option<Arc<Guard>>,
+    subscribers: Subscribers,
+    // Recently read values by index key, if enabled
+    cache: Option<Arc<ValueCache>>,
+    // Bloom filter of the index keys, if enabled. It is only replaced while the index write lock
+    // is held.
+    bloom: Option<Arc<RwLock<BloomFilter>>>,
+    // Log files that could not be read completely when the store was opened in recovery mode
+    skipped: Arc<Vec<PathBuf>>,
     path: PathBuf,
     config: Config,
 }
 
+// LogWriter appends to the current log file. It counts the bytes written, so the offset of the
+// next record is known without asking the file.
+struct LogWriter {
+    writer: BufWriter<File>,
+    // Length of the log file including the buffered bytes, which is where the next write goes
+    offset: u64,
+}
+
+impl LogWriter {
+    // Opens the log file at path for appending, creating it if needed, with a buffer of capacity
+    // bytes
+    fn open(path: &Path, capacity: usize) -> Result<LogWriter> {
+        let f = OpenOptions::new().append(true).create(true).open(path)?;
+        let offset = f.metadata()?.len();
+        Ok(LogWriter {
+            writer: BufWriter::with_capacity(capacity, f),
+            offset,
+        })
+    }
+
+    fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    fn get_ref(&self) -> &File {
+        self.writer.get_ref()
+    }
+
+    // Appends cmd as one record and flushes it, returning the length of the record. If the
+    // record can't be written completely, whatever part of it reached the log is cut off again,
+    // so a failed write never leaves a half record behind.
+    fn append(&mut self, cmd: &Command, compression: Compression) -> Result<u64> {
+        let offset = self.offset;
+        let written = write_record(&mut *self, cmd, compression).and_then(|len| {
+            self.flush()?;
+            Ok(len)
+        });
+        if written.is_err() {
+            self.truncate(offset)?;
+        }
+        written
+    }
+
+    // Cuts the log file off at offset. Bytes still buffered are dropped rather than written after
+    // the cut.
+    fn truncate(&mut self, offset: u64) -> Result<()> {
+        let file = self.writer.get_ref().try_clone()?;
+        let capacity = self.writer.capacity();
+        let buffered = mem::replace(&mut self.writer, BufWriter::with_capacity(capacity, file));
+        // into_parts hands back the buffered bytes instead of flushing them on drop
+        let _ = buffered.into_parts();
+        self.writer.get_ref().set_len(offset)?;
+        self.offset = offset;
+        Ok(())
+    }
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.offset += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// Guard closes the store when it is dropped: it stops the scheduler, flushes and syncs the writer
+// and waits for a running compaction to finish. Only the last user-facing clone drops it, so
+// dropping the other clones writes nothing.
+struct Guard {
+    writer: Arc<Mutex<LogWriter>>,
+    compaction: Arc<Mutex<()>>,
+    closed: Arc<AtomicBool>,
+    scheduler: Option<Scheduler>,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Some(scheduler) = self.scheduler.take() {
+            // Dropping the sender disconnects the channel, which ends the loop
+            drop(scheduler.stop);
+            let _ = scheduler.handle.join();
+        }
+        if let Ok(mut writer) = self.writer.lock() {
+            if writer.flush().is_ok() {
+                let _ = writer.get_ref().sync_all();
+            }
+        }
+        let _compaction = self.compaction.lock();
+        self.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+// Triggered tracks the compactions triggered by roll overs. Triggers that come in while the
+// compaction thread is running are coalesced into one compaction up to the highest log id among
+// them, which the thread runs next rather than starting a thread of its own.
+#[derive(Default)]
+struct Triggered {
+    running: bool,
+    max_id: Option<u64>,
+}
+
+// Scheduler owns the background thread that compacts the logs once enough of them is dead space
+struct Scheduler {
+    stop: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl Scheduler {
+    fn start(store: KvStore, ratio: f64) -> Scheduler {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let interval = store.config.compaction_interval;
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                // Errors are logged and the next tick tries again
+                if let Err(e) = store.compact_if_dead(ratio) {
+                    error!(store.config.logger, "compaction failed"; "error" => %e);
+                }
+            }
+        });
+        Scheduler { stop, handle }
+    }
+}
+
 impl KvsEngine for KvStore {
     /// Writes a key, value pair to KvStore. Can potentially cause compaction which will block method until completed
     /// ```rust
@@ -54,151 +535,1176 @@ impl KvsEngine for KvStore {
     /// # fn main() -> Result<()> {
     /// let curr_dir = env::current_dir().unwrap();
     /// let mut store = KvStore::open(curr_dir.as_path()).expect("Failed to open KvStore");
+    /// store.set_bytes(b"key1".to_vec(), vec![0, 159, 146, 150])?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.set_bytes_in(DEFAULT_NAMESPACE, key, value)
+    }
+
+    /// Reads a value for a key. If key is not found, will return Ok(None)
+    /// ```rust
+    /// # use kvs::{KvStore, Result, KvsEngine};
+    /// # use std::env;
+    /// # fn main() -> Result<()> {
+    /// let curr_dir = env::current_dir().unwrap();
+    /// let mut store = KvStore::open(curr_dir.as_path())?;
+    /// store.set_bytes(b"key1".to_vec(), vec![0, 159, 146, 150])?;
+    /// assert_eq!(Some(vec![0, 159, 146, 150]), store.get_bytes(b"key1".to_vec())?);
+    /// assert_eq!(None, store.get_bytes(b"key2".to_vec())?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.get_bytes_in(DEFAULT_NAMESPACE, key)
+    }
+
+    /// Checks the index for a key without reading its value from the log
+    /// ```rust
+    /// # use kvs::{KvStore, Result, KvsEngine};
+    /// # use tempfile::TempDir;
+    /// # fn main() -> Result<()> {
+    /// # let temp_dir = TempDir::new()?;
+    /// let store = KvStore::open(temp_dir.path())?;
+    /// store.set("key1".to_owned(), "value1".to_owned())?;
+    /// assert!(store.contains_key("key1".to_owned())?);
+    /// assert!(!store.contains_key("key2".to_owned())?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn contains_key_bytes(&self, key: Vec<u8>) -> Result<bool> {
+        Ok(self.contains_key_in(DEFAULT_NAMESPACE, &key))
+    }
+
+    /// Removes a key from the KvStore. Returns KeyNotFoundError if the key does not exist.
+    /// ```rust
+    /// # use kvs::{KvStore, Result, KvsEngine};
+    /// # use std::env;
+    /// # fn main() -> Result<()> {
+    /// let curr_dir = env::current_dir().unwrap();
+    /// let mut store = KvStore::open(curr_dir.as_path())?;
+    /// store.set("key1".to_owned(), "value1".to_owned())?;
+    /// store.remove_bytes(b"key1".to_vec())?;
+    /// assert_eq!(None, store.get("key1".to_owned())?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.remove_bytes_in(DEFAULT_NAMESPACE, key)
+    }
+
+    /// Reads up to limit key, value pairs with keys in start..end, in key order. An empty end
+    /// means the range has no upper bound.
+    /// ```rust
+    /// # use kvs::{KvStore, Result, KvsEngine};
+    /// # use tempfile::TempDir;
+    /// # fn main() -> Result<()> {
+    /// # let temp_dir = TempDir::new()?;
+    /// let store = KvStore::open(temp_dir.path())?;
+    /// store.set("a".to_owned(), "1".to_owned())?;
+    /// store.set("b".to_owned(), "2".to_owned())?;
+    /// store.set("c".to_owned(), "3".to_owned())?;
+    /// let pairs = store.scan("b".to_owned(), "".to_owned(), 10)?;
+    /// assert_eq!(vec![("b".to_owned(), "2".to_owned()), ("c".to_owned(), "3".to_owned())], pairs);
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn scan_bytes(
+        &self,
+        start: Vec<u8>,
+        end: Vec<u8>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan_bytes_in(DEFAULT_NAMESPACE, start, end, limit)
+    }
+
+    /// Sets key to value and returns the value it replaced, as one atomic step
+    /// ```rust
+    /// # use kvs::{KvStore, Result, KvsEngine};
+    /// # use tempfile::TempDir;
+    /// # fn main() -> Result<()> {
+    /// # let temp_dir = TempDir::new()?;
+    /// let store = KvStore::open(temp_dir.path())?;
+    /// assert_eq!(None, store.get_set("key1".to_owned(), "value1".to_owned())?);
+    /// assert_eq!(Some("value1".to_owned()), store.get_set("key1".to_owned(), "value2".to_owned())?);
+    /// assert_eq!(Some("value2".to_owned()), store.get("key1".to_owned())?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn get_set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.get_set_bytes_in(DEFAULT_NAMESPACE, key, value)
+    }
+
+    /// Sets key to value only if it does not exist yet
+    /// ```rust
+    /// # use kvs::{KvStore, Result, KvsEngine};
+    /// # use tempfile::TempDir;
+    /// # fn main() -> Result<()> {
+    /// # let temp_dir = TempDir::new()?;
+    /// let store = KvStore::open(temp_dir.path())?;
+    /// assert!(store.set_nx("lock".to_owned(), "owner1".to_owned())?);
+    /// assert!(!store.set_nx("lock".to_owned(), "owner2".to_owned())?);
+    /// assert_eq!(Some("owner1".to_owned()), store.get("lock".to_owned())?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn set_nx_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        self.set_nx_bytes_in(DEFAULT_NAMESPACE, key, value)
+    }
+
+    /// Appends suffix to the value of key. Records hold whole values, so this reads the value and
+    /// writes a new record with suffix appended, which takes time in the length of the value. It
+    /// is atomic though: no other write gets in between the read and the write.
+    /// ```rust
+    /// # use kvs::{KvStore, Result, KvsEngine};
+    /// # use tempfile::TempDir;
+    /// # fn main() -> Result<()> {
+    /// # let temp_dir = TempDir::new()?;
+    /// let store = KvStore::open(temp_dir.path())?;
+    /// assert_eq!(1, store.append("list".to_owned(), "a".to_owned())?);
+    /// assert_eq!(3, store.append("list".to_owned(), ",b".to_owned())?);
+    /// assert_eq!(Some("a,b".to_owned()), store.get("list".to_owned())?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn append_bytes(&self, key: Vec<u8>, suffix: Vec<u8>) -> Result<usize> {
+        self.append_bytes_in(DEFAULT_NAMESPACE, key, suffix)
+    }
+
+    /// Records a merge operand for a key. The operand is combined with the existing value by the
+    /// merge operator in Config when the key is read, or when the record is compacted.
+    /// Returns an error if no merge operator was configured.
+    /// ```rust
+    /// # use kvs::{Config, KvStore, Result, KvsEngine};
+    /// # use std::sync::Arc;
+    /// # use tempfile::TempDir;
+    /// # fn main() -> Result<()> {
+    /// # let temp_dir = TempDir::new()?;
+    /// let config = Config {
+    ///     merge_operator: Some(Arc::new(|_key: &str, existing: Option<&str>, operand: &str| {
+    ///         existing.unwrap_or_default().to_owned() + operand
+    ///     })),
+    ///     ..Config::default()
+    /// };
+    /// let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    /// store.merge("key1".to_owned(), "a".to_owned())?;
+    /// store.merge("key1".to_owned(), "b".to_owned())?;
+    /// assert_eq!(Some("ab".to_owned()), store.get("key1".to_owned())?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn merge(&self, key: String, operand: String) -> Result<()> {
+        self.merge_in(DEFAULT_NAMESPACE, key, operand)
+    }
+
+    /// Returns the number of keys in the default namespace. The count is kept up to date by every
+    /// write, so this takes constant time.
+    /// ```rust
+    /// # use kvs::{KvStore, Result, KvsEngine};
+    /// # use tempfile::TempDir;
+    /// # fn main() -> Result<()> {
+    /// # let temp_dir = TempDir::new()?;
+    /// let store = KvStore::open(temp_dir.path())?;
+    /// store.set("key1".to_owned(), "value1".to_owned())?;
+    /// store.set("key1".to_owned(), "value2".to_owned())?;
+    /// assert_eq!(1, store.len()?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn len(&self) -> Result<usize> {
+        self.len_in(DEFAULT_NAMESPACE)
+    }
+
+    /// Removes every key of every namespace by deleting the log files and starting a new one.
+    /// Reads and writes wait until the store is cleared. If the process crashes part way, some
+    /// keys may come back, each with its latest value from before the clear.
+    /// ```rust
+    /// # use kvs::{KvStore, Result, KvsEngine};
+    /// # use tempfile::TempDir;
+    /// # fn main() -> Result<()> {
+    /// # let temp_dir = TempDir::new()?;
+    /// let store = KvStore::open(temp_dir.path())?;
+    /// store.set("key1".to_owned(), "value1".to_owned())?;
+    /// store.clear()?;
+    /// assert_eq!(None, store.get("key1".to_owned())?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn clear(&self) -> Result<()> {
+        self.clear_all()
+    }
+
+    fn subscribe(&self, prefix: String) -> Result<Receiver<KeyEvent>> {
+        Ok(KvStore::subscribe(self, prefix))
+    }
+
+    fn compactions(&self) -> u64 {
+        self.compactions.load(Ordering::Relaxed)
+    }
+
+    fn size_on_disk(&self) -> Result<Option<u64>> {
+        Ok(Some(self.log_bytes()?))
+    }
+
+    /// Flush the buffered writes to the current log file and sync it to disk. Writes made before
+    /// flush returns survive a crash of the machine, and the store stays open for more writes.
+    fn flush(&self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        Ok(())
+    }
+}
+
+impl KvStore {
+    /// Open loads all log data inside the logs directory of the given path and assigns a new writer
+    /// to write entries to
+    /// ```
+    /// use kvs::KvStore;
+    /// use std::env;
+    /// fn main() {
+    ///     let curr_dir = env::current_dir().unwrap();
+    ///     let store = KvStore::open(curr_dir.as_path()).expect("Failed to open KvStore");
+    /// }
+    pub fn open(path: &Path) -> Result<KvStore> {
+        KvStore::open_with_config(path, Config::default())
+    }
+
+    /// Open a KvStore with the given config instead of the default one
+    pub fn open_with_config(path: &Path, config: Config) -> Result<KvStore> {
+        KvStore::open_dir_with_config(&path.join("logs"), config)
+    }
+
+    /// Open a KvStore whose log files are directly inside log_dir, instead of in a logs
+    /// directory under it. Other files in the directory are left alone, apart from the
+    /// `.kvs-compact` tempfiles an interrupted compaction leaves behind, which are removed.
+    /// ```rust
+    /// # use kvs::{KvStore, Result, KvsEngine};
+    /// # use tempfile::TempDir;
+    /// # fn main() -> Result<()> {
+    /// # let temp_dir = TempDir::new()?;
+    /// let store = KvStore::open_dir(temp_dir.path())?;
     /// store.set("key1".to_owned(), "value1".to_owned())?;
+    /// assert!(temp_dir.path().join("0.log").exists());
     /// # Ok(())
     /// # }
     /// ```
-    fn set(&self, key: String, value: String) -> Result<()> {
+    pub fn open_dir(log_dir: &Path) -> Result<KvStore> {
+        KvStore::open_dir_with_config(log_dir, Config::default())
+    }
+
+    /// Open a KvStore at log_dir with the given config instead of the default one
+    pub fn open_dir_with_config(log_dir: &Path, config: Config) -> Result<KvStore> {
+        let dir = log_dir.to_owned();
+        create_dir_all(&dir)?;
+        remove_orphans(&dir)?;
+        let (map, mut last_id, skipped) = load(&dir, &config)?;
+        // Records appended after unreadable bytes could not be loaded again
+        if skipped.contains(&get_log_path(&dir, last_id)) {
+            last_id = next_log_id(last_id)?;
+        }
+        let writer = LogWriter::open(&get_log_path(&dir, last_id), config.write_buffer_size)?;
+        let bloom = config
+            .bloom_false_positive_rate
+            .map(|rate| Arc::new(RwLock::new(BloomFilter::build(map.keys(), rate))));
+        let mut store = KvStore {
+            map: Arc::new(RwLock::new(map)),
+            writer: Arc::new(Mutex::new(writer)),
+            id: Arc::new(Mutex::new(last_id)),
+            compaction: Arc::new(Mutex::new(())),
+            triggered: Arc::new(Mutex::new(Triggered::default())),
+            compactions: Arc::new(AtomicU64::new(0)),
+            filled: Arc::new(AtomicU64::new(0)),
+            last_reclaimed: Arc::new(AtomicU64::new(0)),
+            last_compaction: Arc::new(AtomicU64::new(0)),
+            versions: Arc::new(AtomicU64::new(0)),
+            closed: Arc::new(AtomicBool::new(false)),
+            guard: None,
+            subscribers: Subscribers::default(),
+            cache: config
+                .cache_capacity
+                .map(|capacity| Arc::new(ValueCache::new(capacity))),
+            bloom,
+            skipped: Arc::new(skipped),
+            path: dir,
+            config,
+        };
+        let scheduler = match store.config.compaction_policy {
+            CompactionPolicy::BySize(ratio) => {
+                Some(Scheduler::start(store.background_clone(), ratio))
+            }
+            CompactionPolicy::ByFileCount(_) | CompactionPolicy::Manual => None,
+        };
+        store.guard = Some(Arc::new(Guard {
+            writer: store.writer.clone(),
+            compaction: store.compaction.clone(),
+            closed: store.closed.clone(),
+            scheduler,
+        }));
+        Ok(store)
+    }
+
+    /// namespace returns a handle to namespace ns of the store. The keys of a namespace are
+    /// separate from the keys of every other namespace, but all namespaces share the log files
+    /// and compaction of the store. The KvsEngine methods of KvStore use namespace 0.
+    /// ```rust
+    /// # use kvs::{KvStore, Result, KvsEngine};
+    /// # use tempfile::TempDir;
+    /// # fn main() -> Result<()> {
+    /// # let temp_dir = TempDir::new()?;
+    /// let store = KvStore::open(temp_dir.path())?;
+    /// let tenant = store.namespace(1);
+    /// tenant.set("key1".to_owned(), "value1".to_owned())?;
+    /// assert_eq!(Some("value1".to_owned()), tenant.get("key1".to_owned())?);
+    /// assert_eq!(None, store.get("key1".to_owned())?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn namespace(&self, ns: u32) -> NamespacedStore {
+        NamespacedStore {
+            store: self.clone(),
+            ns,
+        }
+    }
+
+    /// clear_namespace removes every key of namespace ns
+    pub fn clear_namespace(&self, ns: u32) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        let mut id = self.id.lock().unwrap();
+        let mut map = self.map.write().unwrap();
+        let index_keys: Vec<Vec<u8>> = map
+            .range(namespace_range(ns))
+            .map(|(k, _)| k.clone())
+            .collect();
+        for index_key in index_keys {
+            let key = strip_namespace(&index_key).to_vec();
+            let cmd = Command {
+                ns,
+                ..Command::new(CommandType::Rm, key, Vec::new(), 0)
+            };
+            self.append_command(&mut writer, &mut id, &cmd)?;
+            map.remove(&index_key);
+            self.uncache(&index_key);
+            self.publish(ns, &cmd.key, |key| KeyEvent::Remove { key });
+        }
+        Ok(())
+    }
+
+    // Deletes every log file and points the writer at a new one. The compaction lock keeps a
+    // compaction from reading the files, and the index write lock keeps reads out, while they are
+    // deleted.
+    fn clear_all(&self) -> Result<()> {
+        let _compaction = self.compaction.lock().unwrap();
+        let mut writer = self.writer.lock().unwrap();
+        let mut id = self.id.lock().unwrap();
+        let mut map = self.map.write().unwrap();
+        writer.flush()?;
+        let old_ids = log_ids(&self.path)?;
+        self.new_log_file(&mut writer, &mut id)?;
+        // Oldest first, so the files left by a crash hold the latest records of their keys
+        for old_id in old_ids {
+            remove_file(get_log_path(&self.path, old_id))?;
+        }
+        for index_key in map
+            .range(namespace_range(DEFAULT_NAMESPACE))
+            .map(|(k, _)| k)
+        {
+            self.publish(DEFAULT_NAMESPACE, strip_namespace(index_key), |key| {
+                KeyEvent::Remove { key }
+            });
+        }
+        map.clear();
+        if let Some(cache) = &self.cache {
+            cache.clear();
+        }
+        if let (Some(bloom), Some(rate)) = (&self.bloom, self.config.bloom_false_positive_rate) {
+            *bloom.write().unwrap() = BloomFilter::build(map.keys(), rate);
+        }
+        self.filled.store(0, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn set_bytes_in(&self, ns: u32, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.config.limits().check(key.len(), value.len() as u64)?;
+        let mut writer = self.writer.lock().unwrap();
+        let mut id = self.id.lock().unwrap();
+        let cmd = Command {
+            ns,
+            ..Command::new(CommandType::Set, key, value, 0)
+        };
+        let fp = self.append_command(&mut writer, &mut id, &cmd)?;
+        let mut map = self.map.write().unwrap();
+        self.publish(ns, &cmd.key, |key| KeyEvent::Set { key });
+        self.bloom_insert(&cmd.index_key());
+        insert_version(&mut map, cmd.index_key(), fp, self.config.history_depth);
+        self.uncache(&cmd.index_key());
+        Ok(())
+    }
+
+    // Sets key like set_bytes_in and returns the value it replaced, which can't change while the
+    // writer lock is held
+    fn get_set_bytes_in(&self, ns: u32, key: Vec<u8>, value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.config.limits().check(key.len(), value.len() as u64)?;
+        let mut writer = self.writer.lock().unwrap();
+        let mut id = self.id.lock().unwrap();
+        let old = self.get_bytes_in(ns, key.clone())?;
+        let cmd = Command {
+            ns,
+            ..Command::new(CommandType::Set, key, value, 0)
+        };
+        let fp = self.append_command(&mut writer, &mut id, &cmd)?;
+        let mut map = self.map.write().unwrap();
+        self.publish(ns, &cmd.key, |key| KeyEvent::Set { key });
+        self.bloom_insert(&cmd.index_key());
+        insert_version(&mut map, cmd.index_key(), fp, self.config.history_depth);
+        self.uncache(&cmd.index_key());
+        Ok(old)
+    }
+
+    // Sets key like set_bytes_in if it does not exist. Every write holds the writer lock, so the
+    // key can't be set by another writer between the check and the write.
+    fn set_nx_bytes_in(&self, ns: u32, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        self.config.limits().check(key.len(), value.len() as u64)?;
         let mut writer = self.writer.lock().unwrap();
         let mut id = self.id.lock().unwrap();
-        let mut offset = writer.seek(SeekFrom::Current(0))?;
+        if self.contains_key_in(ns, &key) {
+            return Ok(false);
+        }
+        let cmd = Command {
+            ns,
+            ..Command::new(CommandType::Set, key, value, 0)
+        };
+        let fp = self.append_command(&mut writer, &mut id, &cmd)?;
+        let mut map = self.map.write().unwrap();
+        self.publish(ns, &cmd.key, |key| KeyEvent::Set { key });
+        self.bloom_insert(&cmd.index_key());
+        insert_version(&mut map, cmd.index_key(), fp, self.config.history_depth);
+        self.uncache(&cmd.index_key());
+        Ok(true)
+    }
+
+    // Sets key to its value with suffix appended, reading the value under the writer lock so no
+    // other write can change it in between
+    fn append_bytes_in(&self, ns: u32, key: Vec<u8>, suffix: Vec<u8>) -> Result<usize> {
+        let mut writer = self.writer.lock().unwrap();
+        let mut id = self.id.lock().unwrap();
+        let mut value = self.get_bytes_in(ns, key.clone())?.unwrap_or_default();
+        value.extend_from_slice(&suffix);
+        self.config.limits().check(key.len(), value.len() as u64)?;
+        let len = value.len();
+        let cmd = Command {
+            ns,
+            ..Command::new(CommandType::Set, key, value, 0)
+        };
+        let fp = self.append_command(&mut writer, &mut id, &cmd)?;
+        let mut map = self.map.write().unwrap();
+        self.publish(ns, &cmd.key, |key| KeyEvent::Set { key });
+        self.bloom_insert(&cmd.index_key());
+        insert_version(&mut map, cmd.index_key(), fp, self.config.history_depth);
+        self.uncache(&cmd.index_key());
+        Ok(len)
+    }
+
+    fn get_bytes_in(&self, ns: u32, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let index_key = index_key(ns, &key);
+        if !self.may_contain(&index_key) {
+            return Ok(None);
+        }
+        let map = self.map.read().unwrap();
+        let fp = match map.get(&index_key) {
+            Some(fp) => fp,
+            None => return Ok(None),
+        };
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return read_value(&self.config, &key, fp, u64::MAX),
+        };
+        if let Some(value) = cache.get(&index_key) {
+            return Ok(Some(value));
+        }
+        // Caching under the index read lock keeps writers, which uncache under the write lock,
+        // from being overtaken by a value read before their write
+        let value = read_value(&self.config, &key, fp, u64::MAX)?;
+        if let Some(value) = &value {
+            cache.insert(index_key, value.clone());
+        }
+        Ok(value)
+    }
+
+    fn contains_key_in(&self, ns: u32, key: &[u8]) -> bool {
+        let index_key = index_key(ns, key);
+        self.may_contain(&index_key) && self.map.read().unwrap().contains_key(&index_key)
+    }
+
+    // Returns false if index_key is certainly not in the index
+    fn may_contain(&self, index_key: &[u8]) -> bool {
+        self.bloom
+            .as_ref()
+            .is_none_or(|bloom| bloom.read().unwrap().may_contain(index_key))
+    }
+
+    // Adds index_key to the Bloom filter. Callers hold the index write lock, so a rebuild of the
+    // filter can't miss it.
+    fn bloom_insert(&self, index_key: &[u8]) {
+        if let Some(bloom) = &self.bloom {
+            bloom.read().unwrap().insert(index_key);
+        }
+    }
+
+    // Drops the cached value of index_key. Callers hold the index write lock, so no read of the
+    // old value can cache it again.
+    fn uncache(&self, index_key: &[u8]) {
+        if let Some(cache) = &self.cache {
+            cache.remove(index_key);
+        }
+    }
+
+    fn remove_bytes_in(&self, ns: u32, key: Vec<u8>) -> Result<()> {
+        // The writer lock is taken before the index lock, like every other write does
+        let mut writer = self.writer.lock().unwrap();
+        let mut map = self.map.write().unwrap();
+        let index_key = index_key(ns, &key);
+        match map.get(&index_key) {
+            Some(_) => {
+                let cmd = Command {
+                    ns,
+                    ..Command::new(CommandType::Rm, key, Vec::new(), 0)
+                };
+                writer.append(&cmd, self.config.compression)?;
+                map.remove(&index_key);
+                self.uncache(&index_key);
+                self.publish(ns, &cmd.key, |key| KeyEvent::Remove { key });
+                Ok(())
+            }
+            None => Err(KvStoreError::KeyNotFoundError {}),
+        }
+    }
+
+    fn scan_bytes_in(
+        &self,
+        ns: u32,
+        start: Vec<u8>,
+        end: Vec<u8>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut pairs = Vec::new();
+        if !end.is_empty() && start >= end {
+            return Ok(pairs);
+        }
+        let upper = if end.is_empty() {
+            namespace_range(ns).1
+        } else {
+            Bound::Excluded(index_key(ns, &end))
+        };
+        let map = self.map.read().unwrap();
+        for (index_key, fp) in map
+            .range((Bound::Included(index_key(ns, &start)), upper))
+            .take(limit)
+        {
+            let key = strip_namespace(index_key);
+            if let Some(value) = read_value(&self.config, key, fp, u64::MAX)? {
+                pairs.push((key.to_vec(), value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn merge_in(&self, ns: u32, key: String, operand: String) -> Result<()> {
+        if self.config.merge_operator.is_none() {
+            return Err(KvStoreError::NoMergeOperatorError {});
+        }
+        self.config
+            .limits()
+            .check(key.len(), operand.len() as u64)?;
+        let mut writer = self.writer.lock().unwrap();
+        let mut id = self.id.lock().unwrap();
+        let cmd = Command {
+            ns,
+            ..Command::new(
+                CommandType::Merge,
+                key.into_bytes(),
+                operand.into_bytes(),
+                0,
+            )
+        };
+        let fp = self.append_command(&mut writer, &mut id, &cmd)?;
+        let mut map = self.map.write().unwrap();
+        self.publish(ns, &cmd.key, |key| KeyEvent::Set { key });
+        match map.get_mut(&cmd.index_key()) {
+            Some(entry) => {
+                entry.operands.push((fp.path, fp.offset, fp.len));
+                entry.modified = fp.modified;
+                entry.version = fp.version;
+                entry.value_len = None;
+            }
+            None => {
+                self.bloom_insert(&cmd.index_key());
+                map.insert(cmd.index_key(), fp);
+            }
+        }
+        self.uncache(&cmd.index_key());
+        Ok(())
+    }
+
+    fn len_in(&self, ns: u32) -> Result<usize> {
+        Ok(self.map.read().unwrap().count(ns))
+    }
+
+    // Publishes a change of key to the subscribers, which only cover the default namespace
+    fn publish(&self, ns: u32, key: &[u8], event: fn(String) -> KeyEvent) {
+        if ns == DEFAULT_NAMESPACE {
+            self.subscribers.publish(key, event);
+        }
+    }
+
+    // Clones the store for a background thread, without keeping the store open
+    fn background_clone(&self) -> KvStore {
+        KvStore {
+            guard: None,
+            ..self.clone()
+        }
+    }
+
+    /// Stats reports how many bytes of the logs are live and how many are on disk in total, and
+    /// how much the compactions run so far have reclaimed
+    /// ```rust
+    /// # use kvs::{KvStore, Result, KvsEngine};
+    /// # use tempfile::TempDir;
+    /// # fn main() -> Result<()> {
+    /// # let temp_dir = TempDir::new()?;
+    /// let store = KvStore::open(temp_dir.path())?;
+    /// store.set("key1".to_owned(), "value1".to_owned())?;
+    /// store.set("key1".to_owned(), "value2".to_owned())?;
+    /// let stats = store.stats()?;
+    /// assert_eq!(stats.dead_bytes(), stats.live_bytes);
+    /// assert_eq!(stats.last_compaction, None);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn stats(&self) -> Result<Stats> {
+        // Compaction renames and removes log files, so keep it from running while they are read
+        let _compaction = self.compaction.lock().unwrap();
+        let mut stats = Stats {
+            compactions: self.compactions.load(Ordering::Relaxed),
+            last_reclaimed_bytes: self.last_reclaimed.load(Ordering::Relaxed),
+            last_compaction: match self.last_compaction.load(Ordering::Relaxed) {
+                0 => None,
+                nanos => Some(UNIX_EPOCH + Duration::from_nanos(nanos)),
+            },
+            total_bytes: self.log_bytes()?,
+            ..Stats::default()
+        };
+        let map = self.map.read().unwrap();
+        let mut readers: HashMap<&Path, BufReader<File>> = HashMap::new();
+        let versions = map
+            .values()
+            .flat_map(|fp| std::iter::once(fp).chain(&fp.history));
+        for fp in versions {
+            let records = std::iter::once((fp.path.as_path(), fp.offset)).chain(
+                fp.operands
+                    .iter()
+                    .map(|(path, offset, _)| (path.as_path(), *offset)),
+            );
+            for (path, offset) in records {
+                let reader = match readers.get_mut(path) {
+                    Some(reader) => reader,
+                    None => readers.entry(path).or_insert(BufReader::with_capacity(
+                        self.config.read_buffer_size,
+                        File::open(path)?,
+                    )),
+                };
+                reader.seek(SeekFrom::Start(offset))?;
+                if let Some((cmd, read)) = read_record(reader)? {
+                    stats.live_bytes += read + cmd.len;
+                }
+            }
+        }
+        Ok(stats)
+    }
+
+    // Returns the size of all log files. Files removed by a compaction while they are being
+    // listed are skipped.
+    fn log_bytes(&self) -> Result<u64> {
+        let mut total = 0;
+        for res in fs::read_dir(&self.path)? {
+            let entry = res?;
+            if get_log_id(&entry.path()).is_none() {
+                continue;
+            }
+            match entry.metadata() {
+                Ok(metadata) => total += metadata.len(),
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(total)
+    }
+
+    // Appends cmd to the current log file
+    fn append_command(
+        &self,
+        writer: &mut LogWriter,
+        id: &mut u64,
+        cmd: &Command,
+    ) -> Result<FilePointer> {
+        let record_len = (cmd.key.len() + cmd.value.len()) as u64;
+        let offset = self.roll_over(writer, id, record_len)?;
+        let len = writer.append(cmd, self.config.compression)?;
+        Ok(FilePointer {
+            path: get_log_path(&self.path, *id),
+            offset,
+            len,
+            operands: Vec::new(),
+            modified: cmd.modified(SystemTime::now()),
+            version: self.versions.fetch_add(1, Ordering::Relaxed) + 1,
+            value_len: cmd.value_len(),
+            history: Vec::new(),
+        })
+    }
+
+    // Returns the offset the next record will be written at, rolling over to a new log file (and
+    // possibly triggering compaction) if the current one is above the filesize limit. A record of
+    // about record_len bytes that is itself above the limit gets a log file of its own. The caller
+    // holds the writer lock.
+    fn roll_over(&self, writer: &mut LogWriter, id: &mut u64, record_len: u64) -> Result<u64> {
+        let mut offset = writer.offset();
+        let limit = self.config.filesize_limit;
         // If current file is above filesize limit, create new log file
-        if offset > self.config.filesize_limit {
-            // Compact files if current id is divisible by compaction_thresh
-            if *id > 0 && *id % self.config.compaction_thresh * 2 == 0 {
-                let max_id = *id;
-                let store = self.clone();
-                thread::spawn(move || {
-                    let temp_file = Builder::new()
-                        .append(true)
-                        .tempfile()
-                        .expect("Could not create tempfile");
-                    let (temp_map, immutable_ids) = store
-                        .compact(&temp_file, max_id)
-                        .expect("Could not compact files");
-                    store
-                        .merge(temp_file.path(), temp_map, immutable_ids, max_id + 1)
-                        .expect("Could not merge files");
-                });
+        if offset > limit || (offset > 0 && record_len > limit) {
+            // Compact files once the ByFileCount policy's number of them have been filled
+            let filled = self.filled.fetch_add(1, Ordering::Relaxed) + 1;
+            if let CompactionPolicy::ByFileCount(count) = self.config.compaction_policy {
+                if filled >= u64::from(count) {
+                    self.filled.store(0, Ordering::Relaxed);
+                    self.spawn_compaction(*id);
+                }
             }
-            *id += 2;
-            let f = OpenOptions::new()
-                .append(true)
-                .create(true)
-                .open(get_log_path(&self.path, *id))?;
-            *writer = BufWriter::new(f);
+            self.new_log_file(writer, id)?;
             offset = 0;
         }
+        Ok(offset)
+    }
+
+    // Compacts log files up to max_id on a background thread. If the thread is already running, it
+    // compacts up to max_id once it is done with the compaction at hand.
+    fn spawn_compaction(&self, max_id: u64) {
+        {
+            let mut triggered = self.triggered.lock().unwrap();
+            triggered.max_id = Some(triggered.max_id.map_or(max_id, |id| id.max(max_id)));
+            if triggered.running {
+                info!(self.config.logger, "compaction already in progress, queued"; "max_id" => max_id);
+                return;
+            }
+            triggered.running = true;
+        }
+        let store = self.background_clone();
+        thread::spawn(move || loop {
+            let max_id = {
+                let mut triggered = store.triggered.lock().unwrap();
+                match triggered.max_id.take() {
+                    Some(max_id) => max_id,
+                    None => {
+                        triggered.running = false;
+                        return;
+                    }
+                }
+            };
+            // A compaction started by compact_now is waited for rather than raced
+            let _compaction = store.compaction.lock().unwrap();
+            if let Err(e) = store.compact_up_to(max_id, None) {
+                error!(store.config.logger, "compaction failed"; "max_id" => max_id, "error" => %e);
+            }
+        });
+    }
+
+    // Points writer at a new log file, leaving the odd id in between free for compaction output
+    fn new_log_file(&self, writer: &mut LogWriter, id: &mut u64) -> Result<()> {
+        *id = next_log_id(*id)?;
+        *writer = LogWriter::open(
+            &get_log_path(&self.path, *id),
+            self.config.write_buffer_size,
+        )?;
+        Ok(())
+    }
+
+    // Rolls over to a new log file and compacts every file before it, including the one that was
+    // being written to
+    fn compact_all(&self, progress: Option<&mut dyn FnMut(CompactionProgress)>) -> Result<()> {
+        let _compaction = self.compaction.lock().unwrap();
+        let max_id = {
+            let mut writer = self.writer.lock().unwrap();
+            let mut id = self.id.lock().unwrap();
+            let max_id = *id;
+            self.new_log_file(&mut writer, &mut id)?;
+            max_id
+        };
+        self.compact_up_to(max_id, progress)
+    }
+
+    /// compact_now compacts every log file, waiting for a compaction that is already running to
+    /// finish first. If progress is given, it is called after each log file is processed.
+    /// ```rust
+    /// # use kvs::{KvStore, Result, KvsEngine};
+    /// # use tempfile::TempDir;
+    /// # fn main() -> Result<()> {
+    /// # let temp_dir = TempDir::new()?;
+    /// let store = KvStore::open(temp_dir.path())?;
+    /// store.set("key1".to_owned(), "value1".to_owned())?;
+    /// store.set("key1".to_owned(), "value2".to_owned())?;
+    /// store.compact_now(Some(&mut |progress| {
+    ///     println!("{}/{} files", progress.files_done, progress.files_total);
+    /// }))?;
+    /// assert_eq!(store.stats()?.dead_bytes(), 0);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn compact_now(&self, progress: Option<&mut dyn FnMut(CompactionProgress)>) -> Result<()> {
+        self.compact_all(progress)
+    }
+
+    // Compacts every log file if more than ratio of the logs is dead space
+    fn compact_if_dead(&self, ratio: f64) -> Result<()> {
+        if self.stats()?.dead_ratio() > ratio {
+            self.compact_all(None)?;
+        }
+        Ok(())
+    }
+
+    // Compacts log files up to max_id into the file with id max_id + 1. The caller must hold the
+    // compaction lock. The tempfile is created in the log directory so it can be renamed into
+    // place.
+    fn compact_up_to(
+        &self,
+        max_id: u64,
+        progress: Option<&mut dyn FnMut(CompactionProgress)>,
+    ) -> Result<()> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        let temp_file = Builder::new()
+            .prefix(COMPACTION_TEMP_PREFIX)
+            .append(true)
+            .tempfile_in(&self.path)?;
+        let copied = self.compact(&temp_file, max_id, progress)?;
+        temp_file.as_file().sync_all()?;
+        // The old log files are only removed once the new one reads back as written. Otherwise
+        // the tempfile is dropped, which deletes it, and the old files stay in use.
+        copied.verify(temp_file.path(), self.config.read_buffer_size)?;
+        self.merge_compacted(temp_file.path(), copied.index, copied.files, max_id + 1)
+    }
+
+    /// Writes len bytes from reader as the value of key without holding the value in memory.
+    /// The bytes are copied directly into the log after the record. Returns an error, and leaves
+    /// the store unchanged, if reader ends before len bytes were read.
+    /// ```rust
+    /// # use kvs::{KvStore, Result};
+    /// # use std::io::Read;
+    /// # use tempfile::TempDir;
+    /// # fn main() -> Result<()> {
+    /// # let temp_dir = TempDir::new()?;
+    /// let store = KvStore::open(temp_dir.path())?;
+    /// let value = vec![7u8; 1 << 20];
+    /// store.set_stream("key1".to_owned(), &value[..], value.len() as u64)?;
+    /// let mut read = Vec::new();
+    /// store.get_stream("key1".to_owned())?.unwrap().read_to_end(&mut read)?;
+    /// assert_eq!(value, read);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_stream(&self, key: String, reader: impl Read, len: u64) -> Result<()> {
+        self.config.limits().check(key.len(), len)?;
+        let mut writer = self.writer.lock().unwrap();
+        let mut id = self.id.lock().unwrap();
+        let offset = self.roll_over(&mut writer, &mut id, key.len() as u64 + len)?;
+        let key = key.into_bytes();
+        let cmd = Command::new(CommandType::Set, key.clone(), Vec::new(), len);
+        let written =
+            write_record(&mut *writer, &cmd, self.config.compression).and_then(|record_len| {
+                let copied = io::copy(&mut reader.take(len), &mut *writer)?;
+                if copied < len {
+                    return Err(io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "value ended before len bytes",
+                    )
+                    .into());
+                }
+                writer.flush()?;
+                Ok(record_len)
+            });
+        let record_len = match written {
+            Ok(record_len) => record_len,
+            Err(e) => {
+                // Cut the incomplete record off so the log can still be loaded
+                writer.truncate(offset)?;
+                return Err(e);
+            }
+        };
         let mut map = self.map.write().unwrap();
-        // Write new entry to log
-        let cmd = Command {
-            cmd: CommandType::Set,
-            key: key.clone(),
-            value: value,
+        self.subscribers.publish(&key, |key| KeyEvent::Set { key });
+        self.bloom_insert(&cmd.index_key());
+        insert_version(
+            &mut map,
+            cmd.index_key(),
+            FilePointer {
+                path: get_log_path(&self.path, *id),
+                offset,
+                len: record_len,
+                operands: Vec::new(),
+                modified: cmd.modified(SystemTime::now()),
+                version: self.versions.fetch_add(1, Ordering::Relaxed) + 1,
+                value_len: Some(len),
+                history: Vec::new(),
+            },
+            self.config.history_depth,
+        );
+        self.uncache(&cmd.index_key());
+        Ok(())
+    }
+
+    /// Returns a reader over the value of key. Values written with set_stream are read directly
+    /// from the log, so they are never held in memory as a whole. Returns Ok(None) if the key is
+    /// not found.
+    pub fn get_stream(&self, key: String) -> Result<Option<impl Read>> {
+        let map = self.map.read().unwrap();
+        let key = key.into_bytes();
+        let fp = match map.get(&index_key(DEFAULT_NAMESPACE, &key)) {
+            Some(fp) => fp,
+            None => return Ok(None),
         };
-        serde_json::to_writer(&mut *writer, &cmd)?;
+        if fp.operands.is_empty() {
+            let (cmd, value) = open_record(&fp.path, fp.offset, fp.len, &key)?;
+            if cmd.cmd == CommandType::Set && cmd.compressed.is_none() {
+                return Ok(Some(match cmd.len {
+                    0 => ValueReader::Memory(Cursor::new(cmd.value)),
+                    _ => ValueReader::Log(BufReader::with_capacity(
+                        self.config.read_buffer_size,
+                        value,
+                    )),
+                }));
+            }
+        }
+        // Merged and compressed values have to be computed in memory
+        let value = read_value(&self.config, &key, fp, u64::MAX)?;
+        Ok(value.map(|value| ValueReader::Memory(Cursor::new(value))))
+    }
+
+    /// Backup writes a point-in-time copy of the store into `dest`. Only the live records are
+    /// written, into a single log file, so the backup is already compacted. Writes to the store
+    /// may continue while the backup is running; they are not included in the backup. A running
+    /// compaction is waited for, and no compaction starts until the backup is done.
+    /// `dest` should be an empty directory and can afterwards be opened with `KvStore::open`.
+    /// ```rust
+    /// # use kvs::{KvStore, Result, KvsEngine};
+    /// # use tempfile::TempDir;
+    /// # fn main() -> Result<()> {
+    /// # let (src_dir, backup_dir) = (TempDir::new()?, TempDir::new()?);
+    /// let store = KvStore::open(src_dir.path())?;
+    /// store.set("key1".to_owned(), "value1".to_owned())?;
+    /// store.backup(backup_dir.path())?;
+    /// let backup = KvStore::open(backup_dir.path())?;
+    /// assert_eq!(Some("value1".to_owned()), backup.get("key1".to_owned())?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn backup(&self, dest: &Path) -> Result<()> {
+        // Held for the whole copy so a compaction can't remove the files the snapshot points into
+        let _compaction = self.compaction.lock().unwrap();
+        let snapshot = self.map.read().unwrap().clone();
+        let dir = dest.join("logs");
+        create_dir_all(&dir)?;
+        let backup_path = get_log_path(&dir, 0);
+        let f = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&backup_path)?;
+        let mut writer = BufWriter::with_capacity(self.config.write_buffer_size, f);
+        let copied = copy_live_records(
+            &self.path,
+            &self.config,
+            &self.skipped,
+            &snapshot,
+            &mut writer,
+            &backup_path,
+            u64::MAX,
+            None,
+        )?;
         writer.flush()?;
-        let path = get_log_path(&self.path, *id);
-        map.insert(
-            key,
-            FilePointer {
-                path: path,
-                offset: offset,
+        copied.verify(&backup_path, self.config.read_buffer_size)
+    }
+
+    /// subscribe returns a receiver of the changes to the keys starting with prefix. An event is
+    /// published once its write is in the log and visible to reads. Delivery is best effort:
+    /// every subscriber has a buffer of 1024 events, and events that arrive while the buffer is
+    /// full are dropped for that subscriber. Dropping the receiver ends the subscription.
+    /// ```rust
+    /// # use kvs::{KeyEvent, KvStore, Result, KvsEngine};
+    /// # use tempfile::TempDir;
+    /// # fn main() -> Result<()> {
+    /// # let temp_dir = TempDir::new()?;
+    /// let store = KvStore::open(temp_dir.path())?;
+    /// let events = store.subscribe("user:".to_owned());
+    /// store.set("user:1".to_owned(), "alice".to_owned())?;
+    /// store.set("group:1".to_owned(), "admins".to_owned())?;
+    /// store.remove("user:1".to_owned())?;
+    /// assert_eq!(events.try_recv().unwrap(), KeyEvent::Set { key: "user:1".to_owned() });
+    /// assert_eq!(events.try_recv().unwrap(), KeyEvent::Remove { key: "user:1".to_owned() });
+    /// assert!(events.try_recv().is_err());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn subscribe(&self, prefix: String) -> Receiver<KeyEvent> {
+        self.subscribers.subscribe(prefix)
+    }
+
+    /// get_version returns the value key had n_back sets ago, where 0 is its latest value. Up to
+    /// config.history_depth earlier values are kept, and removing a key drops them. Returns
+    /// Ok(None) if the key is not found or has fewer earlier values than n_back.
+    /// ```rust
+    /// # use kvs::{Config, KvStore, Result, KvsEngine};
+    /// # use tempfile::TempDir;
+    /// # fn main() -> Result<()> {
+    /// # let temp_dir = TempDir::new()?;
+    /// let config = Config::builder().history_depth(2).build()?;
+    /// let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    /// store.set("key1".to_owned(), "value1".to_owned())?;
+    /// store.set("key1".to_owned(), "value2".to_owned())?;
+    /// assert_eq!(Some("value1".to_owned()), store.get_version("key1".to_owned(), 1)?);
+    /// assert_eq!(None, store.get_version("key1".to_owned(), 2)?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn get_version(&self, key: String, n_back: usize) -> Result<Option<String>> {
+        let index_key = index_key(DEFAULT_NAMESPACE, key.as_bytes());
+        let map = self.map.read().unwrap();
+        let fp = match map.get(&index_key) {
+            Some(fp) if n_back == 0 => fp,
+            Some(fp) => match fp.history.get(n_back - 1) {
+                Some(fp) => fp,
+                None => return Ok(None),
             },
-        );
-        Ok(())
+            None => return Ok(None),
+        };
+        match read_value(&self.config, key.as_bytes(), fp, u64::MAX)? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
     }
 
-    /// Reads a value for a key. If key is not found, will return Ok(None)
+    /// metadata returns the time key was last written and the length of its value, without
+    /// reading the value. Values with pending merge operands are the exception: they are read to
+    /// apply the operands. Returns Ok(None) if the key is not found.
     /// ```rust
     /// # use kvs::{KvStore, Result, KvsEngine};
-    /// # use std::env;
+    /// # use tempfile::TempDir;
     /// # fn main() -> Result<()> {
-    /// let curr_dir = env::current_dir().unwrap();
-    /// let mut store = KvStore::open(curr_dir.as_path())?;
+    /// # let temp_dir = TempDir::new()?;
+    /// let store = KvStore::open(temp_dir.path())?;
     /// store.set("key1".to_owned(), "value1".to_owned())?;
-    /// assert_eq!(Some("value1".to_owned()), store.get("key1".to_owned())?);
-    /// assert_eq!(None, store.get("key2".to_owned())?);
+    /// let metadata = store.metadata("key1".to_owned())?.unwrap();
+    /// assert_eq!(6, metadata.value_len);
+    /// assert!(store.metadata("key2".to_owned())?.is_none());
     /// # Ok(())
     /// # }
     /// ```
-    fn get(&self, key: String) -> Result<Option<String>> {
+    pub fn metadata(&self, key: String) -> Result<Option<KeyMetadata>> {
         let map = self.map.read().unwrap();
-        match map.get(&key) {
-            Some(fp) => {
-                let f = File::open(&fp.path)?;
-                let mut reader = BufReader::new(f);
-                reader.seek(SeekFrom::Start(fp.offset))?;
-                let mut stream =
-                    serde_json::Deserializer::from_reader(reader).into_iter::<Command>();
-                if let Some(res) = stream.next() {
-                    let cmd: Command = res?;
-                    return Ok(Some(cmd.value));
-                }
-                Ok(None)
-            }
-            None => Ok(None),
-        }
+        let key = key.into_bytes();
+        let fp = match map.get(&index_key(DEFAULT_NAMESPACE, &key)) {
+            Some(fp) => fp,
+            None => return Ok(None),
+        };
+        let value_len = match fp.value_len {
+            Some(len) => len as usize,
+            None => match read_value(&self.config, &key, fp, u64::MAX)? {
+                Some(value) => value.len(),
+                None => return Ok(None),
+            },
+        };
+        Ok(Some(KeyMetadata {
+            last_modified: fp.modified,
+            value_len,
+        }))
     }
 
-    /// Removes a key from the KvStore. Succeeds regardless if key exists in KvStore.
+    /// transaction runs f with a Transaction that buffers its writes, then commits them
+    /// atomically. Concurrency is optimistic: the commit only applies the writes if no key read by
+    /// the transaction changed since it was read, and returns TransactionConflict otherwise so
+    /// the caller can retry. If f returns an error, the writes are discarded. The writes are
+    /// appended to the log one by one, so a crash during a commit may persist only some of them.
     /// ```rust
     /// # use kvs::{KvStore, Result, KvsEngine};
-    /// # use std::env;
+    /// # use tempfile::TempDir;
     /// # fn main() -> Result<()> {
-    /// let curr_dir = env::current_dir().unwrap();
-    /// let mut store = KvStore::open(curr_dir.as_path())?;
-    /// store.set("key1".to_owned(), "value1".to_owned())?;
-    /// store.remove("key1".to_owned())?;
-    /// assert_eq!(None, store.get("key1".to_owned())?);
+    /// # let temp_dir = TempDir::new()?;
+    /// let store = KvStore::open(temp_dir.path())?;
+    /// store.set("alice".to_owned(), "10".to_owned())?;
+    /// store.transaction(|tx| {
+    ///     let balance: u64 = tx.get("alice".to_owned())?.unwrap().parse()?;
+    ///     tx.set("alice".to_owned(), (balance - 3).to_string())?;
+    ///     tx.set("bob".to_owned(), "3".to_owned())
+    /// })?;
+    /// assert_eq!(Some("7".to_owned()), store.get("alice".to_owned())?);
+    /// assert_eq!(Some("3".to_owned()), store.get("bob".to_owned())?);
     /// # Ok(())
     /// # }
     /// ```
-    fn remove(&self, key: String) -> Result<()> {
-        let mut map = self.map.write().unwrap();
-        let mut writer = self.writer.lock().unwrap();
-        match map.get(&key) {
-            Some(_) => {
-                let cmd = Command {
-                    cmd: CommandType::Rm,
-                    key: key.clone(),
-                    value: String::default(),
-                };
-                serde_json::to_writer(&mut *writer, &cmd)?;
-                writer.flush()?;
-                map.remove(&key);
-                Ok(())
-            }
-            None => Err(KvStoreError::KeyNotFoundError {}),
-        }
+    pub fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Transaction<'_>) -> Result<T>,
+    {
+        let mut tx = Transaction {
+            store: self,
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        };
+        let res = f(&mut tx)?;
+        tx.commit()?;
+        Ok(res)
     }
-}
 
-impl KvStore {
-    /// Open loads all log data inside the given path and assigns a new writer to write entries to
+    /// skipped_files returns the log files whose records could not all be read when the store was
+    /// opened with `Config::recover`. Only the records before the failure were loaded from them.
+    /// The files are left in place until they are compacted.
+    pub fn skipped_files(&self) -> &[PathBuf] {
+        &self.skipped
+    }
+
+    /// iter returns the key, value pairs of the store in key order. The keys are snapshotted when
+    /// iter is called, but each value is only read when its pair is reached, so a dump of a large
+    /// store doesn't hold every value in memory. A key removed after the snapshot yields
+    /// KeyNotFoundError, and a key or value that is not valid UTF-8 yields an error for that pair
+    /// only; iteration continues with the next key.
+    /// ```rust
+    /// # use kvs::{KvStore, Result, KvsEngine};
+    /// # use tempfile::TempDir;
+    /// # fn main() -> Result<()> {
+    /// # let temp_dir = TempDir::new()?;
+    /// let store = KvStore::open(temp_dir.path())?;
+    /// store.set("b".to_owned(), "2".to_owned())?;
+    /// store.set("a".to_owned(), "1".to_owned())?;
+    /// let pairs = store.iter().collect::<Result<Vec<_>>>()?;
+    /// assert_eq!(vec![("a".to_owned(), "1".to_owned()), ("b".to_owned(), "2".to_owned())], pairs);
+    /// # Ok(())
+    /// # }
     /// ```
-    /// use kvs::KvStore;
-    /// use std::env;
-    /// fn main() {
-    ///     let curr_dir = env::current_dir().unwrap();
-    ///     let store = KvStore::open(curr_dir.as_path()).expect("Failed to open KvStore");
-    /// }
-    pub fn open(path: &Path) -> Result<KvStore> {
-        let dir = path.join("logs");
-        create_dir_all(&dir)?;
-        let (map, last_id) = load(&dir)?;
-        let f = OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(get_log_path(&dir, last_id))?;
-        let mut writer = BufWriter::new(f);
-        writer.seek(SeekFrom::End(0))?;
-        Ok(KvStore {
-            map: Arc::new(RwLock::new(map)),
-            writer: Arc::new(Mutex::new(writer)),
-            id: Arc::new(Mutex::new(last_id)),
-            path: dir,
-            config: Config::default(),
+    pub fn iter(&self) -> impl Iterator<Item = Result<(String, String)>> {
+        let keys: Vec<Vec<u8>> = self
+            .map
+            .read()
+            .unwrap()
+            .range(namespace_range(DEFAULT_NAMESPACE))
+            .map(|(index_key, _)| strip_namespace(index_key).to_vec())
+            .collect();
+        let store = self.clone();
+        keys.into_iter().map(move |key| {
+            let value = store
+                .get_bytes(key.clone())?
+                .ok_or(KvStoreError::KeyNotFoundError {})?;
+            let key = String::from_utf8(key)?;
+            let value = String::from_utf8(value)?;
+            Ok((key, value))
         })
     }
 
@@ -206,146 +1712,872 @@ impl KvStore {
     fn compact(
         &self,
         temp_file: &NamedTempFile,
-        max_id: u16,
-    ) -> Result<(HashMap<String, FilePointer>, HashSet<PathBuf>)> {
-        let mut writer = BufWriter::new(temp_file);
-        let mut temp_map: HashMap<String, FilePointer> = HashMap::new();
-        let mut offset = 0u64;
-        let mut immutable_ids: HashSet<PathBuf> = HashSet::new();
+        max_id: u64,
+        progress: Option<&mut dyn FnMut(CompactionProgress)>,
+    ) -> Result<CopiedRecords> {
+        let mut writer = BufWriter::with_capacity(self.config.write_buffer_size, temp_file);
         let map = self.map.read().unwrap();
-        for res in fs::read_dir(&self.path)? {
-            let entry = res?;
-            let path = entry.path();
-            if let Some(id) = get_log_id(&path)? {
-                if id <= max_id {
-                    let f = File::open(&path)?;
-                    let reader = BufReader::new(f);
-                    let mut stream =
-                        serde_json::Deserializer::from_reader(reader).into_iter::<Command>();
-                    let mut read_offset = 0u64;
-                    while let Some(res) = stream.next() {
-                        let cmd: Command = res?;
-                        match cmd.cmd {
-                            CommandType::Set => {
-                                if let Some(v) = map.get(&cmd.key) {
-                                    if v.path == path.clone() && v.offset == read_offset {
-                                        serde_json::to_writer(&mut writer, &cmd)?;
-                                        temp_map.insert(
-                                            cmd.key,
-                                            FilePointer {
-                                                path: temp_file.path().to_owned(),
-                                                offset: offset,
-                                            },
-                                        );
-                                        offset = writer.seek(SeekFrom::Current(0))?;
-                                    }
-                                }
-                            }
-                            _ => (),
-                        }
-                        read_offset = stream.byte_offset() as u64;
-                    }
-                    immutable_ids.insert(path);
-                }
-            }
-        }
-        Ok((temp_map, immutable_ids))
+        let copied = copy_live_records(
+            &self.path,
+            &self.config,
+            &self.skipped,
+            &map,
+            &mut writer,
+            temp_file.path(),
+            max_id,
+            progress,
+        )?;
+        writer.flush()?;
+        Ok(copied)
     }
     // Merge: Rename tempfile and update map. Requires mutable ref to self
-    fn merge(
+    fn merge_compacted(
         &self,
         old_path: &Path,
-        temp_map: HashMap<String, FilePointer>,
+        copied: HashMap<(u64, u64), (Vec<u8>, FilePointer)>,
         immutable_ids: HashSet<PathBuf>,
-        id: u16,
+        id: u64,
     ) -> Result<()> {
         let new_path = get_log_path(&self.path, id);
         rename(old_path, &new_path)?;
         let mut map = self.map.write().unwrap();
-        for (key, value) in &temp_map {
-            if let Some(fp) = map.get(key) {
-                if let Some(file_id) = get_log_id(&fp.path)? {
-                    if file_id > id {
-                        continue;
-                    }
-                }
+        // Keys removed while compacting stay removed, and values set since stay where they are
+        for fp in map.values_mut() {
+            relocate(fp, &copied, &new_path, id);
+            for version in &mut fp.history {
+                relocate(version, &copied, &new_path, id);
             }
-            map.insert(
-                key.to_owned(),
-                FilePointer {
-                    path: new_path.clone(),
-                    offset: value.offset,
-                },
-            );
         }
+        // Rebuilding drops the keys removed since the filter was built
+        if let (Some(bloom), Some(rate)) = (&self.bloom, self.config.bloom_false_positive_rate) {
+            *bloom.write().unwrap() = BloomFilter::build(map.keys(), rate);
+        }
+        let mut removed_bytes = 0;
         for path in &immutable_ids {
+            removed_bytes += fs::metadata(path)?.len();
             remove_file(path)?;
         }
+        let reclaimed = removed_bytes.saturating_sub(fs::metadata(&new_path)?.len());
+        let finished = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        self.last_reclaimed.store(reclaimed, Ordering::Relaxed);
+        self.last_compaction.store(finished, Ordering::Relaxed);
+        self.compactions.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// NamespacedStore is a namespace of a KvStore, returned by KvStore::namespace
+#[derive(Clone)]
+pub struct NamespacedStore {
+    store: KvStore,
+    ns: u32,
+}
+
+impl KvsEngine for NamespacedStore {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.store.set_bytes_in(self.ns, key, value)
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.store.get_bytes_in(self.ns, key)
+    }
+
+    fn contains_key_bytes(&self, key: Vec<u8>) -> Result<bool> {
+        Ok(self.store.contains_key_in(self.ns, &key))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.store.remove_bytes_in(self.ns, key)
+    }
+
+    fn get_set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.store.get_set_bytes_in(self.ns, key, value)
+    }
+
+    fn set_nx_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        self.store.set_nx_bytes_in(self.ns, key, value)
+    }
+
+    fn append_bytes(&self, key: Vec<u8>, suffix: Vec<u8>) -> Result<usize> {
+        self.store.append_bytes_in(self.ns, key, suffix)
+    }
+
+    fn scan_bytes(
+        &self,
+        start: Vec<u8>,
+        end: Vec<u8>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.store.scan_bytes_in(self.ns, start, end, limit)
+    }
+
+    fn merge(&self, key: String, operand: String) -> Result<()> {
+        self.store.merge_in(self.ns, key, operand)
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.store.len_in(self.ns)
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.store.clear_namespace(self.ns)
+    }
+
+    fn subscribe(&self, prefix: String) -> Result<Receiver<KeyEvent>> {
+        match self.ns {
+            DEFAULT_NAMESPACE => Ok(self.store.subscribe(prefix)),
+            _ => Err(KvStoreError::UnsupportedError {
+                operation: "subscribe outside the default namespace".to_owned(),
+            }),
+        }
+    }
+
+    fn compactions(&self) -> u64 {
+        self.store.compactions()
+    }
+
+    fn size_on_disk(&self) -> Result<Option<u64>> {
+        self.store.size_on_disk()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.store.flush()
+    }
+}
+
+/// Transaction reads and buffers writes for KvStore::transaction. Reads see the writes made
+/// earlier in the same transaction.
+pub struct Transaction<'a> {
+    store: &'a KvStore,
+    // Version of each key read when it was first read, None for missing keys
+    reads: HashMap<Vec<u8>, Option<u64>>,
+    // Buffered writes, None for removals
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl Transaction<'_> {
+    /// get returns the value of key. If the key does not exist, returns None.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(v) => Ok(Some(String::from_utf8(v)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// set buffers a write of value to key
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.store
+            .config
+            .limits()
+            .check(key.len(), value.len() as u64)?;
+        self.writes
+            .insert(key.into_bytes(), Some(value.into_bytes()));
+        Ok(())
+    }
+
+    /// remove buffers the removal of key. Returns KeyNotFoundError if the key does not exist.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        let key = key.into_bytes();
+        if self.get_bytes(key.clone())?.is_none() {
+            return Err(KvStoreError::KeyNotFoundError {});
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        let map = self.store.map.read().unwrap();
+        let fp = map.get(&index_key(DEFAULT_NAMESPACE, &key));
+        let value = match fp {
+            Some(fp) => read_value(&self.store.config, &key, fp, u64::MAX)?,
+            None => None,
+        };
+        self.reads.entry(key).or_insert(fp.map(|fp| fp.version));
+        Ok(value)
+    }
+
+    // Checks that no key read has changed and applies the writes, holding the writer and index
+    // locks throughout so no other write can come in between
+    fn commit(self) -> Result<()> {
+        let store = self.store;
+        let mut writer = store.writer.lock().unwrap();
+        let mut id = store.id.lock().unwrap();
+        let mut map = store.map.write().unwrap();
+        for (key, version) in &self.reads {
+            let fp = map.get(&index_key(DEFAULT_NAMESPACE, key));
+            if fp.map(|fp| fp.version) != *version {
+                return Err(KvStoreError::TransactionConflict {});
+            }
+        }
+        for (key, value) in self.writes {
+            let index_key = index_key(DEFAULT_NAMESPACE, &key);
+            match value {
+                Some(value) => {
+                    let cmd = Command::new(CommandType::Set, key.clone(), value, 0);
+                    let fp = store.append_command(&mut writer, &mut id, &cmd)?;
+                    store.subscribers.publish(&key, |key| KeyEvent::Set { key });
+                    store.bloom_insert(&index_key);
+                    insert_version(&mut map, index_key.clone(), fp, store.config.history_depth);
+                    store.uncache(&index_key);
+                }
+                // Keys set and removed within the transaction were never written
+                None if map.contains_key(&index_key) => {
+                    let cmd = Command::new(CommandType::Rm, key.clone(), Vec::new(), 0);
+                    store.append_command(&mut writer, &mut id, &cmd)?;
+                    map.remove(&index_key);
+                    store.uncache(&index_key);
+                    store
+                        .subscribers
+                        .publish(&key, |key| KeyEvent::Remove { key });
+                }
+                None => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+// Points fp at the record copied from it into the compacted log file at new_path with the given
+// id, if there is one. Operands up to id were folded into the copied record.
+fn relocate(
+    fp: &mut FilePointer,
+    copied: &HashMap<(u64, u64), (Vec<u8>, FilePointer)>,
+    new_path: &Path,
+    id: u64,
+) {
+    let record = match get_log_id(&fp.path).and_then(|log_id| copied.get(&(log_id, fp.offset))) {
+        Some((_, record)) => record,
+        None => return,
+    };
+    fp.operands
+        .retain(|(path, _, _)| get_log_id(path).is_some_and(|file_id| file_id > id));
+    fp.path = new_path.to_owned();
+    fp.offset = record.offset;
+    fp.len = record.len;
+    fp.value_len = if fp.operands.is_empty() {
+        record.value_len
+    } else {
+        None
+    };
+}
+
+// CopiedRecords are the records copy_live_records copied into a new log file
+struct CopiedRecords {
+    // Index keys of the copied records and pointers to them in the new file, by the id of the log
+    // file and the offset they were copied from
+    index: HashMap<(u64, u64), (Vec<u8>, FilePointer)>,
+    // Log files that were read
+    files: HashSet<PathBuf>,
+    // Checksum of the bytes written for each record, by its offset in the new file
+    checksums: HashMap<u64, u64>,
+}
+
+impl CopiedRecords {
+    // Reads back every copied record from the new file at path, which must have been flushed,
+    // through a buffer of capacity bytes. Returns CompactionMismatch if a record is not what was
+    // written or is missing from the end of the file, or KeyMismatch if it belongs to another key
+    // than the index says.
+    fn verify(&self, path: &Path, capacity: usize) -> Result<()> {
+        let mut f = BufReader::with_capacity(capacity, File::open(path)?);
+        for (index_key, fp) in self.index.values() {
+            f.seek(SeekFrom::Start(fp.offset))?;
+            let mut checksum = Checksum::new(io::sink());
+            let mut record = vec![0; fp.len as usize];
+            // A file cut short by a failed write ends before its last records
+            match f.read_exact(&mut record) {
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    return Err(KvStoreError::CompactionMismatch { offset: fp.offset })
+                }
+                res => res?,
+            }
+            checksum.write_all(&record)?;
+            let cmd: Command = serde_json::from_slice(&record)
+                .map_err(|_| KvStoreError::CompactionMismatch { offset: fp.offset })?;
+            if cmd.index_key() != *index_key {
+                return Err(KvStoreError::KeyMismatch {
+                    expected: String::from_utf8_lossy(strip_namespace(index_key)).into_owned(),
+                    found: String::from_utf8_lossy(&cmd.key).into_owned(),
+                });
+            }
+            let copied = io::copy(&mut (&mut f).take(cmd.len), &mut checksum)?;
+            if copied != cmd.len || self.checksums.get(&fp.offset) != Some(&checksum.finish()) {
+                return Err(KvStoreError::CompactionMismatch { offset: fp.offset });
+            }
+        }
         Ok(())
     }
 }
 
-fn get_log_path(path: &PathBuf, id: u16) -> PathBuf {
+// Checksum hashes the bytes written through it to inner. The hash is only meant to be compared
+// within the same process.
+struct Checksum<W> {
+    inner: W,
+    hasher: DefaultHasher,
+}
+
+impl<W: Write> Checksum<W> {
+    fn new(inner: W) -> Self {
+        Checksum {
+            inner,
+            hasher: DefaultHasher::new(),
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.hasher.finish()
+    }
+}
+
+impl<W: Write> Write for Checksum<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.write(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// LiveRecord is a record of a log file that the index still points to, ready to be copied
+struct LiveRecord {
+    // The record to write, with its merge operands folded in and its time set
+    cmd: Command,
+    modified: SystemTime,
+    // Offset and length of the record in its log file, where raw value bytes are copied from
+    read_offset: u64,
+    read_len: u64,
+}
+
+// Copies every record in log files up to max_id that is still referenced by map into writer,
+// reporting to progress after each file. With parallel_compaction set, the live records of as
+// many files as the rayon pool has threads are looked for at once, and then written in order.
+// Returns the new index for the copied records, the set of files that were read and a checksum
+// of each copied record.
+#[allow(clippy::too_many_arguments)]
+fn copy_live_records<W: Write + Seek>(
+    dir: &Path,
+    config: &Config,
+    skipped: &[PathBuf],
+    map: &Index,
+    writer: &mut W,
+    dest_path: &Path,
+    max_id: u64,
+    mut progress: Option<&mut dyn FnMut(CompactionProgress)>,
+) -> Result<CopiedRecords> {
+    // Files are copied in the order they were written, so the earlier values of a key are
+    // loaded before its latest one
+    let ids: Vec<u64> = log_ids(dir)?
+        .into_iter()
+        .filter(|id| *id <= max_id)
+        .collect();
+    let files_total = ids.len();
+    let chunk_size = if config.parallel_compaction {
+        rayon::current_num_threads()
+    } else {
+        1
+    };
+    let mut copied = HashMap::new();
+    let mut checksums = HashMap::new();
+    let mut offset = 0u64;
+    let mut immutable_ids: HashSet<PathBuf> = HashSet::new();
+    let mut files_done = 0;
+    for chunk in ids.chunks(chunk_size) {
+        let find = |id: &u64| live_records(&get_log_path(dir, *id), config, skipped, map, max_id);
+        let found: Vec<Option<Vec<LiveRecord>>> = if chunk.len() > 1 {
+            chunk.par_iter().map(find).collect::<Result<_>>()?
+        } else {
+            chunk.iter().map(find).collect::<Result<_>>()?
+        };
+        for (id, records) in chunk.iter().zip(found) {
+            let path = get_log_path(dir, *id);
+            // A skipped file that can't be opened at all is left for the user to inspect
+            if let Some(records) = records {
+                for record in records {
+                    let LiveRecord {
+                        cmd,
+                        modified,
+                        read_offset,
+                        read_len,
+                    } = record;
+                    let mut checksum = Checksum::new(&mut *writer);
+                    let len = write_record(&mut checksum, &cmd, config.compression)?;
+                    if cmd.len > 0 {
+                        let (_, mut value) = open_record(&path, read_offset, read_len, &cmd.key)?;
+                        io::copy(&mut value, &mut checksum)?;
+                    }
+                    checksums.insert(offset, checksum.finish());
+                    let value_len = cmd.value_len();
+                    copied.insert(
+                        (*id, read_offset),
+                        (
+                            cmd.index_key(),
+                            FilePointer {
+                                path: dest_path.to_owned(),
+                                offset,
+                                len,
+                                operands: Vec::new(),
+                                modified,
+                                version: 0,
+                                value_len,
+                                history: Vec::new(),
+                            },
+                        ),
+                    );
+                    offset = writer.stream_position()?;
+                }
+                immutable_ids.insert(path);
+            }
+            files_done += 1;
+            if let Some(progress) = progress.as_mut() {
+                progress(CompactionProgress {
+                    files_done,
+                    files_total,
+                    bytes_written: offset,
+                });
+            }
+        }
+    }
+    Ok(CopiedRecords {
+        index: copied,
+        files: immutable_ids,
+        checksums,
+    })
+}
+
+// Returns the records of the log file at path that map still points to, in the order they were
+// written, or None if the file is a skipped one that can't be opened
+fn live_records(
+    path: &Path,
+    config: &Config,
+    skipped: &[PathBuf],
+    map: &Index,
+    max_id: u64,
+) -> Result<Option<Vec<LiveRecord>>> {
+    let records = match LogRecords::open(path, config.read_buffer_size) {
+        Err(_) if skipped.iter().any(|p| p == path) => return Ok(None),
+        records => records?,
+    };
+    let mut live = Vec::new();
+    for res in records {
+        // The unreadable rest of a skipped file has no live records
+        let (read_offset, read_len, mut cmd) = match res {
+            Err(_) if skipped.iter().any(|p| p == path) => break,
+            res => res?,
+        };
+        if cmd.cmd == CommandType::Rm {
+            continue;
+        }
+        // The record may be the latest value of its key or one of the earlier ones
+        let v = match map.get(&cmd.index_key()).and_then(|fp| {
+            std::iter::once(fp)
+                .chain(&fp.history)
+                .find(|v| v.path == path && v.offset == read_offset)
+        }) {
+            Some(v) => v,
+            None => continue,
+        };
+        // Fold pending merge operands into a plain Set record
+        let mut modified = cmd.modified(v.modified);
+        if cmd.cmd == CommandType::Merge || !v.operands.is_empty() {
+            if let Some(value) = read_value(config, &cmd.key, v, max_id)? {
+                cmd.cmd = CommandType::Set;
+                cmd.value = value;
+                cmd.len = 0;
+                cmd.compressed = None;
+                modified = last_modified(&cmd.key, v, max_id, modified)?;
+            }
+        }
+        // The time is written even for records that had none, so the fallback is kept once
+        // their log file is gone
+        cmd.time = modified
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|d| d.as_nanos() as u64);
+        live.push(LiveRecord {
+            cmd,
+            modified,
+            read_offset,
+            read_len,
+        });
+    }
+    Ok(Some(live))
+}
+
+// ValueReader reads a value either directly from a log file or from memory
+enum ValueReader {
+    Log(BufReader<Take<File>>),
+    Memory(Cursor<Vec<u8>>),
+}
+
+impl Read for ValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ValueReader::Log(reader) => reader.read(buf),
+            ValueReader::Memory(reader) => reader.read(buf),
+        }
+    }
+}
+
+// LogRecords iterates over the records of a log file along with their offsets and lengths,
+// skipping over the raw value bytes of streamed records
+struct LogRecords {
+    reader: BufReader<File>,
+    offset: u64,
+    // Length of the log file, which the raw value bytes of a record must not run past
+    file_len: u64,
+}
+
+impl LogRecords {
+    // Opens the log file at path, reading it through a buffer of capacity bytes
+    fn open(path: &Path, capacity: usize) -> Result<LogRecords> {
+        let f = File::open(path)?;
+        let file_len = f.metadata()?.len();
+        Ok(LogRecords {
+            reader: BufReader::with_capacity(capacity, f),
+            offset: 0,
+            file_len,
+        })
+    }
+
+    // Returns the next record, or IncompleteRecord if the file ends partway through it
+    fn next_record(&mut self) -> Result<Option<(u64, u64, Command)>> {
+        let incomplete = KvStoreError::IncompleteRecord {
+            offset: self.offset,
+        };
+        let (cmd, read) = match read_record(&mut self.reader) {
+            Ok(Some(record)) => record,
+            Ok(None) => return Ok(None),
+            Err(KvStoreError::SerdeError { error }) if error.is_eof() => return Err(incomplete),
+            Err(e) => return Err(e),
+        };
+        if self.offset + read + cmd.len > self.file_len {
+            return Err(incomplete);
+        }
+        if cmd.len > 0 {
+            self.reader.seek_relative(cmd.len as i64)?;
+        }
+        let offset = self.offset;
+        self.offset += read + cmd.len;
+        Ok(Some((offset, read, cmd)))
+    }
+}
+
+impl Iterator for LogRecords {
+    type Item = Result<(u64, u64, Command)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+// Reads a single record from reader, leaving it at the start of the record's raw value bytes.
+// Returns the record along with the number of bytes read.
+fn read_record(reader: &mut BufReader<File>) -> Result<Option<(Command, u64)>> {
+    let mut stream = serde_json::Deserializer::from_reader(&mut *reader).into_iter::<Command>();
+    match stream.next() {
+        Some(res) => {
+            let cmd = res?;
+            Ok(Some((cmd, stream.byte_offset() as u64)))
+        }
+        None => Ok(None),
+    }
+}
+
+// Writes cmd to writer, returning the length of the record. An inline value is compressed as
+// configured, in which case it is written after the record as raw bytes, like a streamed value,
+// so the record still reads as exactly len bytes.
+fn write_record<W: Write>(writer: &mut W, cmd: &Command, compression: Compression) -> Result<u64> {
+    if let (Compression::Deflate(level), 0) = (compression, cmd.len) {
+        let compressed = compress_to_vec(&cmd.value, level);
+        if compressed.len() < cmd.value.len() {
+            let header = Command {
+                cmd: cmd.cmd,
+                key: cmd.key.clone(),
+                value: Vec::new(),
+                len: compressed.len() as u64,
+                time: cmd.time,
+                ns: cmd.ns,
+                compressed: Some(cmd.value.len() as u64),
+            };
+            // The header and the compressed bytes go out in one write
+            let mut record = serde_json::to_vec(&header)?;
+            let len = record.len() as u64;
+            record.extend_from_slice(&compressed);
+            writer.write_all(&record)?;
+            return Ok(len);
+        }
+    }
+    let record = serde_json::to_vec(cmd)?;
+    writer.write_all(&record)?;
+    Ok(record.len() as u64)
+}
+
+// Opens the record of key with len bytes at offset, returning it along with a reader over its raw
+// value bytes. Exactly len bytes are read, so records don't need to delimit themselves. Returns
+// KeyMismatch if the record belongs to another key.
+fn open_record(path: &Path, offset: u64, len: u64, key: &[u8]) -> Result<(Command, Take<File>)> {
+    let mut f = File::open(path)?;
+    f.seek(SeekFrom::Start(offset))?;
+    let mut record = vec![0; len as usize];
+    f.read_exact(&mut record)?;
+    let cmd: Command = serde_json::from_slice(&record)?;
+    if cmd.key != key {
+        return Err(KvStoreError::KeyMismatch {
+            expected: String::from_utf8_lossy(key).into_owned(),
+            found: String::from_utf8_lossy(&cmd.key).into_owned(),
+        });
+    }
+    let value_len = cmd.len;
+    Ok((cmd, f.take(value_len)))
+}
+
+// Reads the record of key with len bytes at offset, including the value of a streamed record,
+// which is decompressed if it was compressed
+fn read_command(path: &Path, offset: u64, len: u64, key: &[u8]) -> Result<Command> {
+    let (mut cmd, mut value) = open_record(path, offset, len, key)?;
+    if cmd.len > 0 {
+        cmd.value = vec![0; cmd.len as usize];
+        value.read_exact(&mut cmd.value)?;
+    }
+    if let Some(raw_len) = cmd.compressed {
+        cmd.value = decompress_to_vec_with_limit(&cmd.value, raw_len as usize)?;
+    }
+    Ok(cmd)
+}
+
+// Reads the record fp points to and folds its merge operands from log files up to max_id into it
+fn read_value(
+    config: &Config,
+    key: &[u8],
+    fp: &FilePointer,
+    max_id: u64,
+) -> Result<Option<Vec<u8>>> {
+    let base = read_command(&fp.path, fp.offset, fp.len, key)?;
+    let mut value = match base.cmd {
+        CommandType::Merge => apply_merge(config, key, None, &base.value)?,
+        _ => base.value,
+    };
+    for (path, offset, len) in &fp.operands {
+        if get_log_id(path).is_some_and(|id| id > max_id) {
+            break;
+        }
+        let cmd = read_command(path, *offset, *len, key)?;
+        value = apply_merge(config, key, Some(&value), &cmd.value)?;
+    }
+    Ok(Some(value))
+}
+
+// Returns the time of the latest merge operand of key at fp in log files up to max_id, or
+// modified if it has none. Operands without a time are dated by their log file.
+fn last_modified(
+    key: &[u8],
+    fp: &FilePointer,
+    max_id: u64,
+    modified: SystemTime,
+) -> Result<SystemTime> {
+    let mut last = modified;
+    for (path, offset, len) in &fp.operands {
+        if get_log_id(path).is_some_and(|id| id > max_id) {
+            break;
+        }
+        let cmd = read_command(path, *offset, *len, key)?;
+        last = cmd.modified(fs::metadata(path)?.modified()?);
+    }
+    Ok(last)
+}
+
+// The merge operator works on strings, so bytes that are not valid UTF-8 are converted lossily
+fn apply_merge(
+    config: &Config,
+    key: &[u8],
+    existing: Option<&[u8]>,
+    operand: &[u8],
+) -> Result<Vec<u8>> {
+    match &config.merge_operator {
+        Some(merge_operator) => {
+            let existing = existing.map(String::from_utf8_lossy);
+            let merged = merge_operator(
+                &String::from_utf8_lossy(key),
+                existing.as_deref(),
+                &String::from_utf8_lossy(operand),
+            );
+            Ok(merged.into_bytes())
+        }
+        None => Err(KvStoreError::NoMergeOperatorError {}),
+    }
+}
+
+// Returns the id of the log file after id, leaving the odd id in between free for compaction
+// output, or LogIdsExhausted if there is none
+fn next_log_id(id: u64) -> Result<u64> {
+    id.checked_add(2)
+        .filter(|next| *next < u64::MAX)
+        .ok_or(KvStoreError::LogIdsExhausted { id })
+}
+
+fn get_log_path(path: &Path, id: u64) -> PathBuf {
     let mut log_path = path.join(id.to_string());
     log_path.set_extension("log");
     log_path
 }
 
-fn get_log_id(path: &PathBuf) -> Result<Option<u16>> {
-    if let Some(ext) = path.extension() {
-        if *ext == *"log" {
-            if let Some(id) = path.file_stem() {
-                if let Some(id_str) = id.to_str() {
-                    let num_id = id_str.parse::<u16>()?;
-                    return Ok(Some(num_id));
-                }
-            }
+// Returns the id of the log file at path, or None if path is not named like one. Log files are
+// named <id>.log, so a file with a name that is not valid UTF-8 or a stem that is not a u64 isn't
+// a log file, whatever its extension.
+fn get_log_id(path: &Path) -> Option<u64> {
+    if path.extension()? != "log" {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+// Returns the ids of the log files in path in ascending order. Returns CorruptLog if a log file
+// is not named the way get_log_path names it, such as 07.log, since it would either be read in
+// place of the file of its id or not be read at all.
+fn log_ids(path: &Path) -> Result<Vec<u64>> {
+    let mut ids: Vec<u64> = Vec::new();
+    for res in fs::read_dir(path)? {
+        let entry_path = res?.path();
+        let id = match get_log_id(&entry_path) {
+            Some(id) => id,
+            None => continue,
+        };
+        let expected = get_log_path(path, id);
+        if entry_path != expected {
+            let reason = if expected.exists() {
+                format!(
+                    "{} and {} both have log id {}",
+                    entry_path.display(),
+                    expected.display(),
+                    id
+                )
+            } else {
+                format!(
+                    "{} has log id {} but should be named {}",
+                    entry_path.display(),
+                    id,
+                    expected.display()
+                )
+            };
+            return Err(KvStoreError::CorruptLog { reason });
         }
+        ids.push(id);
     }
-    Ok(None)
+    ids.sort_unstable();
+    Ok(ids)
 }
 
-fn load(path: &Path) -> Result<(HashMap<String, FilePointer>, u16)> {
-    // Find all log files and sort them in asc order
-    let mut ids: Vec<u16> = Vec::new();
+// Removes the tempfiles of compactions that were interrupted by a crash. Other files in the log
+// directory are left alone.
+fn remove_orphans(path: &Path) -> Result<()> {
     for res in fs::read_dir(path)? {
         let entry = res?;
         let entry_path = entry.path();
-        if let Some(id) = get_log_id(&entry_path)? {
-            ids.push(id);
+        let orphan = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.starts_with(COMPACTION_TEMP_PREFIX));
+        if entry.file_type()?.is_file() && orphan {
+            remove_file(&entry_path)?;
         }
     }
-    ids.sort_unstable();
-    let mut last_id = 0u16;
-    if ids.len() > 0 {
+    Ok(())
+}
+
+// Loads the index from the log files in path. Returns the index, the id of the last log file and,
+// with config.recover, the files that could only be read in part.
+fn load(path: &Path, config: &Config) -> Result<(Index, u64, Vec<PathBuf>)> {
+    let ids = log_ids(path)?;
+    let mut last_id = 0u64;
+    if !ids.is_empty() {
         last_id = ids[ids.len() - 1];
     }
     // Read files in order and load into map
-    let mut map: HashMap<String, FilePointer> = HashMap::new();
+    let mut map = Index::new();
+    let mut skipped = Vec::new();
     for id in ids {
-        let path_buf = get_log_path(&path.to_owned(), id);
-        let f = File::open(&path_buf)?;
-        let reader = BufReader::new(f);
-        let mut stream = serde_json::Deserializer::from_reader(reader).into_iter::<Command>();
-        let mut offset = 0u64;
-        while let Some(res) = stream.next() {
-            let cmd: Command = res?;
-            match cmd.cmd {
-                CommandType::Set => {
+        let path_buf = get_log_path(path, id);
+        match load_file(&mut map, &path_buf, config) {
+            Ok(()) => {}
+            // A write to the newest log file was cut short, so everything before it is intact
+            Err(KvStoreError::IncompleteRecord { offset }) if id == last_id => {
+                warn!(config.logger, "cut an incomplete record off the end of the log";
+                    "path" => %path_buf.display(), "offset" => offset);
+                OpenOptions::new()
+                    .write(true)
+                    .open(&path_buf)?
+                    .set_len(offset)?;
+            }
+            Err(e) if !config.recover => return Err(e),
+            Err(e) => {
+                warn!(config.logger, "skipped the unreadable rest of a log file";
+                    "path" => %path_buf.display(), "error" => %e);
+                skipped.push(path_buf);
+            }
+        }
+    }
+    Ok((map, last_id, skipped))
+}
+
+// Applies the records of the log file at path to map, up to the first one that can't be read
+fn load_file(map: &mut Index, path: &Path, config: &Config) -> Result<()> {
+    // Records written before times were recorded are dated by their log file
+    let file_modified = fs::metadata(path)?.modified()?;
+    for res in LogRecords::open(path, config.read_buffer_size)? {
+        let (offset, len, cmd) = res?;
+        let modified = cmd.modified(file_modified);
+        match cmd.cmd {
+            CommandType::Set => {
+                let value_len = cmd.value_len();
+                insert_version(
+                    map,
+                    cmd.index_key(),
+                    FilePointer {
+                        path: path.to_owned(),
+                        offset,
+                        len,
+                        operands: Vec::new(),
+                        modified,
+                        version: 0,
+                        value_len,
+                        history: Vec::new(),
+                    },
+                    config.history_depth,
+                );
+            }
+            CommandType::Rm => {
+                map.remove(&cmd.index_key());
+            }
+            CommandType::Merge => match map.get_mut(&cmd.index_key()) {
+                Some(fp) => {
+                    fp.operands.push((path.to_owned(), offset, len));
+                    fp.modified = modified;
+                    fp.value_len = None;
+                }
+                None => {
                     map.insert(
-                        cmd.key,
+                        cmd.index_key(),
                         FilePointer {
-                            path: path_buf.clone(),
-                            offset: offset,
+                            path: path.to_owned(),
+                            offset,
+                            len,
+                            operands: Vec::new(),
+                            modified,
+                            version: 0,
+                            value_len: None,
+                            history: Vec::new(),
                         },
                     );
                 }
-                CommandType::Rm => {
-                    map.remove(&cmd.key);
-                }
-            }
-            offset = stream.byte_offset() as u64;
+            },
         }
     }
-    Ok((map, last_id))
+    Ok(())
 }
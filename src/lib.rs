@@ -6,20 +6,48 @@ extern crate failure_derive;
 #[macro_use]
 extern crate slog;
 
+#[cfg(feature = "async")]
+mod async_client;
+#[cfg(feature = "async")]
+mod async_server;
+mod bloom;
+mod cache;
 mod client;
 mod config;
 mod engine;
 mod error;
 mod kv;
+#[cfg(feature = "metrics")]
+mod metrics;
 mod network;
+mod pubsub;
+#[cfg(feature = "rocksdb")]
+mod rocks;
 mod server;
 /// thread_pool contains various thread pool implementations
 pub mod thread_pool;
 
+#[cfg(feature = "async")]
+pub use async_client::KvsAsyncClient;
+#[cfg(feature = "async")]
+pub use async_server::AsyncKvsServer;
 pub use client::KvsClient;
-pub use config::Config;
-pub use engine::{KvsEngine, SledKvsEngine};
+pub use config::{
+    CompactionPolicy, Compression, Config, ConfigBuilder, LogFormat, MergeOperator, ServerConfig,
+};
+pub use engine::{
+    resolve_engine, Engine, EngineKind, KvsEngine, MemoryKvsEngine, SledKvsEngine, WriteOp,
+};
 pub use error::KvStoreError;
-pub use kv::{KvStore, Result};
-pub use network::{ClientRequest, ClientRequestType, Response};
-pub use server::KvsServer;
+pub use kv::{
+    CompactionProgress, KeyMetadata, KvStore, NamespacedStore, Result, Stats, Transaction,
+};
+#[cfg(feature = "metrics")]
+pub use metrics::{serve_metrics, Metrics};
+pub use network::{
+    resolve_addr, ClientRequest, ClientRequestType, ErrorCode, Response, StatsReport,
+};
+pub use pubsub::KeyEvent;
+#[cfg(feature = "rocksdb")]
+pub use rocks::RocksKvsEngine;
+pub use server::{run_server, run_server_with, KvsServer, ServerMetricsSnapshot};
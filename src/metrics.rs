@@ -0,0 +1,102 @@
+use crate::Result;
+
+use prometheus::{
+    Encoder, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder, TEXT_FORMAT,
+};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+
+/// Metrics are the counters KvsServer exports in the Prometheus text format
+pub struct Metrics {
+    registry: Registry,
+    /// requests counts handled requests by command type
+    pub requests: IntCounterVec,
+    /// errors counts requests that returned an error
+    pub errors: IntCounter,
+    /// connections is the number of connections currently being handled
+    pub connections: IntGauge,
+    /// keys is the number of keys in the engine as of the last scrape
+    pub keys: IntGauge,
+    /// compactions is the number of compactions the engine has run as of the last scrape
+    pub compactions: IntGauge,
+}
+
+impl Metrics {
+    /// new registers a fresh set of metrics
+    pub fn new() -> Result<Metrics> {
+        let registry = Registry::new_custom(Some("kvs".to_owned()), None)?;
+        let requests = IntCounterVec::new(
+            Opts::new("requests_total", "Requests handled by command type"),
+            &["command_type"],
+        )?;
+        let errors = IntCounter::new("errors_total", "Requests that returned an error")?;
+        let connections = IntGauge::new("connections", "Connections currently being handled")?;
+        let keys = IntGauge::new("keys", "Keys in the engine")?;
+        let compactions = IntGauge::new("compactions", "Compactions run by the engine")?;
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(errors.clone()))?;
+        registry.register(Box::new(connections.clone()))?;
+        registry.register(Box::new(keys.clone()))?;
+        registry.register(Box::new(compactions.clone()))?;
+        Ok(Metrics {
+            registry,
+            requests,
+            errors,
+            connections,
+            keys,
+            compactions,
+        })
+    }
+
+    /// encode renders every metric in the Prometheus text format
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// serve_metrics binds addr and answers HTTP requests for /metrics on a background thread.
+/// refresh is called before every scrape to update gauges that are read from the engine.
+pub fn serve_metrics<F>(addr: SocketAddr, metrics: Arc<Metrics>, refresh: F) -> Result<()>
+where
+    F: Fn(&Metrics) + Send + 'static,
+{
+    let listener = TcpListener::bind(addr)?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            refresh(&metrics);
+            // A scraper that goes away mid-response only affects its own scrape
+            let _ = respond(stream, &metrics);
+        }
+    });
+    Ok(())
+}
+
+fn respond(mut stream: TcpStream, metrics: &Metrics) -> Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip the headers, the request has no body
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+    let (status, content_type, body) = match path {
+        "/metrics" => ("200 OK", TEXT_FORMAT, metrics.encode()?),
+        _ => ("404 Not Found", "text/plain", b"Not Found\n".to_vec()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(&body)?;
+    stream.flush()?;
+    Ok(())
+}
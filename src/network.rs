@@ -1,9 +1,33 @@
+use crate::error::KvStoreError;
+use crate::server::ServerMetricsSnapshot;
+
 use serde::de::{self, Deserializer, MapAccess, SeqAccess, Visitor};
 use serde::{Deserialize, Serialize};
 use std::fmt;
+use std::io::Read;
+use std::net::{SocketAddr, ToSocketAddrs};
+
+/// resolve_addr resolves addr, an IP address or hostname and a port such as localhost:4000, to
+/// a socket address. An IPv4 address is picked over an IPv6 one when a hostname has both, as
+/// servers listen on IPv4 by default.
+pub fn resolve_addr(addr: &str) -> crate::Result<SocketAddr> {
+    // A malformed address or unknown host fails the lookup, which is reported the same way
+    let addrs: Vec<SocketAddr> = match addr.to_socket_addrs() {
+        Ok(addrs) => addrs.collect(),
+        Err(_) => Vec::new(),
+    };
+    addrs
+        .iter()
+        .find(|socket| socket.is_ipv4())
+        .or_else(|| addrs.first())
+        .copied()
+        .ok_or_else(|| KvStoreError::AddrResolveError {
+            addr: addr.to_owned(),
+        })
+}
 
 /// NetworkCommandType is type of command sent between client and server
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Hash)]
 pub enum ClientRequestType {
     /// Get retrives key, value pair
     Get,
@@ -11,17 +35,78 @@ pub enum ClientRequestType {
     Set,
     /// Rm removes key, value pair
     Rm,
+    /// Batch runs every request in batch in order
+    Batch,
+    /// Scan retrieves the key, value pairs from key up to, but not including, value
+    Scan,
+    /// ScanPage retrieves up to value, a number, of key, value pairs from key on, without an
+    /// upper bound. The next page starts right after the last key of a page, which is that key
+    /// followed by a NUL character.
+    ScanPage,
+    /// MultiGet retrieves the values of keys
+    MultiGet,
+    /// Subscribe streams the changes of the keys starting with key until the client disconnects
+    Subscribe,
+    /// GetSet sets key to value and returns the value it replaced
+    GetSet,
+    /// SetNx sets key to value only if key does not exist and returns in value "true" if it was
+    /// set or "false" if key already existed
+    SetNx,
+    /// Auth authenticates the connection with the token in value. It must be the first request
+    /// on a connection and is followed by the request to run.
+    Auth,
+    /// Stats returns a StatsReport of the server and its engine as JSON in value
+    Stats,
+    /// Exists returns whether key exists in exists, without sending its value
+    Exists,
+    /// Merge combines value into the value of key with the merge operator of the engine
+    Merge,
+    /// Count returns the number of keys in the engine in value
+    Count,
+    /// Clear removes every key from the engine
+    Clear,
+    /// Echo returns value unchanged, without touching the engine
+    Echo,
+    /// Protocol selects the format of the responses to Get requests on the connection with
+    /// value, "json" or "binary". It is sent at the start of a connection, after Auth if the
+    /// server requires it, and is followed by the request to run. A binary response is one status
+    /// byte, 0 if the key was found, 1 if it was not or 2 for an error, then the length of the
+    /// value or error message as 8 big-endian bytes, then its bytes. Pipelined requests are always
+    /// answered in JSON.
+    Protocol,
 }
 
 /// NetworkCommand is command sent of TCP between client and server.
 #[derive(Serialize, Debug, PartialEq)]
 pub struct ClientRequest {
-    /// command_type is type of client request: Get, Set, Rm
+    /// command_type is type of client request: Get, Set, Rm, Batch, Scan, ScanPage,
+    /// MultiGet, Subscribe, GetSet, SetNx, Auth, Stats, Exists, Merge, Count, Clear, Echo, Protocol
     pub command_type: ClientRequestType,
     /// key is required
     pub key: String,
     /// value is optional
     pub value: String,
+    /// batch holds the requests of a Batch request and is empty otherwise
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub batch: Vec<ClientRequest>,
+    /// keys holds the keys of a MultiGet request and is empty otherwise
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub keys: Vec<String>,
+    /// trace_id is an optional id of the client trace that the server spans of the request link
+    /// to. It is recorded with the tracing feature and ignored otherwise.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+    /// seq is the sequence id of a pipelined request and is echoed on its response. A request
+    /// with one keeps the connection open for more requests, which the server may run
+    /// concurrently but answers in the order they were sent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    /// request_id is an optional id that makes retrying the request safe. A request with the id
+    /// of one the server answered recently gets the same response again instead of being run
+    /// twice, and one with the id of a request that is still running waits for its response.
+    /// Sending the id of a different request is an error.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
 }
 
 impl<'de> Deserialize<'de> for ClientRequest {
@@ -33,6 +118,11 @@ impl<'de> Deserialize<'de> for ClientRequest {
             CommandType,
             Key,
             Value,
+            Batch,
+            Keys,
+            TraceId,
+            Seq,
+            RequestId,
         }
         impl<'de> Deserialize<'de> for Field {
             fn deserialize<D>(deserializer: D) -> Result<Field, D::Error>
@@ -45,7 +135,10 @@ impl<'de> Deserialize<'de> for ClientRequest {
                     type Value = Field;
 
                     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                        formatter.write_str("`command_type`, `key`, or `value`")
+                        formatter.write_str(
+                            "`command_type`, `key`, `value`, `batch`, `keys`, `trace_id`, `seq`, or \
+                             `request_id`",
+                        )
                     }
 
                     fn visit_str<E>(self, value: &str) -> Result<Field, E>
@@ -56,6 +149,11 @@ impl<'de> Deserialize<'de> for ClientRequest {
                             "command_type" => Ok(Field::CommandType),
                             "key" => Ok(Field::Key),
                             "value" => Ok(Field::Value),
+                            "batch" => Ok(Field::Batch),
+                            "keys" => Ok(Field::Keys),
+                            "trace_id" => Ok(Field::TraceId),
+                            "seq" => Ok(Field::Seq),
+                            "request_id" => Ok(Field::RequestId),
                             _ => Err(de::Error::unknown_field(value, FIELDS)),
                         }
                     }
@@ -86,10 +184,20 @@ impl<'de> Deserialize<'de> for ClientRequest {
                 let value = seq
                     .next_element()?
                     .ok_or_else(|| de::Error::invalid_length(2, &self))?;
+                let batch = seq.next_element()?.unwrap_or_default();
+                let keys = seq.next_element()?.unwrap_or_default();
+                let trace_id = seq.next_element()?.unwrap_or_default();
+                let seq_id = seq.next_element()?.unwrap_or_default();
+                let request_id = seq.next_element()?.unwrap_or_default();
                 Ok(ClientRequest {
                     command_type,
                     key,
                     value,
+                    batch,
+                    keys,
+                    trace_id,
+                    seq: seq_id,
+                    request_id,
                 })
             }
 
@@ -100,6 +208,11 @@ impl<'de> Deserialize<'de> for ClientRequest {
                 let mut command_type = None;
                 let mut key = None;
                 let mut value = None;
+                let mut batch = None;
+                let mut keys = None;
+                let mut trace_id = None;
+                let mut seq = None;
+                let mut request_id = None;
                 while let Some(k) = map.next_key()? {
                     match k {
                         Field::CommandType => {
@@ -120,29 +233,186 @@ impl<'de> Deserialize<'de> for ClientRequest {
                             }
                             value = Some(map.next_value()?);
                         }
+                        Field::Batch => {
+                            if batch.is_some() {
+                                return Err(de::Error::duplicate_field("batch"));
+                            }
+                            batch = Some(map.next_value()?);
+                        }
+                        Field::Keys => {
+                            if keys.is_some() {
+                                return Err(de::Error::duplicate_field("keys"));
+                            }
+                            keys = Some(map.next_value()?);
+                        }
+                        Field::TraceId => {
+                            if trace_id.is_some() {
+                                return Err(de::Error::duplicate_field("trace_id"));
+                            }
+                            trace_id = Some(map.next_value()?);
+                        }
+                        Field::Seq => {
+                            if seq.is_some() {
+                                return Err(de::Error::duplicate_field("seq"));
+                            }
+                            seq = Some(map.next_value()?);
+                        }
+                        Field::RequestId => {
+                            if request_id.is_some() {
+                                return Err(de::Error::duplicate_field("request_id"));
+                            }
+                            request_id = Some(map.next_value()?);
+                        }
                     }
                 }
                 let command_type =
                     command_type.ok_or_else(|| de::Error::missing_field("command_type"))?;
                 let key = key.ok_or_else(|| de::Error::missing_field("key"))?;
                 let value = value.ok_or_else(|| de::Error::missing_field("value"))?;
+                // batch is only sent with Batch requests, keys with MultiGet requests, trace_id
+                // by clients that trace their requests, seq by clients that pipeline them and
+                // request_id by clients that retry them
+                let batch = batch.unwrap_or_default();
+                let keys = keys.unwrap_or_default();
+                let trace_id = trace_id.unwrap_or_default();
+                let seq = seq.unwrap_or_default();
+                let request_id = request_id.unwrap_or_default();
                 Ok(ClientRequest {
                     command_type,
                     key,
                     value,
+                    batch,
+                    keys,
+                    trace_id,
+                    seq,
+                    request_id,
                 })
             }
         }
-        const FIELDS: &'static [&'static str] = &["command_type", "key", "value"];
+        const FIELDS: &[&str] = &[
+            "command_type",
+            "key",
+            "value",
+            "batch",
+            "keys",
+            "trace_id",
+            "seq",
+            "request_id",
+        ];
         deserializer.deserialize_struct("ClientRequest", FIELDS, ClientRequestVisitor)
     }
 }
 
+/// StatsReport describes a running server and its engine
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct StatsReport {
+    /// server counts the requests the server has handled
+    pub server: ServerMetricsSnapshot,
+    /// keys is the number of keys in the engine
+    pub keys: u64,
+    /// compactions is the number of compactions the engine has run
+    pub compactions: u64,
+    /// disk_bytes is the number of bytes the engine takes up on disk, if the engine knows
+    pub disk_bytes: Option<u64>,
+}
+
 /// Response is used to respond with OK or value
-#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
 pub struct Response {
     /// arbitrary string value
     pub value: String,
     /// error message
     pub error: String,
+    /// key, value pairs returned by Scan requests
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pairs: Vec<(String, String)>,
+    /// values returned by MultiGet requests, in the order of the keys, None for missing keys
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub values: Vec<Option<String>>,
+    /// whether the key of an Exists request exists, or whether a Get request found its key, which
+    /// tells a key set to the empty string apart from a missing one
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub exists: bool,
+    /// the seq of the pipelined request this responds to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    /// which error the request failed with, for errors clients may want to handle
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<ErrorCode>,
+}
+
+/// ErrorCode identifies an error of a failed request, so clients can return it as the same
+/// KvStoreError variant the server ran into
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// the key of the request was not found
+    KeyNotFound,
+}
+
+impl Response {
+    // Fails the response with e, along with its error code if it has one
+    pub(crate) fn set_error(&mut self, e: &KvStoreError) {
+        self.error = e.to_string();
+        self.error_code = match e {
+            KvStoreError::KeyNotFoundError {} => Some(ErrorCode::KeyNotFound),
+            _ => None,
+        };
+    }
+
+    // Turns the error of a failed response back into a KvStoreError: the variant of its error
+    // code, or ServerError with its message
+    pub(crate) fn into_error(self) -> KvStoreError {
+        match self.error_code {
+            Some(ErrorCode::KeyNotFound) => KvStoreError::KeyNotFoundError {},
+            None => KvStoreError::ServerError { error: self.error },
+        }
+    }
+}
+
+// Status bytes of binary Get responses
+const BINARY_FOUND: u8 = 0;
+const BINARY_NOT_FOUND: u8 = 1;
+const BINARY_ERROR: u8 = 2;
+
+// Encodes the response to a Get request in the binary format selected by a Protocol request
+pub(crate) fn encode_binary(resp: &Response) -> Vec<u8> {
+    let (status, body) = if !resp.error.is_empty() {
+        (BINARY_ERROR, &resp.error)
+    } else if resp.exists {
+        (BINARY_FOUND, &resp.value)
+    } else {
+        (BINARY_NOT_FOUND, &resp.value)
+    };
+    let mut buf = Vec::with_capacity(9 + body.len());
+    buf.push(status);
+    buf.extend_from_slice(&(body.len() as u64).to_be_bytes());
+    buf.extend_from_slice(body.as_bytes());
+    buf
+}
+
+// Reads a binary Get response from reader, returning the value, None for a missing key or
+// ServerError for an error
+pub(crate) fn read_binary<R: Read>(mut reader: R) -> crate::Result<Option<String>> {
+    let mut header = [0; 9];
+    reader.read_exact(&mut header)?;
+    let mut len = [0; 8];
+    len.copy_from_slice(&header[1..]);
+    let mut body = Vec::new();
+    reader
+        .take(u64::from_be_bytes(len))
+        .read_to_end(&mut body)?;
+    if body.len() as u64 != u64::from_be_bytes(len) {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    let body = String::from_utf8(body)?;
+    match header[0] {
+        BINARY_FOUND => Ok(Some(body)),
+        BINARY_NOT_FOUND => Ok(None),
+        _ => Err(KvStoreError::ServerError { error: body }),
+    }
+}
+
+// Responses only carry exists when it is true, a missing field reads as false
+fn is_false(b: &bool) -> bool {
+    !*b
 }
@@ -0,0 +1,62 @@
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+
+// Number of events a subscriber may fall behind before new events are dropped for it
+const SUBSCRIBER_BUFFER: usize = 1024;
+
+/// KeyEvent is a change to a key delivered to subscribers
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum KeyEvent {
+    /// Set is published when the value of key is set or merged
+    Set {
+        /// key that changed
+        key: String,
+    },
+    /// Remove is published when key is removed
+    Remove {
+        /// key that was removed
+        key: String,
+    },
+}
+
+// Subscribers holds the senders of every subscription, shared by the clones of a store
+#[derive(Clone, Default)]
+pub(crate) struct Subscribers {
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+struct Subscriber {
+    prefix: Vec<u8>,
+    sender: Sender<KeyEvent>,
+}
+
+impl Subscribers {
+    // Registers a subscription to the keys starting with prefix
+    pub(crate) fn subscribe(&self, prefix: String) -> Receiver<KeyEvent> {
+        let (sender, receiver) = bounded(SUBSCRIBER_BUFFER);
+        self.subscribers.lock().unwrap().push(Subscriber {
+            prefix: prefix.into_bytes(),
+            sender,
+        });
+        receiver
+    }
+
+    // Sends the event made by event from key to the subscribers whose prefix matches key. The
+    // event is dropped for subscribers whose buffer is full, and subscribers whose receiver is
+    // gone are removed.
+    pub(crate) fn publish(&self, key: &[u8], event: fn(String) -> KeyEvent) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if subscribers.is_empty() {
+            return;
+        }
+        let event = event(String::from_utf8_lossy(key).into_owned());
+        subscribers.retain(|subscriber| {
+            !key.starts_with(&subscriber.prefix)
+                || !matches!(
+                    subscriber.sender.try_send(event.clone()),
+                    Err(TrySendError::Disconnected(_))
+                )
+        });
+    }
+}
@@ -0,0 +1,112 @@
+use crate::config::Limits;
+use crate::{Config, KvStoreError, KvsEngine, Result};
+
+use rocksdb::{Direction, IteratorMode, MergeOperands, Options, DB};
+use std::path::Path;
+use std::sync::Arc;
+
+/// RocksKvsEngine implements the KvsEngine on top of RocksDB
+#[derive(Clone)]
+pub struct RocksKvsEngine {
+    db: Arc<DB>,
+    has_merge_operator: bool,
+    limits: Limits,
+}
+
+impl RocksKvsEngine {
+    /// open opens the RocksDB database at path, creating it if it does not exist
+    pub fn open(path: &Path) -> Result<Self> {
+        RocksKvsEngine::open_with_config(path, Config::default())
+    }
+
+    /// open_with_config opens the database at path and keeps the options of config that apply to
+    /// RocksDB. The merge operator is registered with RocksDB, which folds operands itself.
+    pub fn open_with_config(path: &Path, config: Config) -> Result<Self> {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        let has_merge_operator = config.merge_operator.is_some();
+        if let Some(merge_operator) = config.merge_operator.clone() {
+            opts.set_merge_operator_associative(
+                "kvs",
+                move |key: &[u8], existing: Option<&[u8]>, operands: &MergeOperands| {
+                    let key = String::from_utf8_lossy(key);
+                    let mut value = existing.map(|v| String::from_utf8_lossy(v).into_owned());
+                    for operand in operands {
+                        let operand = String::from_utf8_lossy(operand);
+                        value = Some(merge_operator(&key, value.as_deref(), &operand));
+                    }
+                    value.map(String::into_bytes)
+                },
+            );
+        }
+        let db = DB::open(&opts, path)?;
+        Ok(RocksKvsEngine {
+            db: Arc::new(db),
+            has_merge_operator,
+            limits: config.limits(),
+        })
+    }
+}
+
+impl KvsEngine for RocksKvsEngine {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.limits.check(key.len(), value.len() as u64)?;
+        self.db.put(key, value)?;
+        Ok(())
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.db.get(key)?)
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        if self.db.get(&key)?.is_none() {
+            return Err(KvStoreError::KeyNotFoundError {});
+        }
+        self.db.delete(key)?;
+        Ok(())
+    }
+
+    fn scan_bytes(
+        &self,
+        start: Vec<u8>,
+        end: Vec<u8>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut pairs = Vec::new();
+        for res in self
+            .db
+            .iterator(IteratorMode::From(&start, Direction::Forward))
+        {
+            let (key, value) = res?;
+            if pairs.len() == limit || (!end.is_empty() && *key >= *end) {
+                break;
+            }
+            pairs.push((key.into_vec(), value.into_vec()));
+        }
+        Ok(pairs)
+    }
+
+    fn merge(&self, key: String, operand: String) -> Result<()> {
+        if !self.has_merge_operator {
+            return Err(KvStoreError::NoMergeOperatorError {});
+        }
+        self.limits.check(key.len(), operand.len() as u64)?;
+        self.db.merge(key, operand)?;
+        Ok(())
+    }
+
+    fn len(&self) -> Result<usize> {
+        let mut len = 0;
+        for res in self.db.iterator(IteratorMode::Start) {
+            res?;
+            len += 1;
+        }
+        Ok(len)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
@@ -1,64 +1,1002 @@
-use crate::engine::KvsEngine;
+use crate::cache::{Reserved, ResponseCache};
+use crate::config::{Config, LogFormat, ServerConfig};
+use crate::engine::{Engine, EngineKind, KvsEngine};
+use crate::error::KvStoreError;
 use crate::kv::Result;
-use crate::network::{ClientRequest, ClientRequestType, Response};
+#[cfg(feature = "metrics")]
+use crate::metrics::{serve_metrics, Metrics};
+use crate::network::{encode_binary, ClientRequest, ClientRequestType, Response, StatsReport};
 use crate::thread_pool::*;
 
-use serde::de::Deserialize;
+use crossbeam_channel::{unbounded, Receiver};
+use rolling_file::{RollingConditionBasic, RollingFileAppender};
+use serde::{Deserialize, Serialize};
 use slog::Drain;
+use std::cell::Cell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
 use std::env;
-use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::hash::{Hash, Hasher};
+use std::io::{BufWriter, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Scan responses are capped so a single response stays a reasonable size
+const MAX_SCAN_RESULTS: usize = 1000;
+// Audit records waiting to be written before requests have to wait for the audit log
+const AUDIT_QUEUE_SIZE: usize = 1024;
+
+// How long the accept loop sleeps when there is no connection to accept
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
 /// KvsServer is a TCP server that handles client cmduests to the underlying KvStore
-pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
-    socket: SocketAddr,
-    log: slog::Logger,
+pub struct KvsServer<E: KvsEngine + Clone, P: ThreadPool> {
+    listener: TcpListener,
+    ctx: Context,
     db: E,
     pool: P,
+    #[cfg(feature = "metrics")]
+    metrics_addr: Option<SocketAddr>,
+    max_connections: Option<usize>,
+    stop: Arc<AtomicBool>,
+}
+
+// Context is what every connection needs besides the engine
+#[derive(Clone)]
+pub(crate) struct Context {
+    pub(crate) log: slog::Logger,
+    connections: Arc<Connections>,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) max_request_size: Option<u64>,
+    pub(crate) auth_token: Option<String>,
+    pub(crate) rate_limit: Option<u32>,
+    pub(crate) server_metrics: Arc<ServerMetrics>,
+    pub(crate) responses: Arc<ResponseCache>,
+    audit: Option<slog::Logger>,
+    #[cfg(feature = "metrics")]
+    pub(crate) metrics: Arc<Metrics>,
+}
+
+impl Context {
+    // Checks config and builds the context it describes, logging to log
+    pub(crate) fn new(config: &ServerConfig, log: slog::Logger) -> Result<Context> {
+        if cfg!(not(feature = "metrics")) && config.metrics_addr.is_some() {
+            return Err(KvStoreError::InvalidConfigError {
+                reason: "metrics_addr requires the metrics feature".to_owned(),
+            });
+        }
+        if config.rate_limit == Some(0) {
+            return Err(KvStoreError::InvalidConfigError {
+                reason: "rate_limit must be greater than 0".to_owned(),
+            });
+        }
+        if config.max_request_size == Some(0) {
+            return Err(KvStoreError::InvalidConfigError {
+                reason: "max_request_size must be greater than 0".to_owned(),
+            });
+        }
+        if config.idle_timeout == Some(Duration::from_secs(0)) {
+            return Err(KvStoreError::InvalidConfigError {
+                reason: "idle_timeout must be greater than 0".to_owned(),
+            });
+        }
+
+        let audit = match &config.audit_log {
+            Some(path) => Some(new_audit_logger(config, path)?),
+            None => None,
+        };
+        Ok(Context {
+            log,
+            connections: Arc::new(Connections::default()),
+            idle_timeout: config.idle_timeout,
+            max_request_size: config.max_request_size,
+            auth_token: config.auth_token.clone(),
+            rate_limit: config.rate_limit,
+            server_metrics: Arc::new(ServerMetrics::default()),
+            responses: Arc::new(ResponseCache::new(config.dedup_capacity, config.dedup_ttl)),
+            audit,
+            #[cfg(feature = "metrics")]
+            metrics: Arc::new(Metrics::new()?),
+        })
+    }
+}
+
+/// ServerMetricsSnapshot is a copy of the counters of a KvsServer at one point in time
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ServerMetricsSnapshot {
+    /// requests is the number of requests handled, counting each request of a batch
+    pub requests: u64,
+    /// gets is the number of get requests handled
+    pub gets: u64,
+    /// sets is the number of set requests handled
+    pub sets: u64,
+    /// removes is the number of remove requests handled
+    pub removes: u64,
+    /// errors is the number of requests that returned an error
+    pub errors: u64,
+    /// bytes_served is the number of bytes written to clients
+    pub bytes_served: u64,
+}
+
+// ServerMetrics are the counters behind ServerMetricsSnapshot. They are independent of each other,
+// so relaxed increments are enough.
+#[derive(Default)]
+pub(crate) struct ServerMetrics {
+    requests: AtomicU64,
+    gets: AtomicU64,
+    sets: AtomicU64,
+    removes: AtomicU64,
+    errors: AtomicU64,
+    pub(crate) bytes_served: AtomicU64,
+}
+
+impl ServerMetrics {
+    // Counts a request of command_type that was answered with resp
+    pub(crate) fn record(&self, command_type: ClientRequestType, resp: &Response) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let counter = match command_type {
+            ClientRequestType::Get => Some(&self.gets),
+            ClientRequestType::Set => Some(&self.sets),
+            ClientRequestType::Rm => Some(&self.removes),
+            _ => None,
+        };
+        if let Some(counter) = counter {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        if !resp.error.is_empty() {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> ServerMetricsSnapshot {
+        ServerMetricsSnapshot {
+            requests: self.requests.load(Ordering::Relaxed),
+            gets: self.gets.load(Ordering::Relaxed),
+            sets: self.sets.load(Ordering::Relaxed),
+            removes: self.removes.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            bytes_served: self.bytes_served.load(Ordering::Relaxed),
+        }
+    }
 }
 
-impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
-    /// Instantiates new KvsServer with log and db engine
+impl<E: KvsEngine + Clone, P: ThreadPool> KvsServer<E, P> {
+    /// Instantiates new KvsServer with log and db engine. The listener is bound to socket right
+    /// away, so a socket with port 0 gets a free port that local_addr reports.
     pub fn new(socket: SocketAddr, engine_name: &str, engine: E, pool: P) -> Result<Self> {
-        let decorator = slog_term::TermDecorator::new().stderr().build();
-        let drain = slog_term::FullFormat::new(decorator).build().fuse();
-        let drain = slog_async::Async::new(drain).build().fuse();
-        let log = slog::Logger::root(drain, o!());
+        KvsServer::with_config(socket, engine_name, engine, pool, ServerConfig::default())
+    }
+
+    /// Instantiates new KvsServer with the given config instead of the default one
+    pub fn with_config(
+        socket: SocketAddr,
+        engine_name: &str,
+        engine: E,
+        pool: P,
+        config: ServerConfig,
+    ) -> Result<Self> {
+        let log = new_logger(&config)?;
+        KvsServer::with_logger(socket, engine_name, engine, pool, config, log)
+    }
+
+    // Instantiates new KvsServer that logs to log, which may be shared with the engine
+    fn with_logger(
+        socket: SocketAddr,
+        engine_name: &str,
+        engine: E,
+        pool: P,
+        config: ServerConfig,
+        log: slog::Logger,
+    ) -> Result<Self> {
+        let ctx = Context::new(&config, log)?;
+        let listener = TcpListener::bind(socket)?;
+        // Accepting without blocking lets the loop check the stop flag between connections
+        listener.set_nonblocking(true)?;
 
-        info!(log, "{}", env!("CARGO_PKG_VERSION"));
-        info!(log, "{}", socket);
-        info!(log, "{}", engine_name);
+        info!(ctx.log, "{}", env!("CARGO_PKG_VERSION"));
+        info!(ctx.log, "{}", listener.local_addr()?);
+        info!(ctx.log, "{}", engine_name);
 
         Ok(KvsServer {
-            socket,
-            log,
+            listener,
+            ctx,
             db: engine,
             pool,
+            #[cfg(feature = "metrics")]
+            metrics_addr: config.metrics_addr,
+            max_connections: config.max_connections,
+            stop: Arc::new(AtomicBool::new(false)),
         })
     }
 
-    /// Starts KvsServer and listens for connections
+    /// metrics returns the request counters of the server so far
+    pub fn metrics(&self) -> ServerMetricsSnapshot {
+        self.ctx.server_metrics.snapshot()
+    }
+
+    /// stop_flag returns the flag that stops the server. Once it is set, start stops accepting
+    /// connections and returns. Connections already accepted are still served by the pool.
+    pub fn stop_flag(&self) -> Arc<AtomicBool> {
+        self.stop.clone()
+    }
+
+    /// local_addr returns the address the server listens on, which has the port the OS picked if
+    /// the server was created with port 0
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Starts KvsServer and accepts connections until the stop flag is set
     pub fn start(&self) -> Result<()> {
-        let listener = TcpListener::bind(self.socket)?;
+        #[cfg(feature = "metrics")]
+        if let Some(addr) = self.metrics_addr {
+            serve_engine_metrics(addr, &self.db, &self.ctx)?;
+        }
 
-        for stream in listener.incoming() {
+        while !self.stop.load(Ordering::SeqCst) {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream.set_nonblocking(false).map(|()| stream),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(ACCEPT_POLL_INTERVAL);
+                    continue;
+                }
+                Err(e) => Err(e),
+            };
+            if stream.is_ok() {
+                self.ctx.connections.open();
+            }
             let db = self.db.clone();
-            let log = self.log.clone();
+            let ctx = self.ctx.clone();
+            #[cfg(feature = "tracing")]
+            let accepted = Instant::now();
             self.pool.spawn(move || match stream {
                 Ok(stream) => {
-                    if let Err(e) = process_cmd(db, stream) {
-                        error!(log, "{}", e.to_string());
+                    // The connection span records how long the connection waited for a thread
+                    #[cfg(feature = "tracing")]
+                    let _span = tracing::info_span!(
+                        "connection",
+                        queue_us = accepted.elapsed().as_micros() as u64,
+                        trace_id = tracing::field::Empty,
+                    )
+                    .entered();
+                    #[cfg(feature = "metrics")]
+                    ctx.metrics.connections.inc();
+                    let peer = stream.peer_addr().ok();
+                    // A panicking handler still frees its connection slot below
+                    match panic::catch_unwind(AssertUnwindSafe(|| process_cmd(db, stream, &ctx))) {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => error!(ctx.log, "{}", e.to_string()),
+                        Err(payload) => error!(ctx.log, "connection handler panicked";
+                            "peer" => ?peer, "panic" => panic_message(&*payload)),
                     }
+                    #[cfg(feature = "metrics")]
+                    ctx.metrics.connections.dec();
+                    ctx.connections.close();
                 }
-                Err(e) => error!(log, "{}", e),
+                Err(e) => error!(ctx.log, "{}", e),
             });
+            // New connections wait in the listen backlog until a slot frees up
+            if let Some(max) = self.max_connections {
+                self.ctx.connections.wait_below(max);
+            }
         }
         Ok(())
     }
 }
 
-fn process_cmd<E: KvsEngine>(db: E, stream: TcpStream) -> Result<()> {
-    let mut de = serde_json::Deserializer::from_reader(&stream);
-    let cmd = ClientRequest::deserialize(&mut de)?;
+// Serves the metrics of ctx on addr, with the key count and compactions of db
+#[cfg(feature = "metrics")]
+pub(crate) fn serve_engine_metrics<E: KvsEngine + Clone>(
+    addr: SocketAddr,
+    db: &E,
+    ctx: &Context,
+) -> Result<()> {
+    let db = db.clone();
+    serve_metrics(addr, ctx.metrics.clone(), move |metrics| {
+        if let Ok(len) = db.len() {
+            metrics.keys.set(len as i64);
+        }
+        metrics.compactions.set(db.compactions() as i64);
+    })?;
+    info!(ctx.log, "metrics on {}", addr);
+    Ok(())
+}
+
+// Connections counts the connections being served, so accepting can wait for a free slot
+#[derive(Default)]
+struct Connections {
+    count: Mutex<usize>,
+    closed: Condvar,
+}
+
+impl Connections {
+    fn open(&self) {
+        *self.count.lock().unwrap() += 1;
+    }
+
+    fn close(&self) {
+        *self.count.lock().unwrap() -= 1;
+        self.closed.notify_one();
+    }
+
+    // Blocks until fewer than max connections are being served
+    fn wait_below(&self, max: usize) {
+        let mut count = self.count.lock().unwrap();
+        while *count >= max {
+            count = self.closed.wait(count).unwrap();
+        }
+    }
+}
+
+// Builds the server logger in the format and level of config, writing to its log_file if set
+pub(crate) fn new_logger(config: &ServerConfig) -> Result<slog::Logger> {
+    let drain = match &config.log_file {
+        Some(path) => {
+            let file = open_log_file(config, path)?;
+            match config.log_format {
+                LogFormat::Term => {
+                    let decorator = slog_term::PlainDecorator::new(file);
+                    let drain = slog_term::FullFormat::new(decorator).build().fuse();
+                    slog_async::Async::new(drain).build()
+                }
+                LogFormat::Json => {
+                    let drain = slog_json::Json::new(file)
+                        .set_flush(true)
+                        .add_default_keys()
+                        .build()
+                        .fuse();
+                    slog_async::Async::new(drain).build()
+                }
+            }
+        }
+        None => match config.log_format {
+            LogFormat::Term => {
+                let decorator = slog_term::TermDecorator::new().stderr().build();
+                let drain = slog_term::FullFormat::new(decorator).build().fuse();
+                slog_async::Async::new(drain).build()
+            }
+            LogFormat::Json => {
+                let drain = slog_json::Json::new(std::io::stdout())
+                    .add_default_keys()
+                    .build()
+                    .fuse();
+                slog_async::Async::new(drain).build()
+            }
+        },
+    };
+    let log_level = config.log_level;
+    let drain = drain
+        .filter(move |record| log_level.accepts(record.level()))
+        .fuse();
+    Ok(slog::Logger::root(drain, o!()))
+}
+
+// Returns a logger that appends to the audit log at path. Records are written on a thread of
+// their own, so requests don't wait for the file unless a burst of them fills the queue, and none
+// are dropped when it is full.
+fn new_audit_logger(config: &ServerConfig, path: &Path) -> Result<slog::Logger> {
+    let drain = slog_json::Json::new(open_log_file(config, path)?)
+        .set_flush(true)
+        .add_default_keys()
+        .build()
+        .fuse();
+    let drain = slog_async::Async::new(drain)
+        .chan_size(AUDIT_QUEUE_SIZE)
+        .overflow_strategy(slog_async::OverflowStrategy::Block)
+        .build()
+        .fuse();
+    Ok(slog::Logger::root(drain, o!()))
+}
+
+// Opens the log file at path, rotating it once it reaches the configured size. The drains write
+// a record in pieces and flush after it, so buffering hands the whole record to the appender at
+// once and a rotation never splits it across files.
+fn open_log_file(
+    config: &ServerConfig,
+    path: &Path,
+) -> Result<BufWriter<RollingFileAppender<RollingConditionBasic>>> {
+    if config.log_max_size == Some(0) || config.log_keep == 0 {
+        return Err(KvStoreError::InvalidConfigError {
+            reason: "log_max_size and log_keep must be greater than 0".to_owned(),
+        });
+    }
+    let mut condition = RollingConditionBasic::new();
+    if let Some(size) = config.log_max_size {
+        condition = condition.max_size(size);
+    }
+    Ok(BufWriter::new(RollingFileAppender::new(
+        path,
+        condition,
+        config.log_keep,
+    )?))
+}
+
+/// run_server opens the engine at path and serves it on socket with a pool of num_threads
+/// threads. It only returns if the server fails.
+pub fn run_server(
+    socket: SocketAddr,
+    engine: EngineKind,
+    pool: PoolKind,
+    num_threads: u32,
+    path: &Path,
+    config: ServerConfig,
+) -> Result<()> {
+    run_server_with(socket, engine, pool, num_threads, path, config, |_, _| {})
+}
+
+/// run_server_with is run_server that calls on_start with the address the server is bound to
+/// and its stop flag before it starts serving. Setting the flag stops the server, after which
+/// run_server_with returns Ok.
+pub fn run_server_with(
+    socket: SocketAddr,
+    engine: EngineKind,
+    pool: PoolKind,
+    num_threads: u32,
+    path: &Path,
+    config: ServerConfig,
+    on_start: impl FnOnce(SocketAddr, Arc<AtomicBool>),
+) -> Result<()> {
+    // The engine logs background work such as compaction alongside the requests
+    let log = new_logger(&config)?;
+    let db =
+        Engine::open_with_config(engine, path, Config::builder().logger(log.clone()).build()?)?;
+    let name = engine.as_str();
+    match pool {
+        PoolKind::Crossbeam => {
+            let pool = SharedQueueThreadPool::with_logger(num_threads, log.clone())?;
+            start(
+                KvsServer::with_logger(socket, name, db, pool, config, log)?,
+                on_start,
+            )
+        }
+        PoolKind::Rayon => {
+            let pool = RayonThreadPool::new(num_threads)?;
+            start(
+                KvsServer::with_logger(socket, name, db, pool, config, log)?,
+                on_start,
+            )
+        }
+        PoolKind::WorkStealing => {
+            let pool = WorkStealingThreadPool::new(num_threads)?;
+            start(
+                KvsServer::with_logger(socket, name, db, pool, config, log)?,
+                on_start,
+            )
+        }
+    }
+}
+
+// Hands the address and stop flag of server to on_start, then serves until the server is stopped
+fn start<E: KvsEngine + Clone, P: ThreadPool>(
+    server: KvsServer<E, P>,
+    on_start: impl FnOnce(SocketAddr, Arc<AtomicBool>),
+) -> Result<()> {
+    on_start(server.local_addr()?, server.stop_flag());
+    server.start()
+}
+
+fn process_cmd<E: KvsEngine + Clone>(db: E, stream: TcpStream, ctx: &Context) -> Result<()> {
+    stream.set_read_timeout(ctx.idle_timeout)?;
+    let remaining = Cell::new(0);
+    let mut de = serde_json::Deserializer::from_reader(RequestReader {
+        stream: &stream,
+        remaining: &remaining,
+    });
+    let mut cmd = match read_request(&mut de, &remaining, &stream, ctx)? {
+        Some(cmd) => cmd,
+        None => return Ok(()),
+    };
+    if cmd.command_type == ClientRequestType::Auth {
+        if !authenticate(&stream, &cmd.value, ctx)? {
+            return Ok(());
+        }
+        cmd = match read_request(&mut de, &remaining, &stream, ctx)? {
+            Some(cmd) => cmd,
+            None => return Ok(()),
+        };
+    } else if ctx.auth_token.is_some() {
+        respond(&stream, &unauthenticated(stream.peer_addr().ok(), ctx), ctx)?;
+        return Ok(());
+    }
+    let mut binary = false;
+    if cmd.command_type == ClientRequestType::Protocol {
+        let resp = select_protocol(&cmd.value, &mut binary);
+        respond(&stream, &resp, ctx)?;
+        if !resp.error.is_empty() {
+            return Ok(());
+        }
+        cmd = match read_request(&mut de, &remaining, &stream, ctx)? {
+            Some(cmd) => cmd,
+            None => return Ok(()),
+        };
+    }
+    #[cfg(feature = "tracing")]
+    if let Some(trace_id) = &cmd.trace_id {
+        tracing::Span::current().record("trace_id", trace_id.as_str());
+    }
+    let mut limiter = ctx.rate_limit.map(TokenBucket::new);
+    if cmd.seq.is_some() {
+        return serve_pipelined(db, &stream, &mut de, &remaining, cmd, limiter, ctx);
+    }
+    let mut serve = |cmd: ClientRequest| {
+        let command_type = cmd.command_type;
+        let resp = if limiter.as_mut().is_none_or(TokenBucket::take) {
+            handle_request(&db, cmd, ctx)
+        } else {
+            throttled(stream.peer_addr().ok(), ctx)
+        };
+        ctx.server_metrics.record(command_type, &resp);
+        resp
+    };
+    match cmd.command_type {
+        // A batch with a request id is answered from the response cache like a single request
+        ClientRequestType::Batch => {
+            let resps = match cmd.request_id.take() {
+                Some(id) => match ctx.responses.reserve(id.clone(), request_digest(&cmd)) {
+                    Reserved::New(reservation) => {
+                        let resps: Vec<Response> = cmd.batch.into_iter().map(&mut serve).collect();
+                        reservation.answer(resps.clone());
+                        resps
+                    }
+                    Reserved::Done(resps) => resps,
+                    Reserved::Reused => vec![reused(id)],
+                },
+                None => cmd.batch.into_iter().map(&mut serve).collect(),
+            };
+            respond(&stream, &resps, ctx)?;
+        }
+        ClientRequestType::Subscribe => subscribe(&db, stream, cmd.key, ctx)?,
+        ClientRequestType::Get if binary => respond_binary(&stream, &serve(cmd), ctx)?,
+        _ => respond(&stream, &serve(cmd), ctx)?,
+    }
+    Ok(())
+}
+
+// Serves the requests of a pipelined connection, starting with first, until the client closes its
+// side of the connection. Every request runs as its own job, so a slow request doesn't hold up the
+// ones after it, and a writer thread sends the responses back in the order the requests came in.
+// The jobs run on the global rayon pool rather than the server's, which may have no thread to
+// spare while this connection holds one.
+fn serve_pipelined<E, R>(
+    db: E,
+    stream: &TcpStream,
+    de: &mut serde_json::Deserializer<R>,
+    remaining: &Cell<u64>,
+    first: ClientRequest,
+    mut limiter: Option<TokenBucket>,
+    ctx: &Context,
+) -> Result<()>
+where
+    E: KvsEngine + Clone,
+    R: serde_json::de::Read<'static>,
+{
+    let (sender, receiver) = unbounded();
+    let peer = stream.peer_addr().ok();
+    thread::scope(|scope| {
+        let writer = scope.spawn(|| write_in_order(stream, receiver, ctx));
+        let mut cmd = first;
+        for index in 0.. {
+            let seq = cmd.seq;
+            let command_type = cmd.command_type;
+            match command_type {
+                // These take over the connection or answer with more than one response
+                ClientRequestType::Batch
+                | ClientRequestType::Subscribe
+                | ClientRequestType::Auth => {
+                    let resp = Response {
+                        error: format!("{:?} requests can't be pipelined", command_type),
+                        seq,
+                        ..Response::default()
+                    };
+                    let _ = sender.send((index, resp));
+                }
+                _ if !limiter.as_mut().is_none_or(TokenBucket::take) => {
+                    let resp = throttled(stream.peer_addr().ok(), ctx);
+                    ctx.server_metrics.record(command_type, &resp);
+                    let _ = sender.send((index, Response { seq, ..resp }));
+                }
+                _ => {
+                    let db = db.clone();
+                    let ctx = ctx.clone();
+                    let sender = sender.clone();
+                    rayon::spawn(move || {
+                        // rayon aborts on a panicking job, and the writer needs every response
+                        let resp = panic::catch_unwind(AssertUnwindSafe(|| {
+                            handle_request(&db, cmd, &ctx)
+                        }))
+                        .unwrap_or_else(|payload| {
+                            error!(ctx.log, "request handler panicked";
+                                "peer" => ?peer, "command_type" => ?command_type,
+                                "panic" => panic_message(&*payload));
+                            Response {
+                                error: "Request failed with a panic".to_owned(),
+                                ..Response::default()
+                            }
+                        });
+                        ctx.server_metrics.record(command_type, &resp);
+                        // The writer is gone if the client stopped reading responses
+                        let _ = sender.send((index, Response { seq, ..resp }));
+                    });
+                }
+            }
+            if !next_request_follows(de, remaining, stream, ctx)? {
+                break;
+            }
+            cmd = match read_request(de, remaining, stream, ctx)? {
+                Some(cmd) => cmd,
+                None => break,
+            };
+        }
+        // The writer returns once every job has sent its response
+        drop(sender);
+        writer.join().unwrap()
+    })
+}
+
+// Waits for the next request on a pipelined connection. Returns false once the client has closed
+// its side of the connection or left it idle for longer than the idle timeout.
+fn next_request_follows<R: serde_json::de::Read<'static>>(
+    de: &mut serde_json::Deserializer<R>,
+    remaining: &Cell<u64>,
+    stream: &TcpStream,
+    ctx: &Context,
+) -> Result<bool> {
+    remaining.set(ctx.max_request_size.unwrap_or(u64::MAX));
+    match de.end() {
+        Ok(()) => Ok(false),
+        Err(e) if e.is_io() => {
+            let e = std::io::Error::from(e);
+            if let ErrorKind::WouldBlock | ErrorKind::TimedOut = e.kind() {
+                warn!(ctx.log, "dropped idle connection"; "peer" => ?stream.peer_addr().ok());
+                return Ok(false);
+            }
+            Err(e.into())
+        }
+        // Anything but whitespace is the start of the next request
+        Err(_) => Ok(true),
+    }
+}
+
+// Writes the responses of a pipelined connection in the order of their index, holding back the
+// ones that finish before those sent ahead of them
+fn write_in_order(
+    stream: &TcpStream,
+    responses: Receiver<(u64, Response)>,
+    ctx: &Context,
+) -> Result<()> {
+    let mut pending = BTreeMap::new();
+    let mut next = 0;
+    for (index, resp) in responses {
+        pending.insert(index, resp);
+        while let Some(resp) = pending.remove(&next) {
+            respond(stream, &resp, ctx)?;
+            next += 1;
+        }
+    }
+    Ok(())
+}
+
+// Answers a request over the rate limit of the connection from peer
+pub(crate) fn throttled(peer: Option<SocketAddr>, ctx: &Context) -> Response {
+    warn!(ctx.log, "throttled request"; "peer" => ?peer);
+    Response {
+        error: "Rate limit exceeded".to_owned(),
+        ..Response::default()
+    }
+}
+
+// Answers a request that was sent with the request id of a different request
+pub(crate) fn reused(id: String) -> Response {
+    let mut resp = Response::default();
+    resp.set_error(&KvStoreError::RequestIdReused { id });
+    resp
+}
+
+// Returns a digest of what cmd asks for, leaving out its ids, so a retry can be told from a
+// different request that reuses its request id
+pub(crate) fn request_digest(cmd: &ClientRequest) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    cmd.command_type.hash(&mut hasher);
+    cmd.key.hash(&mut hasher);
+    cmd.value.hash(&mut hasher);
+    cmd.keys.hash(&mut hasher);
+    for op in &cmd.batch {
+        request_digest(op).hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+// Writes value to stream and counts it towards the bytes served
+fn respond<T: Serialize>(mut stream: &TcpStream, value: &T, ctx: &Context) -> Result<()> {
+    let buf = serde_json::to_vec(value)?;
+    stream.write_all(&buf)?;
+    ctx.server_metrics
+        .bytes_served
+        .fetch_add(buf.len() as u64, Ordering::Relaxed);
+    Ok(())
+}
+
+// Writes the response to a Get request to stream in the binary format
+fn respond_binary(mut stream: &TcpStream, resp: &Response, ctx: &Context) -> Result<()> {
+    let buf = encode_binary(resp);
+    stream.write_all(&buf)?;
+    ctx.server_metrics
+        .bytes_served
+        .fetch_add(buf.len() as u64, Ordering::Relaxed);
+    Ok(())
+}
+
+// TokenBucket limits the rate of requests on a connection. It holds up to a second's worth of
+// tokens and every request takes one.
+pub(crate) struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(rate: u32) -> Self {
+        TokenBucket {
+            rate: f64::from(rate),
+            tokens: f64::from(rate),
+            last: Instant::now(),
+        }
+    }
+
+    // Takes a token if there is one, after adding the tokens earned since the last call
+    pub(crate) fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let earned = now.duration_since(self.last).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + earned).min(self.rate);
+        self.last = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+// RequestReader reads requests from a connection, failing instead of reading past the bytes the
+// current request has left
+struct RequestReader<'a> {
+    stream: &'a TcpStream,
+    // Bytes left to the current request, u64::MAX if requests are not limited
+    remaining: &'a Cell<u64>,
+}
+
+impl Read for RequestReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.remaining.get();
+        if remaining == 0 {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "request is too large",
+            ));
+        }
+        let len = remaining.min(buf.len() as u64) as usize;
+        let read = self.stream.read(&mut buf[..len])?;
+        self.remaining.set(remaining - read as u64);
+        Ok(read)
+    }
+}
+
+// Reads the next request from de, allowing it the max request size in remaining. Returns None if
+// the connection was idle for longer than the idle timeout, or if the request was too large or
+// malformed, in which case the client is sent an error.
+fn read_request<R: serde_json::de::Read<'static>>(
+    de: &mut serde_json::Deserializer<R>,
+    remaining: &Cell<u64>,
+    stream: &TcpStream,
+    ctx: &Context,
+) -> Result<Option<ClientRequest>> {
+    remaining.set(ctx.max_request_size.unwrap_or(u64::MAX));
+    match ClientRequest::deserialize(de) {
+        Ok(cmd) => Ok(Some(cmd)),
+        Err(e) if e.is_io() && remaining.get() == 0 => {
+            respond(stream, &too_large(stream.peer_addr().ok(), ctx), ctx)?;
+            // Closing the write side first gets the response to the client even though the rest of
+            // the request is left unread
+            stream.shutdown(Shutdown::Write)?;
+            Ok(None)
+        }
+        Err(e) if e.is_io() => {
+            let e = std::io::Error::from(e);
+            if let ErrorKind::WouldBlock | ErrorKind::TimedOut = e.kind() {
+                warn!(ctx.log, "dropped idle connection"; "peer" => ?stream.peer_addr().ok());
+                return Ok(None);
+            }
+            Err(e.into())
+        }
+        // The client is gone, so there is no one to answer
+        Err(e) if e.is_eof() => Err(e.into()),
+        Err(e) => {
+            respond(stream, &bad_request(stream.peer_addr().ok(), &e, ctx), ctx)?;
+            // The rest of the stream can't be read as requests once one of them is malformed
+            stream.shutdown(Shutdown::Write)?;
+            Ok(None)
+        }
+    }
+}
+
+// Answers a request from peer that is not valid JSON or not a valid request
+pub(crate) fn bad_request(
+    peer: Option<SocketAddr>,
+    error: &serde_json::Error,
+    ctx: &Context,
+) -> Response {
+    warn!(ctx.log, "rejected malformed request"; "peer" => ?peer, "error" => %error);
+    Response {
+        error: format!("Bad request: {}", error),
+        ..Response::default()
+    }
+}
+
+// Answers a request from peer that is over the max request size
+pub(crate) fn too_large(peer: Option<SocketAddr>, ctx: &Context) -> Response {
+    let max = ctx.max_request_size.unwrap_or(u64::MAX);
+    warn!(ctx.log, "rejected large request"; "peer" => ?peer, "max" => max);
+    Response {
+        error: format!("Request is larger than the limit of {} bytes", max),
+        ..Response::default()
+    }
+}
+
+// Answers a request from peer that came before the connection authenticated
+pub(crate) fn unauthenticated(peer: Option<SocketAddr>, ctx: &Context) -> Response {
+    warn!(ctx.log, "rejected unauthenticated request"; "peer" => ?peer);
+    Response {
+        error: KvStoreError::AuthError {}.to_string(),
+        ..Response::default()
+    }
+}
+
+// Checks token against the auth token of the server and responds with the result. Servers
+// without an auth token accept any token.
+fn authenticate(stream: &TcpStream, token: &str, ctx: &Context) -> Result<bool> {
+    let resp = auth_response(token, stream.peer_addr().ok(), ctx);
+    respond(stream, &resp, ctx)?;
+    Ok(resp.error.is_empty())
+}
+
+// Answers an auth request from peer with token, with no error if the token is accepted
+pub(crate) fn auth_response(token: &str, peer: Option<SocketAddr>, ctx: &Context) -> Response {
+    let mut resp = Response::default();
+    if ctx.auth_token.as_deref().is_none_or(|t| t == token) {
+        resp.value = "OK".to_owned();
+    } else {
+        warn!(ctx.log, "rejected auth token"; "peer" => ?peer);
+        resp.error = KvStoreError::AuthError {}.to_string();
+    }
+    resp
+}
+
+// Answers a protocol request for format, setting binary if it selects the binary format
+pub(crate) fn select_protocol(format: &str, binary: &mut bool) -> Response {
+    let mut resp = Response::default();
+    match format {
+        "json" => *binary = false,
+        "binary" => *binary = true,
+        _ => {
+            resp.error = format!("Unknown protocol {}", format);
+            return resp;
+        }
+    }
+    resp.value = "OK".to_owned();
+    resp
+}
+
+// Acknowledges a subscription with a response, then writes every event to stream until the
+// client disconnects. A subscription keeps its pool thread busy for as long as it lasts.
+pub(crate) fn subscribe<E: KvsEngine>(
+    db: &E,
+    stream: TcpStream,
+    prefix: String,
+    ctx: &Context,
+) -> Result<()> {
+    let events = match db.subscribe(prefix.clone()) {
+        Ok(events) => events,
+        Err(e) => {
+            let resp = Response {
+                error: e.to_string(),
+                ..Response::default()
+            };
+            respond(&stream, &resp, ctx)?;
+            return Ok(());
+        }
+    };
+    let resp = Response {
+        value: "OK".to_owned(),
+        ..Response::default()
+    };
+    respond(&stream, &resp, ctx)?;
+    info!(ctx.log, "subscribed"; "prefix" => &prefix);
+    for event in events {
+        if let Err(e) = respond(&stream, &event, ctx) {
+            info!(ctx.log, "unsubscribed"; "prefix" => &prefix, "reason" => %e);
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Runs a single request and logs its command type, key, latency and result
+pub(crate) fn handle_request<E: KvsEngine>(
+    db: &E,
+    mut cmd: ClientRequest,
+    ctx: &Context,
+) -> Response {
+    let start = Instant::now();
+    #[cfg(feature = "tracing")]
+    let span = tracing::info_span!(
+        "request",
+        command_type = ?cmd.command_type,
+        key_len = cmd.key.len(),
+        result = tracing::field::Empty,
+        duration_us = tracing::field::Empty,
+    )
+    .entered();
+    let command_type = format!("{:?}", cmd.command_type);
+    let key = cmd.key.clone();
+    // A request with the id of one already answered is a retry, so it gets the same response
+    let resp = match cmd.request_id.take() {
+        Some(id) => match ctx.responses.reserve(id.clone(), request_digest(&cmd)) {
+            Reserved::New(reservation) => {
+                let resp = execute_request(db, cmd, ctx);
+                reservation.answer(vec![resp.clone()]);
+                resp
+            }
+            Reserved::Done(mut resps) => resps.pop().unwrap_or_default(),
+            Reserved::Reused => reused(id),
+        },
+        None => execute_request(db, cmd, ctx),
+    };
+    let result = if resp.error.is_empty() {
+        "ok"
+    } else {
+        resp.error.as_str()
+    };
+    #[cfg(feature = "tracing")]
+    {
+        span.record("result", result);
+        span.record("duration_us", start.elapsed().as_micros() as u64);
+    }
+    #[cfg(feature = "metrics")]
+    {
+        ctx.metrics
+            .requests
+            .with_label_values(&[&command_type])
+            .inc();
+        if !resp.error.is_empty() {
+            ctx.metrics.errors.inc();
+        }
+    }
+    info!(ctx.log, "request";
+        "command_type" => command_type,
+        "key" => key,
+        "latency_us" => start.elapsed().as_micros() as u64,
+        "result" => result,
+    );
+    resp
+}
+
+fn execute_request<E: KvsEngine>(db: &E, cmd: ClientRequest, ctx: &Context) -> Response {
+    if let Some(audit) = &ctx.audit {
+        if let ClientRequestType::Set
+        | ClientRequestType::Rm
+        | ClientRequestType::GetSet
+        | ClientRequestType::SetNx
+        | ClientRequestType::Merge
+        | ClientRequestType::Clear = cmd.command_type
+        {
+            info!(audit, "request"; "command_type" => ?cmd.command_type, "key" => &cmd.key);
+        }
+    }
     let mut resp = Response::default();
     match cmd.command_type {
         ClientRequestType::Set => match db.set(cmd.key, cmd.value) {
@@ -66,7 +1004,7 @@ fn process_cmd<E: KvsEngine>(db: E, stream: TcpStream) -> Result<()> {
                 resp.value = "OK".to_owned();
             }
             Err(e) => {
-                resp.error = e.to_string();
+                resp.set_error(&e);
             }
         },
         ClientRequestType::Rm => match db.remove(cmd.key) {
@@ -74,23 +1012,139 @@ fn process_cmd<E: KvsEngine>(db: E, stream: TcpStream) -> Result<()> {
                 resp.value = "OK".to_owned();
             }
             Err(e) => {
-                resp.error = e.to_string();
+                resp.set_error(&e);
             }
         },
         ClientRequestType::Get => match db.get(cmd.key) {
             Ok(res) => match res {
                 Some(value) => {
                     resp.value = value;
+                    resp.exists = true;
                 }
                 None => {
                     resp.value = "".to_owned();
                 }
             },
             Err(e) => {
-                resp.error = e.to_string();
+                resp.set_error(&e);
+            }
+        },
+        ClientRequestType::Merge => match db.merge(cmd.key, cmd.value) {
+            Ok(()) => {
+                resp.value = "OK".to_owned();
+            }
+            Err(e) => {
+                resp.set_error(&e);
+            }
+        },
+        ClientRequestType::GetSet => match db.get_set(cmd.key, cmd.value) {
+            Ok(old) => {
+                resp.exists = old.is_some();
+                resp.value = old.unwrap_or_default();
+            }
+            Err(e) => {
+                resp.set_error(&e);
+            }
+        },
+        ClientRequestType::SetNx => match db.set_nx(cmd.key, cmd.value) {
+            Ok(set) => {
+                resp.value = set.to_string();
+            }
+            Err(e) => {
+                resp.set_error(&e);
+            }
+        },
+        ClientRequestType::Exists => match db.contains_key(cmd.key) {
+            Ok(exists) => {
+                resp.exists = exists;
+            }
+            Err(e) => {
+                resp.set_error(&e);
+            }
+        },
+        ClientRequestType::Scan => match db.scan(cmd.key, cmd.value, MAX_SCAN_RESULTS) {
+            Ok(pairs) => {
+                resp.pairs = pairs;
+            }
+            Err(e) => {
+                resp.set_error(&e);
+            }
+        },
+        ClientRequestType::ScanPage => {
+            let scanned = cmd
+                .value
+                .parse::<usize>()
+                .map_err(KvStoreError::from)
+                .and_then(|limit| db.scan(cmd.key, String::new(), limit.min(MAX_SCAN_RESULTS)));
+            match scanned {
+                Ok(pairs) => {
+                    resp.pairs = pairs;
+                }
+                Err(e) => {
+                    resp.set_error(&e);
+                }
+            }
+        }
+        ClientRequestType::MultiGet => {
+            match cmd.keys.into_iter().map(|key| db.get(key)).collect() {
+                Ok(values) => {
+                    resp.values = values;
+                }
+                Err(e) => {
+                    resp.set_error(&e);
+                }
+            }
+        }
+        ClientRequestType::Batch => {
+            resp.error = "Batch requests cannot be nested".to_owned();
+        }
+        ClientRequestType::Subscribe => {
+            resp.error = "Subscribe requests cannot be batched".to_owned();
+        }
+        ClientRequestType::Auth => {
+            resp.error = "Auth must be the first request on a connection".to_owned();
+        }
+        ClientRequestType::Protocol => {
+            resp.error = "Protocol must be sent at the start of a connection".to_owned();
+        }
+        ClientRequestType::Count => match db.len() {
+            Ok(len) => {
+                resp.value = len.to_string();
+            }
+            Err(e) => {
+                resp.set_error(&e);
+            }
+        },
+        ClientRequestType::Clear => match db.clear() {
+            Ok(()) => {
+                resp.value = "OK".to_owned();
+            }
+            Err(e) => {
+                resp.set_error(&e);
+            }
+        },
+        ClientRequestType::Echo => {
+            resp.value = cmd.value;
+        }
+        ClientRequestType::Stats => match stats_report(db, ctx) {
+            Ok(value) => {
+                resp.value = value;
+            }
+            Err(e) => {
+                resp.set_error(&e);
             }
         },
     }
-    serde_json::to_writer(stream, &resp)?;
-    Ok(())
+    resp
+}
+
+// Returns the StatsReport of the server and db as JSON
+fn stats_report<E: KvsEngine>(db: &E, ctx: &Context) -> Result<String> {
+    let report = StatsReport {
+        server: ctx.server_metrics.snapshot(),
+        keys: db.len()? as u64,
+        compactions: db.compactions(),
+        disk_bytes: db.size_on_disk()?,
+    };
+    Ok(serde_json::to_string(&report)?)
 }
@@ -1,7 +1,15 @@
-use crate::Result;
+use crate::{KvStoreError, Result};
 
-use crossbeam_channel::{unbounded, Receiver, Sender};
-use rayon::prelude::*;
+use crossbeam_channel::{select, unbounded, Receiver, Sender};
+use crossbeam_deque::{Injector, Steal, Stealer, Worker};
+use slog::{Discard, Logger};
+use std::any::Any;
+use std::fmt;
+use std::iter;
+use std::panic::{self, AssertUnwindSafe};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 
 /// ThreadPool is trait for spawning multiple worker threads to complete jobs
@@ -16,37 +24,184 @@ pub trait ThreadPool {
         F: FnOnce() + Send + 'static;
 }
 
-/// NaiveThreadPool is a naive implementation of ThreadPool
+/// PoolKind names the thread pools the server can run with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PoolKind {
+    /// Crossbeam is SharedQueueThreadPool
+    Crossbeam,
+    /// Rayon is RayonThreadPool
+    Rayon,
+    /// WorkStealing is WorkStealingThreadPool
+    WorkStealing,
+}
+
+impl PoolKind {
+    /// ALL lists every pool
+    pub const ALL: &'static [PoolKind] =
+        &[PoolKind::Crossbeam, PoolKind::Rayon, PoolKind::WorkStealing];
+
+    /// as_str returns the name the pool is parsed from
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PoolKind::Crossbeam => "crossbeam",
+            PoolKind::Rayon => "rayon",
+            PoolKind::WorkStealing => "worksteal",
+        }
+    }
+}
+
+impl FromStr for PoolKind {
+    type Err = KvStoreError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "crossbeam" => Ok(PoolKind::Crossbeam),
+            "rayon" => Ok(PoolKind::Rayon),
+            "worksteal" => Ok(PoolKind::WorkStealing),
+            _ => Err(KvStoreError::UnknownPoolError { name: s.to_owned() }),
+        }
+    }
+}
+
+impl fmt::Display for PoolKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Every pool needs at least one thread, as jobs spawned on an empty pool would never run
+fn check_threads(threads: u32) -> Result<()> {
+    if threads == 0 {
+        return Err(KvStoreError::InvalidConfigError {
+            reason: "a thread pool needs at least one thread".to_owned(),
+        });
+    }
+    Ok(())
+}
+
+/// NaiveThreadPool is a naive implementation of ThreadPool. It spawns a new thread for every job
+/// but runs at most `threads` jobs at a time, so spawn blocks until a running job finishes.
 pub struct NaiveThreadPool {
+    slots: Arc<Slots>,
+}
+
+// Slots counts the jobs running on a NaiveThreadPool
+struct Slots {
+    running: Mutex<u32>,
+    freed: Condvar,
     threads: u32,
 }
 
+// SlotGuard frees its slot when the job holding it finishes, even if the job panics
+struct SlotGuard(Arc<Slots>);
+
+impl Drop for SlotGuard {
+    fn drop(&mut self) {
+        *self.0.running.lock().unwrap() -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
 impl ThreadPool for NaiveThreadPool {
     fn new(threads: u32) -> Result<Self> {
-        Ok(NaiveThreadPool { threads })
+        check_threads(threads)?;
+        let slots = Slots {
+            running: Mutex::new(0),
+            freed: Condvar::new(),
+            threads,
+        };
+        Ok(NaiveThreadPool {
+            slots: Arc::new(slots),
+        })
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        thread::spawn(move || job());
+        let mut running = self.slots.running.lock().unwrap();
+        while *running >= self.slots.threads {
+            running = self.slots.freed.wait(running).unwrap();
+        }
+        *running += 1;
+        drop(running);
+        let slot = SlotGuard(self.slots.clone());
+        thread::spawn(move || {
+            let _slot = slot;
+            job();
+        });
     }
 }
 
 /// Shared queue thread pool
 pub struct SharedQueueThreadPool {
     sender: Sender<Box<dyn FnOnce() + Send + 'static>>,
+    receiver: Receiver<Box<dyn FnOnce() + Send + 'static>>,
+    live: Arc<AtomicUsize>,
+    // One per worker the pool wants running. Sending on it tells the worker to exit.
+    stops: Mutex<Vec<Sender<()>>>,
+    log: Logger,
 }
 
-impl ThreadPool for SharedQueueThreadPool {
-    fn new(threads: u32) -> Result<Self> {
+impl SharedQueueThreadPool {
+    /// with_logger initializes a pool of threads workers that log the message of every job that
+    /// panics to log
+    pub fn with_logger(threads: u32, log: Logger) -> Result<Self> {
+        check_threads(threads)?;
         let (sender, receiver) = unbounded::<Box<dyn FnOnce() + Send + 'static>>();
+        let pool = SharedQueueThreadPool {
+            sender,
+            receiver,
+            live: Arc::new(AtomicUsize::new(0)),
+            stops: Mutex::new(Vec::new()),
+            log,
+        };
         for _ in 0..threads {
-            let rx = TaskReceiver(receiver.clone());
-            thread::Builder::new().spawn(move || run_tasks(rx))?;
+            let stop = pool.spawn_worker()?;
+            pool.stops.lock().unwrap().push(stop);
+        }
+        Ok(pool)
+    }
+
+    /// resize grows or shrinks the pool to new_threads workers. Surplus workers exit once they
+    /// finish the job they are running, and queued jobs are left for the remaining workers.
+    pub fn resize(&self, new_threads: u32) -> Result<()> {
+        check_threads(new_threads)?;
+        let mut stops = self.stops.lock().unwrap();
+        let keep = stops.len().min(new_threads as usize);
+        for stop in stops.drain(keep..) {
+            // Workers only exit on their stop signal while the pool is alive, so this can't fail
+            let _ = stop.send(());
         }
-        Ok(SharedQueueThreadPool { sender })
+        while stops.len() < new_threads as usize {
+            stops.push(self.spawn_worker()?);
+        }
+        Ok(())
+    }
+
+    // Starts a worker and returns the sender that keeps it running
+    fn spawn_worker(&self) -> Result<Sender<()>> {
+        let (stop, stopped) = unbounded();
+        let rx = TaskReceiver {
+            receiver: self.receiver.clone(),
+            stopped,
+            live: self.live.clone(),
+            log: self.log.clone(),
+        };
+        rx.spawn_worker()?;
+        Ok(stop)
+    }
+
+    /// live_threads returns the number of worker threads running. A worker whose job panics is
+    /// replaced by a new one before it exits, so panicking jobs don't shrink the pool.
+    pub fn live_threads(&self) -> usize {
+        self.live.load(Ordering::SeqCst)
+    }
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        SharedQueueThreadPool::with_logger(threads, Logger::root(Discard, o!()))
     }
     fn spawn<F>(&self, job: F)
     where
@@ -59,32 +214,78 @@ impl ThreadPool for SharedQueueThreadPool {
     }
 }
 
+// TaskReceiver is owned by a worker for as long as it runs. A worker is counted as live once it
+// is spawned, and dropping its TaskReceiver marks it as exited. The replacement of a panicked
+// worker takes over its stop signal.
 #[derive(Clone)]
-struct TaskReceiver(Receiver<Box<dyn FnOnce() + Send + 'static>>);
+struct TaskReceiver {
+    receiver: Receiver<Box<dyn FnOnce() + Send + 'static>>,
+    stopped: Receiver<()>,
+    live: Arc<AtomicUsize>,
+    log: Logger,
+}
+
+impl TaskReceiver {
+    fn spawn_worker(self) -> std::io::Result<()> {
+        self.live.fetch_add(1, Ordering::SeqCst);
+        thread::Builder::new().spawn(move || run_tasks(self))?;
+        Ok(())
+    }
+}
 
 impl Drop for TaskReceiver {
     fn drop(&mut self) {
         if thread::panicking() {
-            let rx = self.clone();
-            if let Err(e) = thread::Builder::new().spawn(move || run_tasks(rx)) {
-                eprintln!("{}", e.to_string());
+            if let Err(e) = self.clone().spawn_worker() {
+                eprintln!("{}", e);
             }
         }
+        self.live.fetch_sub(1, Ordering::SeqCst);
     }
 }
 
 fn run_tasks(rx: TaskReceiver) {
     loop {
-        match rx.0.recv() {
-            Ok(job) => job(),
-            Err(e) => {
-                eprintln!("Error: {}", e.to_string());
+        select! {
+            recv(rx.receiver) -> job => match job {
+                Ok(job) => run_job(job, &rx.log),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    return;
+                }
+            },
+            recv(rx.stopped) -> stop => {
+                // The pool was dropped rather than resized, so run the jobs still queued
+                if stop.is_err() {
+                    for job in rx.receiver.iter() {
+                        run_job(job, &rx.log);
+                    }
+                }
                 return;
             }
         }
     }
 }
 
+// Runs job, logging its panic message if it panics. The panic then carries on unwinding the
+// worker, which is replaced as it exits.
+fn run_job(job: Box<dyn FnOnce() + Send + 'static>, log: &Logger) {
+    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+        error!(log, "job panicked"; "panic" => panic_message(&*payload));
+        panic::resume_unwind(payload);
+    }
+}
+
+// Returns the message a panic was started with, if it was a string
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => payload
+            .downcast_ref::<String>()
+            .map_or("<non-string panic payload>", String::as_str),
+    }
+}
+
 /// Rayon thread pool
 pub struct RayonThreadPool {
     threads: rayon::ThreadPool,
@@ -92,6 +293,7 @@ pub struct RayonThreadPool {
 
 impl ThreadPool for RayonThreadPool {
     fn new(threads: u32) -> Result<Self> {
+        check_threads(threads)?;
         let threads = rayon::ThreadPoolBuilder::new()
             .num_threads(threads as usize)
             .build()?;
@@ -107,6 +309,101 @@ impl ThreadPool for RayonThreadPool {
     }
 }
 
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Work stealing thread pool. Every worker has a deque of its own that it fills with batches of
+/// jobs taken from a shared queue, and idle workers steal jobs from the deques of busy ones, so
+/// jobs queued behind a slow job get run elsewhere.
+pub struct WorkStealingThreadPool {
+    shared: Arc<Shared>,
+}
+
+// Shared is what the pool and its workers have in common
+struct Shared {
+    injector: Injector<Job>,
+    stealers: Vec<Stealer<Job>>,
+    // Idle workers wait on wake, holding idle while they check for jobs, so a job spawned after
+    // the check wakes them
+    idle: Mutex<()>,
+    wake: Condvar,
+    shutdown: AtomicBool,
+}
+
+impl ThreadPool for WorkStealingThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        check_threads(threads)?;
+        let workers: Vec<Worker<Job>> = (0..threads).map(|_| Worker::new_fifo()).collect();
+        let shared = Arc::new(Shared {
+            injector: Injector::new(),
+            stealers: workers.iter().map(Worker::stealer).collect(),
+            idle: Mutex::new(()),
+            wake: Condvar::new(),
+            shutdown: AtomicBool::new(false),
+        });
+        for local in workers {
+            let shared = shared.clone();
+            thread::Builder::new().spawn(move || steal_tasks(local, &shared))?;
+        }
+        Ok(WorkStealingThreadPool { shared })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shared.injector.push(Box::new(job));
+        let _idle = self.shared.idle.lock().unwrap();
+        self.shared.wake.notify_one();
+    }
+}
+
+impl Drop for WorkStealingThreadPool {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+        let _idle = self.shared.idle.lock().unwrap();
+        self.shared.wake.notify_all();
+    }
+}
+
+impl Shared {
+    // Returns a job from local, or else a batch of jobs from the shared queue, or else a job
+    // stolen from another worker
+    fn find_job(&self, local: &Worker<Job>) -> Option<Job> {
+        local.pop().or_else(|| {
+            iter::repeat_with(|| {
+                self.injector
+                    .steal_batch_and_pop(local)
+                    .or_else(|| self.stealers.iter().map(Stealer::steal).collect())
+            })
+            .find(|steal| !steal.is_retry())
+            .and_then(Steal::success)
+        })
+    }
+
+    fn has_jobs(&self) -> bool {
+        !self.injector.is_empty() || self.stealers.iter().any(|stealer| !stealer.is_empty())
+    }
+}
+
+// Runs jobs until the pool is dropped and no job is left. A panicking job is caught so the
+// worker, and the jobs in its deque, keep going.
+fn steal_tasks(local: Worker<Job>, shared: &Shared) {
+    loop {
+        if let Some(job) = shared.find_job(&local) {
+            let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            continue;
+        }
+        let idle = shared.idle.lock().unwrap();
+        if shared.has_jobs() {
+            continue;
+        }
+        if shared.shutdown.load(Ordering::SeqCst) {
+            return;
+        }
+        drop(shared.wake.wait(idle).unwrap());
+    }
+}
+
 // struct ThreadPool {
 //     workers: Vec<Worker>,
 //     sender: mpsc::Sender<Job>,
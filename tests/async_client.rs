@@ -0,0 +1,125 @@
+#![cfg(feature = "async")]
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{AsyncKvsServer, KvStore, KvStoreError, KvsAsyncClient, Result, ServerConfig};
+
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, TcpListener};
+use std::time::Duration;
+
+use tempfile::TempDir;
+
+mod common;
+use common::{spawn_test_server, spawn_test_server_with_config};
+
+// Client setting, getting and removing keys over one connection to the sync server
+#[tokio::test]
+async fn async_client_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let (_server, addr) = spawn_test_server(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(1)?,
+    );
+    let mut client = KvsAsyncClient::connect(addr).await?;
+    for i in 0..10 {
+        assert_eq!(
+            client
+                .set(format!("key{}", i), format!("value{}", i))
+                .await?,
+            "OK"
+        );
+    }
+    for i in 0..10 {
+        assert_eq!(
+            client.get(format!("key{}", i)).await?,
+            Some(format!("value{}", i))
+        );
+    }
+    client.remove("key0".to_owned()).await?;
+    assert_eq!(client.get("key0".to_owned()).await?, None);
+    match client.remove("key0".to_owned()).await {
+        Err(KvStoreError::KeyNotFoundError {}) => {}
+        other => panic!(
+            "expected KeyNotFoundError, got {:?}",
+            other.map_err(|e| e.to_string())
+        ),
+    }
+    // A server error leaves the client usable
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    Ok(())
+}
+
+// Client talking to the async server from inside the same runtime
+#[tokio::test(flavor = "multi_thread")]
+async fn async_client_async_server() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let server = AsyncKvsServer::new(
+        (Ipv4Addr::LOCALHOST, 0).into(),
+        "test",
+        KvStore::open(temp_dir.path())?,
+    )?;
+    let addr = server.local_addr()?;
+    let server = tokio::spawn(server.serve());
+
+    let clients: Vec<_> = (0..4)
+        .map(|t| {
+            tokio::spawn(async move {
+                let mut client = KvsAsyncClient::connect(addr).await?;
+                for i in 0..25 {
+                    let key = format!("key{}-{}", t, i);
+                    client.set(key.clone(), format!("value{}", i)).await?;
+                    assert_eq!(client.get(key).await?, Some(format!("value{}", i)));
+                }
+                Ok::<(), KvStoreError>(())
+            })
+        })
+        .collect();
+    for client in clients {
+        client.await.unwrap()?;
+    }
+    server.abort();
+    Ok(())
+}
+
+// A connection the server dropped for being idle is replaced without failing the request
+#[tokio::test]
+async fn async_client_reconnects() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let config = ServerConfig {
+        idle_timeout: Some(Duration::from_millis(100)),
+        ..ServerConfig::default()
+    };
+    let (_server, addr) = spawn_test_server_with_config(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        config,
+    );
+    let mut client = KvsAsyncClient::connect(addr).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    Ok(())
+}
+
+// A server that never answers fails the request once the timeout is up
+#[tokio::test]
+async fn async_client_timeout() -> Result<()> {
+    // Connections wait in the backlog of a listener that never accepts
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let mut client = KvsAsyncClient::connect(listener.local_addr()?).await?;
+    client.set_timeout(Some(Duration::from_millis(100)));
+    match client.get("key1".to_owned()).await {
+        Err(KvStoreError::IoError { error }) => assert_eq!(error.kind(), ErrorKind::TimedOut),
+        other => panic!(
+            "expected a timeout, got {:?}",
+            other.map_err(|e| e.to_string())
+        ),
+    }
+    Ok(())
+}
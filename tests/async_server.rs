@@ -0,0 +1,177 @@
+#![cfg(feature = "async")]
+
+use kvs::{AsyncKvsServer, ClientRequest, ClientRequestType, KvStore, KvsClient, Response, Result};
+
+use std::io::Write;
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::thread;
+
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+
+// Starts an async server for the store in dir on an ephemeral port. The server runs until the
+// returned runtime is dropped.
+fn spawn_async_server(dir: &TempDir) -> Result<(Runtime, SocketAddr)> {
+    let server = AsyncKvsServer::new(
+        (Ipv4Addr::LOCALHOST, 0).into(),
+        "test",
+        KvStore::open(dir.path())?,
+    )?;
+    let addr = server.local_addr()?;
+    let runtime = Runtime::new()?;
+    runtime.spawn(server.serve());
+    Ok((runtime, addr))
+}
+
+fn request(command_type: ClientRequestType, key: String, value: String) -> ClientRequest {
+    ClientRequest {
+        command_type,
+        key,
+        value,
+        batch: Vec::new(),
+        keys: Vec::new(),
+        trace_id: None,
+        seq: None,
+        request_id: None,
+    }
+}
+
+// Clients on many threads set and get their own keys at the same time
+#[test]
+fn async_concurrent_get_set() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let (_runtime, addr) = spawn_async_server(&temp_dir)?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            thread::spawn(move || -> Result<()> {
+                for i in 0..50 {
+                    let key = format!("key{}-{}", t, i);
+                    let value = format!("value{}-{}", t, i);
+                    KvsClient::new(addr)?.set(key.clone(), value.clone())?;
+                    assert_eq!(KvsClient::new(addr)?.get(key)?, Some(value));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    for t in 0..8 {
+        for i in 0..50 {
+            assert_eq!(
+                KvsClient::new(addr)?.get(format!("key{}-{}", t, i))?,
+                Some(format!("value{}-{}", t, i))
+            );
+        }
+    }
+    assert_eq!(KvsClient::new(addr)?.get("missing".to_owned())?, None);
+
+    let mut client = KvsClient::new(addr)?;
+    client.enable_binary_gets()?;
+    assert_eq!(
+        client.get("key0-0".to_owned())?,
+        Some("value0-0".to_owned())
+    );
+    Ok(())
+}
+
+// Batches and pipelines get the same responses as from the sync server
+#[test]
+fn async_batch_and_pipeline() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let (_runtime, addr) = spawn_async_server(&temp_dir)?;
+
+    let resps = KvsClient::new(addr)?.batch(vec![
+        request(ClientRequestType::Set, "a".to_owned(), "1".to_owned()),
+        request(ClientRequestType::Get, "a".to_owned(), String::new()),
+    ])?;
+    assert_eq!(resps.len(), 2);
+    assert_eq!(resps[0].value, "OK");
+    assert_eq!(resps[1].value, "1");
+
+    // A batch retried with its request id is answered without being run again
+    let batch = || {
+        vec![request(
+            ClientRequestType::Set,
+            "b".to_owned(),
+            "1".to_owned(),
+        )]
+    };
+    let resps = KvsClient::new(addr)?.batch_with_request_id(batch(), "batch1".to_owned())?;
+    assert_eq!(resps[0].value, "OK");
+    KvsClient::new(addr)?.set("b".to_owned(), "2".to_owned())?;
+    let resps = KvsClient::new(addr)?.batch_with_request_id(batch(), "batch1".to_owned())?;
+    assert_eq!(resps[0].value, "OK");
+    assert_eq!(
+        KvsClient::new(addr)?.get("b".to_owned())?,
+        Some("2".to_owned())
+    );
+
+    let sets = (0..100)
+        .map(|i| {
+            request(
+                ClientRequestType::Set,
+                format!("key{}", i),
+                format!("value{}", i),
+            )
+        })
+        .collect();
+    let resps = KvsClient::new(addr)?.pipeline(sets)?;
+    assert_eq!(resps.len(), 100);
+    for (i, resp) in resps.iter().enumerate() {
+        assert_eq!(resp.seq, Some(i as u64));
+        assert_eq!(resp.value, "OK");
+    }
+
+    let gets = (0..100)
+        .map(|i| request(ClientRequestType::Get, format!("key{}", i), String::new()))
+        .collect();
+    let resps = KvsClient::new(addr)?.pipeline(gets)?;
+    for (i, resp) in resps.iter().enumerate() {
+        assert_eq!(resp.seq, Some(i as u64));
+        assert_eq!(resp.value, format!("value{}", i));
+    }
+    Ok(())
+}
+
+// Requests should be read whole however they are split up, with brackets and quotes in their
+// strings, and however large they are
+#[test]
+fn async_split_and_large_requests() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let (_runtime, addr) = spawn_async_server(&temp_dir)?;
+
+    let value = r#"{"nested": ["\"]}"], "escaped \\"#.to_owned();
+    let req = request(ClientRequestType::Set, "key}1".to_owned(), value.clone());
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_nodelay(true)?;
+    for piece in serde_json::to_vec(&req)?.chunks(3) {
+        stream.write_all(piece)?;
+        thread::sleep(std::time::Duration::from_millis(1));
+    }
+    let resp: Response = serde_json::from_reader(&mut stream)?;
+    assert_eq!(resp.value, "OK");
+    assert_eq!(KvsClient::new(addr)?.get("key}1".to_owned())?, Some(value));
+
+    let large = "x".repeat(1 << 20);
+    KvsClient::new(addr)?.set("key2".to_owned(), large.clone())?;
+    assert_eq!(KvsClient::new(addr)?.get("key2".to_owned())?, Some(large));
+    Ok(())
+}
+
+// A malformed request gets an error response before the connection is closed
+#[test]
+fn async_bad_request() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let (_runtime, addr) = spawn_async_server(&temp_dir)?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(br#"{"command_type":"Bogus","key":"key1","value":""}"#)?;
+    let resp: Response = serde_json::from_reader(&mut stream)?;
+    assert!(resp.error.starts_with("Bad request"), "{}", resp.error);
+    assert_eq!(KvsClient::new(addr)?.get("key1".to_owned())?, None);
+    Ok(())
+}
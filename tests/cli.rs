@@ -1,7 +1,9 @@
 use assert_cmd::prelude::*;
-use predicates::str::{contains, is_empty};
+use predicates::ord::eq;
+use predicates::prelude::*;
+use predicates::str::{contains, is_empty, starts_with};
 use std::fs::{self, File};
-use std::process::Command;
+use std::process::{Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -20,28 +22,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +54,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +93,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +125,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +136,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -145,7 +147,7 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -157,13 +159,14 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("server was not running");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -178,18 +181,20 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
-            .failure();
+            .failure()
+            .stderr(contains("does not match previous data engine sled"));
     }
 
     // kvs first, sled second
@@ -197,15 +202,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -217,19 +223,20 @@ fn cli_access_server(engine: &str, addr: &str) {
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -237,7 +244,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -245,7 +252,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -253,7 +260,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -261,7 +268,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -269,7 +276,23 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["exists", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("true\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["exists", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("false\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -277,7 +300,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -285,7 +308,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -298,26 +321,27 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -335,3 +359,480 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_access_server_ipv6() {
+    cli_access_server("kvs", "[::1]:4042");
+}
+
+#[cfg(feature = "rocksdb")]
+#[test]
+fn cli_access_server_rocksdb_engine() {
+    cli_access_server("rocksdb", "127.0.0.1:4024");
+}
+
+#[test]
+fn client_cli_json_output() {
+    let addr = "127.0.0.1:4006";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--format", "json", "get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let hit: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        hit,
+        serde_json::json!({ "key": "key1", "value": "value1", "found": true })
+    );
+
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--addr", addr, "--format", "json"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let miss: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        miss,
+        serde_json::json!({ "key": "key2", "value": null, "found": false })
+    );
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server was not running");
+}
+
+#[test]
+fn client_cli_scan() {
+    let addr = "127.0.0.1:4014";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for key in ["key1", "key2", "key3"].iter() {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", key, "value", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("key2 value\nkey3 value\n"));
+
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--format", "json", "scan", "key1", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let pairs: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        pairs,
+        serde_json::json!([{ "key": "key1", "value": "value" }])
+    );
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server was not running");
+}
+
+#[test]
+fn client_cli_repl() {
+    let addr = "127.0.0.1:4015";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["repl", "--addr", addr])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer(
+            "# comments and blank lines are skipped\n\nset key1 value 1\nset key2 value2\n\
+             get key1\nrm key3\nget key3\nscan key2\nfoo bar\nstats\n",
+        )
+        .assert()
+        .success()
+        .stdout(
+            starts_with("value 1\nKey not found\nkey2 value2\nkeys 2\n")
+                .and(contains("\nsets 2\n")),
+        )
+        .stderr(contains("Key not found").and(contains("Unknown command: foo bar")));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["repl", "--addr", addr, "--format", "json"])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("stats\n")
+        .assert()
+        .success()
+        .stdout(contains("\"keys\":2").and(contains("\"sets\":2")));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server was not running");
+}
+
+#[test]
+fn server_cli_json_log() {
+    let addr = "127.0.0.1:4016";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr, "--log-format", "json"])
+        .current_dir(&temp_dir)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    thread::sleep(Duration::from_secs(1));
+    child.kill().expect("server exited before killed");
+    let output = child.wait_with_output().unwrap();
+
+    let records: Vec<serde_json::Value> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("log line is not valid JSON"))
+        .collect();
+    let requests: Vec<&serde_json::Value> = records
+        .iter()
+        .filter(|record| record["msg"] == "request")
+        .collect();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0]["command_type"], "Set");
+    assert_eq!(requests[0]["key"], "key1");
+    assert_eq!(requests[0]["result"], "ok");
+    assert!(requests[0]["latency_us"].is_u64());
+    assert_eq!(requests[1]["command_type"], "Get");
+}
+
+#[test]
+fn server_cli_log_level() {
+    let addr = "127.0.0.1:4019";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--engine",
+            "kvs",
+            "--addr",
+            addr,
+            "--log-format",
+            "json",
+            "--log-level",
+            "warning",
+        ])
+        .current_dir(&temp_dir)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    thread::sleep(Duration::from_secs(1));
+    child.kill().expect("server exited before killed");
+    let output = child.wait_with_output().unwrap();
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn server_cli_log_file() {
+    let addr = "127.0.0.1:4038";
+    let temp_dir = TempDir::new().unwrap();
+    let log_dir = TempDir::new().unwrap();
+    let log_file = log_dir.path().join("kvs.log");
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr, "--log-format", "json"])
+        .arg("--log-file")
+        .arg(&log_file)
+        .args(["--log-max-size", "200", "--log-keep", "2"])
+        .current_dir(&temp_dir)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for i in 0..5 {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", &format!("key{}", i), "value", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    thread::sleep(Duration::from_secs(1));
+    child.kill().expect("server exited before killed");
+    let output = child.wait_with_output().unwrap();
+    assert!(output.stdout.is_empty());
+
+    // Every record is over 200 bytes, so each one after the first is written to a new file
+    let last: serde_json::Value =
+        serde_json::from_str(fs::read_to_string(&log_file).unwrap().trim()).unwrap();
+    assert_eq!(last["msg"], "request");
+    assert_eq!(last["key"], "key4");
+    assert!(log_dir.path().join("kvs.log.1").exists());
+    assert!(log_dir.path().join("kvs.log.2").exists());
+    assert!(!log_dir.path().join("kvs.log.3").exists());
+}
+
+#[test]
+fn client_cli_load() {
+    let addr = "127.0.0.1:4017";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "sled", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut input = String::from("# seed data\n\n");
+    for i in 0..2500 {
+        input.push_str(&format!("set key{} value {}\n", i, i));
+    }
+    input.push_str("rm key0\nrm missing\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["load", "-", "--addr", addr])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer(input)
+        .assert()
+        .success()
+        .stdout(eq("Applied 2501 operations, 1 failed\n"))
+        .stderr(contains("Key not found"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2499", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value 2499\n"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key0", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found\n"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server was not running");
+}
+
+#[test]
+fn client_cli_output_json_errors() {
+    let addr = "127.0.0.1:4018";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--output", "json", "get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let miss: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(miss["found"], false);
+
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--output", "json", "rm", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    let error: serde_json::Value = serde_json::from_slice(&output.stderr).unwrap();
+    assert!(error["error"].as_str().unwrap().contains("Key not found"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server was not running");
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn server_cli_metrics() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    let addr = "127.0.0.1:4020";
+    let metrics_addr = "127.0.0.1:4021";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--engine",
+            "kvs",
+            "--addr",
+            addr,
+            "--metrics-addr",
+            metrics_addr,
+        ])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let mut stream = TcpStream::connect(metrics_addr).unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    child.kill().expect("server exited before killed");
+    child.wait().expect("couldn't wait on child");
+
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("kvs_requests_total{command_type=\"Set\"} 1"));
+    assert!(response.contains("kvs_requests_total{command_type=\"Rm\"} 1"));
+    assert!(response.contains("kvs_errors_total 1"));
+    assert!(response.contains("kvs_keys 1"));
+    assert!(response.contains("kvs_compactions 0"));
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn server_cli_tracing() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    let addr = "127.0.0.1:4033";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(br#"{"command_type":"Set","key":"key1","value":"value1","trace_id":"trace1"}"#)
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.contains("OK"));
+    thread::sleep(Duration::from_millis(500));
+    child.kill().expect("server exited before killed");
+    let mut stderr = String::new();
+    child
+        .stderr
+        .take()
+        .unwrap()
+        .read_to_string(&mut stderr)
+        .unwrap();
+    child.wait().expect("couldn't wait on child");
+
+    let span = stderr
+        .lines()
+        .find(|line| line.contains("request{"))
+        .expect("no request span was written");
+    assert!(span.contains("trace_id=\"trace1\""));
+    assert!(span.contains("command_type=Set"));
+    assert!(span.contains("key_len=4"));
+    assert!(span.contains("result=\"ok\""));
+    assert!(span.contains("queue_us="));
+}
+
+#[cfg(not(feature = "metrics"))]
+#[test]
+fn server_cli_metrics_disabled() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--addr",
+            "127.0.0.1:4020",
+            "--metrics-addr",
+            "127.0.0.1:4021",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("metrics feature"));
+}
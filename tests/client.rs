@@ -1,30 +1,30 @@
 use kvs::thread_pool::*;
-use kvs::{KvStore, KvsClient, KvsEngine, KvsServer, Result};
+use kvs::{
+    resolve_addr, run_server_with, ClientRequest, ClientRequestType, Config, EngineKind, ErrorCode,
+    KeyEvent, KvStore, KvStoreError, KvsClient, KvsEngine, KvsServer, MemoryKvsEngine, Response,
+    Result, ServerConfig, ServerMetricsSnapshot, SledKvsEngine,
+};
 
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::{sync, thread, time};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::sync::atomic::Ordering;
+use std::sync::{mpsc, Arc};
+use std::{thread, time};
 
-use num_cpus;
 use tempfile::TempDir;
 
+mod common;
+use common::{spawn_test_server, spawn_test_server_with_config, wait_for_server};
+
 // Test client performing multiple commands
 #[test]
 fn test_client() -> Result<()> {
-    let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000);
-    let engine = "kvs";
     let temp_dir = TempDir::new().unwrap();
-    let server = KvsServer::new(
-        socket,
-        engine,
+    let (_server, socket) = spawn_test_server(
         KvStore::open(temp_dir.path()).expect("Could not open KvStore"),
         SharedQueueThreadPool::new((num_cpus::get() * 2) as u32)
             .expect("Could not create thread pool"),
-    )
-    .expect("Could not create server");
-    thread::spawn(move || {
-        server.start().expect("server stopped");
-    });
-    thread::sleep(time::Duration::from_secs(2));
+    );
     let mut client = KvsClient::new(socket).expect("Could not create client");
     client
         .set(format!("key{}", 0), format!("value{}", 0))
@@ -36,3 +36,1178 @@ fn test_client() -> Result<()> {
     );
     Ok(())
 }
+
+// Batch responses should match the order of the requests
+#[test]
+fn test_client_batch() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let (_server, socket) = spawn_test_server(
+        KvStore::open(temp_dir.path()).expect("Could not open KvStore"),
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+    );
+
+    let request = |command_type, key: &str, value: &str| ClientRequest {
+        command_type,
+        key: key.to_owned(),
+        value: value.to_owned(),
+        batch: Vec::new(),
+        keys: Vec::new(),
+        trace_id: None,
+        seq: None,
+        request_id: None,
+    };
+    let mut client = KvsClient::new(socket).expect("Could not create client");
+    let resps = client.batch(vec![
+        request(ClientRequestType::Set, "key1", "value1"),
+        request(ClientRequestType::Get, "key1", ""),
+        request(ClientRequestType::Rm, "key2", ""),
+        request(ClientRequestType::Set, "key2", "value2"),
+        request(ClientRequestType::Rm, "key1", ""),
+        request(ClientRequestType::Get, "key1", ""),
+    ])?;
+    let values: Vec<&str> = resps.iter().map(|resp| resp.value.as_str()).collect();
+    assert_eq!(values, vec!["OK", "value1", "", "OK", "OK", ""]);
+    assert!(resps[2].error.contains("Key not found"));
+
+    client = KvsClient::new(socket).expect("Could not create client");
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Server should work with an engine that never touches disk
+#[test]
+fn test_client_memory_engine() -> Result<()> {
+    let (_server, socket) = spawn_test_server(
+        MemoryKvsEngine::new(),
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+    );
+
+    let mut client = KvsClient::new(socket).expect("Could not create client");
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client = KvsClient::new(socket).expect("Could not create client");
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client = KvsClient::new(socket).expect("Could not create client");
+    client.remove("key1".to_owned())?;
+    client = KvsClient::new(socket).expect("Could not create client");
+    assert_eq!(client.get("key1".to_owned())?, None);
+    client = KvsClient::new(socket).expect("Could not create client");
+    assert!(client.remove("key1".to_owned()).is_err());
+    Ok(())
+}
+
+// Removing a missing key over the network should fail with KeyNotFoundError, like it does locally
+#[test]
+fn test_client_remove_missing_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (_server, socket) = spawn_test_server(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+    );
+
+    match KvsClient::new(socket)?.remove("missing".to_owned()) {
+        Err(KvStoreError::KeyNotFoundError {}) => {}
+        other => panic!(
+            "expected KeyNotFoundError, got {:?}",
+            other.map_err(|e| e.to_string())
+        ),
+    }
+    // Responses to batched requests carry the error code for the caller to check
+    let resp = KvsClient::new(socket)?.batch(vec![ClientRequest {
+        command_type: ClientRequestType::Rm,
+        key: "missing".to_owned(),
+        value: String::new(),
+        batch: Vec::new(),
+        keys: Vec::new(),
+        trace_id: None,
+        seq: None,
+        request_id: None,
+    }])?;
+    assert_eq!(resp[0].error_code, Some(ErrorCode::KeyNotFound));
+    Ok(())
+}
+
+// Server should work with an engine picked at runtime behind a trait object
+#[test]
+fn test_client_dyn_engine() -> Result<()> {
+    let engine: Arc<dyn KvsEngine> = Arc::new(MemoryKvsEngine::new());
+    let (_server, socket) = spawn_test_server(
+        engine,
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+    );
+
+    let mut client = KvsClient::new(socket).expect("Could not create client");
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client = KvsClient::new(socket).expect("Could not create client");
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Writes above the engine's size limits should come back as error responses
+#[test]
+fn test_client_size_limits() -> Result<()> {
+    let config = Config::builder()
+        .max_key_size(8)
+        .max_value_size(16)
+        .build()?;
+    let (_server, socket) = spawn_test_server(
+        MemoryKvsEngine::with_config(config),
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+    );
+
+    let mut client = KvsClient::new(socket).expect("Could not create client");
+    match client.set("k".repeat(9), "value1".to_owned()) {
+        Err(KvStoreError::ServerError { error }) => assert!(error.contains("Key of 9 bytes")),
+        res => panic!("expected a server error, got {:?}", res),
+    }
+    client = KvsClient::new(socket).expect("Could not create client");
+    match client.set("key1".to_owned(), "v".repeat(17)) {
+        Err(KvStoreError::ServerError { error }) => assert!(error.contains("Value of 17 bytes")),
+        res => panic!("expected a server error, got {:?}", res),
+    }
+    client = KvsClient::new(socket).expect("Could not create client");
+    client.set("k".repeat(8), "v".repeat(16))?;
+    client = KvsClient::new(socket).expect("Could not create client");
+    assert_eq!(client.get("k".repeat(8))?, Some("v".repeat(16)));
+    Ok(())
+}
+
+// run_server should serve every combination of engine and pool
+#[test]
+fn test_run_server() -> Result<()> {
+    let combinations = EngineKind::ALL
+        .iter()
+        .flat_map(|&engine| PoolKind::ALL.iter().map(move |&pool| (engine, pool)));
+    let mut temp_dirs = Vec::new();
+    let mut servers = Vec::new();
+    for (engine, pool) in combinations {
+        let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().to_owned();
+        temp_dirs.push(temp_dir);
+        let (started, bound) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            run_server_with(
+                socket,
+                engine,
+                pool,
+                2,
+                &path,
+                ServerConfig::default(),
+                |addr, stop| started.send((addr, stop)).unwrap(),
+            )
+        });
+        let (addr, stop) = bound.recv().expect("server failed to start");
+        servers.push((engine, pool, addr, stop, handle));
+    }
+
+    for (engine, pool, addr, _, _) in servers.iter() {
+        let value = format!("{}-{}", engine, pool);
+        let mut client = KvsClient::new(*addr).expect("Could not create client");
+        client.set("key1".to_owned(), value.clone())?;
+        client = KvsClient::new(*addr).expect("Could not create client");
+        assert_eq!(client.get("key1".to_owned())?, Some(value));
+    }
+    for (_, _, _, stop, handle) in servers {
+        stop.store(true, Ordering::SeqCst);
+        handle.join().unwrap()?;
+    }
+    Ok(())
+}
+
+// MultiGet values should line up with the requested keys
+#[test]
+fn test_client_multi_get() -> Result<()> {
+    let (_server, socket) = spawn_test_server(
+        MemoryKvsEngine::new(),
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+    );
+
+    let mut client = KvsClient::new(socket).expect("Could not create client");
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client = KvsClient::new(socket).expect("Could not create client");
+    client.set("key3".to_owned(), "".to_owned())?;
+    client = KvsClient::new(socket).expect("Could not create client");
+    let keys = ["key2", "key1", "key3", "key4", "key1"];
+    assert_eq!(
+        client.multi_get(keys.iter().map(|key| key.to_string()).collect())?,
+        vec![
+            None,
+            Some("value1".to_owned()),
+            Some("".to_owned()),
+            None,
+            Some("value1".to_owned()),
+        ]
+    );
+    client = KvsClient::new(socket).expect("Could not create client");
+    assert!(client.multi_get(Vec::new())?.is_empty());
+    Ok(())
+}
+
+// Subscribers should be streamed the changes of matching keys over the network
+#[test]
+fn test_client_subscribe() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let (_server, socket) = spawn_test_server(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+    );
+
+    let client = KvsClient::new(socket).expect("Could not create client");
+    let mut events = client.subscribe("user:".to_owned())?;
+    let mut client = KvsClient::new(socket).expect("Could not create client");
+    client.set("group:1".to_owned(), "admins".to_owned())?;
+    client = KvsClient::new(socket).expect("Could not create client");
+    client.set("user:1".to_owned(), "alice".to_owned())?;
+    client = KvsClient::new(socket).expect("Could not create client");
+    client.remove("user:1".to_owned())?;
+
+    assert_eq!(
+        events.next().transpose()?,
+        Some(KeyEvent::Set {
+            key: "user:1".to_owned()
+        })
+    );
+    assert_eq!(
+        events.next().transpose()?,
+        Some(KeyEvent::Remove {
+            key: "user:1".to_owned()
+        })
+    );
+    Ok(())
+}
+
+// Connections beyond max_connections should wait until a slot frees up
+#[test]
+fn test_client_max_connections() -> Result<()> {
+    let config = ServerConfig {
+        max_connections: Some(1),
+        ..ServerConfig::default()
+    };
+    let (_server, socket) = spawn_test_server_with_config(
+        MemoryKvsEngine::new(),
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+        config,
+    );
+
+    // An idle connection holds the only slot
+    let idle = KvsClient::new(socket).expect("Could not create client");
+    thread::sleep(time::Duration::from_millis(100));
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut client = KvsClient::new(socket).expect("Could not create client");
+        sender
+            .send(client.set("key1".to_owned(), "value1".to_owned()))
+            .unwrap();
+    });
+    assert!(receiver
+        .recv_timeout(time::Duration::from_millis(500))
+        .is_err());
+
+    drop(idle);
+    receiver
+        .recv_timeout(time::Duration::from_secs(5))
+        .expect("request was not served after a slot freed up")?;
+    let mut client = KvsClient::new(socket).expect("Could not create client");
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Connections that send nothing should be dropped after idle_timeout, freeing their thread
+#[test]
+fn test_client_idle_timeout() -> Result<()> {
+    let config = ServerConfig {
+        idle_timeout: Some(time::Duration::from_millis(500)),
+        ..ServerConfig::default()
+    };
+    let (_server, socket) = spawn_test_server_with_config(
+        MemoryKvsEngine::new(),
+        SharedQueueThreadPool::new(1).expect("Could not create thread pool"),
+        config,
+    );
+
+    // The idle connection holds the only pool thread until it times out
+    let mut idle = TcpStream::connect(socket)?;
+    let start = time::Instant::now();
+    let mut client = KvsClient::new(socket).expect("Could not create client");
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(start.elapsed() >= time::Duration::from_millis(400));
+    let mut buf = Vec::new();
+    assert_eq!(idle.read_to_end(&mut buf)?, 0);
+    Ok(())
+}
+
+// Servers with an auth token should only serve connections that authenticate with it
+#[test]
+fn test_client_auth_token() -> Result<()> {
+    let config = ServerConfig {
+        auth_token: Some("secret".to_owned()),
+        ..ServerConfig::default()
+    };
+    let (_server, socket) = spawn_test_server_with_config(
+        MemoryKvsEngine::new(),
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+        config,
+    );
+
+    let mut client = KvsClient::new(socket).expect("Could not create client");
+    assert!(matches!(
+        client.set("key1".to_owned(), "value1".to_owned()),
+        Err(KvStoreError::ServerError { .. })
+    ));
+    assert!(matches!(
+        KvsClient::with_auth_token(socket, "wrong".to_owned()),
+        Err(KvStoreError::AuthError {})
+    ));
+
+    let auth = || KvsClient::with_auth_token(socket, "secret".to_owned());
+    assert_eq!(auth()?.set("key1".to_owned(), "value1".to_owned())?, "OK");
+    assert_eq!(auth()?.get("key1".to_owned())?, Some("value1".to_owned()));
+    let mut client = auth()?;
+    client.enable_binary_gets()?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Requests on a connection beyond its rate limit should be throttled instead of run
+#[test]
+fn test_client_rate_limit() -> Result<()> {
+    let config = ServerConfig {
+        rate_limit: Some(5),
+        ..ServerConfig::default()
+    };
+    let (_server, socket) = spawn_test_server_with_config(
+        MemoryKvsEngine::new(),
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+        config,
+    );
+
+    let ops = (0..20)
+        .map(|i| ClientRequest {
+            command_type: ClientRequestType::Set,
+            key: format!("key{}", i),
+            value: "value".to_owned(),
+            batch: Vec::new(),
+            keys: Vec::new(),
+            trace_id: None,
+            seq: None,
+            request_id: None,
+        })
+        .collect();
+    let mut client = KvsClient::new(socket).expect("Could not create client");
+    let resps = client.batch(ops)?;
+    assert!(resps[..5].iter().all(|resp| resp.value == "OK"));
+    let throttled = resps
+        .iter()
+        .filter(|resp| resp.error == "Rate limit exceeded")
+        .count();
+    assert!(throttled >= 10);
+
+    // Throttled ops are not run, and a new connection starts with a full bucket
+    let mut client = KvsClient::new(socket).expect("Could not create client");
+    assert_eq!(client.get("key0".to_owned())?, Some("value".to_owned()));
+    let mut client = KvsClient::new(socket).expect("Could not create client");
+    assert_eq!(client.get("key19".to_owned())?, None);
+    Ok(())
+}
+
+// get_set should return the value the server had before the set
+#[test]
+fn test_client_get_set() -> Result<()> {
+    let (_server, socket) = spawn_test_server(
+        MemoryKvsEngine::new(),
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+    );
+
+    let mut client = KvsClient::new(socket).expect("Could not create client");
+    assert_eq!(
+        client.get_set("key1".to_owned(), "value1".to_owned())?,
+        None
+    );
+    client = KvsClient::new(socket).expect("Could not create client");
+    assert_eq!(
+        client.get_set("key1".to_owned(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    client = KvsClient::new(socket).expect("Could not create client");
+    assert_eq!(client.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    // An empty value is still a value
+    client = KvsClient::new(socket).expect("Could not create client");
+    assert_eq!(client.get_set("key2".to_owned(), "".to_owned())?, None);
+    client = KvsClient::new(socket).expect("Could not create client");
+    assert_eq!(
+        client.get_set("key2".to_owned(), "value3".to_owned())?,
+        Some("".to_owned())
+    );
+    Ok(())
+}
+
+// set_nx should only set keys the server does not have yet
+#[test]
+fn test_client_set_nx() -> Result<()> {
+    let (_server, socket) = spawn_test_server(
+        MemoryKvsEngine::new(),
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+    );
+
+    assert!(KvsClient::new(socket)?.set_nx("key1".to_owned(), "value1".to_owned())?);
+    assert!(!KvsClient::new(socket)?.set_nx("key1".to_owned(), "value2".to_owned())?);
+    assert_eq!(
+        KvsClient::new(socket)?.get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    Ok(())
+}
+
+// Client count should get the number of keys, which overwrites don't change
+#[test]
+fn test_client_count() -> Result<()> {
+    let (_server, socket) = spawn_test_server(
+        MemoryKvsEngine::new(),
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+    );
+
+    assert_eq!(KvsClient::new(socket)?.count()?, 0);
+    KvsClient::new(socket)?.set("key1".to_owned(), "value1".to_owned())?;
+    KvsClient::new(socket)?.set("key2".to_owned(), "value2".to_owned())?;
+    KvsClient::new(socket)?.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(KvsClient::new(socket)?.count()?, 2);
+    KvsClient::new(socket)?.remove("key1".to_owned())?;
+    assert_eq!(KvsClient::new(socket)?.count()?, 1);
+    Ok(())
+}
+
+// Gets answered in the binary format should tell found, missing and empty values and errors apart
+// like JSON ones
+#[test]
+fn test_client_binary_get() -> Result<()> {
+    let engine = MemoryKvsEngine::new();
+    let (_server, socket) = spawn_test_server(
+        engine.clone(),
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+    );
+    engine.set("key1".to_owned(), "value1 ✓".to_owned())?;
+    engine.set("empty".to_owned(), String::new())?;
+    engine.set_bytes(b"invalid".to_vec(), vec![0xff, 0xfe])?;
+
+    let binary = || -> Result<KvsClient> {
+        let mut client = KvsClient::new(socket)?;
+        client.enable_binary_gets()?;
+        Ok(client)
+    };
+    assert_eq!(
+        binary()?.get("key1".to_owned())?,
+        Some("value1 ✓".to_owned())
+    );
+    assert_eq!(binary()?.get("empty".to_owned())?, Some(String::new()));
+    assert_eq!(binary()?.get("missing".to_owned())?, None);
+    assert!(matches!(
+        binary()?.get("invalid".to_owned()),
+        Err(KvStoreError::ServerError { .. })
+    ));
+    // Other requests on a binary connection are still answered in JSON
+    assert_eq!(binary()?.set("key2".to_owned(), "value2".to_owned())?, "OK");
+    assert_eq!(
+        KvsClient::new(socket)?.get("key2".to_owned())?,
+        Some("value2".to_owned())
+    );
+    Ok(())
+}
+
+// Client echo should get back exactly the payload it sent, without writing to the engine
+#[test]
+fn test_client_echo() -> Result<()> {
+    let engine = MemoryKvsEngine::new();
+    let (_server, socket) = spawn_test_server(
+        engine.clone(),
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+    );
+
+    let payloads = vec![
+        String::new(),
+        "value1".to_owned(),
+        "line1\nline2\t\"quoted\" \\ {\"json\": [1, 2]}".to_owned(),
+        "ключ 🔑 \u{0}".to_owned(),
+        "x".repeat(1 << 20),
+    ];
+    for payload in payloads {
+        assert_eq!(KvsClient::new(socket)?.echo(payload.clone())?, payload);
+    }
+    assert_eq!(engine.len()?, 0);
+    Ok(())
+}
+
+// Client clear should remove every key from the server's engine
+#[test]
+fn test_client_clear() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (_server, socket) = spawn_test_server(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+    );
+
+    for i in 0..10 {
+        KvsClient::new(socket)?.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert_eq!(KvsClient::new(socket)?.clear()?, "OK");
+    assert_eq!(KvsClient::new(socket)?.count()?, 0);
+    assert_eq!(KvsClient::new(socket)?.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Client get_or should get the default for missing keys only, not for keys set to ""
+#[test]
+fn test_client_get_or() -> Result<()> {
+    let (_server, socket) = spawn_test_server(
+        MemoryKvsEngine::new(),
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+    );
+
+    KvsClient::new(socket)?.set("key1".to_owned(), "value1".to_owned())?;
+    KvsClient::new(socket)?.set("empty".to_owned(), "".to_owned())?;
+    let get_or = |key: &str| KvsClient::new(socket)?.get_or(key.to_owned(), "default".to_owned());
+    assert_eq!(get_or("key1")?, "value1");
+    assert_eq!(get_or("empty")?, "");
+    assert_eq!(get_or("key2")?, "default");
+    assert_eq!(
+        KvsClient::new(socket)?.get("empty".to_owned())?,
+        Some(String::new())
+    );
+    Ok(())
+}
+
+// Requests sent with a trace id should be served like any other
+#[test]
+fn test_client_trace_id() -> Result<()> {
+    let (_server, socket) = spawn_test_server(
+        MemoryKvsEngine::new(),
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+    );
+
+    let mut client = KvsClient::new(socket).expect("Could not create client");
+    client.set_trace_id(Some("trace1".to_owned()));
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client = KvsClient::new(socket).expect("Could not create client");
+    client.set_trace_id(Some("trace1".to_owned()));
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let request: ClientRequest = serde_json::from_str(
+        r#"{"command_type":"Get","key":"key1","value":"","trace_id":"trace1"}"#,
+    )?;
+    assert_eq!(request.trace_id, Some("trace1".to_owned()));
+    Ok(())
+}
+
+// Engine and pool names should parse into their kinds
+#[test]
+fn parse_kinds() {
+    assert_eq!("kvs".parse::<EngineKind>().unwrap(), EngineKind::Kvs);
+    assert_eq!("sled".parse::<EngineKind>().unwrap(), EngineKind::Sled);
+    assert_eq!(
+        "crossbeam".parse::<PoolKind>().unwrap(),
+        PoolKind::Crossbeam
+    );
+    assert_eq!("rayon".parse::<PoolKind>().unwrap(), PoolKind::Rayon);
+    assert_eq!(
+        "worksteal".parse::<PoolKind>().unwrap(),
+        PoolKind::WorkStealing
+    );
+    match "rocks".parse::<EngineKind>() {
+        Err(KvStoreError::UnknownEngineError { name }) => assert_eq!(name, "rocks"),
+        res => panic!("unexpected result: {:?}", res),
+    }
+    match "naive".parse::<PoolKind>() {
+        Err(KvStoreError::UnknownPoolError { name }) => assert_eq!(name, "naive"),
+        res => panic!("unexpected result: {:?}", res),
+    }
+}
+
+// Scan should return the pairs in range over the network
+#[test]
+fn test_client_scan() -> Result<()> {
+    let (_server, socket) = spawn_test_server(
+        MemoryKvsEngine::new(),
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+    );
+
+    for i in 0..5 {
+        let mut client = KvsClient::new(socket).expect("Could not create client");
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let mut client = KvsClient::new(socket).expect("Could not create client");
+    assert_eq!(
+        client.scan("key1".to_owned(), "key3".to_owned())?,
+        vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "value2".to_owned()),
+        ]
+    );
+    client = KvsClient::new(socket).expect("Could not create client");
+    assert_eq!(client.scan("key3".to_owned(), "".to_owned())?.len(), 2);
+    Ok(())
+}
+
+// scan_page should page through the keys of the server
+#[test]
+fn test_client_scan_page() -> Result<()> {
+    let (_server, socket) = spawn_test_server(
+        MemoryKvsEngine::new(),
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+    );
+
+    for i in 0..5 {
+        KvsClient::new(socket)?.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let page = KvsClient::new(socket)?.scan_page(None, 3)?;
+    assert_eq!(page.len(), 3);
+    assert_eq!(page[2], ("key2".to_owned(), "value2".to_owned()));
+    let page = KvsClient::new(socket)?.scan_page(Some("key2".to_owned()), 3)?;
+    assert_eq!(
+        page,
+        [
+            ("key3".to_owned(), "value3".to_owned()),
+            ("key4".to_owned(), "value4".to_owned())
+        ]
+    );
+    Ok(())
+}
+
+// The metrics of a server should count every request it handled and the bytes it wrote
+#[test]
+fn test_client_server_metrics() -> Result<()> {
+    let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4034);
+    let server = Arc::new(
+        KvsServer::new(
+            socket,
+            "memory",
+            MemoryKvsEngine::new(),
+            SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+        )
+        .expect("Could not create server"),
+    );
+    {
+        let server = server.clone();
+        thread::spawn(move || {
+            server.start().expect("server stopped");
+        });
+    }
+    wait_for_server(socket);
+    assert_eq!(server.metrics(), ServerMetricsSnapshot::default());
+
+    for i in 0..3 {
+        let mut client = KvsClient::new(socket).expect("Could not create client");
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..2 {
+        let mut client = KvsClient::new(socket).expect("Could not create client");
+        assert_eq!(
+            client.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    let mut client = KvsClient::new(socket).expect("Could not create client");
+    client.remove("key0".to_owned())?;
+    let mut client = KvsClient::new(socket).expect("Could not create client");
+    assert!(client.remove("key0".to_owned()).is_err());
+    let mut client = KvsClient::new(socket).expect("Could not create client");
+    client.scan("".to_owned(), "".to_owned())?;
+
+    let metrics = server.metrics();
+    assert_eq!(metrics.requests, 8);
+    assert_eq!(metrics.sets, 3);
+    assert_eq!(metrics.gets, 2);
+    assert_eq!(metrics.removes, 2);
+    assert_eq!(metrics.errors, 1);
+    assert!(metrics.bytes_served > 0);
+    Ok(())
+}
+
+// Starts a server for engine and checks the stats it reports after a few writes
+fn check_stats<E: KvsEngine + Clone>(engine: E) -> Result<()> {
+    let (_server, socket) = spawn_test_server(
+        engine,
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+    );
+
+    for i in 0..5 {
+        let mut client = KvsClient::new(socket).expect("Could not create client");
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let mut client = KvsClient::new(socket).expect("Could not create client");
+    client.remove("key0".to_owned())?;
+
+    let mut client = KvsClient::new(socket).expect("Could not create client");
+    let report = client.stats()?;
+    assert_eq!(report.keys, 4);
+    assert_eq!(report.server.sets, 5);
+    assert_eq!(report.server.removes, 1);
+    assert!(report.disk_bytes.is_some_and(|bytes| bytes > 0));
+    Ok(())
+}
+
+// Stats should report the server counters and the engine's keys and disk usage
+#[test]
+fn test_client_stats() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    check_stats(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().unwrap();
+    check_stats(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// Setting the stop flag should make start return soon, even with no connections to accept
+#[test]
+fn test_client_stop_flag() -> Result<()> {
+    let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4037);
+    let server = KvsServer::new(
+        socket,
+        "memory",
+        MemoryKvsEngine::new(),
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+    )
+    .expect("Could not create server");
+    let stop = server.stop_flag();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        sender.send(server.start()).unwrap();
+    });
+    wait_for_server(socket);
+
+    let mut client = KvsClient::new(socket).expect("Could not create client");
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(receiver.try_recv().is_err());
+
+    stop.store(true, Ordering::SeqCst);
+    receiver
+        .recv_timeout(time::Duration::from_secs(1))
+        .expect("server did not stop")?;
+    Ok(())
+}
+
+// Hostnames and bracketed IPv6 addresses should resolve, preferring IPv4 for hostnames
+#[test]
+fn test_resolve_addr() -> Result<()> {
+    let socket = resolve_addr("localhost:0")?;
+    assert!(socket.is_ipv4());
+    assert!(socket.ip().is_loopback());
+    assert_eq!(socket.port(), 0);
+
+    let socket = resolve_addr("[::1]:4000")?;
+    assert_eq!(
+        socket,
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 4000)
+    );
+
+    assert!(resolve_addr("::1").is_err());
+    Ok(())
+}
+
+// A client should connect to a server by hostname
+#[test]
+fn test_client_connect_hostname() -> Result<()> {
+    let (_server, socket) = spawn_test_server(
+        MemoryKvsEngine::new(),
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+    );
+
+    let mut client = KvsClient::connect(format!("localhost:{}", socket.port()).as_str())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let mut client = KvsClient::connect(("localhost", socket.port()))?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Exists should report whether keys exist without sending their values back
+#[test]
+fn test_client_exists() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let (_server, socket) = spawn_test_server(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+    );
+
+    let mut client = KvsClient::new(socket)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let mut client = KvsClient::new(socket)?;
+    assert!(client.exists("key1".to_owned())?);
+    let mut client = KvsClient::new(socket)?;
+    assert!(!client.exists("key2".to_owned())?);
+
+    // The response to an exists request should not carry the value
+    let mut stream = TcpStream::connect(socket)?;
+    let req = ClientRequest {
+        command_type: ClientRequestType::Exists,
+        key: "key1".to_owned(),
+        value: "".to_owned(),
+        batch: Vec::new(),
+        keys: Vec::new(),
+        trace_id: None,
+        seq: None,
+        request_id: None,
+    };
+    serde_json::to_writer(&mut stream, &req)?;
+    let mut buf = String::new();
+    stream.read_to_string(&mut buf)?;
+    assert!(!buf.contains("value1"));
+    Ok(())
+}
+
+// Requests above max_request_size should be rejected without stopping the server
+#[test]
+fn test_client_max_request_size() -> Result<()> {
+    let (_server, socket) = spawn_test_server_with_config(
+        MemoryKvsEngine::new(),
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+        ServerConfig {
+            max_request_size: Some(1024),
+            ..ServerConfig::default()
+        },
+    );
+
+    let mut client = KvsClient::new(socket)?;
+    client.set("key1".to_owned(), "a".repeat(512))?;
+
+    let mut client = KvsClient::new(socket)?;
+    match client.set("key2".to_owned(), "b".repeat(4096)) {
+        Err(KvStoreError::ServerError { error }) => assert!(error.contains("1024 bytes")),
+        res => panic!("large request was not rejected: {:?}", res),
+    }
+    // A request far above the limit may not even be sent whole before the connection is closed
+    let mut client = KvsClient::new(socket)?;
+    assert!(client.set("key2".to_owned(), "b".repeat(1 << 24)).is_err());
+
+    let mut client = KvsClient::new(socket)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("a".repeat(512)));
+    let mut client = KvsClient::new(socket)?;
+    assert_eq!(client.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Malformed requests should get an error response rather than a dropped connection
+#[test]
+fn test_client_bad_request() -> Result<()> {
+    let (_server, socket) = spawn_test_server(
+        MemoryKvsEngine::new(),
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+    );
+
+    for request in [
+        &b"{not json"[..],
+        br#"{"command_type":"Bogus","key":"key1","value":""}"#,
+        br#"{"command_type":"Get","key":1,"value":""}"#,
+    ] {
+        let mut stream = TcpStream::connect(socket)?;
+        stream.write_all(request)?;
+        let resp: Response = serde_json::from_reader(&mut stream)?;
+        assert!(resp.error.starts_with("Bad request"), "{}", resp.error);
+    }
+
+    let mut client = KvsClient::new(socket)?;
+    assert_eq!(client.set("key1".to_owned(), "value1".to_owned())?, "OK");
+    Ok(())
+}
+
+// A test server should serve requests as soon as it is spawned and stop listening once dropped
+#[test]
+fn test_spawn_test_server() -> Result<()> {
+    let (server, socket) = spawn_test_server(
+        MemoryKvsEngine::new(),
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+    );
+    let mut client = KvsClient::new(socket)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let mut client = KvsClient::new(socket)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(server);
+    assert!(TcpStream::connect(socket).is_err());
+    Ok(())
+}
+
+// A server created with port 0 should report the port the OS picked and serve clients on it
+#[test]
+fn test_client_local_addr() -> Result<()> {
+    let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0);
+    let server = KvsServer::new(
+        socket,
+        "memory",
+        MemoryKvsEngine::new(),
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+    )
+    .expect("Could not create server");
+    let addr = server.local_addr()?;
+    assert_eq!(addr.ip(), socket.ip());
+    assert_ne!(addr.port(), 0);
+    thread::spawn(move || {
+        server.start().expect("server stopped");
+    });
+
+    let mut client = KvsClient::new(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let mut client = KvsClient::new(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Pipelined requests should each get a response carrying their seq, in the order they were sent
+#[test]
+fn test_client_pipeline() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let (_server, socket) = spawn_test_server(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(1).expect("Could not create thread pool"),
+    );
+    let request = |command_type, key: String, value: String| ClientRequest {
+        command_type,
+        key,
+        value,
+        batch: Vec::new(),
+        keys: Vec::new(),
+        trace_id: None,
+        seq: None,
+        request_id: None,
+    };
+
+    let sets = (0..100)
+        .map(|i| {
+            request(
+                ClientRequestType::Set,
+                format!("key{}", i),
+                format!("value{}", i),
+            )
+        })
+        .collect();
+    let resps = KvsClient::new(socket)?.pipeline(sets)?;
+    assert_eq!(resps.len(), 100);
+    for (i, resp) in resps.iter().enumerate() {
+        assert_eq!(resp.seq, Some(i as u64));
+        assert_eq!(resp.value, "OK");
+    }
+
+    let mut reqs: Vec<ClientRequest> = (0..100)
+        .rev()
+        .map(|i| request(ClientRequestType::Get, format!("key{}", i), "".to_owned()))
+        .collect();
+    reqs.push(request(
+        ClientRequestType::Rm,
+        "key100".to_owned(),
+        "".to_owned(),
+    ));
+    reqs.push(request(
+        ClientRequestType::Batch,
+        "".to_owned(),
+        "".to_owned(),
+    ));
+    let resps = KvsClient::new(socket)?.pipeline(reqs)?;
+    let values: Vec<String> = resps[..100].iter().map(|resp| resp.value.clone()).collect();
+    let expected: Vec<String> = (0..100).rev().map(|i| format!("value{}", i)).collect();
+    assert_eq!(values, expected);
+    assert!(resps[100].error.contains("Key not found"));
+    assert!(resps[101].error.contains("can't be pipelined"));
+    assert_eq!(resps[101].seq, Some(101));
+
+    // Requests without a seq are still answered one per connection
+    let mut client = KvsClient::new(socket)?;
+    assert_eq!(client.get("key7".to_owned())?, Some("value7".to_owned()));
+    Ok(())
+}
+
+// A request retried with the same request id should be answered again without being run twice
+#[test]
+fn test_client_request_id() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let config = Config::builder()
+        .merge_operator(Arc::new(
+            |_key: &str, existing: Option<&str>, operand: &str| {
+                let count: u64 = existing.unwrap_or("0").parse().unwrap();
+                (count + operand.parse::<u64>().unwrap()).to_string()
+            },
+        ))
+        .build()?;
+    let (_server, socket) = spawn_test_server_with_config(
+        KvStore::open_with_config(temp_dir.path(), config)?,
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+        ServerConfig {
+            dedup_ttl: time::Duration::from_millis(500),
+            ..ServerConfig::default()
+        },
+    );
+    let increment = |request_id: &str| -> Result<String> {
+        KvsClient::new(socket)?.merge_with_request_id(
+            "counter".to_owned(),
+            "1".to_owned(),
+            request_id.to_owned(),
+        )
+    };
+    let get = || KvsClient::new(socket)?.get("counter".to_owned());
+
+    assert_eq!(increment("req1")?, "OK");
+    assert_eq!(increment("req1")?, "OK");
+    assert_eq!(get()?, Some("1".to_owned()));
+    increment("req2")?;
+    assert_eq!(get()?, Some("2".to_owned()));
+
+    // Once its response expires, an id is run again
+    thread::sleep(time::Duration::from_millis(600));
+    increment("req1")?;
+    assert_eq!(get()?, Some("3".to_owned()));
+
+    // A set retried after the counter moved on does not reset it
+    let reset = || {
+        KvsClient::new(socket)?.set_with_request_id(
+            "counter".to_owned(),
+            "0".to_owned(),
+            "req3".to_owned(),
+        )
+    };
+    assert_eq!(reset()?, "OK");
+    increment("req4")?;
+    assert_eq!(reset()?, "OK");
+    assert_eq!(get()?, Some("1".to_owned()));
+    Ok(())
+}
+
+// A request id should only be run once even if its retries race it, should not answer a different
+// request, and should cover a whole batch
+#[test]
+fn test_client_request_id_retries() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let config = Config::builder()
+        .merge_operator(Arc::new(
+            |_key: &str, existing: Option<&str>, operand: &str| {
+                let count: u64 = existing.unwrap_or("0").parse().unwrap();
+                (count + operand.parse::<u64>().unwrap()).to_string()
+            },
+        ))
+        .build()?;
+    let (_server, socket) = spawn_test_server(
+        KvStore::open_with_config(temp_dir.path(), config)?,
+        SharedQueueThreadPool::new(8).expect("Could not create thread pool"),
+    );
+    let increment = move |request_id: &str| -> Result<String> {
+        KvsClient::new(socket)?.merge_with_request_id(
+            "counter".to_owned(),
+            "1".to_owned(),
+            request_id.to_owned(),
+        )
+    };
+    let get = |key: &str| KvsClient::new(socket)?.get(key.to_owned());
+
+    let barrier = Arc::new(std::sync::Barrier::new(8));
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                increment("req1")
+            })
+        })
+        .collect();
+    for handle in handles {
+        assert_eq!(handle.join().unwrap()?, "OK");
+    }
+    assert_eq!(get("counter")?, Some("1".to_owned()));
+
+    // The same id with another request is an error rather than the first response
+    let res = KvsClient::new(socket)?.set_with_request_id(
+        "counter".to_owned(),
+        "0".to_owned(),
+        "req1".to_owned(),
+    );
+    match res {
+        Err(KvStoreError::ServerError { error }) => assert!(error.contains("req1"), "{}", error),
+        other => panic!("expected ServerError, got {:?}", other),
+    }
+    assert_eq!(get("counter")?, Some("1".to_owned()));
+
+    // A batch is run once for its own id
+    let op = |key: &str| ClientRequest {
+        command_type: ClientRequestType::Merge,
+        key: key.to_owned(),
+        value: "1".to_owned(),
+        batch: Vec::new(),
+        keys: Vec::new(),
+        trace_id: None,
+        seq: None,
+        request_id: None,
+    };
+    for _ in 0..2 {
+        let resps = KvsClient::new(socket)?
+            .batch_with_request_id(vec![op("counter"), op("other")], "req2".to_owned())?;
+        assert!(resps.iter().all(|resp| resp.error.is_empty()));
+    }
+    assert_eq!(get("counter")?, Some("2".to_owned()));
+    assert_eq!(get("other")?, Some("1".to_owned()));
+    let resps =
+        KvsClient::new(socket)?.batch_with_request_id(vec![op("other")], "req2".to_owned())?;
+    assert!(resps[0].error.contains("req2"));
+    assert_eq!(get("other")?, Some("1".to_owned()));
+    Ok(())
+}
+
+// Requests that write should be appended to the audit log, and reads should not
+#[test]
+fn test_client_audit_log() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let audit_log = temp_dir.path().join("audit.log");
+    let (_server, socket) = spawn_test_server_with_config(
+        MemoryKvsEngine::new(),
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+        ServerConfig {
+            audit_log: Some(audit_log.clone()),
+            ..ServerConfig::default()
+        },
+    );
+    KvsClient::new(socket)?.set("key1".to_owned(), "value1".to_owned())?;
+    KvsClient::new(socket)?.get("key1".to_owned())?;
+    KvsClient::new(socket)?.remove("key1".to_owned())?;
+
+    // Records are written in the background, so wait for both to show up
+    let deadline = time::Instant::now() + time::Duration::from_secs(5);
+    let records: Vec<serde_json::Value> = loop {
+        let content = std::fs::read_to_string(&audit_log)?;
+        if content.lines().count() >= 2 || time::Instant::now() > deadline {
+            break content
+                .lines()
+                .map(serde_json::from_str)
+                .collect::<serde_json::Result<_>>()?;
+        }
+        thread::sleep(time::Duration::from_millis(10));
+    };
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["command_type"], "Set");
+    assert_eq!(records[0]["key"], "key1");
+    assert!(records[0]["ts"].is_string());
+    assert_eq!(records[1]["command_type"], "Rm");
+    assert_eq!(records[1]["key"], "key1");
+    Ok(())
+}
+
+// Clients should connect to IPv6 servers, and report addresses that resolve to nothing clearly
+#[test]
+fn test_client_ipv6() -> Result<()> {
+    let server = KvsServer::new(
+        resolve_addr("[::1]:0")?,
+        "memory",
+        MemoryKvsEngine::new(),
+        SharedQueueThreadPool::new(2).expect("Could not create thread pool"),
+    )
+    .expect("Could not create server");
+    let addr = format!("[::1]:{}", server.local_addr()?.port());
+    thread::spawn(move || {
+        server.start().expect("server stopped");
+    });
+
+    KvsClient::new(addr.as_str())?.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(
+        KvsClient::new(addr.as_str())?.get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+
+    for addr in ["no-such-host.invalid:4000", "::1"] {
+        match KvsClient::new(addr) {
+            Err(KvStoreError::AddrResolveError { .. }) => {}
+            res => panic!("{} resolved: {:?}", addr, res.map(|_| ())),
+        }
+    }
+    Ok(())
+}
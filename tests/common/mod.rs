@@ -0,0 +1,86 @@
+//! Helpers shared by the integration tests and benchmarks for running a real server
+// Each test crate uses only some of the helpers
+#![allow(dead_code)]
+
+use kvs::thread_pool::ThreadPool;
+use kvs::{KvsEngine, KvsServer, Result, ServerConfig};
+
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// How long to keep trying to connect to a server that is starting up
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+// How long to wait between attempts to connect to a server that is starting up
+const CONNECT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// TestServer is a KvsServer running on its own thread. Dropping it stops the server.
+pub struct TestServer {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<Result<()>>>,
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// spawn_test_server starts a server for engine on an ephemeral port and returns its address
+pub fn spawn_test_server<E, P>(engine: E, pool: P) -> (TestServer, SocketAddr)
+where
+    E: KvsEngine + Clone,
+    P: ThreadPool + Send + 'static,
+{
+    spawn_test_server_with_config(engine, pool, ServerConfig::default())
+}
+
+/// spawn_test_server_with_config is spawn_test_server for a server with the given config
+pub fn spawn_test_server_with_config<E, P>(
+    engine: E,
+    pool: P,
+    config: ServerConfig,
+) -> (TestServer, SocketAddr)
+where
+    E: KvsEngine + Clone,
+    P: ThreadPool + Send + 'static,
+{
+    let server = KvsServer::with_config(
+        (Ipv4Addr::LOCALHOST, 0).into(),
+        "test",
+        engine,
+        pool,
+        config,
+    )
+    .expect("Could not create server");
+    // The listener is already bound, so connections wait in its backlog until start accepts them
+    let addr = server
+        .local_addr()
+        .expect("Could not get the server address");
+    let stop = server.stop_flag();
+    let handle = thread::spawn(move || server.start());
+    let server = TestServer {
+        stop,
+        handle: Some(handle),
+    };
+    (server, addr)
+}
+
+/// wait_for_server returns once a server accepts connections on addr, and panics if none does
+/// within a few seconds
+pub fn wait_for_server(addr: SocketAddr) {
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    while TcpStream::connect(addr).is_err() {
+        assert!(
+            Instant::now() < deadline,
+            "server did not start on {}",
+            addr
+        );
+        thread::sleep(CONNECT_POLL_INTERVAL);
+    }
+}
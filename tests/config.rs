@@ -0,0 +1,73 @@
+use kvs::{CompactionPolicy, Compression, Config, KvStore, KvsEngine, Result};
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn builder_rejects_zero_filesize_limit() {
+    assert!(Config::builder().filesize_limit(0).build().is_err());
+}
+
+// Manual compaction disables automatic compaction, a file count of 0 is rejected
+#[test]
+fn builder_compaction_policy() -> Result<()> {
+    let config = Config::builder()
+        .compaction_policy(CompactionPolicy::Manual)
+        .build()?;
+    assert_eq!(config.compaction_policy, CompactionPolicy::Manual);
+    assert!(Config::builder()
+        .compaction_policy(CompactionPolicy::ByFileCount(0))
+        .build()
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn builder_defaults() -> Result<()> {
+    let config = Config::builder().build()?;
+    let default = Config::default();
+    assert_eq!(config.filesize_limit, default.filesize_limit);
+    assert_eq!(config.compaction_policy, default.compaction_policy);
+    assert!(config.merge_operator.is_none());
+    Ok(())
+}
+
+#[test]
+fn builder_round_trip() -> Result<()> {
+    let config = Config::builder()
+        .filesize_limit(4096)
+        .compaction_policy(CompactionPolicy::ByFileCount(8))
+        .merge_operator(Arc::new(
+            |_key: &str, _existing: Option<&str>, operand: &str| operand.to_owned(),
+        ))
+        .build()?;
+    assert_eq!(config.filesize_limit, 4096);
+    assert_eq!(config.compaction_policy, CompactionPolicy::ByFileCount(8));
+    assert!(config.merge_operator.is_some());
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn builder_rejects_invalid_dead_byte_ratio() {
+    let by_size = |ratio| Config::builder().compaction_policy(CompactionPolicy::BySize(ratio));
+    assert!(by_size(0.0).build().is_err());
+    assert!(by_size(1.5).build().is_err());
+    assert!(by_size(0.5)
+        .compaction_interval(Duration::from_secs(0))
+        .build()
+        .is_err());
+    assert!(by_size(0.5).build().is_ok());
+}
+
+#[test]
+fn builder_rejects_invalid_deflate_level() {
+    let deflate = |level| Config::builder().compression(Compression::Deflate(level));
+    assert!(deflate(11).build().is_err());
+    assert!(deflate(0).build().is_ok());
+    assert!(deflate(10).build().is_ok());
+}
@@ -1,6 +1,11 @@
-use kvs::{KvStore, KvsEngine, Result};
-use std::sync::{Arc, Barrier};
+use kvs::{
+    resolve_engine, CompactionPolicy, Compression, Config, Engine, EngineKind, KeyEvent, KvStore,
+    KvStoreError, KvsEngine, MemoryKvsEngine, Result, SledKvsEngine, WriteOp,
+};
+use std::io::Read;
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
+use std::time::{Duration, SystemTime};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -25,6 +30,262 @@ fn get_stored_value() -> Result<()> {
     Ok(())
 }
 
+// A key pointing at the record of another key should fail with KeyMismatch instead of returning
+// the other value
+#[test]
+fn get_key_mismatch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    // The two records have the same length, so swapping them leaves each key pointing at the
+    // other's record
+    let log = WalkDir::new(temp_dir.path().join("logs"))
+        .into_iter()
+        .map(|entry| entry.unwrap().into_path())
+        .find(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .expect("no log file");
+    let mut bytes = std::fs::read(&log)?;
+    assert_eq!(bytes.len() % 2, 0);
+    let half = bytes.len() / 2;
+    bytes.rotate_left(half);
+    std::fs::write(&log, bytes)?;
+
+    match store.get("key1".to_owned()) {
+        Err(KvStoreError::KeyMismatch { expected, found }) => {
+            assert_eq!(expected, "key1");
+            assert_eq!(found, "key2");
+        }
+        res => panic!("expected KeyMismatch, got {:?}", res),
+    }
+    Ok(())
+}
+
+// Should report keys as existing until they are removed, without mixing up namespaces
+#[test]
+fn contains_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.contains_key("key1".to_owned())?);
+    assert!(!store.contains_key("key2".to_owned())?);
+    assert!(!store.namespace(1).contains_key("key1".to_owned())?);
+
+    store.remove("key1".to_owned())?;
+    assert!(!store.contains_key("key1".to_owned())?);
+
+    let sled = SledKvsEngine::open(&temp_dir.path().join("sled"))?;
+    sled.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(sled.contains_key("key1".to_owned())?);
+    assert!(!sled.contains_key("key2".to_owned())?);
+    Ok(())
+}
+
+// len should count sets of new keys and removes, but not overwrites
+#[test]
+fn len_tracks_writes() -> Result<()> {
+    let check = |engine: &dyn KvsEngine| -> Result<()> {
+        assert_eq!(engine.len()?, 0);
+        engine.set("key1".to_owned(), "value1".to_owned())?;
+        engine.set("key2".to_owned(), "value2".to_owned())?;
+        assert_eq!(engine.len()?, 2);
+        engine.set("key1".to_owned(), "value3".to_owned())?;
+        assert_eq!(engine.len()?, 2);
+        engine.remove("key2".to_owned())?;
+        assert_eq!(engine.len()?, 1);
+        assert!(engine.remove("key2".to_owned()).is_err());
+        assert_eq!(engine.len()?, 1);
+        Ok(())
+    };
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(&KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(&SledKvsEngine::open(temp_dir.path())?)?;
+    check(&MemoryKvsEngine::new())
+}
+
+// len of each namespace should count its own keys, through overwrites, removes, merges,
+// transactions, clears and reopening
+#[test]
+fn len_counts_namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = || {
+        Config::builder()
+            .merge_operator(Arc::new(
+                |_key: &str, existing: Option<&str>, operand: &str| {
+                    existing.unwrap_or_default().to_owned() + operand
+                },
+            ))
+            .build()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config()?)?;
+    let (first, last) = (store.namespace(1), store.namespace(u32::MAX));
+    for i in 0..10 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+        first.set(format!("key{}", i), "value".to_owned())?;
+    }
+    for i in 0..5 {
+        store.set(format!("key{}", i), "value2".to_owned())?;
+        first.remove(format!("key{}", i))?;
+        last.merge(format!("key{}", i), "a".to_owned())?;
+        last.merge(format!("key{}", i), "b".to_owned())?;
+    }
+    assert_eq!((store.len()?, first.len()?, last.len()?), (10, 5, 5));
+
+    store.transaction(|tx| {
+        tx.remove("key0".to_owned())?;
+        tx.set("key0".to_owned(), "value3".to_owned())?;
+        tx.remove("key1".to_owned())?;
+        tx.set("new".to_owned(), "value".to_owned())
+    })?;
+    store.clear_namespace(u32::MAX)?;
+    assert_eq!((store.len()?, first.len()?, last.len()?), (10, 5, 0));
+
+    drop((store, first, last));
+    let store = KvStore::open_with_config(temp_dir.path(), config()?)?;
+    assert_eq!(store.len()?, 10);
+    assert_eq!(store.namespace(1).len()?, 5);
+    assert_eq!(store.namespace(u32::MAX).len()?, 0);
+    store.clear()?;
+    assert_eq!(store.len()?, 0);
+    Ok(())
+}
+
+// clear should remove every key, and the store should take new writes and reopen empty
+#[test]
+fn clear() -> Result<()> {
+    let check = |engine: &dyn KvsEngine| -> Result<()> {
+        for i in 0..100 {
+            engine.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        engine.clear()?;
+        assert_eq!(engine.len()?, 0);
+        for i in 0..100 {
+            assert_eq!(engine.get(format!("key{}", i))?, None);
+        }
+        engine.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+        Ok(())
+    };
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder()
+        .filesize_limit(500)
+        .compaction_policy(CompactionPolicy::Manual)
+        .cache_capacity(10)
+        .bloom_false_positive_rate(0.01)
+        .build()?;
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    store
+        .namespace(1)
+        .set("key1".to_owned(), "value1".to_owned())?;
+    store.get("key1".to_owned())?;
+    check(&store)?;
+    assert_eq!(store.namespace(1).len()?, 0);
+    assert!(store.stats()?.total_bytes < 500);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len()?, 1);
+    assert_eq!(store.get("key0".to_owned())?, None);
+    drop(store);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(&SledKvsEngine::open(temp_dir.path())?)?;
+    check(&MemoryKvsEngine::new())
+}
+
+// get_or should fall back to the default for missing keys only, not for keys set to ""
+#[test]
+fn get_or() -> Result<()> {
+    let check = |engine: &dyn KvsEngine| -> Result<()> {
+        engine.set("key1".to_owned(), "value1".to_owned())?;
+        engine.set("empty".to_owned(), "".to_owned())?;
+        assert_eq!(
+            engine.get_or("key1".to_owned(), "default".to_owned())?,
+            "value1"
+        );
+        assert_eq!(engine.get_or("empty".to_owned(), "default".to_owned())?, "");
+        assert_eq!(
+            engine.get_or("key2".to_owned(), "default".to_owned())?,
+            "default"
+        );
+        Ok(())
+    };
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(&KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(&SledKvsEngine::open(temp_dir.path())?)?;
+    check(&MemoryKvsEngine::new())
+}
+
+// Compressed stores should read back every kind of value, also once compacted and reopened with
+// compression turned off, and take up less disk than uncompressed ones
+#[test]
+fn compressed_values() -> Result<()> {
+    let config = |compression| Config {
+        compression,
+        compaction_policy: CompactionPolicy::Manual,
+        merge_operator: Some(Arc::new(
+            |_key: &str, existing: Option<&str>, operand: &str| {
+                format!("{}{}", existing.unwrap_or_default(), operand)
+            },
+        )),
+        ..Config::default()
+    };
+    let text = "the quick brown fox jumps over the lazy dog ".repeat(50);
+    let bytes = [0u8, 159, 146, 150].repeat(100);
+    let write = |store: &KvStore| -> Result<()> {
+        for i in 0..20 {
+            store.set(format!("text{}", i), text.clone())?;
+        }
+        store.set("short".to_owned(), "v".to_owned())?;
+        store.set_bytes(b"bytes".to_vec(), bytes.clone())?;
+        store.merge("merged".to_owned(), text.clone())?;
+        store.merge("merged".to_owned(), text.clone())?;
+        Ok(())
+    };
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..20 {
+            assert_eq!(store.get(format!("text{}", i))?, Some(text.clone()));
+        }
+        assert_eq!(store.get("short".to_owned())?, Some("v".to_owned()));
+        assert_eq!(store.get_bytes(b"bytes".to_vec())?, Some(bytes.clone()));
+        assert_eq!(store.get("merged".to_owned())?, Some(text.repeat(2)));
+        let mut read = String::new();
+        store
+            .get_stream("text0".to_owned())?
+            .unwrap()
+            .read_to_string(&mut read)?;
+        assert_eq!(read, text);
+        let metadata = store.metadata("text0".to_owned())?.unwrap();
+        assert_eq!(metadata.value_len, text.len());
+        Ok(())
+    };
+
+    let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+    let plain = KvStore::open_with_config(plain_dir.path(), config(Compression::None))?;
+    write(&plain)?;
+    check(&plain)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_config(temp_dir.path(), config(Compression::Deflate(6)))?;
+    write(&store)?;
+    check(&store)?;
+    assert!(store.size_on_disk()?.unwrap() * 4 < plain.size_on_disk()?.unwrap());
+
+    store.compact_now(None)?;
+    check(&store)?;
+    drop(store);
+
+    let store = KvStore::open_with_config(temp_dir.path(), config(Compression::None))?;
+    check(&store)?;
+    store.set("text0".to_owned(), text.clone())?;
+    store.compact_now(None)?;
+    check(&store)?;
+    Ok(())
+}
+
 // Should overwrite existent value
 #[test]
 fn overwrite_value() -> Result<()> {
@@ -88,15 +349,13 @@ fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
 
+    // A background compaction may remove a log file between listing it and reading its size
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
-        let len: walkdir::Result<u64> = entries
-            .map(|res| {
-                res.and_then(|entry| entry.metadata())
-                    .map(|metadata| metadata.len())
-            })
-            .sum();
-        len.expect("fail to get directory size")
+        entries
+            .filter_map(|res| res.and_then(|entry| entry.metadata()).ok())
+            .map(|metadata| metadata.len())
+            .sum::<u64>()
     };
 
     let mut current_size = dir_size();
@@ -209,3 +468,2062 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Backup should only contain the live records at the time it was taken
+#[test]
+fn backup_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    store.remove("key3".to_owned())?;
+    store.backup(backup_dir.path())?;
+    store.set("key4".to_owned(), "value5".to_owned())?;
+
+    let log_files = WalkDir::new(backup_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .count();
+    assert_eq!(log_files, 1);
+
+    let backup = KvStore::open(backup_dir.path())?;
+    assert_eq!(backup.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(backup.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(backup.get("key3".to_owned())?, None);
+    assert_eq!(backup.get("key4".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, Some("value5".to_owned()));
+
+    Ok(())
+}
+
+// A backup taken while a compaction is running should wait for it rather than read removed files
+#[test]
+fn backup_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+
+    let mut backup = None;
+    store.compact_now(Some(&mut |_| {
+        if backup.is_none() {
+            let store = store.clone();
+            let dest = backup_dir.path().to_owned();
+            let handle = thread::spawn(move || store.backup(&dest));
+            thread::sleep(Duration::from_millis(100));
+            assert!(!handle.is_finished(), "backup ran alongside the compaction");
+            backup = Some(handle);
+        }
+    }))?;
+    backup
+        .expect("compaction reported no progress")
+        .join()
+        .unwrap()?;
+
+    let backup = KvStore::open(backup_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(backup.get(format!("key{}", key_id))?, Some("9".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Merge operands should accumulate onto the existing value
+#[test]
+fn merge_concat() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = || Config {
+        merge_operator: Some(Arc::new(
+            |_key: &str, existing: Option<&str>, operand: &str| {
+                format!("{}{}", existing.unwrap_or_default(), operand)
+            },
+        )),
+        ..Config::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config())?;
+
+    store.merge("key1".to_owned(), "a".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("a".to_owned()));
+    store.merge("key1".to_owned(), "b".to_owned())?;
+    store.merge("key1".to_owned(), "c".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("abc".to_owned()));
+
+    store.set("key2".to_owned(), "x".to_owned())?;
+    store.merge("key2".to_owned(), "y".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("xy".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open_with_config(temp_dir.path(), config())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("abc".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("xy".to_owned()));
+    store.set("key1".to_owned(), "z".to_owned())?;
+    store.merge("key1".to_owned(), "d".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("zd".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn merge_without_operator() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.merge("key1".to_owned(), "a".to_owned()).is_err());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Should store values that are not valid UTF-8
+#[test]
+fn binary_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let key = vec![0xff, 0x00, 0x01];
+    let value = vec![0x00, 0x9f, 0x92, 0x96];
+
+    store.set_bytes(key.clone(), value.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get_bytes(key.clone())?, Some(value.clone()));
+    assert_eq!(store.get_bytes(b"key1".to_vec())?, Some(b"value1".to_vec()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(key.clone())?, Some(value));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.remove_bytes(key.clone())?;
+    assert_eq!(store.get_bytes(key)?, None);
+
+    Ok(())
+}
+
+#[test]
+fn sled_binary_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = SledKvsEngine::open(temp_dir.path())?;
+    let value = vec![0x00, 0x9f, 0x92, 0x96];
+
+    db.set_bytes(b"key1".to_vec(), value.clone())?;
+    assert_eq!(db.get_bytes(b"key1".to_vec())?, Some(value));
+    assert!(db.get("key1".to_owned()).is_err());
+    db.remove_bytes(b"key1".to_vec())?;
+    assert_eq!(db.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Writes through the sled db of the engine and through the engine should see each other
+#[test]
+fn sled_db() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(
+        engine.db().get("key1")?.as_deref(),
+        Some(b"value1".as_ref())
+    );
+
+    let swapped = engine
+        .db()
+        .compare_and_swap("key1", Some("value1"), Some("value2"))?;
+    assert!(swapped.is_ok());
+    let swapped = engine
+        .db()
+        .compare_and_swap("key1", Some("value1"), Some("value3"))?;
+    assert!(swapped.is_err());
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A sled batch should apply all of its sets and removes, or none of them if one is rejected
+#[test]
+fn sled_apply_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder().max_value_size(16).build()?;
+    let db = SledKvsEngine::open_with_config(temp_dir.path(), config)?;
+    db.set("key0".to_owned(), "value0".to_owned())?;
+    let set = |key: &str, value: &str| WriteOp::Set {
+        key: key.as_bytes().to_vec(),
+        value: value.as_bytes().to_vec(),
+    };
+
+    let rejected = vec![
+        set("key1", "value1"),
+        WriteOp::Remove {
+            key: b"key0".to_vec(),
+        },
+        set("key2", &"x".repeat(17)),
+    ];
+    match db.apply_batch(rejected) {
+        Err(KvStoreError::ValueTooLarge { size: 17, max: 16 }) => {}
+        res => panic!("batch was not rejected: {:?}", res),
+    }
+    assert_eq!(db.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(db.get("key1".to_owned())?, None);
+
+    db.apply_batch(vec![
+        set("key1", "value1"),
+        set("key2", "value2"),
+        WriteOp::Remove {
+            key: b"key0".to_vec(),
+        },
+        set("key1", "value3"),
+        WriteOp::Remove {
+            key: b"missing".to_vec(),
+        },
+    ])?;
+    assert_eq!(db.get("key0".to_owned())?, None);
+    assert_eq!(db.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(db.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Files in the log directory that are not named like log files should be ignored, even if their
+// names are not valid UTF-8
+#[test]
+fn stray_log_files() -> Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let logs = temp_dir.path().join("logs");
+    std::fs::write(logs.join("notes.log"), "not a log")?;
+    std::fs::write(logs.join("99999999999999999999.log"), "not a log")?;
+    std::fs::write(
+        logs.join(std::ffi::OsStr::from_bytes(b"\xff\xfe.log")),
+        "not a log",
+    )?;
+    let stats = store.stats()?;
+    assert_eq!(stats.total_bytes, stats.live_bytes);
+    store.compact_now(None)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let error: KvStoreError = String::from_utf8(vec![0xff]).unwrap_err().into();
+    assert!(matches!(error, KvStoreError::Utf8Error { .. }));
+    Ok(())
+}
+
+// Log files that can't be told apart by their id should fail the open instead of being loaded
+#[test]
+fn duplicate_log_ids() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let logs = temp_dir.path().join("logs");
+    std::fs::copy(logs.join("0.log"), logs.join("00.log"))?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvStoreError::CorruptLog { reason }) => {
+            assert!(reason.contains("00.log") && reason.contains("both have log id 0"))
+        }
+        other => panic!("expected CorruptLog, got {:?}", other.map(|_| ())),
+    }
+
+    std::fs::rename(logs.join("0.log"), logs.join("+0.log"))?;
+    std::fs::remove_file(logs.join("00.log"))?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvStoreError::CorruptLog { reason }) => assert!(reason.contains("should be named")),
+        other => panic!("expected CorruptLog, got {:?}", other.map(|_| ())),
+    }
+
+    std::fs::rename(logs.join("+0.log"), logs.join("0.log"))?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// The Manual compaction policy should never trigger compaction
+#[test]
+fn manual_compaction_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config {
+        filesize_limit: 64,
+        compaction_policy: CompactionPolicy::Manual,
+        ..Config::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for i in 0..200 {
+        store.set(format!("key{}", i % 10), format!("value{}", i))?;
+    }
+
+    // Compacted logs are written with odd ids, rolled over logs with even ids
+    let mut ids = Vec::new();
+    for entry in WalkDir::new(temp_dir.path().join("logs")).min_depth(1) {
+        let entry = entry.expect("unable to read log directory");
+        let stem = entry.path().file_stem().unwrap().to_str().unwrap();
+        ids.push(
+            stem.parse::<u16>()
+                .expect("unexpected file in log directory"),
+        );
+    }
+    assert!(ids.len() > 10);
+    assert!(ids.iter().all(|id| id % 2 == 0));
+
+    for i in 190..200 {
+        assert_eq!(
+            store.get(format!("key{}", i % 10))?,
+            Some(format!("value{}", i))
+        );
+    }
+    Ok(())
+}
+
+// The ByFileCount policy should compact once the given number of log files has been filled
+#[test]
+fn file_count_compaction_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder()
+        .filesize_limit(64)
+        .compaction_policy(CompactionPolicy::ByFileCount(3))
+        .build()?;
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    // Each record fills a log file of its own
+    for i in 0..2 {
+        store.set(format!("key{}", i), "x".repeat(64))?;
+    }
+    store.set("key2".to_owned(), "x".repeat(64))?;
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(store.compactions(), 0);
+
+    // Polling stats would take the compaction lock and make the compaction skip
+    store.set("key3".to_owned(), "x".repeat(64))?;
+    let start = std::time::Instant::now();
+    while store.compactions() == 0 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(10));
+    }
+    for i in 0..4 {
+        assert_eq!(store.get(format!("key{}", i))?, Some("x".repeat(64)));
+    }
+
+    assert!(Config::builder()
+        .compaction_policy(CompactionPolicy::ByFileCount(0))
+        .build()
+        .is_err());
+    Ok(())
+}
+
+// Overwriting one key within a single log file should be reclaimed by the dead space scheduler
+#[test]
+fn dead_space_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder()
+        .filesize_limit(u64::MAX)
+        .compaction_policy(CompactionPolicy::BySize(0.5))
+        .compaction_interval(Duration::from_millis(50))
+        .build()?;
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    for i in 0..1000 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    let before = store.stats()?;
+    assert!(before.total_bytes > 0);
+    assert_eq!(before.compactions, 0);
+    assert_eq!(before.last_compaction, None);
+    let started = SystemTime::now();
+
+    let mut after = before;
+    for _ in 0..100 {
+        thread::sleep(Duration::from_millis(50));
+        after = store.stats()?;
+        if after.total_bytes < before.total_bytes {
+            break;
+        }
+    }
+    assert!(after.total_bytes < before.total_bytes);
+    assert!(after.dead_ratio() <= 0.5);
+    assert!(after.compactions >= 1);
+    assert!(after.last_reclaimed_bytes > 0);
+    assert!(after.last_compaction.is_some_and(|t| t >= started));
+    assert_eq!(store.get("key1".to_owned())?, Some("value999".to_owned()));
+
+    drop(store);
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value999".to_owned()));
+    Ok(())
+}
+
+// Messages collects the message of every record logged to it
+#[derive(Clone, Default)]
+struct Messages(Arc<Mutex<Vec<String>>>);
+
+impl slog::Drain for Messages {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(
+        &self,
+        record: &slog::Record,
+        _values: &slog::OwnedKVList,
+    ) -> std::result::Result<(), slog::Never> {
+        self.0.lock().unwrap().push(record.msg().to_string());
+        Ok(())
+    }
+}
+
+// Compaction triggered on every roll over should never run concurrently or fail. Triggers that
+// come in during a compaction are queued for one more compaction afterwards.
+#[test]
+fn overlapping_compaction_triggers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let messages = Messages::default();
+    let config = Config::builder()
+        .filesize_limit(64)
+        .compaction_policy(CompactionPolicy::ByFileCount(1))
+        .logger(slog::Logger::root(messages.clone(), slog::o!()))
+        .build()?;
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..100 {
+                    store
+                        .set(format!("key{}", t), format!("value{}", i))
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    // Wait for the last triggered compaction to finish
+    thread::sleep(Duration::from_millis(500));
+    store.stats()?;
+
+    for t in 0..4 {
+        assert_eq!(store.get(format!("key{}", t))?, Some("value99".to_owned()));
+    }
+    // Far fewer compactions run than the hundreds of roll overs that triggered one
+    assert!(store.compactions() >= 1);
+    assert!(store.compactions() < 400);
+    {
+        let messages = messages.0.lock().unwrap();
+        assert!(messages
+            .iter()
+            .all(|msg| msg == "compaction already in progress, queued"));
+    }
+    drop(store);
+
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for t in 0..4 {
+        assert_eq!(store.get(format!("key{}", t))?, Some("value99".to_owned()));
+    }
+    Ok(())
+}
+
+// A failing compaction should be logged while the store keeps serving reads and writes
+#[test]
+fn failed_compaction_is_logged() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let messages = Messages::default();
+    let config = Config::builder()
+        .filesize_limit(64)
+        .compaction_policy(CompactionPolicy::ByFileCount(1))
+        .logger(slog::Logger::root(messages.clone(), slog::o!()))
+        .build()?;
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    // The first compaction writes 3.log, which can't replace a directory
+    std::fs::create_dir_all(temp_dir.path().join("logs").join("3.log").join("blocked"))?;
+    for i in 0..100 {
+        store.set(format!("key{}", i % 10), format!("value{}", i))?;
+    }
+    // Wait for the last triggered compaction to finish
+    thread::sleep(Duration::from_millis(500));
+    store.stats()?;
+
+    assert!(messages
+        .0
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|msg| msg == "compaction failed"));
+    for i in 90..100 {
+        assert_eq!(
+            store.get(format!("key{}", i % 10))?,
+            Some(format!("value{}", i))
+        );
+    }
+    store.set("key1".to_owned(), "value100".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value100".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Dropping the store should wait for compaction, so reopening it right away sees every write and
+// no leftover tempfiles
+#[test]
+fn drop_waits_for_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder()
+        .filesize_limit(64)
+        .compaction_policy(CompactionPolicy::ByFileCount(1))
+        .build()?;
+    for round in 0..5 {
+        let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        for i in 0..100 {
+            store.set(format!("key{}", i % 10), format!("value{}-{}", round, i))?;
+        }
+        drop(store);
+
+        let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        for i in 90..100 {
+            assert_eq!(
+                store.get(format!("key{}", i % 10))?,
+                Some(format!("value{}-{}", round, i))
+            );
+        }
+        for entry in WalkDir::new(temp_dir.path().join("logs")).min_depth(1) {
+            let entry = entry.expect("unable to read log directory");
+            assert_eq!(entry.path().extension().unwrap(), "log");
+        }
+    }
+    Ok(())
+}
+
+// Tempfiles left in the log directory by an interrupted compaction should be removed on open,
+// while other files are kept
+#[test]
+fn open_removes_orphaned_tempfiles() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let logs = temp_dir.path().join("logs");
+    let orphan = logs.join(".kvs-compactAbC123");
+    let others = [logs.join(".tmpAbC123"), logs.join("partial.log")];
+    for path in others.iter().chain(Some(&orphan)) {
+        std::fs::write(path, b"{\"cmd\":\"Set\",\"key\":\"key")?;
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!orphan.exists());
+    for path in &others {
+        assert!(path.exists());
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Streamed values should round trip and survive reopening and compaction
+#[test]
+fn stream_large_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+    store.set_stream("big".to_owned(), &value[..], value.len() as u64)?;
+    store.set("small".to_owned(), "value".to_owned())?;
+
+    let mut read = Vec::new();
+    store
+        .get_stream("big".to_owned())?
+        .expect("streamed value not found")
+        .read_to_end(&mut read)?;
+    assert_eq!(read, value);
+    assert_eq!(store.get_bytes(b"big".to_vec())?, Some(value.clone()));
+    assert!(store.get_stream("missing".to_owned())?.is_none());
+
+    // Overwrite enough to trigger compaction of the streamed record
+    for iter in 0..100 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    thread::sleep(Duration::from_secs(1));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let mut read = Vec::new();
+    store
+        .get_stream("big".to_owned())?
+        .expect("streamed value not found")
+        .read_to_end(&mut read)?;
+    assert_eq!(read, value);
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key99".to_owned())?, Some("99".to_owned()));
+    Ok(())
+}
+
+// A reader that ends early should fail without leaving a broken record in the log
+#[test]
+fn stream_short_reader() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store
+        .set_stream("key2".to_owned(), &b"short"[..], 100)
+        .is_err());
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Records should be found where they were written when the writer rolls over to new log files
+// and when it appends to the last log file after the store is opened again
+#[test]
+fn write_offsets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = || Config {
+        filesize_limit: 64,
+        compaction_policy: CompactionPolicy::Manual,
+        ..Config::default()
+    };
+    let check = |store: &KvStore, n: usize| -> Result<()> {
+        for i in 0..n {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+        Ok(())
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config())?;
+    for i in 0..50 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    check(&store, 50)?;
+    drop(store);
+
+    let store = KvStore::open_with_config(temp_dir.path(), config())?;
+    for i in 50..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    check(&store, 100)?;
+    drop(store);
+
+    check(&KvStore::open_with_config(temp_dir.path(), config())?, 100)
+}
+
+// Log ids should keep counting past u16::MAX, so rotation and compaction still see the newest
+// log file as the last one
+#[test]
+fn log_ids_past_u16() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = || Config {
+        filesize_limit: 64,
+        compaction_policy: CompactionPolicy::Manual,
+        ..Config::default()
+    };
+    let log_ids = || -> Vec<u64> {
+        let mut ids: Vec<u64> = std::fs::read_dir(temp_dir.path().join("logs"))
+            .unwrap()
+            .filter_map(|entry| entry.unwrap().path().file_stem()?.to_str()?.parse().ok())
+            .collect();
+        ids.sort_unstable();
+        ids
+    };
+    std::fs::create_dir(temp_dir.path().join("logs"))?;
+    std::fs::File::create(
+        temp_dir
+            .path()
+            .join("logs")
+            .join(format!("{}.log", u16::MAX - 1)),
+    )?;
+
+    let store = KvStore::open_with_config(temp_dir.path(), config())?;
+    for i in 0..20 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert!(log_ids().last().is_some_and(|&id| id > u16::MAX as u64 + 1));
+
+    // Overwrites in the newest log file must win over the older values in the lower ids
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("new{}", i))?;
+    }
+    store.compact_now(None)?;
+    assert!(log_ids().iter().all(|&id| id > u16::MAX as u64));
+    drop(store);
+
+    let store = KvStore::open_with_config(temp_dir.path(), config())?;
+    for i in 0..20 {
+        let value = if i < 10 { "new" } else { "value" };
+        assert_eq!(
+            store.get(format!("key{}", i))?,
+            Some(format!("{}{}", value, i))
+        );
+    }
+    Ok(())
+}
+
+// Running out of log ids should fail the write that needs a new log file instead of overflowing,
+// and keep the values written before it
+#[test]
+fn log_ids_exhausted() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::create_dir(temp_dir.path().join("logs"))?;
+    std::fs::File::create(
+        temp_dir
+            .path()
+            .join("logs")
+            .join(format!("{}.log", u64::MAX - 1)),
+    )?;
+    let config = Config {
+        filesize_limit: 64,
+        compaction_policy: CompactionPolicy::Manual,
+        ..Config::default()
+    };
+
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    let mut written = 0;
+    let err = loop {
+        match store.set(format!("key{}", written), format!("value{}", written)) {
+            Ok(()) => written += 1,
+            Err(e) => break e,
+        }
+        assert!(written < 100, "log file never filled up");
+    };
+    match err {
+        KvStoreError::LogIdsExhausted { id } => assert_eq!(id, u64::MAX - 1),
+        e => panic!("expected LogIdsExhausted, got {}", e),
+    }
+    assert!(written > 0);
+    for i in 0..written {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// MemoryKvsEngine should behave like the other engines
+#[test]
+fn memory_engine() -> Result<()> {
+    let engine = MemoryKvsEngine::new();
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+
+    // Clones share the same data
+    let clone = engine.clone();
+    clone.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(engine.remove("key1".to_owned()).is_err());
+
+    engine.set_bytes(b"key3".to_vec(), vec![0, 159, 146, 150])?;
+    assert_eq!(
+        engine.get_bytes(b"key3".to_vec())?,
+        Some(vec![0, 159, 146, 150])
+    );
+    assert!(engine.get("key3".to_owned()).is_err());
+    Ok(())
+}
+
+// MemoryKvsEngine should use the merge operator from its config
+#[test]
+fn memory_engine_merge() -> Result<()> {
+    assert!(MemoryKvsEngine::new()
+        .merge("key1".to_owned(), "a".to_owned())
+        .is_err());
+
+    let config = Config {
+        merge_operator: Some(Arc::new(
+            |_key: &str, existing: Option<&str>, operand: &str| {
+                existing.unwrap_or_default().to_owned() + operand
+            },
+        )),
+        ..Config::default()
+    };
+    let engine = MemoryKvsEngine::with_config(config);
+    engine.merge("key1".to_owned(), "a".to_owned())?;
+    engine.merge("key1".to_owned(), "b".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("ab".to_owned()));
+    Ok(())
+}
+
+// RocksKvsEngine should behave like the other engines, including its merge operator
+#[cfg(feature = "rocksdb")]
+#[test]
+fn rocksdb_engine() -> Result<()> {
+    use kvs::RocksKvsEngine;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = RocksKvsEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    engine.remove("key1".to_owned())?;
+    match engine.remove("key1".to_owned()) {
+        Err(KvStoreError::KeyNotFoundError {}) => {}
+        res => panic!("expected KeyNotFoundError, got {:?}", res),
+    }
+    assert!(engine.merge("key1".to_owned(), "a".to_owned()).is_err());
+    check_scan(&engine)?;
+    drop(engine);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config {
+        merge_operator: Some(Arc::new(
+            |_key: &str, existing: Option<&str>, operand: &str| {
+                existing.unwrap_or_default().to_owned() + operand
+            },
+        )),
+        ..Config::default()
+    };
+    let engine = RocksKvsEngine::open_with_config(temp_dir.path(), config)?;
+    engine.merge("key1".to_owned(), "a".to_owned())?;
+    engine.merge("key1".to_owned(), "b".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("ab".to_owned()));
+    Ok(())
+}
+
+// Scans should return pairs in key order within the range
+fn check_scan<E: KvsEngine>(engine: &E) -> Result<()> {
+    for key in ["d", "a", "c", "e", "b"].iter() {
+        engine.set(key.to_string(), format!("value-{}", key))?;
+    }
+    engine.remove("c".to_owned())?;
+
+    let keys = |pairs: Vec<(String, String)>| -> Vec<String> {
+        pairs.into_iter().map(|(key, _)| key).collect()
+    };
+    assert_eq!(
+        keys(engine.scan("b".to_owned(), "e".to_owned(), 10)?),
+        ["b", "d"]
+    );
+    assert_eq!(
+        keys(engine.scan("".to_owned(), "".to_owned(), 10)?),
+        ["a", "b", "d", "e"]
+    );
+    assert_eq!(
+        keys(engine.scan("a".to_owned(), "".to_owned(), 2)?),
+        ["a", "b"]
+    );
+    assert!(engine.scan("e".to_owned(), "a".to_owned(), 10)?.is_empty());
+    assert_eq!(
+        engine.scan("e".to_owned(), "".to_owned(), 10)?,
+        vec![("e".to_owned(), "value-e".to_owned())]
+    );
+    Ok(())
+}
+
+#[test]
+fn scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_scan(&store)?;
+    drop(store);
+
+    // Order should survive reopening
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan("".to_owned(), "".to_owned(), 10)?.len(), 4);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scan(&SledKvsEngine::open(temp_dir.path())?)?;
+    check_scan(&MemoryKvsEngine::new())
+}
+
+fn check_scan_page<E: KvsEngine>(engine: &E) -> Result<()> {
+    // "a\0" sorts right after "a", where the next page after "a" starts
+    let keys = ["", "a", "a\0", "ab", "b", "c", "d"];
+    for key in keys.iter().rev() {
+        engine.set(key.to_string(), format!("value-{}", key))?;
+    }
+
+    let mut after = None;
+    let mut pages = Vec::new();
+    loop {
+        let page = engine.scan_page(after.clone(), 3)?;
+        if page.is_empty() {
+            break;
+        }
+        after = page.last().map(|(key, _)| key.clone());
+        pages.push(page.into_iter().map(|(key, _)| key).collect::<Vec<_>>());
+    }
+    assert_eq!(pages, [&keys[..3], &keys[3..6], &keys[6..]]);
+    assert_eq!(
+        engine.scan_page(Some("b".to_owned()), 10)?,
+        [
+            ("c".to_owned(), "value-c".to_owned()),
+            ("d".to_owned(), "value-d".to_owned())
+        ]
+    );
+    Ok(())
+}
+
+// scan_page should page through every key once, resuming after the last key of each page
+#[test]
+fn scan_page() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scan_page(&KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scan_page(&SledKvsEngine::open(temp_dir.path())?)?;
+    check_scan_page(&MemoryKvsEngine::new())
+}
+
+fn check_get_set<E: KvsEngine>(engine: &E) -> Result<()> {
+    assert_eq!(
+        engine.get_set("key1".to_owned(), "value1".to_owned())?,
+        None
+    );
+    assert_eq!(
+        engine.get_set("key1".to_owned(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    engine.remove("key1".to_owned())?;
+    assert_eq!(
+        engine.get_set("key1".to_owned(), "value3".to_owned())?,
+        None
+    );
+    Ok(())
+}
+
+// get_set should return the value it replaced, None for new keys
+#[test]
+fn get_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_get_set(&store)?;
+    check_get_set(&store.namespace(1))?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_get_set(&SledKvsEngine::open(temp_dir.path())?)?;
+    check_get_set(&MemoryKvsEngine::new())
+}
+
+fn check_set_nx<E: KvsEngine>(engine: &E) -> Result<()> {
+    assert!(engine.set_nx("key1".to_owned(), "value1".to_owned())?);
+    assert!(!engine.set_nx("key1".to_owned(), "value2".to_owned())?);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    engine.remove("key1".to_owned())?;
+    assert!(engine.set_nx("key1".to_owned(), "value3".to_owned())?);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// set_nx should only write keys that don't exist, leaving existing values alone
+#[test]
+fn set_nx() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_set_nx(&store)?;
+    check_set_nx(&store.namespace(1))?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    // Only one of many threads racing for the same key gets it
+    let barrier = Arc::new(Barrier::new(8));
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            let store = store.clone();
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                store.set_nx("lock".to_owned(), format!("owner{}", i))
+            })
+        })
+        .collect();
+    let mut winners = 0;
+    for handle in handles {
+        if handle.join().unwrap()? {
+            winners += 1;
+        }
+    }
+    assert_eq!(winners, 1);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_set_nx(&SledKvsEngine::open(temp_dir.path())?)?;
+    check_set_nx(&MemoryKvsEngine::new())
+}
+
+fn check_append<E: KvsEngine>(engine: &E) -> Result<()> {
+    assert_eq!(engine.append("list".to_owned(), "a".to_owned())?, 1);
+    assert_eq!(engine.append("list".to_owned(), ",b".to_owned())?, 3);
+    assert_eq!(engine.get("list".to_owned())?, Some("a,b".to_owned()));
+    Ok(())
+}
+
+// append should add to the end of a value without losing appends made at the same time
+#[test]
+fn append() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_append(&store)?;
+    check_append(&store.namespace(1))?;
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    store.append("counter".to_owned(), "x".to_owned())?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("counter".to_owned())?, Some("x".repeat(200)));
+    assert_eq!(store.get("list".to_owned())?, Some("a,b".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_append(&SledKvsEngine::open(temp_dir.path())?)?;
+    check_append(&MemoryKvsEngine::new())
+}
+
+// iter should visit every key once, in key order, and report keys removed while iterating
+#[test]
+fn iter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..2000 {
+        store.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+
+    let mut visited = 0;
+    for (i, pair) in store.iter().enumerate() {
+        assert_eq!(pair?, (format!("key{:04}", i), format!("value{}", i)));
+        visited += 1;
+    }
+    assert_eq!(visited, 2000);
+
+    let mut iter = store.iter();
+    store.remove("key0000".to_owned())?;
+    match iter.next() {
+        Some(Err(KvStoreError::KeyNotFoundError {})) => {}
+        res => panic!("expected KeyNotFoundError, got {:?}", res),
+    }
+    assert_eq!(
+        iter.next().transpose()?,
+        Some(("key0001".to_owned(), "value1".to_owned()))
+    );
+    assert_eq!(iter.count(), 1998);
+    Ok(())
+}
+
+// Subscribers should get the changes of matching keys in order, and drop events once behind
+#[test]
+fn subscribe() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let users = store.subscribe("user:".to_owned());
+    let all = store.subscribe("".to_owned());
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    store.set("group:1".to_owned(), "admins".to_owned())?;
+    store.remove("user:1".to_owned())?;
+    assert!(store.remove("user:2".to_owned()).is_err());
+
+    let set = |key: &str| KeyEvent::Set {
+        key: key.to_owned(),
+    };
+    let remove = |key: &str| KeyEvent::Remove {
+        key: key.to_owned(),
+    };
+    assert_eq!(
+        users.try_iter().collect::<Vec<_>>(),
+        vec![set("user:1"), remove("user:1")]
+    );
+    assert_eq!(
+        all.try_iter().collect::<Vec<_>>(),
+        vec![set("user:1"), set("group:1"), remove("user:1")]
+    );
+
+    // Events beyond the buffer are dropped instead of blocking writes
+    drop(all);
+    for i in 0..2000 {
+        store.set(format!("user:{}", i), "value".to_owned())?;
+    }
+    assert_eq!(users.try_iter().count(), 1024);
+
+    // Engines without change events report that subscribing is not supported
+    match KvsEngine::subscribe(&MemoryKvsEngine::new(), "".to_owned()) {
+        Err(KvStoreError::UnsupportedError { operation }) => assert_eq!(operation, "subscribe"),
+        res => panic!("expected UnsupportedError, got {:?}", res.map(|_| ())),
+    }
+    Ok(())
+}
+
+// metadata should track the last write of a key across reopening and old log records
+#[test]
+fn metadata() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let first = store.metadata("key1".to_owned())?.unwrap();
+    assert_eq!(first.value_len, 6);
+    thread::sleep(Duration::from_millis(10));
+    store.set("key1".to_owned(), "value10".to_owned())?;
+    let second = store.metadata("key1".to_owned())?.unwrap();
+    assert_eq!(second.value_len, 7);
+    assert!(second.last_modified > first.last_modified);
+    assert!(store.metadata("key2".to_owned())?.is_none());
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.metadata("key1".to_owned())?, Some(second));
+    drop(store);
+
+    // Records written before times were recorded are dated by their log file
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::create_dir(temp_dir.path().join("logs"))?;
+    std::fs::write(
+        temp_dir.path().join("logs").join("0.log"),
+        r#"{"cmd":"Set","key":"key1","value":"value1"}"#,
+    )?;
+    let store = KvStore::open(temp_dir.path())?;
+    let metadata = store.metadata("key1".to_owned())?.unwrap();
+    assert_eq!(metadata.value_len, 6);
+    assert_eq!(
+        metadata.last_modified,
+        std::fs::metadata(temp_dir.path().join("logs").join("0.log"))?.modified()?
+    );
+    Ok(())
+}
+
+// Transactions should apply all of their writes or none of them
+#[test]
+fn transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let value = store.transaction(|tx| {
+        let value = tx.get("key1".to_owned())?;
+        tx.set("key2".to_owned(), "value2".to_owned())?;
+        tx.remove("key1".to_owned())?;
+        assert_eq!(tx.get("key1".to_owned())?, None);
+        assert_eq!(tx.get("key2".to_owned())?, Some("value2".to_owned()));
+        Ok(value)
+    })?;
+    assert_eq!(value, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // An error in the closure rolls the writes back
+    let res: Result<()> = store.transaction(|tx| {
+        tx.set("key3".to_owned(), "value3".to_owned())?;
+        tx.remove("key1".to_owned())
+    });
+    assert!(matches!(res, Err(KvStoreError::KeyNotFoundError {})));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    // A key read by the transaction changing before commit is a conflict
+    let res = store.transaction(|tx| {
+        tx.get("key2".to_owned())?;
+        tx.get("key4".to_owned())?;
+        tx.set("key3".to_owned(), "value3".to_owned())?;
+        store.set("key4".to_owned(), "value4".to_owned())
+    });
+    assert!(matches!(res, Err(KvStoreError::TransactionConflict {})));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    // Committed writes survive reopening
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Concurrent read-modify-write transactions retried on conflict should not lose updates
+#[test]
+fn transaction_retry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    loop {
+                        let res = store.transaction(|tx| {
+                            let counter: u64 = tx.get("counter".to_owned())?.unwrap().parse()?;
+                            tx.set("counter".to_owned(), (counter + 1).to_string())
+                        });
+                        match res {
+                            Err(KvStoreError::TransactionConflict {}) => continue,
+                            res => break res?,
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("200".to_owned()));
+    Ok(())
+}
+
+// Opening in recovery mode should skip the unreadable rest of a log file and load the others
+#[test]
+fn recover() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = || {
+        Config::builder()
+            .filesize_limit(100)
+            .compaction_policy(CompactionPolicy::Manual)
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config().build()?)?;
+    for i in 0..30 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let corrupt = temp_dir.path().join("logs").join("4.log");
+    assert!(corrupt.exists());
+    std::fs::write(
+        &corrupt,
+        r#"{"cmd":"Set","key":"salvaged","value":"value"}{"cmd":"Se"#,
+    )?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    let store = KvStore::open_with_config(temp_dir.path(), config().recover(true).build()?)?;
+    assert_eq!(store.skipped_files(), [corrupt]);
+    assert_eq!(store.get("salvaged".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key29".to_owned())?, Some("value29".to_owned()));
+    drop(store);
+
+    // An incomplete record at the end of the last log file, as left by a crash during a write, is
+    // cut off on open even without recovery mode
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let last = temp_dir.path().join("logs").join("0.log");
+    let len = std::fs::metadata(&last)?.len();
+    let mut contents = std::fs::read(&last)?;
+    contents.extend_from_slice(br#"{"cmd":"Se"#);
+    std::fs::write(&last, contents)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.skipped_files().is_empty());
+    assert_eq!(std::fs::metadata(&last)?.len(), len);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Namespaces should keep their keys apart through scans, compaction and reopening
+#[test]
+fn namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = || {
+        Config::builder()
+            .filesize_limit(200)
+            .compaction_policy(CompactionPolicy::ByFileCount(2))
+            .build()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config()?)?;
+    let (first, last) = (store.namespace(1), store.namespace(u32::MAX));
+    for i in 0..20 {
+        store.set(format!("key{:02}", i), format!("default{}", i))?;
+        first.set(format!("key{:02}", i), format!("first{}", i))?;
+        last.set(format!("key{:02}", i), format!("last{}", i))?;
+    }
+    first.remove("key00".to_owned())?;
+    assert!(last.remove("key20".to_owned()).is_err());
+    check_scan(&store.namespace(2))?;
+
+    assert_eq!(store.len()?, 20);
+    assert_eq!(first.len()?, 19);
+    assert_eq!(store.namespace(2).len()?, 4);
+    assert_eq!(store.get("key00".to_owned())?, Some("default0".to_owned()));
+    assert_eq!(first.get("key00".to_owned())?, None);
+    assert_eq!(last.get("key19".to_owned())?, Some("last19".to_owned()));
+    assert_eq!(last.scan("key18".to_owned(), "".to_owned(), 10)?.len(), 2);
+    assert_eq!(store.iter().count(), 20);
+
+    // Clearing a namespace leaves the others alone
+    store.clear_namespace(1)?;
+    assert!(first.is_empty()?);
+    assert_eq!(store.len()?, 20);
+    assert_eq!(last.len()?, 20);
+    drop((store, first, last));
+
+    let store = KvStore::open_with_config(temp_dir.path(), config()?)?;
+    assert_eq!(store.len()?, 20);
+    assert!(store.namespace(1).is_empty()?);
+    assert_eq!(
+        store.namespace(u32::MAX).get("key05".to_owned())?,
+        Some("last5".to_owned())
+    );
+    assert_eq!(
+        store
+            .namespace(2)
+            .scan("".to_owned(), "".to_owned(), 10)?
+            .len(),
+        4
+    );
+    Ok(())
+}
+
+// A compacted log file that doesn't read back as written should fail the compaction and keep the
+// old log files
+#[test]
+fn compaction_verifies_output() -> Result<()> {
+    use std::io::{Seek, SeekFrom, Write};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder()
+        .filesize_limit(1500)
+        .compaction_policy(CompactionPolicy::Manual)
+        .build()?;
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    let value = "x".repeat(1000);
+    for i in 0..20 {
+        store.set(format!("key{}", i), value.clone())?;
+    }
+    let log_dir = temp_dir.path().join("logs");
+    let files = || -> Vec<std::path::PathBuf> {
+        let mut files: Vec<_> = std::fs::read_dir(&log_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        files.sort();
+        files
+    };
+    let logs = files();
+
+    // Once the last file has been copied, part of the new file is on disk and gets a byte flipped
+    let mut corrupted = false;
+    let res = store.compact_now(Some(&mut |progress| {
+        if progress.files_done < progress.files_total {
+            return;
+        }
+        let temp = files()
+            .into_iter()
+            .find(|path| path.extension().is_none_or(|ext| ext != "log"))
+            .expect("no compaction tempfile");
+        let bytes = std::fs::read(&temp).unwrap();
+        let pos = bytes
+            .windows(8)
+            .position(|w| w == b"xxxxxxxx")
+            .expect("no value on disk yet");
+        let mut f = std::fs::OpenOptions::new().write(true).open(&temp).unwrap();
+        f.seek(SeekFrom::Start(pos as u64)).unwrap();
+        f.write_all(b"y").unwrap();
+        corrupted = true;
+    }));
+    assert!(corrupted);
+    match res {
+        Err(KvStoreError::CompactionMismatch { .. }) => {}
+        res => panic!("expected CompactionMismatch, got {:?}", res),
+    }
+
+    // Compacting rolls over to a new log file first, which stays along with the old ones
+    assert!(logs.iter().all(|path| path.exists()));
+    assert!(files()
+        .iter()
+        .all(|path| path.extension().is_some_and(|ext| ext == "log")));
+    for i in 0..20 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value.clone()));
+    }
+    store.compact_now(None)?;
+    for i in 0..20 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value.clone()));
+    }
+    Ok(())
+}
+
+// A compacted log file cut short, as by a write that failed with the disk full, should fail the
+// compaction and keep the old log files
+#[test]
+fn compaction_detects_short_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder()
+        .filesize_limit(1500)
+        .compaction_policy(CompactionPolicy::Manual)
+        .build()?;
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    let value = "x".repeat(1000);
+    for i in 0..20 {
+        store.set(format!("key{}", i), value.clone())?;
+    }
+    let log_dir = temp_dir.path().join("logs");
+    let logs: Vec<_> = std::fs::read_dir(&log_dir)?
+        .map(|entry| entry.unwrap().path())
+        .collect();
+
+    let mut truncated = false;
+    let res = store.compact_now(Some(&mut |progress| {
+        if progress.files_done < progress.files_total {
+            return;
+        }
+        let temp = std::fs::read_dir(&log_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().is_none_or(|ext| ext != "log"))
+            .expect("no compaction tempfile");
+        let f = std::fs::OpenOptions::new().write(true).open(&temp).unwrap();
+        f.set_len(f.metadata().unwrap().len() / 2).unwrap();
+        truncated = true;
+    }));
+    assert!(truncated);
+    match res {
+        Err(KvStoreError::CompactionMismatch { .. }) => {}
+        res => panic!("expected CompactionMismatch, got {:?}", res),
+    }
+    assert!(logs.iter().all(|path| path.exists()));
+    for i in 0..20 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value.clone()));
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..20 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value.clone()));
+    }
+    Ok(())
+}
+
+// Stores with buffers far smaller or larger than a record should read and write the same data
+#[test]
+fn buffer_sizes() -> Result<()> {
+    for size in [1, 7, 1 << 20] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = Config::builder()
+            .filesize_limit(300)
+            .compaction_policy(CompactionPolicy::Manual)
+            .write_buffer_size(size)
+            .read_buffer_size(size)
+            .build()?;
+        let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        for i in 0..50 {
+            store.set(format!("key{}", i % 20), format!("value{}", i))?;
+        }
+        let streamed = "s".repeat(1000);
+        store.set_stream("streamed".to_owned(), streamed.as_bytes(), 1000)?;
+        store.compact_now(None)?;
+        drop(store);
+
+        let store = KvStore::open_with_config(temp_dir.path(), config)?;
+        for i in 30..50 {
+            assert_eq!(
+                store.get(format!("key{}", i % 20))?,
+                Some(format!("value{}", i))
+            );
+        }
+        let mut value = String::new();
+        store
+            .get_stream("streamed".to_owned())?
+            .expect("streamed value is gone")
+            .read_to_string(&mut value)?;
+        assert_eq!(value, streamed);
+    }
+    assert!(Config::builder().read_buffer_size(0).build().is_err());
+    assert!(Config::builder().write_buffer_size(0).build().is_err());
+    Ok(())
+}
+
+// A write that fails partway should leave the log as it was, without half a record at its end
+#[test]
+fn failed_write_leaves_no_partial_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder()
+        .filesize_limit(100)
+        .compaction_policy(CompactionPolicy::Manual)
+        .build()?;
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let last = temp_dir.path().join("logs").join("0.log");
+    let len = std::fs::metadata(&last)?.len();
+
+    // The reader fails after more bytes than the write buffer holds, so some reach the file
+    let failing = std::io::repeat(b'v').take(20_000).chain(FailingReader);
+    assert!(store
+        .set_stream("key2".to_owned(), failing, 40_000)
+        .is_err());
+    assert_eq!(std::fs::metadata(&last)?.len(), len);
+
+    // Every write to a log file on a full disk fails
+    #[cfg(target_os = "linux")]
+    {
+        store.set("key3".to_owned(), "v".repeat(200))?;
+        let full = temp_dir.path().join("logs").join("4.log");
+        std::os::unix::fs::symlink("/dev/full", &full)?;
+        assert!(store.set("key4".to_owned(), "value4".to_owned()).is_err());
+        assert_eq!(store.get("key4".to_owned())?, None);
+        assert_eq!(store.get("key3".to_owned())?, Some("v".repeat(200)));
+        drop(store);
+        std::fs::remove_file(&full)?;
+    }
+    #[cfg(not(target_os = "linux"))]
+    drop(store);
+
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key5".to_owned(), "value5".to_owned())?;
+    assert_eq!(store.get("key5".to_owned())?, Some("value5".to_owned()));
+    Ok(())
+}
+
+// FailingReader fails every read
+struct FailingReader;
+
+impl Read for FailingReader {
+    fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+        Err(std::io::Error::other("injected failure"))
+    }
+}
+
+// get_version should walk back through the earlier values of a key, which compaction and
+// reopening keep up to the history depth
+#[test]
+fn get_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder()
+        .filesize_limit(100)
+        .compaction_policy(CompactionPolicy::Manual)
+        .history_depth(3)
+        .merge_operator(Arc::new(|_key, existing, operand| {
+            format!("{}{}", existing.unwrap_or(""), operand)
+        }))
+        .build()?;
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    for i in 0..5 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+        store.set(format!("other{}", i), "value".to_owned())?;
+    }
+    store.merge("key1".to_owned(), "+".to_owned())?;
+    store.set("key2".to_owned(), "value".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key2".to_owned(), "new".to_owned())?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        let versions: Vec<_> = (0..5)
+            .map(|n| store.get_version("key1".to_owned(), n))
+            .collect::<Result<_>>()?;
+        assert_eq!(
+            versions,
+            vec![
+                Some("value4+".to_owned()),
+                Some("value3".to_owned()),
+                Some("value2".to_owned()),
+                Some("value1".to_owned()),
+                None,
+            ]
+        );
+        // Removing a key drops its history
+        assert_eq!(
+            store.get_version("key2".to_owned(), 0)?,
+            Some("new".to_owned())
+        );
+        assert_eq!(store.get_version("key2".to_owned(), 1)?, None);
+        assert_eq!(store.get_version("key3".to_owned(), 0)?, None);
+        Ok(())
+    };
+    check(&store)?;
+    store.compact_now(None)?;
+    assert_eq!(store.stats()?.dead_bytes(), 0);
+    check(&store)?;
+    store.set("key1".to_owned(), "value5".to_owned())?;
+    assert_eq!(
+        store.get_version("key1".to_owned(), 1)?,
+        Some("value4+".to_owned())
+    );
+    assert_eq!(
+        store.get_version("key1".to_owned(), 3)?,
+        Some("value2".to_owned())
+    );
+    drop(store);
+
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(
+        store.get_version("key1".to_owned(), 1)?,
+        Some("value4+".to_owned())
+    );
+    assert_eq!(
+        store.get_version("key1".to_owned(), 3)?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(store.get_version("key1".to_owned(), 4)?, None);
+    drop(store);
+
+    // Without a history depth only the latest value is kept
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_version("key1".to_owned(), 0)?,
+        Some("value5".to_owned())
+    );
+    assert_eq!(store.get_version("key1".to_owned(), 1)?, None);
+    Ok(())
+}
+
+// Parallel compaction should keep the same data as serial compaction, including merged and
+// streamed values, and report every file once
+#[test]
+fn parallel_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder()
+        .filesize_limit(200)
+        .compaction_policy(CompactionPolicy::Manual)
+        .parallel_compaction(true)
+        .merge_operator(Arc::new(|_key, existing, operand| {
+            format!("{}{}", existing.unwrap_or(""), operand)
+        }))
+        .build()?;
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i % 25), format!("value{}", i))?;
+    }
+    for i in 0..25 {
+        store.merge(format!("key{}", i), "+".to_owned())?;
+    }
+    for i in 20..25 {
+        store.remove(format!("key{}", i))?;
+    }
+    let streamed = "s".repeat(500);
+    store.set_stream("streamed".to_owned(), &mut streamed.as_bytes(), 500)?;
+    let expected: Vec<_> = store.scan("".to_owned(), "".to_owned(), 100)?;
+    assert_eq!(expected.len(), 21);
+
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build()?;
+    let mut reports = Vec::new();
+    pool.install(|| store.compact_now(Some(&mut |progress| reports.push(progress))))?;
+    for (i, progress) in reports.iter().enumerate() {
+        assert_eq!(progress.files_done, i + 1);
+    }
+    assert_eq!(store.stats()?.dead_bytes(), 0);
+    assert_eq!(store.scan("".to_owned(), "".to_owned(), 100)?, expected);
+    drop(store);
+
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.scan("".to_owned(), "".to_owned(), 100)?, expected);
+    assert_eq!(store.get("key0".to_owned())?, Some("value75+".to_owned()));
+    let mut value = String::new();
+    store
+        .get_stream("streamed".to_owned())?
+        .expect("streamed value is gone")
+        .read_to_string(&mut value)?;
+    assert_eq!(value, streamed);
+    Ok(())
+}
+
+// compact_now should report progress after every log file, ending with all of them done
+#[test]
+fn compaction_progress() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder()
+        .filesize_limit(100)
+        .compaction_policy(CompactionPolicy::Manual)
+        .build()?;
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for i in 0..30 {
+        store.set(format!("key{}", i % 10), format!("value{}", i))?;
+    }
+    let mut reports = Vec::new();
+    store.compact_now(Some(&mut |progress| reports.push(progress)))?;
+
+    assert!(reports.len() > 1);
+    let files_total = reports[0].files_total;
+    for (i, progress) in reports.iter().enumerate() {
+        assert_eq!(progress.files_done, i + 1);
+        assert_eq!(progress.files_total, files_total);
+    }
+    for pair in reports.windows(2) {
+        assert!(pair[1].bytes_written >= pair[0].bytes_written);
+    }
+    let last = reports.last().unwrap();
+    assert_eq!(last.files_done, last.files_total);
+    assert!(last.bytes_written > 0);
+
+    assert_eq!(store.stats()?.dead_bytes(), 0);
+    store.compact_now(None)?;
+    for i in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", i))?,
+            Some(format!("value{}", i + 20))
+        );
+    }
+    Ok(())
+}
+
+// A value larger than filesize_limit should get a log file of its own without breaking rotation
+#[test]
+fn oversized_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = || {
+        Config::builder()
+            .filesize_limit(100)
+            .compaction_policy(CompactionPolicy::Manual)
+            .build()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config()?)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let large = "x".repeat(10_000);
+    store.set("large".to_owned(), large.clone())?;
+    assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+    for i in 2..20 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    // The log file holding the large value should hold nothing else
+    let mut holding = Vec::new();
+    for entry in std::fs::read_dir(temp_dir.path().join("logs"))? {
+        let contents = std::fs::read_to_string(entry?.path())?;
+        if contents.contains(&large) {
+            holding.push(contents);
+        }
+    }
+    assert_eq!(holding.len(), 1);
+    assert_eq!(holding[0].matches("\"cmd\"").count(), 1);
+    drop(store);
+
+    let store = KvStore::open_with_config(temp_dir.path(), config()?)?;
+    assert_eq!(store.get("large".to_owned())?, Some(large));
+    for i in 1..20 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// Writes flushed with flush should all be there after reopening
+#[test]
+fn flush() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.flush()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len()?, 100);
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    // Engines without buffered writes accept flush too
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.flush()?;
+    MemoryKvsEngine::new().flush()?;
+    Ok(())
+}
+
+// Writes from every clone should be there after the last clone is dropped and the store reopened,
+// and dropping the other clones should leave the store open
+#[test]
+fn drop_flushes_last_clone() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..25 {
+                    store.set(format!("key{}-{}", t, i), format!("value{}-{}", t, i))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    store.set("last".to_owned(), "value".to_owned())?;
+    assert_eq!(store.len()?, 101);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len()?, 101);
+    for t in 0..4 {
+        for i in 0..25 {
+            assert_eq!(
+                store.get(format!("key{}-{}", t, i))?,
+                Some(format!("value{}-{}", t, i))
+            );
+        }
+    }
+    assert_eq!(store.get("last".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Values changed through any write should never be served stale from the cache
+// Keys are found whether or not they were added after the Bloom filter was built
+#[test]
+fn bloom_filter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder()
+        .bloom_false_positive_rate(0.01)
+        .filesize_limit(u64::MAX)
+        .compaction_policy(CompactionPolicy::BySize(0.5))
+        .compaction_interval(Duration::from_millis(50))
+        .build()?;
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.get_set("swapped".to_owned(), "new".to_owned())?;
+    store.transaction(|tx| tx.set("tx".to_owned(), "value".to_owned()))?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        assert_eq!(store.get(format!("missing{}", i))?, None);
+    }
+    assert_eq!(store.get("swapped".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("tx".to_owned())?, Some("value".to_owned()));
+    for i in 0..50 {
+        store.remove(format!("key{}", i))?;
+    }
+    assert_eq!(store.get("key0".to_owned())?, None);
+    drop(store);
+
+    // The filter is rebuilt on open and after compactions
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for iter in 0..20 {
+        for i in 50..100 {
+            store.set(format!("key{}", i), format!("{}", iter))?;
+        }
+    }
+    let before = store.stats()?;
+    let mut after = before;
+    for _ in 0..100 {
+        thread::sleep(Duration::from_millis(50));
+        after = store.stats()?;
+        if after.total_bytes < before.total_bytes {
+            break;
+        }
+    }
+    assert!(after.total_bytes < before.total_bytes);
+    store.set("late".to_owned(), "value".to_owned())?;
+    for i in 0..50 {
+        assert_eq!(store.get(format!("key{}", i))?, None);
+    }
+    for i in 50..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some("19".to_owned()));
+    }
+    assert_eq!(store.get("late".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("swapped".to_owned())?, Some("new".to_owned()));
+
+    assert!(Config::builder()
+        .bloom_false_positive_rate(1.0)
+        .build()
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::builder()
+        .cache_capacity(2)
+        .merge_operator(Arc::new(|_key, existing, operand| {
+            format!("{}{}", existing.unwrap_or(""), operand)
+        }))
+        .build()?;
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.merge("key1".to_owned(), "+".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2+".to_owned()));
+    store.transaction(|tx| tx.set("key1".to_owned(), "value3".to_owned()))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // Evicted values are read from the logs again, and namespaces are cached apart
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        store
+            .namespace(1)
+            .set(format!("key{}", i), format!("other{}", i))?;
+    }
+    for _ in 0..2 {
+        for i in 0..10 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+            assert_eq!(
+                store.namespace(1).get(format!("key{}", i))?,
+                Some(format!("other{}", i))
+            );
+        }
+    }
+    store.clear_namespace(1)?;
+    assert_eq!(store.namespace(1).get("key9".to_owned())?, None);
+
+    // Readers racing a writer should never see a value older than one they already saw
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for i in 0..500 {
+                store.set("counter".to_owned(), format!("{:04}", i))?;
+            }
+            Ok(())
+        })
+    };
+    let readers: Vec<_> = (0..2)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                let mut last = String::new();
+                while last != "0499" {
+                    if let Some(value) = store.get("counter".to_owned())? {
+                        assert!(value >= last);
+                        last = value;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    writer.join().unwrap()?;
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("0499".to_owned()));
+    Ok(())
+}
+
+// open_dir should use the given directory for log files as is
+#[test]
+fn open_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let log_dir = temp_dir.path().join("logs");
+    let store = KvStore::open_dir(&log_dir)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    assert!(!log_dir.join("logs").exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+fn check_size_limits<E: KvsEngine>(engine: &E) -> Result<()> {
+    engine.set("k".repeat(8), "v".repeat(16))?;
+    assert_eq!(engine.get("k".repeat(8))?, Some("v".repeat(16)));
+    match engine.set("k".repeat(9), "value1".to_owned()) {
+        Err(KvStoreError::KeyTooLarge { size: 9, max: 8 }) => {}
+        res => panic!("expected KeyTooLarge, got {:?}", res),
+    }
+    match engine.set("key1".to_owned(), "v".repeat(17)) {
+        Err(KvStoreError::ValueTooLarge { size: 17, max: 16 }) => {}
+        res => panic!("expected ValueTooLarge, got {:?}", res),
+    }
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Writes above the size limits should be rejected while writes at the limits succeed
+#[test]
+fn size_limits() -> Result<()> {
+    let config = || Config::builder().max_key_size(8).max_value_size(16).build();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_config(temp_dir.path(), config()?)?;
+    check_size_limits(&store)?;
+    assert!(store
+        .set_stream("key1".to_owned(), &[0u8; 17][..], 17)
+        .is_err());
+    store.set_stream("key1".to_owned(), &[0u8; 16][..], 16)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_size_limits(&SledKvsEngine::open_with_config(
+        temp_dir.path(),
+        config()?,
+    )?)?;
+    check_size_limits(&MemoryKvsEngine::with_config(config()?))
+}
+
+// Engine should open the engine of the requested kind and dispatch to it
+#[test]
+fn engine_dispatch() -> Result<()> {
+    for kind in [EngineKind::Kvs, EngineKind::Sled] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let engine = Engine::open(kind, temp_dir.path())?;
+        assert_eq!(engine.kind(), kind);
+        check_scan(&engine)?;
+        assert_eq!(engine.len()?, 4);
+        drop(engine);
+
+        let engine = Engine::open(kind, temp_dir.path())?;
+        assert_eq!(engine.scan("".to_owned(), "".to_owned(), 10)?.len(), 4);
+    }
+    Ok(())
+}
+
+// Engines picked at runtime should be usable as trait objects
+#[test]
+fn dyn_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engines: Vec<Box<dyn KvsEngine>> = vec![
+        Box::new(KvStore::open(&temp_dir.path().join("kvs"))?),
+        Box::new(SledKvsEngine::open(&temp_dir.path().join("sled"))?),
+        Box::new(MemoryKvsEngine::new()),
+    ];
+    for engine in &engines {
+        engine.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(engine.len()?, 1);
+        engine.remove("key1".to_owned())?;
+        assert!(engine.is_empty()?);
+    }
+
+    let shared: Arc<dyn KvsEngine> = Arc::new(MemoryKvsEngine::new());
+    check_scan(&shared)
+}
+
+// Requesting a different engine than the existing data should be a typed error
+#[test]
+fn resolve_engine_mismatch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert_eq!(resolve_engine(temp_dir.path(), None)?, EngineKind::Kvs);
+    assert!(temp_dir.path().join("engine").join("kvs").exists());
+    assert_eq!(
+        resolve_engine(temp_dir.path(), Some(EngineKind::Kvs))?,
+        EngineKind::Kvs
+    );
+    match resolve_engine(temp_dir.path(), Some(EngineKind::Sled)) {
+        Err(KvStoreError::EngineMismatch {
+            existing,
+            requested,
+        }) => {
+            assert_eq!(existing, EngineKind::Kvs);
+            assert_eq!(requested, EngineKind::Sled);
+        }
+        res => panic!("unexpected result: {:?}", res),
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert_eq!(
+        resolve_engine(temp_dir.path(), Some(EngineKind::Sled))?,
+        EngineKind::Sled
+    );
+    assert_eq!(resolve_engine(temp_dir.path(), None)?, EngineKind::Sled);
+    Ok(())
+}
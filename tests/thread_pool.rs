@@ -1,5 +1,7 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use kvs::thread_pool::*;
 use kvs::Result;
@@ -46,12 +48,68 @@ fn spawn_panic_task<P: ThreadPool>() -> Result<()> {
     spawn_counter(pool)
 }
 
+fn spawn_exactly_once<P: ThreadPool>(pool: P) -> Result<()> {
+    const TASK_NUM: usize = 1000;
+
+    let wg = WaitGroup::new();
+    let runs: Arc<Vec<AtomicUsize>> =
+        Arc::new((0..TASK_NUM).map(|_| AtomicUsize::new(0)).collect());
+    for i in 0..TASK_NUM {
+        let runs = Arc::clone(&runs);
+        let wg = wg.clone();
+        pool.spawn(move || {
+            // Uneven job durations make idle workers steal
+            if i % 100 == 0 {
+                thread::sleep(Duration::from_millis(5));
+            }
+            runs[i].fetch_add(1, Ordering::SeqCst);
+            drop(wg);
+        })
+    }
+
+    wg.wait();
+    assert!(runs.iter().all(|runs| runs.load(Ordering::SeqCst) == 1));
+    Ok(())
+}
+
 #[test]
 fn naive_thread_pool_spawn_counter() -> Result<()> {
     let pool = NaiveThreadPool::new(4)?;
     spawn_counter(pool)
 }
 
+#[test]
+fn naive_thread_pool_cap() -> Result<()> {
+    let pool = NaiveThreadPool::new(2)?;
+    let running = Arc::new(AtomicUsize::new(0));
+    let max_running = Arc::new(AtomicUsize::new(0));
+    let wg = WaitGroup::new();
+    for _ in 0..20 {
+        let running = Arc::clone(&running);
+        let max_running = Arc::clone(&max_running);
+        let wg = wg.clone();
+        pool.spawn(move || {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            max_running.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(5));
+            running.fetch_sub(1, Ordering::SeqCst);
+            drop(wg);
+        })
+    }
+    wg.wait();
+    assert_eq!(max_running.load(Ordering::SeqCst), 2);
+    assert!(NaiveThreadPool::new(0).is_err());
+    assert!(SharedQueueThreadPool::new(0).is_err());
+    assert!(RayonThreadPool::new(0).is_err());
+    assert!(WorkStealingThreadPool::new(0).is_err());
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<NaiveThreadPool>()
+}
+
 #[test]
 fn shared_queue_thread_pool_spawn_counter() -> Result<()> {
     let pool = SharedQueueThreadPool::new(4)?;
@@ -64,7 +122,138 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
     spawn_counter(pool)
 }
 
+#[test]
+fn work_stealing_thread_pool_spawn_counter() -> Result<()> {
+    let pool = WorkStealingThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
 #[test]
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn work_stealing_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<WorkStealingThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_exactly_once() -> Result<()> {
+    spawn_exactly_once(SharedQueueThreadPool::new(4)?)
+}
+
+#[test]
+fn work_stealing_thread_pool_exactly_once() -> Result<()> {
+    spawn_exactly_once(WorkStealingThreadPool::new(4)?)
+}
+
+#[test]
+fn shared_queue_thread_pool_live_threads() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(4)?;
+    assert_eq!(pool.live_threads(), 4);
+
+    pool.spawn(|| {
+        panic_control::disable_hook_in_current_thread();
+        panic!();
+    });
+    let (sender, receiver) = std::sync::mpsc::channel();
+    pool.spawn(move || sender.send(()).unwrap());
+    receiver.recv().unwrap();
+
+    // The replacement of the panicked worker is counted before the worker exits
+    let start = Instant::now();
+    while pool.live_threads() != 4 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(10));
+    }
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_resize() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(2)?;
+    let wait_for_threads = |threads| {
+        let start = Instant::now();
+        while pool.live_threads() != threads {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+    };
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let spawn_jobs = |count| {
+        for _ in 0..count {
+            let sender = sender.clone();
+            pool.spawn(move || {
+                thread::sleep(Duration::from_millis(1));
+                sender.send(()).unwrap();
+            });
+        }
+    };
+
+    spawn_jobs(100);
+    pool.resize(8)?;
+    wait_for_threads(8);
+    spawn_jobs(100);
+    pool.resize(1)?;
+    spawn_jobs(100);
+    for _ in 0..300 {
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+    wait_for_threads(1);
+
+    assert!(pool.resize(0).is_err());
+    pool.resize(3)?;
+    wait_for_threads(3);
+    Ok(())
+}
+
+// Logged collects every record logged to it as its message followed by its key=value pairs
+#[derive(Clone, Default)]
+struct Logged(Arc<Mutex<Vec<String>>>);
+
+impl slog::Drain for Logged {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(
+        &self,
+        record: &slog::Record,
+        _values: &slog::OwnedKVList,
+    ) -> std::result::Result<(), slog::Never> {
+        let mut line = record.msg().to_string();
+        slog::KV::serialize(&record.kv(), record, &mut Pairs(&mut line)).unwrap();
+        self.0.lock().unwrap().push(line);
+        Ok(())
+    }
+}
+
+// Pairs appends the key=value pairs of a record to a line
+struct Pairs<'a>(&'a mut String);
+
+impl slog::Serializer for Pairs<'_> {
+    fn emit_arguments(&mut self, key: slog::Key, val: &std::fmt::Arguments) -> slog::Result {
+        self.0.push_str(&format!(" {}={}", key, val));
+        Ok(())
+    }
+}
+
+#[test]
+fn shared_queue_thread_pool_logs_panics() -> Result<()> {
+    let logged = Logged::default();
+    let pool =
+        SharedQueueThreadPool::with_logger(2, slog::Logger::root(logged.clone(), slog::o!()))?;
+    pool.spawn(|| {
+        panic_control::disable_hook_in_current_thread();
+        panic!("handler failed on request 7");
+    });
+
+    // The pool keeps running jobs after the panic
+    spawn_counter(pool)?;
+    let logged = logged.0.lock().unwrap();
+    assert_eq!(
+        *logged,
+        ["job panicked panic=handler failed on request 7".to_owned()]
+    );
+    Ok(())
+}